@@ -1,7 +1,7 @@
-use tokio::net::TcpStream;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, stdin};
 
 use crate::server::config::ClientConfig;
+use crate::client::utils::tls;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -9,9 +9,13 @@ async fn main() -> anyhow::Result<()> {
     let _ = dotenvy::dotenv();
     let client_config = ClientConfig::from_env();
     let addr = std::env::args().nth(1).unwrap_or_else(|| format!("{}:{}", client_config.default_host, client_config.default_port));
+    // Come nella GUI: `tls://host:port` forza TLS, altrimenti vale CLIENT_TLS
+    let (addr, tls_settings) = match addr.strip_prefix("tls://") {
+        Some(a) => (a.to_string(), Some(client_config.tls_settings())),
+        None => (addr.clone(), client_config.command_tls()),
+    };
     println!("[CLIENT] Benvenuto! Digita i comandi (es: /register user pass, /login user pass):");
-    let stream = TcpStream::connect(&addr).await?;
-    let (reader, writer) = stream.into_split();
+    let (reader, writer) = tls::connect(&addr, tls_settings.as_ref()).await?;
     let mut server_reader = BufReader::new(reader);
     let mut server_writer = BufWriter::new(writer);
     let mut input = BufReader::new(stdin());
@@ -31,13 +35,13 @@ async fn main() -> anyhow::Result<()> {
         let command = parts.next().unwrap_or("");
         let args: Vec<&str> = parts.collect();
         // Comandi che NON richiedono session_token
-    let public_cmds = ["/register", "/login", "/users", "/all_users", "/logout", "/help", "/quit"];
+    let public_cmds = ["/register", "/login", "/cert_login", "/users", "/all_users", "/logout", "/help", "/quit"];
         let friend_cmds = [
             "/send_friend_request", "/accept_friend_request", "/reject_friend_request",
             "/list_friends", "/received_friend_requests", "/sent_friend_requests"
         ];
         // Comandi di messaggistica che richiedono token ma hanno parsing speciale
-        let msg_cmds = ["/send", "/send_private", "/private", "/get_group_messages", "/get_private_messages", "/delete_group_messages", "/delete_private_messages"];
+        let msg_cmds = ["/send", "/send_private", "/private", "/get_group_messages", "/get_private_messages", "/delete_group_messages", "/delete_private_messages", "/edit_message", "/delete_message", "/react", "/unreact", "/reply_group_message", "/reply_private_message", "/get_thread", "/mark_private_read", "/mark_group_read"];
         let mut to_send = String::new();
         // Limite lunghezza messaggio
         if msg_cmds.contains(&command) && args.len() >= 2 {
@@ -98,11 +102,35 @@ async fn main() -> anyhow::Result<()> {
                         let message = &args[1..].join(" ");
                         to_send = format!("{} {} {} {}", command, token, user, message);
                     }
-                    "/get_group_messages" if args.len() == 1 => {
-                        to_send = format!("/get_group_messages {} {}", token, args[0]);
+                    // Paginazione opzionale: [limit] [before=<id>|after=<id>]
+                    "/get_group_messages" if (1..=3).contains(&args.len()) => {
+                        to_send = format!("/get_group_messages {} {}", token, args.join(" "));
                     }
-                    "/get_private_messages" if args.len() == 1 => {
-                        to_send = format!("/get_private_messages {} {}", token, args[0]);
+                    "/get_private_messages" if (1..=3).contains(&args.len()) => {
+                        to_send = format!("/get_private_messages {} {}", token, args.join(" "));
+                    }
+                    "/edit_message" if args.len() >= 2 => {
+                        to_send = format!("/edit_message {} {} {}", token, args[0], args[1..].join(" "));
+                    }
+                    // Risposta: destinatario, id del messaggio citato e testo
+                    "/reply_group_message" | "/reply_private_message" if args.len() >= 3 => {
+                        to_send = format!("{} {} {} {} {}", command, token, args[0], args[1], args[2..].join(" "));
+                    }
+                    "/get_thread" if args.len() == 1 => {
+                        to_send = format!("/get_thread {} {}", token, args[0]);
+                    }
+                    // Conferma di lettura: chat e, facoltativo, l'ultimo messaggio letto
+                    "/mark_private_read" | "/mark_group_read" if (1..=2).contains(&args.len()) => {
+                        to_send = format!("{} {} {}", command, token, args.join(" "));
+                    }
+                    "/react" if args.len() == 2 => {
+                        to_send = format!("/react {} {} {}", token, args[0], args[1]);
+                    }
+                    "/unreact" if args.len() == 1 => {
+                        to_send = format!("/unreact {} {}", token, args[0]);
+                    }
+                    "/delete_message" if args.len() == 1 => {
+                        to_send = format!("/delete_message {} {}", token, args[0]);
                     }
                     "/delete_group_messages" if args.len() == 1 => {
                         to_send = format!("/delete_group_messages {} {}", token, args[0]);
@@ -137,6 +165,10 @@ async fn main() -> anyhow::Result<()> {
             println!("[CLIENT] Server disconnesso");
             break;
         }
+        if tls_settings.is_none() && tls::is_tls_record(&server_line) {
+            println!("[CLIENT][ERROR] {}", tls::SERVER_REQUIRES_TLS);
+            break;
+        }
         let raw_response = server_line.trim().to_string();
         // Do not print raw server lines that may contain session tokens. Show sanitized messages instead.
         let cleaned = raw_response.split("SESSION:").next().map(|s| s.trim()).unwrap_or("");
@@ -149,7 +181,7 @@ async fn main() -> anyhow::Result<()> {
             println!("[SERVER] {}", cleaned);
         }
         // Estrai session_token dopo login
-        if command == "/login" && raw_response.contains("SESSION:") {
+        if (command == "/login" || command == "/cert_login") && raw_response.contains("SESSION:") {
             if let Some(line) = raw_response.lines().find(|l| l.contains("SESSION:")) {
                 if let Some(token) = line.split("SESSION:").nth(1) {
                     session_token = Some(token.trim().to_string());
@@ -1,8 +1,9 @@
-use iced::{Application, Command, Element, Theme};
-use crate::client::models::app_state::{AppState, ChatAppState};
+use iced::{Application, Command, Element, Subscription, Theme};
+use crate::client::models::app_state::{append_newer, newest_message_id, AppState, ChatAppState, IDLE_CHECK_SECS};
 use crate::client::models::messages::Message;
 use crate::client::services::chat_service::ChatService;
-use crate::client::services::message_parser::format_timestamp;
+use crate::common::protocol::{Command as ServerCommand, HistoryPage, ResponseData};
+use crate::common::error::ErrorCode;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use crate::client::utils::session_store;
@@ -20,7 +21,9 @@ impl Application for ChatApp {
 
     fn new(_flags: ()) -> (Self, Command<Message>) {
         // Create default app and attempt to auto-validate saved session token.
-        let chat_service = Arc::new(Mutex::new(ChatService::new()));
+        let mut service = ChatService::new();
+        service.set_tls(crate::server::config::ClientConfig::from_env().command_tls());
+        let chat_service = Arc::new(Mutex::new(service));
         let app = ChatApp {
             state: ChatAppState::default(),
             chat_service: chat_service.clone(),
@@ -37,23 +40,42 @@ impl Application for ChatApp {
                 // Use the app-level ChatService (persistent) to validate the saved session.
                 let svc = chat_service.clone();
                 let mut guard = svc.lock().await;
-                match guard.send_command(&host, format!("/validate_session {}", token)).await {
-                    Ok(response) => {
-                        if response.starts_with("OK:") {
-                            // Extract username from response for auto-login display
-                            let username = response.trim_start_matches("OK:").trim();
-                            Message::AuthResult { 
-                                success: true, 
-                                message: username.to_string(), 
-                                token: Some(token) 
-                            }
-                        } else {
+                match guard.request(&host, Some(&token), ServerCommand::ValidateSession).await {
+                    Ok(ResponseData::SessionValid { username }) => {
+                        // Username returned by the server for auto-login display
+                        Message::AuthResult { 
+                            success: true, 
+                            message: username, 
+                            token: Some(token) 
+                        }
+                    }
+                    Err(e) if e.code == ErrorCode::SessionExpired => {
+                        // Il token salvato non è più valido: rimuovilo
+                        let _ = session_store::clear_session_token();
+                        Message::SessionMissing
+                    }
+                    _ => Message::SessionMissing,
+                }
+        } else {
+                    let cfg = crate::server::config::ClientConfig::from_env();
+                    if !cfg.has_client_cert() {
+                        return Message::SessionMissing;
+                    }
+                    // Nessun token salvato ma certificato client configurato: login senza password (mutual TLS)
+                    let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+                    let mut guard = chat_service.lock().await;
+                    match guard.request(&host, None, ServerCommand::CertificateLogin).await {
+                        Ok(ResponseData::Session { username, session_token }) => {
+                            println!("[APP_START] Logged in with client certificate as {}", username);
+                            Message::AuthResult { success: true, message: username, token: Some(session_token) }
+                        }
+                        Ok(_) => Message::SessionMissing,
+                        Err(e) => {
+                            println!("[APP_START] Certificate login failed: {}", e);
                             Message::SessionMissing
                         }
                     }
-                    Err(_) => Message::SessionMissing,
                 }
-        } else { Message::SessionMissing }
             },
             |m| m,
         );
@@ -73,11 +95,8 @@ impl Application for ChatApp {
                 let password = self.state.password.clone();
                 // Resolve host selection: use ClientConfig from env for defaults
                 let cfg = crate::server::config::ClientConfig::from_env();
-                let host = match self.state.selected_host {
-                    crate::client::gui::views::registration::HostType::Localhost => format!("{}:{}", cfg.default_host, cfg.default_port),
-                    crate::client::gui::views::registration::HostType::Remote => format!("{}:{}", cfg.public_host, cfg.default_port),
-                    crate::client::gui::views::registration::HostType::Manual => self.state.manual_host.clone(),
-                };
+                let (host, use_tls) = self.state.selected_host.endpoint(&cfg, &self.state.manual_host);
+                let tls = use_tls.then(|| cfg.tls_settings());
                 let is_login = self.state.is_login;
                 self.state.loading = true;
                 self.state.error_message = None;
@@ -93,26 +112,21 @@ impl Application for ChatApp {
                     async move {
                         // Use the persistent ChatService stored in the app
                         let mut guard = svc_outer.lock().await;
-                        let cmd = if is_login {
-                            format!("/login {} {}", username, password)
+                        guard.set_tls(tls);
+                        let command = if is_login {
+                            ServerCommand::Login { username, password }
                         } else {
-                            format!("/register {} {}", username, password)
+                            ServerCommand::Register { username, password }
                         };
-                        match guard.send_command(&host, cmd).await {
-                            Ok(response) => {
-                                let token = response.lines().find_map(|l| {
-                                    if l.contains("SESSION:") {
-                                        Some(l.split("SESSION:").nth(1).map(|s| s.trim().to_string()).unwrap_or_default())
-                                    } else { None }
-                                });
-                                let cleaned = response.split("SESSION:").next().map(|s| s.trim().to_string()).unwrap_or_default();
-                                if response.contains("OK: Registered") || response.contains("OK: Logged in") {
-                                    Msg::AuthResult { success: true, message: cleaned, token }
-                                } else {
-                                    Msg::AuthResult { success: false, message: cleaned, token: None }
-                                }
+                        match guard.request(&host, None, command).await {
+                            Ok(ResponseData::Session { username, session_token }) => {
+                                Msg::AuthResult { success: true, message: username, token: Some(session_token) }
+                            }
+                            Ok(other) => Msg::AuthResult { success: false, message: format!("Unexpected response: {:?}", other), token: None },
+                            Err(e) if e.code == ErrorCode::Transport => {
+                                Msg::AuthResult { success: false, message: format!("Connessione fallita: {}", e), token: None }
                             }
-                            Err(e) => Msg::AuthResult { success: false, message: format!("Connessione fallita: {}", e), token: None },
+                            Err(e) => Msg::AuthResult { success: false, message: e.message, token: None },
                         }
                     },
                     |msg| msg,
@@ -133,24 +147,14 @@ impl Application for ChatApp {
                     if let Some(token) = token {
                         self.state.session_token = Some(token.clone());
                         
-                        // Estrai l'username dal messaggio - gestisci diversi formati
-                        let message_content = message.trim_start_matches("OK:").trim();
-                        let username = if message_content.starts_with("Logged in as ") {
-                            // Format: "Logged in as luigi"
-                            message_content.strip_prefix("Logged in as ").unwrap_or(message_content)
-                        } else if message_content.starts_with("Registered as ") {
-                            // Format: "Registered as luigi"  
-                            message_content.strip_prefix("Registered as ").unwrap_or(message_content)
-                        } else {
-                            // Fallback: assume the whole content is the username
-                            message_content
-                        };
+                        // In caso di successo il messaggio contiene l'username restituito dal server
+                        let username = message.as_str();
                         
-                        println!("🔴 [DEBUG] AuthResult in app.rs - message: '{}', extracted username: '{}'", message, username);
+                        println!("🔴 [DEBUG] AuthResult in app.rs - username: '{}'", username);
                         self.state.username = username.to_string();
                         
                         // Salva il token in modo sicuro
-                        crate::client::utils::session_store::save_session_token(&token);
+                        let _ = crate::client::utils::session_store::save_session_token(&token);
                         
                         // Imposta l'utente corrente nel ChatService
                         let svc = self.chat_service.clone();
@@ -165,9 +169,9 @@ impl Application for ChatApp {
                                 
                                 // Connetti il WebSocket
                                 let cfg = crate::server::config::ClientConfig::from_env();
-                                let ws_port = cfg.default_port + 1; // WebSocket su porta +1
+                                let ws_port = cfg.websocket_port; // Di default la stessa porta del server
                                 println!("[APP] Tentativo connessione WebSocket a {}:{}", cfg.default_host, ws_port);
-                                match guard.connect_websocket(&cfg.default_host, ws_port, &token_clone).await {
+                                match guard.connect_websocket(&cfg.default_host, ws_port, &token_clone, cfg.websocket_tls_settings()).await {
                                     Ok(()) => {
                                         println!("[APP] WebSocket connesso, avviando controllo messaggi");
                                         Msg::WebSocketConnected
@@ -187,7 +191,7 @@ impl Application for ChatApp {
                     self.state.logger.clear(); // Pulisci i messaggi precedenti
                     self.state.logger.push(LogMessage {
                         level: LogLevel::Error,
-                        message: message.trim_start_matches("ERR:").trim().to_string(),
+                        message: message.clone(),
                     });
                 }
                 
@@ -223,7 +227,12 @@ impl Application for ChatApp {
                     |msg| msg,
                 );
                 
-                return Command::batch(vec![cleanup_delay, websocket_loop]);
+                // Presenza salvata sul server (stato scelto e testo di stato)
+                let load_presence = Command::perform(async { Msg::LoadPresence }, |msg| msg);
+                // Non letti e ultimi messaggi di tutte le chat
+                let load_inbox = Command::perform(async { Msg::LoadInbox }, |msg| msg);
+
+                return Command::batch(vec![cleanup_delay, websocket_loop, load_presence, load_inbox]);
             }
             Msg::WebSocketError { error } => {
                 println!("[APP] Errore WebSocket: {}", error);
@@ -239,8 +248,8 @@ impl Application for ChatApp {
                 
                 return Command::perform(
                     async move {
-                        match svc.lock().await.get_private_messages(&cfg.default_host, &token, &username).await {
-                            Ok(messages) => Msg::NewMessagesReceived { with: username, messages },
+                        match svc.lock().await.get_private_messages(&cfg.default_host, &token, &username, HistoryPage::latest()).await {
+                            Ok((messages, _)) => Msg::NewMessagesReceived { with: username, messages },
                             Err(e) => {
                                 println!("[APP] Error loading initial messages for {}: {}", username, e);
                                 Msg::NewMessagesReceived { with: username, messages: vec![] }
@@ -250,7 +259,7 @@ impl Application for ChatApp {
                     |msg| msg,
                 );
             }
-            Msg::StartGroupMessagePolling { group_id } => {
+            Msg::StartGroupMessagePolling { group_id: _ } => {
                 // Group messages now use WebSocket real-time updates only (no polling)
                 self.state.group_polling_active = false;
                 return Command::<Message>::none();
@@ -268,7 +277,7 @@ impl Application for ChatApp {
                 self.state.loading_group_chats.remove(&group_id);
                 return Command::<Message>::none();
             }
-            Msg::TriggerImmediateGroupRefresh { group_id } => {
+            Msg::TriggerImmediateGroupRefresh { group_id: _ } => {
                 // Group messages now use WebSocket real-time updates only (no manual refresh needed)
                 return Command::<Message>::none();
             }
@@ -281,11 +290,14 @@ impl Application for ChatApp {
             Msg::NewMessagesReceived { with, messages } => {
                 println!("[APP] NewMessagesReceived for {}: {} messages", with, messages.len());
                 if self.state.polling_active {
-                    self.state.private_chats.insert(with.clone(), messages.to_vec());
+                    // Il polling scarica solo i messaggi successivi all'ultimo in cache
+                    let cached = self.state.private_chats.entry(with.clone()).or_default();
+                    append_newer(cached, messages.to_vec());
+                    let page = newest_message_id(cached).map(HistoryPage::after).unwrap_or_default();
                     // clear loading flag when messages arrive
                     self.state.loading_private_chats.remove(&with);
                     
-                    println!("[APP] Updated private_chats cache for {}, total cached: {}", with, messages.len());
+                    println!("[APP] Updated private_chats cache for {}, total cached: {}", with, cached.len());
                     
                     // Continue polling
                     let svc = self.chat_service.clone();
@@ -298,8 +310,8 @@ impl Application for ChatApp {
                         async move {
                             tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
                             let mut guard = svc.lock().await;
-                            match guard.get_private_messages(&host, &token, &username).await {
-                                Ok(messages) => {
+                            match guard.get_private_messages(&host, &token, &username, page).await {
+                                Ok((messages, _)) => {
                                     drop(guard);
                                     Msg::NewMessagesReceived { with: username.clone(), messages }
                                 }
@@ -320,11 +332,15 @@ impl Application for ChatApp {
                 let host = format!("{}:{}", cfg.default_host, cfg.default_port);
                 let token = self.state.session_token.clone().unwrap_or_default();
                 let svc = self.chat_service.clone();
+                let page = self.state.private_chats.get(&with)
+                    .and_then(|cached| newest_message_id(cached))
+                    .map(HistoryPage::after)
+                    .unwrap_or_default();
                     let with_cloned = with.clone();
                     return iced::Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            guard.get_private_messages(&host, &token, &with_cloned).await.unwrap_or_default()
+                            guard.get_private_messages(&host, &token, &with_cloned, page).await.map(|(messages, _)| messages).unwrap_or_default()
                         },
                         move |messages| Msg::NewMessagesReceived { with: with.clone(), messages }
                     );
@@ -339,7 +355,7 @@ impl Application for ChatApp {
                         // Only check for messages if WebSocket is connected
                         if guard.is_websocket_connected().await {
                             if let Some(ws_message) = guard.try_receive_websocket_message().await {
-                                return Msg::WebSocketMessageReceived(ws_message);
+                                return Msg::WebSocketMessageReceived(Box::new(ws_message));
                             }
                         }
                         
@@ -377,26 +393,39 @@ impl Application for ChatApp {
             return Command::perform(
                 async move {
                     let mut guard = svc.lock().await;
-                    let cmd = format!("/send_friend_request {} {} {}", token, username_clone, message_clone);
-                    match guard.send_command(&host, cmd).await {
-                        Ok(response) => {
-                            if response.starts_with("OK:") {
-                                Msg::FriendRequestResult { success: true, message: "Friend request sent successfully!".to_string() }
-                            } else {
-                                Msg::FriendRequestResult { success: false, message: response }
-                            }
-                        }
-                        Err(e) => Msg::FriendRequestResult { success: false, message: format!("Error: {}", e) }
+                    let command = ServerCommand::SendFriendRequest { to: username_clone, message: message_clone };
+                    match guard.request(&host, Some(&token), command).await {
+                        Ok(_) => Msg::FriendRequestResult { success: true, message: "Friend request sent successfully!".to_string() },
+                        Err(e) => Msg::FriendRequestResult { success: false, message: e.message }
                     }
                 },
                 |msg| msg,
             );
         }
         
-        self.state.update(message, &self.chat_service)
+        let state_update = self.state.update(message, &self.chat_service);
+        // Indicatore di scrittura: segue il testo in composizione e la chat aperta
+        Command::batch([state_update, self.state.sync_typing(&self.chat_service)])
+    }
+
+    /// Da loggati: attività di tastiera e mouse e controllo periodico dell'inattività,
+    /// per lo stato "away" automatico.
+    fn subscription(&self) -> Subscription<Message> {
+        if self.state.session_token.is_none() {
+            return Subscription::none();
+        }
+        Subscription::batch([
+            iced::event::listen_with(|event, _status| match event {
+                iced::Event::Keyboard(iced::keyboard::Event::KeyPressed { .. })
+                | iced::Event::Mouse(iced::mouse::Event::ButtonPressed(_))
+                | iced::Event::Mouse(iced::mouse::Event::WheelScrolled { .. }) => Some(Message::UserActivity),
+                _ => None,
+            }),
+            iced::time::every(std::time::Duration::from_secs(IDLE_CHECK_SECS)).map(|_| Message::CheckIdle),
+        ])
     }
 
-    fn view(&self) -> Element<Message> {
+    fn view(&self) -> Element<'_, Message> {
         match &self.state.app_state {
             AppState::CheckingSession => iced::widget::Text::new("Controllo sessione...").into(),
             AppState::Registration => crate::client::gui::views::registration::view(&self.state),
@@ -412,6 +441,7 @@ impl Application for ChatApp {
             AppState::MyGroupInvites => crate::client::gui::views::my_group_invites::view(&self.state),
             AppState::SendFriendRequest => crate::client::gui::views::send_friend_request::view(&self.state),
             AppState::ViewFriends => crate::client::gui::views::view_friends::view(&self.state),
+            AppState::Inbox => crate::client::gui::views::inbox::view(&self.state),
         }
     }
 }
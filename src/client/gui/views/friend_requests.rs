@@ -87,7 +87,7 @@ fn empty_state_appearance(_: &iced::Theme) -> iced::widget::container::Appearanc
     }
 }
 
-pub fn view(state: &ChatAppState) -> Element<Message> {
+pub fn view(state: &ChatAppState) -> Element<'_, Message> {
     // Top logger bar
     let logger_bar = if !state.logger.is_empty() {
         Container::new(logger_view(&state.logger))
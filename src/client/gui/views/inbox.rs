@@ -0,0 +1,211 @@
+use iced::{Element, Length, Alignment, Color, Font};
+use iced::widget::{Column, Row, Text, Button, Container, Space, Scrollable};
+use crate::client::models::messages::Message;
+use crate::client::models::app_state::ChatAppState;
+use crate::client::services::message_parser::format_timestamp;
+use crate::common::protocol::{ChatRef, InboxEntry};
+
+// Modern color palette consistent with other views
+const BG_MAIN: Color = Color::from_rgb(0.06, 0.07, 0.18);
+const CARD_BG: Color = Color::from_rgb(0.18, 0.19, 0.36);
+const INPUT_BG: Color = Color::from_rgb(0.12, 0.13, 0.26);
+const ACCENT_COLOR: Color = Color::from_rgb(0.0, 0.7, 0.3);
+const TEXT_PRIMARY: Color = Color::WHITE;
+const TEXT_SECONDARY: Color = Color::from_rgb(0.7, 0.7, 0.7);
+
+const EMOJI_FONT: Font = Font::with_name("Segoe UI Emoji");
+const BOLD_FONT: Font = Font {
+    family: iced::font::Family::SansSerif,
+    weight: iced::font::Weight::Bold,
+    ..Font::DEFAULT
+};
+
+fn bg_main_appearance(_: &iced::Theme) -> iced::widget::container::Appearance {
+    iced::widget::container::Appearance {
+        background: Some(iced::Background::Color(BG_MAIN)),
+        text_color: Some(TEXT_PRIMARY),
+        ..Default::default()
+    }
+}
+
+fn header_appearance(_: &iced::Theme) -> iced::widget::container::Appearance {
+    iced::widget::container::Appearance {
+        background: Some(iced::Background::Color(INPUT_BG)),
+        text_color: Some(TEXT_PRIMARY),
+        shadow: iced::Shadow {
+            offset: iced::Vector::new(0.0, 2.0),
+            blur_radius: 8.0,
+            color: Color::from_rgba(0.0, 0.0, 0.0, 0.2),
+        },
+        ..Default::default()
+    }
+}
+
+fn chat_item_appearance(_: &iced::Theme) -> iced::widget::container::Appearance {
+    iced::widget::container::Appearance {
+        background: Some(iced::Background::Color(CARD_BG)),
+        text_color: Some(TEXT_PRIMARY),
+        border: iced::Border {
+            width: 1.0,
+            color: Color::from_rgb(0.2, 0.2, 0.3),
+            radius: 12.0.into(),
+        },
+        ..Default::default()
+    }
+}
+
+fn badge_appearance(_: &iced::Theme) -> iced::widget::container::Appearance {
+    iced::widget::container::Appearance {
+        background: Some(iced::Background::Color(ACCENT_COLOR)),
+        text_color: Some(TEXT_PRIMARY),
+        border: iced::Border {
+            radius: 10.0.into(),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// Pallino con il numero di messaggi non letti (nessuno se zero).
+pub fn unread_badge<'a>(unread: u32) -> Option<Element<'a, Message>> {
+    (unread > 0).then(|| {
+        Container::new(Text::new(unread.to_string()).font(BOLD_FONT).size(12))
+            .padding([2, 8])
+            .style(iced::theme::Container::Custom(Box::new(badge_appearance)))
+            .into()
+    })
+}
+
+fn chat_item(entry: &InboxEntry) -> Element<'_, Message> {
+    let (icon, title, open) = match &entry.chat {
+        ChatRef::Private { with } => ("👤", with.as_str(), Message::OpenPrivateChat(with.clone())),
+        ChatRef::Group { group_id, name } => ("👥", name.as_str(), Message::OpenGroupChat(group_id.clone(), name.clone())),
+    };
+    let preview = match &entry.last_message {
+        Some(last) if last.deleted => format!("{}: messaggio eliminato", last.sender),
+        Some(last) => format!("{}: {}", last.sender, last.snippet),
+        None => "Nessun messaggio".to_string(),
+    };
+    let title_font = if entry.unread > 0 { BOLD_FONT } else { Font::DEFAULT };
+
+    let details = Column::new()
+        .spacing(4)
+        .width(Length::Fill)
+        .push(Text::new(title).font(title_font).size(16).style(TEXT_PRIMARY))
+        .push(Text::new(preview).size(13).style(TEXT_SECONDARY));
+    let side = Column::new()
+        .spacing(6)
+        .align_items(Alignment::End)
+        .push_maybe(entry.last_message.as_ref().map(|last| Text::new(format_timestamp(last.sent_at)).size(12).style(TEXT_SECONDARY)))
+        .push_maybe(unread_badge(entry.unread));
+
+    Button::new(
+        Container::new(
+            Row::new()
+                .spacing(16)
+                .align_items(Alignment::Center)
+                .push(Text::new(icon).font(EMOJI_FONT).size(24))
+                .push(details)
+                .push(side)
+        )
+        .padding(16)
+        .width(Length::Fill)
+        .style(iced::theme::Container::Custom(Box::new(chat_item_appearance)))
+    )
+    .style(iced::theme::Button::Text)
+    .on_press(open)
+    .padding(0)
+    .width(Length::Fill)
+    .into()
+}
+
+pub fn view(state: &ChatAppState) -> Element<'_, Message> {
+    let back_button = Button::new(
+        Container::new(
+            Row::new()
+                .spacing(8)
+                .align_items(Alignment::Center)
+                .push(Text::new("←").font(EMOJI_FONT).size(18))
+                .push(Text::new("Back").font(BOLD_FONT).size(14))
+        )
+        .width(Length::Fill)
+        .center_x()
+    )
+    .style(iced::theme::Button::Secondary)
+    .on_press(Message::OpenMainActions)
+    .padding(12)
+    .width(Length::Fixed(100.0));
+
+    let unread_total: u32 = state.inbox.values().map(|entry| entry.unread).sum();
+    let subtitle = match unread_total {
+        0 => "No unread messages".to_string(),
+        1 => "1 unread message".to_string(),
+        n => format!("{} unread messages", n),
+    };
+    let title_section = Column::new()
+        .spacing(4)
+        .align_items(Alignment::Center)
+        .push(
+            Row::new()
+                .spacing(8)
+                .align_items(Alignment::Center)
+                .push(Text::new("📥").font(EMOJI_FONT).size(24))
+                .push(Text::new("Inbox").font(BOLD_FONT).size(24).style(TEXT_PRIMARY))
+        )
+        .push(Text::new(subtitle).size(14).style(TEXT_SECONDARY));
+
+    let header = Container::new(
+        Row::new()
+            .spacing(16)
+            .align_items(Alignment::Center)
+            .push(back_button)
+            .push(Container::new(title_section).width(Length::Fill).center_x())
+            .push(Space::new(Length::Fixed(100.0), Length::Fixed(0.0)))
+    )
+    .padding([20, 24])
+    .width(Length::Fill)
+    .style(iced::theme::Container::Custom(Box::new(header_appearance)));
+
+    let entries = state.inbox_entries();
+    let content: Element<Message> = if state.loading_inbox && entries.is_empty() {
+        Container::new(Text::new("Loading chats...").font(BOLD_FONT).size(16).style(TEXT_SECONDARY))
+            .width(Length::Fill)
+            .center_x()
+            .padding(40)
+            .into()
+    } else if entries.is_empty() {
+        Container::new(
+            Column::new()
+                .spacing(16)
+                .align_items(Alignment::Center)
+                .push(Text::new("📭").font(EMOJI_FONT).size(48).style(TEXT_SECONDARY))
+                .push(Text::new("No conversations yet").font(BOLD_FONT).size(20).style(TEXT_SECONDARY))
+                .push(Text::new("Start a private chat or join a group to see it here.").size(14).style(TEXT_SECONDARY))
+        )
+        .width(Length::Fill)
+        .center_x()
+        .padding(40)
+        .into()
+    } else {
+        let chats = entries.into_iter().fold(Column::new().spacing(12), |column, entry| column.push(chat_item(entry)));
+        Container::new(Scrollable::new(chats).width(Length::Fill).height(Length::Fill))
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .padding([0, 24])
+            .into()
+    };
+
+    let main_content = Column::new()
+        .push(header)
+        .push(Space::new(Length::Fill, Length::Fixed(16.0)))
+        .push(content)
+        .push(Space::new(Length::Fill, Length::Fixed(24.0)))
+        .width(Length::Fill)
+        .height(Length::Fill);
+
+    Container::new(main_content)
+        .width(Length::Fill)
+        .height(Length::Fill)
+        .style(iced::theme::Container::Custom(Box::new(bg_main_appearance)))
+        .into()
+}
@@ -1,7 +1,8 @@
 use iced::{Element, Length, Alignment, Color, Font};
-use iced::widget::{Column, Row, Text, Button, Container, Space};
+use iced::widget::{Column, Row, Text, Button, Container, Space, TextInput};
 use crate::client::models::messages::Message;
 use crate::client::models::app_state::ChatAppState;
+use crate::common::protocol::{LastSeenVisibility, PresenceStatus};
 use crate::client::gui::views::logger::logger_view;
 
 // Modern color palette consistent with registration.rs
@@ -72,7 +73,7 @@ fn header_appearance(_: &iced::Theme) -> iced::widget::container::Appearance {
 }
 
 // Build a modern action card with icon, title, detail and buttons
-fn action_card<'a>(icon: &'a str, title: &'a str, detail: &'a str, btn_label: &'a str, action: Message, secondary: Option<(&'a str, Message)>) -> Element<'a, Message> {
+fn action_card<'a>(icon: &'a str, title: &'a str, detail: impl Into<std::borrow::Cow<'a, str>>, btn_label: &'a str, action: Message, secondary: Option<(&'a str, Message)>) -> Element<'a, Message> {
     let title_row = Row::new()
         .spacing(if title == "Invites" { 8 } else { 12 })
         .align_items(Alignment::Center)
@@ -125,7 +126,7 @@ fn action_card<'a>(icon: &'a str, title: &'a str, detail: &'a str, btn_label: &'
         .into()
 }
 
-pub fn view(state: &ChatAppState) -> Element<Message> {
+pub fn view(state: &ChatAppState) -> Element<'_, Message> {
     // Modern header with title and logout button
     let logout_button = Button::new(
         Container::new(
@@ -174,7 +175,82 @@ pub fn view(state: &ChatAppState) -> Element<Message> {
     .center_x()
     .padding([0, 24, 16, 24]);
 
+    // Presenza visibile agli amici: stato scelto e testo di stato
+    let presence_button = |label: &'static str, status: PresenceStatus| {
+        let style = if state.my_presence == status { iced::theme::Button::Primary } else { iced::theme::Button::Secondary };
+        Button::new(Text::new(label).size(13))
+            .style(style)
+            .on_press(Message::SetPresence { status })
+            .padding([6, 12])
+    };
+    let away_label = if state.auto_away { "Away (auto)" } else { "Away" };
+    let last_seen_button = |label: &'static str, visibility: LastSeenVisibility| {
+        let style = if state.last_seen_visibility == visibility { iced::theme::Button::Primary } else { iced::theme::Button::Secondary };
+        Button::new(Text::new(label).size(12))
+            .style(style)
+            .on_press(Message::SetLastSeenVisibility { visibility })
+            .padding([4, 10])
+    };
+    let presence_bar = Container::new(
+        Column::new()
+            .spacing(8)
+            .align_items(Alignment::Center)
+            .push(
+                Row::new()
+                    .spacing(8)
+                    .push(presence_button("Online", PresenceStatus::Online))
+                    .push(presence_button(away_label, PresenceStatus::Away))
+                    .push(presence_button("Busy", PresenceStatus::Busy))
+                    .push(presence_button("Invisible", PresenceStatus::Invisible))
+            )
+            .push(
+                Row::new()
+                    .spacing(8)
+                    .align_items(Alignment::Center)
+                    .push(
+                        TextInput::new("Status message...", &state.status_message_input)
+                            .on_input(Message::StatusMessageInputChanged)
+                            .on_submit(Message::SubmitStatusMessage)
+                            .padding(8)
+                            .width(Length::Fixed(280.0))
+                    )
+                    .push(
+                        Button::new(Text::new("Set").size(13))
+                            .style(iced::theme::Button::Secondary)
+                            .on_press(Message::SubmitStatusMessage)
+                            .padding([8, 12])
+                    )
+            )
+            .push(
+                Row::new()
+                    .spacing(6)
+                    .align_items(Alignment::Center)
+                    .push(Text::new("Last seen visible to:").size(12).style(TEXT_SECONDARY))
+                    .push(last_seen_button("Everyone", LastSeenVisibility::Everyone))
+                    .push(last_seen_button("Friends", LastSeenVisibility::Friends))
+                    .push(last_seen_button("Nobody", LastSeenVisibility::Nobody))
+            )
+    )
+    .width(Length::Fill)
+    .center_x()
+    .padding([0, 24, 16, 24]);
+
     // Action cards with modern styling
+    let unread_chats = state.inbox.values().filter(|entry| entry.unread > 0).count();
+    let unread_total: u32 = state.inbox.values().map(|entry| entry.unread).sum();
+    let inbox_detail = match unread_total {
+        0 => "No unread messages".to_string(),
+        n => format!("{} unread message{} in {} chat{}", n, if n == 1 { "" } else { "s" }, unread_chats, if unread_chats == 1 { "" } else { "s" }),
+    };
+    let inbox_card = action_card(
+        "📥",
+        "Inbox",
+        inbox_detail,
+        "Open Inbox",
+        Message::OpenInbox,
+        None
+    );
+
     let users_card = action_card(
         "👤",
         "Users",
@@ -215,6 +291,7 @@ pub fn view(state: &ChatAppState) -> Element<Message> {
     let cards_container = Column::new()
         .spacing(20)
         .padding([0, 24])
+        .push(inbox_card)
         .push(users_card)
         .push(groups_card)
         .push(invites_card)
@@ -234,6 +311,7 @@ pub fn view(state: &ChatAppState) -> Element<Message> {
     let main_content = Column::new()
         .push(header)
         .push(user_info)
+        .push(presence_bar)
         .push(
             iced::widget::scrollable(cards_container)
                 .width(Length::Fill)
@@ -10,4 +10,5 @@ pub mod my_groups;
 pub mod invite_to_group;
 pub mod my_group_invites;
 pub mod send_friend_request;
-pub mod view_friends;
\ No newline at end of file
+pub mod view_friends;
+pub mod inbox;
\ No newline at end of file
@@ -2,6 +2,7 @@ use iced::{Element, Length, Alignment, Color, Font};
 use iced::widget::{Column, Row, Text, Button, Container, Space, Scrollable};
 use crate::client::models::messages::Message;
 use crate::client::models::app_state::ChatAppState;
+use crate::client::gui::views::inbox::unread_badge;
 
 // Modern color palette consistent with other views
 const BG_MAIN: Color = Color::from_rgb(0.06, 0.07, 0.18);
@@ -86,7 +87,7 @@ fn empty_state_appearance(_: &iced::Theme) -> iced::widget::container::Appearanc
     }
 }
 
-pub fn view(state: &ChatAppState) -> Element<Message> {
+pub fn view(state: &ChatAppState) -> Element<'_, Message> {
     // Modern header with back button and title
     let back_button = Button::new(
         Container::new(
@@ -222,6 +223,7 @@ pub fn view(state: &ChatAppState) -> Element<Message> {
                             .push(Text::new(group_name).font(BOLD_FONT).size(16).style(TEXT_PRIMARY))
                     )
                     .push(Space::new(Length::Fill, Length::Fixed(0.0)))
+                    .push_maybe(unread_badge(state.unread(&format!("group_{}", group_id))))
                     .push(
                         Row::new()
                             .spacing(8)
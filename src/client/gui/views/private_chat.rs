@@ -2,6 +2,9 @@ use iced::{Element, Length, Alignment, Color, Font};
 use iced::widget::{Column, Row, Text, TextInput, Button, Container, Scrollable, Space, scrollable};
 use crate::client::models::messages::Message;
 use crate::client::models::app_state::{ChatAppState};
+use crate::client::gui::widgets::{reactions, reply};
+use crate::client::services::message_parser::format_last_seen;
+use crate::common::protocol::PresenceStatus;
 
 // Color palette per chat moderna (WhatsApp-like)
 const BG_MAIN: Color = Color::from_rgb(0.06, 0.07, 0.18); // Deep navy
@@ -28,8 +31,16 @@ pub fn view<'a>(state: &'a ChatAppState, username: &'a str) -> Element<'a, Messa
         .style(iced::theme::Button::Secondary)
         .padding(8);
 
+    // Sotto il nome: chi sta scrivendo, altrimenti la presenza o l'ultimo accesso
+    let subtitle = state.typing_label(username).or_else(|| {
+        state.peer_presence.get(username).map(|peer| match (peer.status, peer.last_seen_at) {
+            (PresenceStatus::Offline | PresenceStatus::Invisible, Some(last_seen)) => format!("last seen {}", format_last_seen(last_seen)),
+            (status, _) => status.as_str().to_string(),
+        })
+    });
     let user_info = Column::new()
         .push(Text::new(username).font(BOLD_FONT).size(20).style(TEXT_PRIMARY))
+        .push_maybe(subtitle.map(|subtitle| Text::new(subtitle).size(12).style(TEXT_SECONDARY)))
         .spacing(2);
 
     let discard_btn = Button::new(Text::new("🗑️").font(EMOJI_FONT).size(16))
@@ -60,13 +71,14 @@ pub fn view<'a>(state: &'a ChatAppState, username: &'a str) -> Element<'a, Messa
     // Input area
     let input_area = build_input_area(state, username);
 
-    // Layout principale
-    let content = Column::new()
+    // Layout principale, con la discussione aperta in un pannello laterale
+    let main = Column::new()
         .push(header)
         .push(messages_area)
         .push(input_area)
         .width(Length::Fill)
         .height(Length::Fill);
+    let content = Row::new().push(main).push_maybe(reply::thread_panel(state));
 
     Container::new(content)
         .width(Length::Fill)
@@ -85,10 +97,24 @@ fn build_messages_area<'a>(state: &'a ChatAppState, username: &'a str) -> Elemen
 
     // Check if messages are discarded for this user
 
+    // Storico paginato: controllo per le pagine più vecchie (utile anche se la chat non scorre)
+    if let Some(paging) = state.private_paging.get(username).filter(|p| p.has_older) {
+        let older: Element<'a, Message> = if paging.loading_older {
+            Text::new("Caricamento messaggi precedenti...").size(12).style(TEXT_SECONDARY).into()
+        } else {
+            Button::new(Text::new("Carica messaggi precedenti").size(12))
+                .on_press(Message::LoadOlderPrivateMessages { with: username.to_string() })
+                .style(iced::theme::Button::Secondary)
+                .padding([4, 10])
+                .into()
+        };
+        messages_column = messages_column.push(Container::new(older).width(Length::Fill).center_x());
+    }
+
     // Show cached messages or appropriate placeholder
     if let Some(chat_messages) = state.private_chats.get(username) {
         // Only print count, not individual messages to reduce spam
-        if chat_messages.len() > 0 {
+        if !chat_messages.is_empty() {
             // println!("[PRIVATE_CHAT_VIEW] Found {} cached messages for {}", chat_messages.len(), username);
         }
         if chat_messages.is_empty() {
@@ -103,10 +129,15 @@ fn build_messages_area<'a>(state: &'a ChatAppState, username: &'a str) -> Elemen
                 .padding(20)
             );
         } else {
-            for (i, msg) in chat_messages.iter().enumerate() {
+            // I messaggi ritirati per tutti non vengono mostrati
+            for msg in chat_messages.iter().filter(|m| !m.deleted) {
                 // println!("[PRIVATE_CHAT_VIEW] Message {}: {} -> {}", i, msg.sender, msg.content);
                 let is_my_message = msg.sender == state.username;
-                let message_bubble = create_message_bubble(msg, is_my_message);
+                let picker_open = msg.id.is_some() && state.reaction_picker == msg.id;
+                let reactions = reactions::view(msg, &state.username, picker_open);
+                // Risposta, o messaggio a cui qualcuno ha risposto: fa parte di una discussione
+                let in_thread = msg.reply_to.is_some() || (msg.id.is_some() && chat_messages.iter().any(|m| m.reply_to == msg.id));
+                let message_bubble = create_message_bubble(msg, is_my_message, in_thread, reactions);
                 messages_column = messages_column.push(message_bubble);
             }
         }
@@ -145,7 +176,15 @@ fn build_messages_area<'a>(state: &'a ChatAppState, username: &'a str) -> Elemen
     let scrollable_messages = Scrollable::new(messages_column)
             .width(Length::Fill)
             .height(Length::Fill)
-            .id(scrollable::Id::new("messages_scroll"));
+            .id(scrollable::Id::new("messages_scroll"))
+            // Arrivati in cima si richiede la pagina precedente dello storico
+            .on_scroll(move |viewport| {
+                if viewport.relative_offset().y <= 0.0 {
+                    Message::LoadOlderPrivateMessages { with: username.to_string() }
+                } else {
+                    Message::NoOp
+                }
+            });
 
     Container::new(scrollable_messages)
     .width(Length::Fill)
@@ -159,13 +198,15 @@ fn build_messages_area<'a>(state: &'a ChatAppState, username: &'a str) -> Elemen
     .into()
 }
 
-fn create_message_bubble(msg: &crate::client::models::app_state::ChatMessage, is_my_message: bool) -> Element<'_, Message> {
+fn create_message_bubble<'a>(msg: &'a crate::client::models::app_state::ChatMessage, is_my_message: bool, in_thread: bool, reactions: Option<Element<'a, Message>>) -> Element<'a, Message> {
     let bubble_color = if is_my_message { MY_MESSAGE_BG } else { OTHER_MESSAGE_BG };
 
     let message_content = Column::new()
+        .push_maybe(msg.quoted.as_ref().map(reply::quote))
         .push(Text::new(&msg.content).size(14).style(TEXT_PRIMARY))
         .push(Space::new(Length::Fixed(0.0), Length::Fixed(4.0)))
-        .push(Text::new(&msg.formatted_time).size(10).style(TEXT_SECONDARY))
+        .push(message_footer(msg, is_my_message, in_thread))
+        .push_maybe(reactions)
         .spacing(2);
 
     let bubble = Container::new(message_content)
@@ -195,14 +236,79 @@ fn create_message_bubble(msg: &crate::client::models::app_state::ChatMessage, is
         .into()
 }
 
+/// Orario (con l'indicazione di modifica), lo stato di consegna dei propri messaggi, i pulsanti di discussione, risposta e reazione e,
+/// sui propri messaggi già confermati, quelli di modifica ed eliminazione.
+fn message_footer(msg: &crate::client::models::app_state::ChatMessage, is_my_message: bool, in_thread: bool) -> Element<'_, Message> {
+    let time = match msg.edited_at {
+        Some(_) => format!("{} · modificato", msg.formatted_time),
+        None => msg.formatted_time.clone(),
+    };
+    // Stato dei propri messaggi: inviato, consegnato, letto
+    let receipt = match (is_my_message && msg.id.is_some(), msg.delivered_to, msg.read_by) {
+        (false, _, _) => None,
+        (true, _, read) if read > 0 => Some("✓✓ letto"),
+        (true, delivered, _) if delivered > 0 => Some("✓✓"),
+        (true, _, _) => Some("✓"),
+    };
+    let mut footer = Row::new()
+        .spacing(6)
+        .align_items(Alignment::Center)
+        .push(Text::new(time).size(10).style(TEXT_SECONDARY))
+        .push_maybe(receipt.map(|r| Text::new(r).size(10).style(TEXT_SECONDARY)))
+        .push(Space::new(Length::Fill, Length::Fixed(0.0)));
+    if let (false, Some(id)) = (msg.is_pending, msg.id) {
+        if in_thread {
+            footer = footer.push(
+                Button::new(Text::new("🧵").font(EMOJI_FONT).size(10))
+                    .on_press(Message::OpenThread { id })
+                    .style(iced::theme::Button::Text)
+                    .padding(0),
+            );
+        }
+        footer = footer.push(
+            Button::new(Text::new("↩").size(10))
+                .on_press(Message::StartReply { id })
+                .style(iced::theme::Button::Text)
+                .padding(0),
+        );
+        footer = footer.push(
+            Button::new(Text::new("🙂").font(EMOJI_FONT).size(10))
+                .on_press(Message::ToggleReactionPicker { id })
+                .style(iced::theme::Button::Text)
+                .padding(0),
+        );
+    }
+    if let (true, Some(id)) = (is_my_message, msg.id) {
+        footer = footer.push(
+            Button::new(Text::new("✏️").font(EMOJI_FONT).size(10))
+                .on_press(Message::StartEditMessage { id, content: msg.content.clone() })
+                .style(iced::theme::Button::Text)
+                .padding(0),
+        );
+        footer = footer.push(
+            Button::new(Text::new("🗑").font(EMOJI_FONT).size(10))
+                .on_press(Message::DeleteMessage { id })
+                .style(iced::theme::Button::Text)
+                .padding(0),
+        );
+    }
+    footer.into()
+}
+
 fn build_input_area<'a>(state: &'a ChatAppState, username: &'a str) -> Element<'a, Message> {
     // Create the TextInput and wrap it in a Container to reproduce the
     // desired background, border and radius without implementing a
     // custom `text_input::StyleSheet` trait. This keeps the style while
     // avoiding trait mismatch issues across iced versions.
+    // In modifica l'invio salva il nuovo testo del messaggio selezionato
+    let submit = if state.editing_message.is_some() {
+        Message::SubmitEditMessage
+    } else {
+        Message::SendPrivateMessage { to: username.to_string() }
+    };
     let raw_input = TextInput::new("Scrivi un messaggio...", &state.current_message_input)
         .on_input(Message::MessageInputChanged)
-        .on_submit(Message::SendPrivateMessage { to: username.to_string() })
+        .on_submit(submit.clone())
         .padding(12)
         .size(14)
         .width(Length::Fill);
@@ -222,8 +328,9 @@ fn build_input_area<'a>(state: &'a ChatAppState, username: &'a str) -> Element<'
             }
         })));
 
-    let send_button = Button::new(Text::new("Invia").size(14))
-        .on_press(Message::SendPrivateMessage { to: username.to_string() })
+    let send_label = if state.editing_message.is_some() { "Salva" } else { "Invia" };
+    let send_button = Button::new(Text::new(send_label).size(14))
+        .on_press(submit)
         .style(iced::theme::Button::Primary)
         .padding([12, 16]);
 
@@ -233,7 +340,25 @@ fn build_input_area<'a>(state: &'a ChatAppState, username: &'a str) -> Element<'
         .push(message_input)
         .push(send_button);
 
-    Container::new(input_row)
+    let mut input_column = Column::new().spacing(6);
+    if state.editing_message.is_some() {
+        input_column = input_column.push(
+            Row::new()
+                .spacing(8)
+                .align_items(Alignment::Center)
+                .push(Text::new("Modifica messaggio").size(12).style(TEXT_SECONDARY))
+                .push(Space::new(Length::Fill, Length::Fixed(0.0)))
+                .push(
+                    Button::new(Text::new("Annulla").size(12))
+                        .on_press(Message::CancelEditMessage)
+                        .style(iced::theme::Button::Secondary)
+                        .padding([4, 10]),
+                ),
+        );
+    }
+    let input_column = input_column.push_maybe(reply::reply_bar(state)).push(input_row);
+
+    Container::new(input_column)
         .padding([12, 16])
         .width(Length::Fill)
         .style(iced::theme::Container::Custom(Box::new(|_: &iced::Theme| {
@@ -3,6 +3,7 @@ use iced::widget::{Column, Row, Text, TextInput, Button, PickList, Container, Sp
 use crate::client::models::messages::Message;
 use crate::client::models::app_state::ChatAppState;
 use crate::client::gui::views::logger::logger_view;
+use crate::server::config::ClientConfig;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum HostType {
@@ -29,8 +30,26 @@ impl HostType {
     pub fn all() -> &'static [HostType] {
         &ALL_HOSTS
     }
+
+    /// Indirizzo `host:port` da contattare e se usare TLS.
+    /// Gli host predefiniti seguono `CLIENT_TLS`; per `Manual` decide il prefisso `tls://`.
+    pub fn endpoint(self, cfg: &ClientConfig, manual_host: &str) -> (String, bool) {
+        match self {
+            HostType::Localhost => (format!("{}:{}", cfg.default_host, cfg.default_port), cfg.use_tls),
+            HostType::Remote => (format!("{}:{}", cfg.public_host, cfg.default_port), cfg.use_tls),
+            HostType::Manual => {
+                let manual_host = manual_host.trim();
+                match manual_host.strip_prefix(TLS_PREFIX) {
+                    Some(addr) => (addr.to_string(), true),
+                    None => (manual_host.to_string(), false),
+                }
+            }
+        }
+    }
 }
 
+const TLS_PREFIX: &str = "tls://";
+
 // Consistent color palette with main_actions and private_chat
 const BG_MAIN: Color = Color::from_rgb(0.06, 0.07, 0.18); // Deep navy
 const CARD_BG: Color = Color::from_rgb(0.18, 0.19, 0.36); // Muted indigo for card bodies
@@ -116,7 +135,7 @@ fn host_selector_appearance(_: &iced::Theme) -> iced::widget::container::Appeara
     }
 }
 
-pub fn view(state: &ChatAppState) -> Element<Message> {
+pub fn view(state: &ChatAppState) -> Element<'_, Message> {
     let username = &state.username;
     let password = &state.password;
     let selected_host = state.selected_host;
@@ -183,7 +202,7 @@ pub fn view(state: &ChatAppState) -> Element<Message> {
                 )
                 .push(
                     Container::new(
-                        TextInput::new("host:port (e.g., 127.0.0.1:5000)", manual_host)
+                        TextInput::new("host:port or tls://host:port (e.g., 127.0.0.1:5000)", manual_host)
                             .on_input(Message::ManualHostChanged)
                             .on_submit(if submit_enabled { Message::SubmitLoginOrRegister } else { Message::None })
                             .width(Length::Fill)
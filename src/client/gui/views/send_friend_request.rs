@@ -104,7 +104,7 @@ fn user_item_appearance(_: &iced::Theme) -> iced::widget::container::Appearance
     }
 }
 
-pub fn view(state: &ChatAppState) -> Element<Message> {
+pub fn view(state: &ChatAppState) -> Element<'_, Message> {
     // Top logger bar
     let logger_bar = if !state.logger.is_empty() {
         Container::new(logger_view(&state.logger))
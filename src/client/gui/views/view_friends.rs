@@ -2,6 +2,9 @@ use iced::{Element, Length, Alignment, Color, Font};
 use iced::widget::{Column, Row, Text, Button, Container, Space, Scrollable};
 use crate::client::models::messages::Message;
 use crate::client::models::app_state::ChatAppState;
+use crate::common::protocol::PresenceStatus;
+use crate::client::services::message_parser::format_last_seen;
+use crate::client::gui::views::inbox::unread_badge;
 use crate::client::gui::views::logger::logger_view;
 
 // Modern color palette consistent with other views
@@ -10,6 +13,9 @@ const CARD_BG: Color = Color::from_rgb(0.18, 0.19, 0.36);
 const INPUT_BG: Color = Color::from_rgb(0.12, 0.13, 0.26);
 const TEXT_PRIMARY: Color = Color::WHITE;
 const TEXT_SECONDARY: Color = Color::from_rgb(0.7, 0.7, 0.7);
+const ONLINE_COLOR: Color = Color::from_rgb(0.0, 0.7, 0.3);
+const AWAY_COLOR: Color = Color::from_rgb(0.95, 0.7, 0.2);
+const BUSY_COLOR: Color = Color::from_rgb(0.9, 0.3, 0.3);
 
 const EMOJI_FONT: Font = Font::with_name("Segoe UI Emoji");
 const BOLD_FONT: Font = Font {
@@ -87,7 +93,7 @@ fn empty_state_appearance(_: &iced::Theme) -> iced::widget::container::Appearanc
     }
 }
 
-pub fn view(state: &ChatAppState) -> Element<Message> {
+pub fn view(state: &ChatAppState) -> Element<'_, Message> {
     // Top logger bar
     let logger_bar = if !state.logger.is_empty() {
         Container::new(logger_view(&state.logger))
@@ -196,7 +202,19 @@ pub fn view(state: &ChatAppState) -> Element<Message> {
         // Friends list
         let mut friends_column = Column::new().spacing(12);
         
-        for friend_username in &state.friends_list {
+        for friend in &state.friends_list {
+            let friend_username = &friend.username;
+            let presence = match friend.status {
+                PresenceStatus::Online => Text::new("● Online").size(12).style(ONLINE_COLOR),
+                PresenceStatus::Away => Text::new("● Away").size(12).style(AWAY_COLOR),
+                PresenceStatus::Busy => Text::new("● Busy").size(12).style(BUSY_COLOR),
+                PresenceStatus::Invisible | PresenceStatus::Offline => match friend.last_seen_at {
+                    Some(last_seen) => Text::new(format!("○ Offline · last seen {}", format_last_seen(last_seen))).size(12).style(TEXT_SECONDARY),
+                    None => Text::new("○ Offline").size(12).style(TEXT_SECONDARY),
+                },
+            };
+            let status_message = friend.status_message.as_ref()
+                .map(|message| Text::new(format!("“{}”", message)).size(12).style(TEXT_SECONDARY));
             let friend_item = Container::new(
                 Row::new()
                     .spacing(16)
@@ -221,9 +239,11 @@ pub fn view(state: &ChatAppState) -> Element<Message> {
                         Column::new()
                             .spacing(4)
                             .push(Text::new(friend_username).font(BOLD_FONT).size(16).style(TEXT_PRIMARY))
-                            .push(Text::new("Friend").size(12).style(TEXT_SECONDARY))
+                            .push(presence)
+                            .push_maybe(status_message)
                     )
                     .push(Space::new(Length::Fill, Length::Fixed(0.0)))
+                    .push_maybe(unread_badge(state.unread(friend_username)))
                     .push(
                         Button::new(
                             Container::new(
@@ -1,3 +1,5 @@
 pub mod alert;
 pub mod message_list;
 pub mod input_section;
+pub mod reactions;
+pub mod reply;
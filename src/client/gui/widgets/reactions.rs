@@ -0,0 +1,48 @@
+// Widget per le reazioni sotto un messaggio
+use iced::{Element, Font};
+use iced::widget::{Button, Column, Row, Text};
+use crate::client::models::app_state::ChatMessage;
+use crate::client::models::messages::Message;
+
+/// Emoji proposte dal selettore delle reazioni
+pub const QUICK_REACTIONS: [&str; 6] = ["👍", "❤️", "😂", "😮", "😢", "🙏"];
+
+const EMOJI_FONT: Font = Font::with_name("Segoe UI Emoji");
+
+/// Chip delle reazioni di un messaggio (evidenziate quelle dell'utente) e, se aperto, il
+/// selettore. Un clic aggiunge la reazione o, se è già la propria, la toglie.
+pub fn view<'a>(msg: &ChatMessage, username: &str, picker_open: bool) -> Option<Element<'a, Message>> {
+    let id = msg.id?;
+    if msg.reactions.is_empty() && !picker_open {
+        return None;
+    }
+
+    let mut chips = Row::new().spacing(4);
+    for reaction in &msg.reactions {
+        let style = if reaction.users.iter().any(|u| u == username) {
+            iced::theme::Button::Primary
+        } else {
+            iced::theme::Button::Secondary
+        };
+        chips = chips.push(
+            Button::new(Text::new(format!("{} {}", reaction.emoji, reaction.count)).font(EMOJI_FONT).size(11))
+                .on_press(Message::ToggleReaction { id, emoji: reaction.emoji.clone() })
+                .style(style)
+                .padding([2, 6]),
+        );
+    }
+
+    let mut column = Column::new().spacing(4).push(chips);
+    if picker_open {
+        let picker = QUICK_REACTIONS.iter().fold(Row::new().spacing(4), |row, emoji| {
+            row.push(
+                Button::new(Text::new(*emoji).font(EMOJI_FONT).size(14))
+                    .on_press(Message::ToggleReaction { id, emoji: emoji.to_string() })
+                    .style(iced::theme::Button::Text)
+                    .padding([2, 4]),
+            )
+        });
+        column = column.push(picker);
+    }
+    Some(column.into())
+}
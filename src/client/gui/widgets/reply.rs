@@ -0,0 +1,121 @@
+// Widget per risposte e discussioni: citazione, barra di risposta e pannello laterale
+use iced::{Alignment, Color, Element, Font, Length};
+use iced::widget::{Button, Column, Container, Row, Scrollable, Space, Text};
+use crate::client::models::app_state::ChatAppState;
+use crate::client::models::messages::Message;
+use crate::common::protocol::QuotedMessage;
+
+const PANEL_BG: Color = Color::from_rgb(0.10, 0.11, 0.24);
+const QUOTE_BG: Color = Color::from_rgba(0.0, 0.0, 0.0, 0.25);
+const TEXT_PRIMARY: Color = Color::WHITE;
+const TEXT_SECONDARY: Color = Color::from_rgb(0.7, 0.7, 0.7);
+
+const BOLD_FONT: Font = Font {
+    family: iced::font::Family::SansSerif,
+    weight: iced::font::Weight::Bold,
+    ..Font::DEFAULT
+};
+
+fn snippet_text(quoted: &QuotedMessage) -> String {
+    if quoted.deleted {
+        "Messaggio eliminato".to_string()
+    } else {
+        quoted.snippet.clone()
+    }
+}
+
+/// Messaggio citato in cima a una risposta; un clic apre la discussione.
+pub fn quote<'a>(quoted: &QuotedMessage) -> Element<'a, Message> {
+    let content = Column::new()
+        .spacing(2)
+        .push(Text::new(quoted.sender.clone()).font(BOLD_FONT).size(11).style(TEXT_PRIMARY))
+        .push(Text::new(snippet_text(quoted)).size(11).style(TEXT_SECONDARY));
+    let boxed = Container::new(content)
+        .padding([4, 8])
+        .width(Length::Fill)
+        .style(iced::theme::Container::Custom(Box::new(|_: &iced::Theme| {
+            iced::widget::container::Appearance {
+                background: Some(iced::Background::Color(QUOTE_BG)),
+                border: iced::Border { radius: 6.0.into(), ..Default::default() },
+                ..Default::default()
+            }
+        })));
+    Button::new(boxed)
+        .on_press(Message::OpenThread { id: quoted.id })
+        .style(iced::theme::Button::Text)
+        .padding(0)
+        .into()
+}
+
+/// Barra sopra il campo di input mentre si risponde a un messaggio.
+pub fn reply_bar<'a>(state: &ChatAppState) -> Option<Element<'a, Message>> {
+    let quoted = state.replying_to.as_ref()?;
+    Some(
+        Row::new()
+            .spacing(8)
+            .align_items(Alignment::Center)
+            .push(Text::new(format!("Risposta a {}: {}", quoted.sender, snippet_text(quoted))).size(12).style(TEXT_SECONDARY))
+            .push(Space::new(Length::Fill, Length::Fixed(0.0)))
+            .push(
+                Button::new(Text::new("Annulla").size(12))
+                    .on_press(Message::CancelReply)
+                    .style(iced::theme::Button::Secondary)
+                    .padding([4, 10]),
+            )
+            .into(),
+    )
+}
+
+/// Pannello laterale con la discussione aperta (radice e risposte in ordine).
+pub fn thread_panel<'a>(state: &ChatAppState) -> Option<Element<'a, Message>> {
+    let thread = state.thread.as_ref()?;
+
+    let header = Row::new()
+        .align_items(Alignment::Center)
+        .push(Text::new("Discussione").font(BOLD_FONT).size(16).style(TEXT_PRIMARY))
+        .push(Space::new(Length::Fill, Length::Fixed(0.0)))
+        .push(
+            Button::new(Text::new("✕").size(14))
+                .on_press(Message::CloseThread)
+                .style(iced::theme::Button::Secondary)
+                .padding([4, 8]),
+        );
+
+    let mut list = Column::new().spacing(10);
+    if thread.loading {
+        list = list.push(Text::new("Caricamento discussione...").size(13).style(TEXT_SECONDARY));
+    }
+    for msg in &thread.messages {
+        let content = if msg.deleted { "Messaggio eliminato".to_string() } else { msg.content.clone() };
+        let highlight = if msg.id == Some(thread.message_id) { TEXT_PRIMARY } else { TEXT_SECONDARY };
+        list = list.push(
+            Column::new()
+                .spacing(2)
+                .push(
+                    Row::new()
+                        .spacing(6)
+                        .push(Text::new(msg.sender.clone()).font(BOLD_FONT).size(12).style(highlight))
+                        .push(Text::new(msg.formatted_time.clone()).size(10).style(TEXT_SECONDARY)),
+                )
+                .push(Text::new(content).size(13).style(TEXT_PRIMARY)),
+        );
+    }
+
+    let panel = Column::new()
+        .spacing(12)
+        .padding([12, 16])
+        .push(header)
+        .push(Scrollable::new(list).height(Length::Fill));
+    Some(
+        Container::new(panel)
+            .width(Length::Fixed(300.0))
+            .height(Length::Fill)
+            .style(iced::theme::Container::Custom(Box::new(|_: &iced::Theme| {
+                iced::widget::container::Appearance {
+                    background: Some(iced::Background::Color(PANEL_BG)),
+                    ..Default::default()
+                }
+            })))
+            .into(),
+    )
+}
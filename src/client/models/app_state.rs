@@ -6,6 +6,9 @@ use crate::client::services::chat_service::ChatService;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use iced::Command;
+use crate::common::protocol::{ChatRef, Command as ServerCommand, GroupInfo, HistoryPage, InboxEntry, LastSeenVisibility, PresenceStatus, QuotedMessage, ReactionCount, ResponseData, UserInfo};
+use crate::common::error::ErrorCode;
+use crate::client::services::chat_service::error_code;
 use iced::widget::scrollable;
 
 #[derive(Debug, Clone, PartialEq, Default)]
@@ -25,6 +28,7 @@ pub enum AppState {
     MyGroupInvites,
     SendFriendRequest,
     ViewFriends,
+    Inbox,
 }
 
 // Helper function to extract username from friend request action messages
@@ -42,8 +46,28 @@ fn extract_username_from_friend_action(message: &str) -> Option<String> {
     }
     None
 }
+
+// Helper function to turn the typed group list into (id, name, member_count) rows
+fn groups_to_rows(groups: Vec<GroupInfo>, member_count: usize) -> Vec<(String, String, usize)> {
+    groups.into_iter().map(|g| (g.id, g.name, member_count)).collect()
+}
+
+// Helper function to extract the confirmation text from an acknowledgement
+fn ack_message(response: ResponseData) -> String {
+    match response {
+        ResponseData::Ack { message } => message,
+        other => format!("{:?}", other),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ChatMessage {
+    /// Id assegnato dal server (None per i messaggi locali non ancora confermati)
+    pub id: Option<i64>,
+    /// Numero di sequenza nella chat, per ordinare i messaggi confermati
+    pub seq: Option<i64>,
+    /// UUID scelto dal client all'invio, usato per la deduplicazione e la conferma
+    pub client_msg_id: Option<String>,
     pub sender: String,
     pub content: String,
     pub timestamp: i64,
@@ -51,9 +75,185 @@ pub struct ChatMessage {
     pub sent_at: i64,
     /// True if this is a temporary local message awaiting server confirmation
     pub is_pending: bool,
+    /// Orario dell'ultima modifica del testo, se modificato
+    pub edited_at: Option<i64>,
+    /// Messaggio ritirato per tutti: non viene più mostrato
+    pub deleted: bool,
+    pub reactions: Vec<ReactionCount>,
+    /// Messaggio a cui risponde e il suo estratto
+    pub reply_to: Option<i64>,
+    pub quoted: Option<QuotedMessage>,
+    /// Conferme dei propri messaggi: destinatari che li hanno ricevuti e letti
+    pub delivered_to: u32,
+    pub read_by: u32,
 }
 
-#[derive(Debug, Clone)]
+/// Pannello laterale con la discussione di un messaggio.
+#[derive(Debug, Clone, Default)]
+pub struct ThreadPanel {
+    /// Messaggio da cui è stata aperta la discussione
+    pub message_id: i64,
+    pub messages: Vec<ChatMessage>,
+    pub loading: bool,
+}
+
+/// Stato della paginazione dello storico di una chat.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HistoryPaging {
+    /// Il server ha messaggi più vecchi di quelli in cache
+    pub has_older: bool,
+    /// È in corso il caricamento di una pagina più vecchia
+    pub loading_older: bool,
+}
+
+/// Id del messaggio più vecchio in cache, cursore per la pagina precedente.
+pub fn oldest_message_id(messages: &[ChatMessage]) -> Option<i64> {
+    messages.iter().filter_map(|m| m.id).min()
+}
+
+/// Id del messaggio più recente in cache, cursore per scaricare solo le novità.
+pub fn newest_message_id(messages: &[ChatMessage]) -> Option<i64> {
+    messages.iter().filter_map(|m| m.id).max()
+}
+
+/// Inserisce in testa una pagina di messaggi più vecchi, scartando quelli già presenti.
+/// Restituisce quanti messaggi sono stati aggiunti.
+pub fn prepend_older(cached: &mut Vec<ChatMessage>, older: Vec<ChatMessage>) -> usize {
+    let oldest = oldest_message_id(cached);
+    let older: Vec<ChatMessage> = older
+        .into_iter()
+        .filter(|m| match (m.id, oldest) {
+            (Some(id), Some(oldest)) => id < oldest,
+            _ => true,
+        })
+        .collect();
+    let added = older.len();
+    cached.splice(0..0, older);
+    added
+}
+
+/// Accoda i messaggi più recenti dell'ultimo in cache, scartando quelli già presenti.
+pub fn append_newer(cached: &mut Vec<ChatMessage>, newer: Vec<ChatMessage>) {
+    let newest = newest_message_id(cached);
+    cached.extend(newer.into_iter().filter(|m| match (m.id, newest) {
+        (Some(id), Some(newest)) => id > newest,
+        _ => true,
+    }));
+}
+
+/// Dopo l'inserimento in testa di `added` messaggi riporta la vista (circa) sul messaggio
+/// che era in cima, così un nuovo scroll verso l'alto richiede la pagina successiva.
+fn keep_scroll_position(id: &'static str, added: usize, total: usize) -> Command<Message> {
+    scrollable::snap_to(
+        scrollable::Id::new(id),
+        scrollable::RelativeOffset { x: 0.0, y: added as f32 / total as f32 },
+    )
+}
+
+/// Aggiorna il testo di un messaggio modificato dal mittente. Restituisce `false` se il
+/// messaggio non è in cache (verrà caricato già modificato con lo storico).
+pub fn apply_edit(messages: &mut [ChatMessage], id: i64, content: &str, edited_at: i64) -> bool {
+    // Anche le risposte mostrano l'estratto aggiornato
+    for quote in messages.iter_mut().filter_map(|m| m.quoted.as_mut()).filter(|q| q.id == id) {
+        quote.snippet = QuotedMessage::snippet(content);
+    }
+    match messages.iter_mut().find(|m| m.id == Some(id)) {
+        Some(msg) => {
+            msg.content = content.to_string();
+            msg.edited_at = Some(edited_at);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Chiave locale di una chat dell'inbox: l'username per le chat private, `group_<id>` per i gruppi.
+pub fn inbox_key(chat: &ChatRef) -> String {
+    match chat {
+        ChatRef::Private { with } => with.clone(),
+        ChatRef::Group { group_id, .. } => format!("group_{}", group_id),
+    }
+}
+
+/// Segna un messaggio come ritirato e ne scarta testo e reazioni. Restituisce `false` se il
+/// messaggio non è in cache.
+pub fn apply_delete(messages: &mut [ChatMessage], id: i64) -> bool {
+    for quote in messages.iter_mut().filter_map(|m| m.quoted.as_mut()).filter(|q| q.id == id) {
+        quote.snippet.clear();
+        quote.deleted = true;
+    }
+    match messages.iter_mut().find(|m| m.id == Some(id)) {
+        Some(msg) => {
+            msg.content.clear();
+            msg.reactions.clear();
+            msg.deleted = true;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Sostituisce le reazioni di un messaggio. Restituisce `false` se il messaggio non è in cache.
+pub fn apply_reactions(messages: &mut [ChatMessage], id: i64, reactions: &[ReactionCount]) -> bool {
+    match messages.iter_mut().find(|m| m.id == Some(id)) {
+        Some(msg) => {
+            msg.reactions = reactions.to_vec();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Ogni quanti secondi rinnovare `start_typing` mentre l'utente scrive (il server lo fa
+/// scadere dopo `TYPING_EXPIRY_SECS`)
+const TYPING_REFRESH_SECS: i64 = 3;
+
+/// Inattività dopo cui la presenza passa automaticamente ad "away"
+pub const AUTO_AWAY_SECS: i64 = 300;
+/// Intervallo del controllo dell'inattività
+pub const IDLE_CHECK_SECS: u64 = 30;
+
+/// Aggiorna le conferme di consegna e lettura di un messaggio. Restituisce `false` se il
+/// messaggio non è in cache.
+pub fn apply_receipt(messages: &mut [ChatMessage], id: i64, delivered_to: u32, read_by: u32) -> bool {
+    match messages.iter_mut().find(|m| m.id == Some(id)) {
+        Some(msg) => {
+            msg.delivered_to = delivered_to;
+            msg.read_by = read_by;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Inserisce un messaggio confermato dal server (con id): ignora i duplicati, sostituisce il
+/// messaggio locale in attesa (stesso `client_msg_id`, o stesso mittente e testo) e lo colloca
+/// in base alla sequenza. Restituisce `false` se il messaggio era già presente.
+pub fn insert_confirmed(messages: &mut Vec<ChatMessage>, msg: ChatMessage) -> bool {
+    if msg.id.is_some() && messages.iter().any(|m| m.id == msg.id) {
+        return false;
+    }
+    let pending = match &msg.client_msg_id {
+        Some(client_msg_id) => messages.iter().position(|m| m.is_pending && m.client_msg_id.as_ref() == Some(client_msg_id)),
+        None => messages.iter().position(|m| m.is_pending && m.sender == msg.sender && m.content == msg.content),
+    };
+    if let Some(pending) = pending {
+        messages.remove(pending);
+    }
+    // Dopo l'ultimo messaggio confermato con sequenza minore (i locali in attesa restano in coda)
+    let position = match msg.seq {
+        Some(seq) => messages
+            .iter()
+            .rposition(|m| m.seq.is_some_and(|s| s < seq))
+            .map(|i| i + 1)
+            .unwrap_or_else(|| if messages.iter().any(|m| m.seq.is_some()) { 0 } else { messages.len() }),
+        None => messages.len(),
+    };
+    messages.insert(position, msg);
+    true
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct ChatAppState {
     pub app_state: AppState,
     pub username: String,
@@ -69,6 +269,22 @@ pub struct ChatAppState {
     pub users_search_query: String,
     pub users_search_results: Vec<String>,
     pub current_message_input: String,
+    /// Messaggio in modifica: il campo di input contiene il nuovo testo
+    pub editing_message: Option<i64>,
+    /// Messaggio su cui è aperto il selettore delle reazioni
+    pub reaction_picker: Option<i64>,
+    /// Messaggio citato dal prossimo invio
+    pub replying_to: Option<QuotedMessage>,
+    pub thread: Option<ThreadPanel>,
+    /// Ultimo messaggio segnato come letto per chat (username, o `group_<id>`)
+    pub read_up_to: HashMap<String, i64>,
+    /// Chi sta scrivendo in ciascuna chat (stesse chiavi di `read_up_to`)
+    pub typing_users: HashMap<String, Vec<String>>,
+    /// Non letti e anteprima dell'ultimo messaggio di ogni chat (stesse chiavi di `read_up_to`)
+    pub inbox: HashMap<String, InboxEntry>,
+    pub loading_inbox: bool,
+    /// Chat in cui stiamo scrivendo e orario dell'ultimo `start_typing` inviato
+    pub typing_sent: Option<(String, i64)>,
     pub private_chats: HashMap<String, Vec<ChatMessage>>,
     pub loading_private_chats: std::collections::HashSet<String>,
     /// Track the latest timestamp loaded via HTTP for each chat to avoid WebSocket duplicates
@@ -77,6 +293,9 @@ pub struct ChatAppState {
     /// Track if WebSocket message polling is active
     pub websocket_polling_active: bool,
     pub group_chats: HashMap<String, Vec<ChatMessage>>,
+    /// Paginazione dello storico per chat privata (username) e di gruppo (group_id)
+    pub private_paging: HashMap<String, HistoryPaging>,
+    pub group_paging: HashMap<String, HistoryPaging>,
     pub loading_group_chats: std::collections::HashSet<String>,
     pub group_polling_active: bool,
     pub create_group_name: String,
@@ -85,48 +304,124 @@ pub struct ChatAppState {
     pub loading_groups: bool,
     pub my_group_invites: Vec<(i64, String, String)>, // (invite_id, group_name, invited_by)
     pub loading_invites: bool,
-    pub friends_list: Vec<String>,
+    /// Amici con la loro presenza, aggiornata dagli eventi del WebSocket
+    pub friends_list: Vec<UserInfo>,
+    /// Presenza scelta e testo di stato, come salvati sul server
+    pub my_presence: PresenceStatus,
+    pub my_status_message: Option<String>,
+    pub status_message_input: String,
+    /// Chi può vedere il nostro ultimo accesso
+    pub last_seen_visibility: LastSeenVisibility,
+    /// Presenza degli interlocutori delle chat private aperte, per username
+    pub peer_presence: HashMap<String, UserInfo>,
+    /// Stato "away" impostato dall'inattività: la prossima attività riporta online
+    pub auto_away: bool,
+    /// Ultima attività dell'utente (tastiera o mouse), in secondi
+    pub last_activity: i64,
     pub friend_requests: Vec<(String, String)>, // (username, message)
 }
 
-impl Default for ChatAppState {
-    fn default() -> Self {
-        Self {
-            app_state: AppState::default(),
-            username: String::new(),
-            password: String::new(),
-            selected_host: HostType::default(),
-            manual_host: String::new(),
-            is_login: false,
-            loading: false,
-            error_message: None,
-            session_token: None,
-            show_password: false,
-            logger: Vec::new(),
-            users_search_query: String::new(),
-            users_search_results: Vec::new(),
-            current_message_input: String::new(),
-            private_chats: HashMap::new(),
-            loading_private_chats: std::collections::HashSet::new(),
-            last_http_timestamp: HashMap::new(),
-            polling_active: false,
-            websocket_polling_active: false,
-            group_chats: HashMap::new(),
-            loading_group_chats: std::collections::HashSet::new(),
-            group_polling_active: false,
-            create_group_name: String::new(),
-            selected_participants: std::collections::HashSet::new(),
-            my_groups: Vec::new(),
-            loading_groups: false,
-            my_group_invites: Vec::new(),
-            loading_invites: false,
-            friends_list: Vec::new(),
-            friend_requests: Vec::new(),
+impl ChatAppState {
+    /// Carica presenza e ultimo accesso di un interlocutore (`UserInfoLoaded`).
+    fn load_user_info(&self, username: &str, chat_service: &Arc<Mutex<ChatService>>) -> Command<Message> {
+        let Some(token) = self.session_token.clone() else { return Command::none() };
+        let svc = chat_service.clone();
+        let username = username.to_string();
+        let cfg = crate::server::config::ClientConfig::from_env();
+        let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+        Command::perform(
+            async move {
+                match svc.lock().await.get_user(&host, &token, &username).await {
+                    Ok(user) => Message::UserInfoLoaded { user },
+                    Err(e) => Message::LogError(format!("Presenza di {} non disponibile: {}", username, e)),
+                }
+            },
+            |msg| msg,
+        )
+    }
+
+    /// Invia la presenza al server; la risposta aggiorna lo stato locale.
+    fn send_presence(&self, status: PresenceStatus, message: Option<String>, chat_service: &Arc<Mutex<ChatService>>) -> Command<Message> {
+        let Some(token) = self.session_token.clone() else { return Command::none() };
+        let svc = chat_service.clone();
+        let cfg = crate::server::config::ClientConfig::from_env();
+        let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+        Command::perform(
+            async move {
+                match svc.lock().await.set_presence(&host, &token, status, message).await {
+                    Ok(message) => Message::PresenceSet { status, message },
+                    Err(e) => Message::LogError(format!("Presenza non aggiornata: {}", e)),
+                }
+            },
+            |msg| msg,
+        )
+    }
+
+    /// Messaggi non letti di una chat.
+    pub fn unread(&self, chat_key: &str) -> u32 {
+        self.inbox.get(chat_key).map_or(0, |entry| entry.unread)
+    }
+
+    /// Chat dell'inbox, dalla più recente (le chat vuote in fondo).
+    pub fn inbox_entries(&self) -> Vec<&InboxEntry> {
+        let mut entries: Vec<&InboxEntry> = self.inbox.values().collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.last_message.as_ref().map(|m| m.id)));
+        entries
+    }
+
+    /// Testo per l'intestazione della chat: chi sta scrivendo, se qualcuno.
+    pub fn typing_label(&self, chat_key: &str) -> Option<String> {
+        match self.typing_users.get(chat_key).map(Vec::as_slice) {
+            None | Some([]) => None,
+            Some([user]) => Some(format!("{} sta scrivendo…", user)),
+            Some(users) => Some(format!("{} stanno scrivendo…", users.join(", "))),
         }
     }
-}
 
-impl ChatAppState {
+    /// Invia `start_typing`/`stop_typing` in base al testo in composizione nella chat aperta;
+    /// mentre si continua a scrivere lo start viene rinnovato ogni `TYPING_REFRESH_SECS`,
+    /// prima che scada sul server.
+    pub fn sync_typing(&mut self, chat_service: &Arc<Mutex<ChatService>>) -> Command<Message> {
+        let composing = match &self.app_state {
+            AppState::PrivateChat(with) => Some(with.clone()),
+            AppState::GroupChat(group_id, _) => Some(format!("group_{}", group_id)),
+            _ => None,
+        }.filter(|_| self.editing_message.is_none() && !self.current_message_input.trim().is_empty());
+        let now = chrono::Utc::now().timestamp();
+        let mut frames = Vec::new();
+        if let Some((chat_key, sent_at)) = self.typing_sent.take() {
+            match &composing {
+                Some(current) if *current == chat_key && now - sent_at < TYPING_REFRESH_SECS => {
+                    self.typing_sent = Some((chat_key, sent_at));
+                    return Command::none();
+                }
+                Some(current) if *current == chat_key => {}
+                _ => frames.push((chat_key, false)),
+            }
+        }
+        if let Some(chat_key) = composing {
+            self.typing_sent = Some((chat_key.clone(), now));
+            frames.push((chat_key, true));
+        }
+        if frames.is_empty() {
+            return Command::none();
+        }
+        let svc = chat_service.clone();
+        Command::perform(
+            async move {
+                let guard = svc.lock().await;
+                for (chat_key, typing) in frames {
+                    match chat_key.strip_prefix("group_") {
+                        Some(group_id) => guard.send_typing(None, Some(group_id), typing).await,
+                        None => guard.send_typing(Some(&chat_key), None, typing).await,
+                    }
+                }
+                Message::NoOp
+            },
+            |msg| msg,
+        )
+    }
+
     pub fn update(&mut self, message: Message, chat_service: &Arc<Mutex<ChatService>>) -> Command<Message> {
         use crate::client::gui::views::logger::{LogMessage, LogLevel};
         use crate::client::utils::session_store;
@@ -174,51 +469,10 @@ impl ChatAppState {
                                     // Failed to save session token to secure store; ignore (non-fatal)
                                 }
                         
-                        // Extract username from success message for auto-login cases
-                        if message.starts_with("OK:") {
-                            println!("🟡 [DEBUG] Processing server response: '{}'", message);
-                            let username_part = message.trim_start_matches("OK:").trim();
-                            println!("🟡 [DEBUG] After removing 'OK:': '{}'", username_part);
-                            
-                            // Check for different response formats:
-                            let actual_username = if username_part.contains("Logged in as") {
-                                // Format: "Logged in as luigi SESSION: ..."
-                                if let Some(after_logged_in) = username_part.strip_prefix("Logged in as ") {
-                                    // Extract just the username part before " SESSION:"
-                                    let username_only = after_logged_in.split(" SESSION:").next()
-                                        .unwrap_or(after_logged_in)
-                                        .trim();
-                                    println!("🟡 [DEBUG] Extracted from 'Logged in as': '{}'", username_only);
-                                    username_only
-                                } else {
-                                    // Fallback: split by "logged in as" and take the last part
-                                    let extracted = username_part.split("logged in as").last()
-                                        .map(|s| s.trim())
-                                        .unwrap_or("");
-                                    println!("🟡 [DEBUG] Fallback extraction: '{}'", extracted);
-                                    extracted
-                                }
-                            } else {
-                                // Format: just the username
-                                println!("🟡 [DEBUG] No 'Logged in as' found, using whole part: '{}'", username_part);
-                                username_part
-                            };
-                            
-                            println!("🟡 [DEBUG] Final extracted username: '{}'", actual_username);
-                            println!("🟡 [DEBUG] Current username state: '{}'", self.username);
-                            println!("🟡 [DEBUG] Should set username? (actual_username not empty: {}, self.username empty: {})", 
-                                    !actual_username.is_empty(), self.username.is_empty());
-                            
-                            if !actual_username.is_empty() && self.username.is_empty() {
-                                println!("🟡 [DEBUG] Setting username from server response to: '{}'", actual_username);
-                                self.username = actual_username.to_string();
-                                println!("🟡 [DEBUG] After setting - username: '{}'", self.username);
-                            } else {
-                                println!("🟡 [DEBUG] NOT setting username from server (actual_username: '{}', username empty: {})", 
-                                        actual_username, self.username.is_empty());
-                            }
-                        } else {
-                            println!("🟡 [DEBUG] Server response does not start with 'OK:': '{}'", message);
+                        // On success the message carries the username returned by the server
+                        if !message.is_empty() && self.username.is_empty() {
+                            println!("🟡 [DEBUG] Setting username from server response to: '{}'", message);
+                            self.username = message.clone();
                         }
                     }
                     println!("🟢 [DEBUG] About to transition to MainActions - username: '{}'", self.username);
@@ -241,7 +495,7 @@ impl ChatAppState {
                         Command::perform(
                             async move {
                                 let mut guard = ws_svc.lock().await;
-                                match guard.connect_websocket(&ws_config.websocket_host, ws_config.websocket_port, &ws_token).await {
+                                match guard.connect_websocket(&ws_config.websocket_host, ws_config.websocket_port, &ws_token, ws_config.websocket_tls_settings()).await {
                                     Ok(_) => Message::WebSocketConnected,
                                     Err(e) => Message::WebSocketError { error: format!("WebSocket connection failed: {}", e) }
                                 }
@@ -280,6 +534,25 @@ impl ChatAppState {
                 self.app_state = AppState::Registration;
                 self.logger.clear(); // Clear any previous messages
             }
+            Message::SessionExpired => {
+                // Token rifiutato dal server: torna al login senza chiamare /logout
+                let _ = session_store::clear_session_token();
+                self.session_token = None;
+                self.password.clear();
+                self.polling_active = false;
+                self.group_polling_active = false;
+                self.websocket_polling_active = false;
+                self.private_chats.clear();
+                self.loading_private_chats.clear();
+                self.group_chats.clear();
+                self.loading_group_chats.clear();
+                self.app_state = AppState::Registration;
+                self.logger.clear();
+                self.logger.push(LogMessage {
+                    level: LogLevel::Error,
+                    message: "Sessione scaduta, effettua di nuovo il login".to_string(),
+                });
+            }
             Message::Logout => {
                 // Clear session token from secure storage
                 let _ = session_store::clear_session_token();
@@ -314,6 +587,12 @@ impl ChatAppState {
                 self.session_token = None;
                 self.username.clear();
                 self.password.clear();
+                self.my_presence = PresenceStatus::Online;
+                self.my_status_message = None;
+                self.last_seen_visibility = LastSeenVisibility::default();
+                self.peer_presence.clear();
+                self.inbox.clear();
+                self.auto_away = false;
                 self.websocket_polling_active = false;  // Stop WebSocket polling
                 self.app_state = AppState::Registration;
                 self.websocket_polling_active = false; // Stop WebSocket polling
@@ -364,23 +643,35 @@ impl ChatAppState {
             Message::OpenPrivateChat(username) => {
                 self.app_state = AppState::PrivateChat(username.clone());
                 self.current_message_input.clear();
+                self.editing_message = None;
+                self.reaction_picker = None;
+                self.replying_to = None;
+                self.thread = None;
+                let load_presence = self.load_user_info(&username, chat_service);
                 
                 // If we already have messages cached, don't mark as loading
                 if !self.private_chats.contains_key(&username) {
                     self.loading_private_chats.insert(username.clone());
                     
                     // Load messages once - with WebSocket connected, no need for polling
-                    return Command::perform(
-                        async move { Message::LoadPrivateMessages { with: username } },
-                        |msg| msg,
-                    );
+                    return Command::batch(vec![
+                        load_presence,
+                        Command::perform(
+                            async move { Message::LoadPrivateMessages { with: username } },
+                            |msg| msg,
+                        ),
+                    ]);
                 }
                 
-                return Command::none();
+                return Command::batch(vec![load_presence, self.update(Message::MarkChatRead, chat_service)]);
             }
             Message::OpenGroupChat(group_id, group_name) => {
                 self.app_state = AppState::GroupChat(group_id.clone(), group_name.clone());
                 self.current_message_input.clear();
+                self.editing_message = None;
+                self.reaction_picker = None;
+                self.replying_to = None;
+                self.thread = None;
                 // Mark this group chat as loading so the UI shows a loader
                 self.loading_group_chats.insert(group_id.clone());
 
@@ -454,29 +745,11 @@ impl ChatAppState {
                     return Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            match guard.send_command(&host, format!("/my_groups {}", token_clone)).await {
-                                Ok(response) => {
-                                    if response.starts_with("OK: My groups:") {
-                                        let groups_part = response.trim_start_matches("OK: My groups:").trim();
-                                        let groups: Vec<(String, String, usize)> = if groups_part.is_empty() {
-                                            vec![]
-                                        } else {
-                                            groups_part.split(',').filter_map(|s| {
-                                                let s = s.trim();
-                                                if let Some((id, name)) = s.split_once(':') {
-                                                    // For now, set member count to 1 (will be improved with server support)
-                                                    Some((id.to_string(), name.to_string(), 1))
-                                                } else {
-                                                    None
-                                                }
-                                            }).collect()
-                                        };
-                                        Message::MyGroupsLoaded { groups }
-                                    } else {
-                                        Message::MyGroupsLoaded { groups: vec![] }
-                                    }
-                                }
-                                Err(_) => Message::MyGroupsLoaded { groups: vec![] },
+                            match guard.request(&host, Some(&token_clone), ServerCommand::MyGroups).await {
+                                // For now, set member count to 1 (will be improved with server support)
+                                Ok(ResponseData::Groups { groups }) => Message::MyGroupsLoaded { groups: groups_to_rows(groups, 1) },
+                                Err(e) if e.code == ErrorCode::SessionExpired => Message::SessionExpired,
+                                _ => Message::MyGroupsLoaded { groups: vec![] },
                             }
                         },
                         |msg| msg,
@@ -503,19 +776,15 @@ impl ChatAppState {
                         
                         // Get group members to filter them out
                         let mut guard = svc.lock().await;
-            let group_members_resp = guard.send_command(&host, format!("/group_members {} {}", token_clone, group_id_for_filter)).await.unwrap_or_default();
+            let group_members_resp = guard.request(&host, Some(&token_clone), ServerCommand::GroupMembers { group_id: group_id_for_filter }).await;
                         drop(guard);
                         
-                        // Parse group members (format "OK: Group members: user1, user2")
-                        let existing_members: Vec<String> = if group_members_resp.starts_with("OK: Group members:") {
-                            group_members_resp.trim_start_matches("OK: Group members:").trim()
-                                .split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
-                        } else {
-                            vec![]
+                        let existing_members: Vec<String> = match group_members_resp {
+                            Ok(ResponseData::GroupMembers { members }) => members,
+                            _ => vec![],
                         };
                         
-                        println!("[INVITE] Group members response: {}", group_members_resp);
-                        println!("[INVITE] Parsed existing members: {:?}", existing_members);
+                        println!("[INVITE] Existing members: {:?}", existing_members);
                         println!("[INVITE] All users before filter: {:?}", all_users);
                         
                         // Filter out existing members and current user
@@ -550,6 +819,34 @@ impl ChatAppState {
                     |msg| msg,
                 );
             }
+            Message::OpenInbox => {
+                self.app_state = AppState::Inbox;
+                return self.update(Message::LoadInbox, chat_service);
+            }
+            Message::LoadInbox => {
+                if let Some(token) = self.session_token.clone() {
+                    self.loading_inbox = true;
+                    let svc = chat_service.clone();
+                    let cfg = crate::server::config::ClientConfig::from_env();
+                    let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+                    return Command::perform(
+                        async move {
+                            let mut guard = svc.lock().await;
+                            match guard.request(&host, Some(&token), ServerCommand::Inbox).await {
+                                Ok(ResponseData::Inbox { chats }) => Message::InboxLoaded { chats },
+                                Err(e) if e.code == ErrorCode::SessionExpired => Message::SessionExpired,
+                                Err(e) => Message::LogError(format!("Inbox non disponibile: {}", e)),
+                                Ok(_) => Message::InboxLoaded { chats: vec![] },
+                            }
+                        },
+                        |msg| msg,
+                    );
+                }
+            }
+            Message::InboxLoaded { chats } => {
+                self.loading_inbox = false;
+                self.inbox = chats.into_iter().map(|entry| (inbox_key(&entry.chat), entry)).collect();
+            }
             Message::OpenViewFriends => {
                 self.app_state = AppState::ViewFriends;
                 self.loading = true;
@@ -564,21 +861,10 @@ impl ChatAppState {
                     return Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            match guard.send_command(&host, format!("/list_friends {}", token_clone)).await {
-                                Ok(response) => {
-                                    if response.starts_with("OK: Friends:") {
-                                        let friends_part = response.trim_start_matches("OK: Friends:").trim();
-                                        let friends: Vec<String> = if friends_part.is_empty() {
-                                            vec![]
-                                        } else {
-                                            friends_part.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
-                                        };
-                                        Message::FriendsLoaded { friends }
-                                    } else {
-                                        Message::FriendsLoaded { friends: vec![] }
-                                    }
-                                }
-                                Err(_) => Message::FriendsLoaded { friends: vec![] },
+                            match guard.request(&host, Some(&token_clone), ServerCommand::ListFriends).await {
+                                Ok(ResponseData::Friends { friends }) => Message::FriendsLoaded { friends },
+                                Err(e) if e.code == ErrorCode::SessionExpired => Message::SessionExpired,
+                                _ => Message::FriendsLoaded { friends: vec![] },
                             }
                         },
                         |msg| msg,
@@ -599,27 +885,13 @@ impl ChatAppState {
                     return Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            match guard.send_command(&host, format!("/received_friend_requests {}", token_clone)).await {
-                                Ok(response) => {
-                                    if response.starts_with("OK: Richieste ricevute:") {
-                                        let requests_part = response.trim_start_matches("OK: Richieste ricevute:").trim();
-                                        let requests: Vec<(String, String)> = if requests_part.is_empty() {
-                                            vec![]
-                                        } else {
-                                            requests_part.split(" | ").filter_map(|s| {
-                                                if let Some((username, message)) = s.trim().split_once(':') {
-                                                    Some((username.trim().to_string(), message.trim().to_string()))
-                                                } else {
-                                                    None
-                                                }
-                                            }).collect()
-                                        };
-                                        Message::FriendRequestsLoaded { requests }
-                                    } else {
-                                        Message::FriendRequestsLoaded { requests: vec![] }
-                                    }
+                            match guard.request(&host, Some(&token_clone), ServerCommand::ReceivedFriendRequests).await {
+                                Ok(ResponseData::ReceivedFriendRequests { requests }) => {
+                                    let requests = requests.into_iter().map(|r| (r.username, r.message)).collect();
+                                    Message::FriendRequestsLoaded { requests }
                                 }
-                                Err(_) => Message::FriendRequestsLoaded { requests: vec![] },
+                                Err(e) if e.code == ErrorCode::SessionExpired => Message::SessionExpired,
+                                _ => Message::FriendRequestsLoaded { requests: vec![] },
                             }
                         },
                         |msg| msg,
@@ -636,20 +908,11 @@ impl ChatAppState {
                     return Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            match guard.send_command(&host, format!("/reject_friend_request {} {}", token_clone, username)).await {
-                                Ok(response) => {
-                                    if response.starts_with("OK:") {
-                                        Message::FriendRequestResult { 
-                                            success: true, 
-                                            message: format!("Friend request from {} rejected.", username) 
-                                        }
-                                    } else {
-                                        Message::FriendRequestResult { 
-                                            success: false, 
-                                            message: response 
-                                        }
-                                    }
-                                }
+                            match guard.request(&host, Some(&token_clone), ServerCommand::RejectFriendRequest { from: username.clone() }).await {
+                                Ok(_) => Message::FriendRequestResult { 
+                                    success: true, 
+                                    message: format!("Friend request from {} rejected.", username) 
+                                },
                                 Err(e) => Message::FriendRequestResult { 
                                     success: false, 
                                     message: format!("Error rejecting friend request: {}", e) 
@@ -669,15 +932,8 @@ impl ChatAppState {
                 return Command::perform(
                     async move {
                         let mut guard = svc.lock().await;
-                        let cmd = format!("/accept_friend_request {} {}", token, username_clone);
-                        match guard.send_command(&host, cmd).await {
-                            Ok(response) => {
-                                if response.starts_with("OK:") {
-                                    Message::FriendRequestResult { success: true, message: "Friend request accepted!".to_string() }
-                                } else {
-                                    Message::FriendRequestResult { success: false, message: response }
-                                }
-                            }
+                        match guard.request(&host, Some(&token), ServerCommand::AcceptFriendRequest { from: username_clone }).await {
+                            Ok(_) => Message::FriendRequestResult { success: true, message: "Friend request accepted!".to_string() },
                             Err(e) => Message::FriendRequestResult { success: false, message: format!("Error: {}", e) }
                         }
                     },
@@ -688,6 +944,82 @@ impl ChatAppState {
                 self.loading = false;
                 self.friends_list = friends;
             }
+            Message::LoadPresence => {
+                self.last_activity = chrono::Utc::now().timestamp();
+                if let Some(token) = self.session_token.clone() {
+                    let svc = chat_service.clone();
+                    let cfg = crate::server::config::ClientConfig::from_env();
+                    let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+                    return Command::perform(
+                        async move {
+                            match svc.lock().await.get_presence(&host, &token).await {
+                                Ok((status, message, last_seen_visibility)) => Message::PresenceLoaded { status, message, last_seen_visibility },
+                                Err(e) => Message::LogError(format!("Presenza non disponibile: {}", e)),
+                            }
+                        },
+                        |msg| msg,
+                    );
+                }
+            }
+            Message::PresenceLoaded { status, message, last_seen_visibility } => {
+                self.last_seen_visibility = last_seen_visibility;
+                return self.update(Message::PresenceSet { status, message }, chat_service);
+            }
+            Message::PresenceSet { status, message } => {
+                self.my_presence = status;
+                self.status_message_input = message.clone().unwrap_or_default();
+                self.my_status_message = message;
+            }
+            Message::SetLastSeenVisibility { visibility } => {
+                if let Some(token) = self.session_token.clone() {
+                    let svc = chat_service.clone();
+                    let cfg = crate::server::config::ClientConfig::from_env();
+                    let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+                    return Command::perform(
+                        async move {
+                            match svc.lock().await.set_last_seen_visibility(&host, &token, visibility).await {
+                                Ok(visibility) => Message::LastSeenVisibilitySet { visibility },
+                                Err(e) => Message::LogError(format!("Privacy dell'ultimo accesso non aggiornata: {}", e)),
+                            }
+                        },
+                        |msg| msg,
+                    );
+                }
+            }
+            Message::LastSeenVisibilitySet { visibility } => {
+                self.last_seen_visibility = visibility;
+            }
+            Message::UserInfoLoaded { user } => {
+                self.peer_presence.insert(user.username.clone(), user);
+            }
+            Message::SetPresence { status } => {
+                self.auto_away = false;
+                return self.send_presence(status, self.my_status_message.clone(), chat_service);
+            }
+            Message::StatusMessageInputChanged(input) => {
+                self.status_message_input = input;
+            }
+            Message::SubmitStatusMessage => {
+                let message = Some(self.status_message_input.trim().to_string()).filter(|m| !m.is_empty());
+                return self.send_presence(self.my_presence, message, chat_service);
+            }
+            Message::UserActivity => {
+                self.last_activity = chrono::Utc::now().timestamp();
+                if self.auto_away {
+                    self.auto_away = false;
+                    println!("[APP] User is back, presence returns online");
+                    return self.send_presence(PresenceStatus::Online, self.my_status_message.clone(), chat_service);
+                }
+            }
+            Message::CheckIdle => {
+                let idle = chrono::Utc::now().timestamp() - self.last_activity;
+                // Solo da "online": away, busy e invisible scelti a mano restano invariati
+                if self.session_token.is_some() && self.my_presence == PresenceStatus::Online && !self.auto_away && idle >= AUTO_AWAY_SECS {
+                    self.auto_away = true;
+                    println!("[APP] Idle for {}s, presence set to away", idle);
+                    return self.send_presence(PresenceStatus::Away, self.my_status_message.clone(), chat_service);
+                }
+            }
             Message::FriendRequestsLoaded { requests } => {
                 self.loading = false;
                 self.friend_requests = requests;
@@ -721,32 +1053,12 @@ impl ChatAppState {
                      return iced::Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            let cmd = format!("/received_friend_requests {}", token);
-                            match guard.send_command(&host, cmd).await {
-                                Ok(response) => {
-                                    if response.starts_with("OK:") {
-                                        // Parse the response to extract friend requests
-                                        let after = response.splitn(3, ':').nth(2).unwrap_or("");
-                                        let requests: Vec<(String, String)> = after
-                                            .split('|')
-                                            .filter_map(|item| {
-                                                let item = item.trim();
-                                                if item.is_empty() { return None; }
-                                                if let Some(colon_pos) = item.find(':') {
-                                                    let username = item[..colon_pos].trim().to_string();
-                                                    let message = item[colon_pos + 1..].trim().to_string();
-                                                    Some((username, message))
-                                                } else {
-                                                    None
-                                                }
-                                            })
-                                            .collect();
-                                        Message::FriendRequestsLoaded { requests }
-                                    } else {
-                                        Message::FriendRequestsLoaded { requests: vec![] }
-                                    }
+                            match guard.request(&host, Some(&token), ServerCommand::ReceivedFriendRequests).await {
+                                Ok(ResponseData::ReceivedFriendRequests { requests }) => {
+                                    let requests = requests.into_iter().map(|r| (r.username, r.message)).collect();
+                                    Message::FriendRequestsLoaded { requests }
                                 }
-                                Err(_) => Message::FriendRequestsLoaded { requests: vec![] }
+                                _ => Message::FriendRequestsLoaded { requests: vec![] }
                             }
                         },
                         |msg| msg,
@@ -813,40 +1125,34 @@ impl ChatAppState {
             Message::RemoveParticipant(username) => {
                 self.selected_participants.remove(&username);
             }
-            Message::CreateGroupSubmit => {
-                if !self.create_group_name.trim().is_empty() && !self.selected_participants.is_empty() {
-                    if let Some(token) = &self.session_token {
-                        let svc = chat_service.clone();
-                        let token_clone = token.clone();
-                        let name_clone = self.create_group_name.trim().to_string();
-                        let participants = self.selected_participants.clone();
-                        let cfg = crate::server::config::ClientConfig::from_env();
-                        let host = format!("{}:{}", cfg.default_host, cfg.default_port);
-                        
-                        self.loading = true;
-                        
-                        return Command::perform(
-                            async move {
-                                let mut guard = svc.lock().await;
-                                let participants_str = participants.into_iter().collect::<Vec<_>>().join(",");
-                                match guard.send_command(&host, format!("/create_group {} {} {}", token_clone, name_clone, participants_str)).await {
-                                    Ok(response) => {
-                                        // Extract group_id from response: "OK: Group 'name' created with ID: uuid"
-                                        if let Some(id_part) = response.split("ID: ").nth(1) {
-                                            let group_id = id_part.trim().to_string();
-                                            Message::GroupCreated { group_id, group_name: name_clone }
-                                        } else {
-                                            // Fallback: generate a temporary ID (shouldn't happen)
-                                            Message::GroupCreated { group_id: format!("temp_{}", chrono::Utc::now().timestamp()), group_name: name_clone }
-                                        }
-                                        
-                                    }
-                                    Err(e) => Message::LogError(format!("Errore nella creazione del gruppo: {}", e)),
+            Message::CreateGroupSubmit if !self.create_group_name.trim().is_empty() && !self.selected_participants.is_empty() => {
+                if let Some(token) = &self.session_token {
+                    let svc = chat_service.clone();
+                    let token_clone = token.clone();
+                    let name_clone = self.create_group_name.trim().to_string();
+                    let participants = self.selected_participants.clone();
+                    let cfg = crate::server::config::ClientConfig::from_env();
+                    let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+                    
+                    self.loading = true;
+                    
+                    return Command::perform(
+                        async move {
+                            let mut guard = svc.lock().await;
+                            let command = ServerCommand::CreateGroup {
+                                name: name_clone.clone(),
+                                participants: participants.into_iter().collect(),
+                            };
+                            match guard.request(&host, Some(&token_clone), command).await {
+                                Ok(ResponseData::GroupCreated { group_id, name }) => {
+                                    Message::GroupCreated { group_id, group_name: name }
                                 }
-                            },
-                            |msg| msg,
-                        );
-                    }
+                                Ok(other) => Message::LogError(format!("Errore nella creazione del gruppo: {:?}", other)),
+                                Err(e) => Message::LogError(format!("Errore nella creazione del gruppo: {}", e)),
+                            }
+                        },
+                        |msg| msg,
+                    );
                 }
             }
             Message::GroupCreated { group_id, group_name } => {
@@ -888,23 +1194,15 @@ impl ChatAppState {
                     return Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            match guard.send_command(&host, format!("/invite {} {} {}", token_clone, username_clone, group_id_clone)).await {
-                                Ok(response) => {
-                                    if response.starts_with("OK:") {
-                                        Message::InviteToGroupResult { 
-                                            success: true, 
-                                            message: format!("Invite successfully sent toa {}!", username_clone) 
-                                        }
-                                    } else {
-                                        Message::InviteToGroupResult { 
-                                            success: false, 
-                                            message: response 
-                                        }
-                                    }
-                                }
+                            let command = ServerCommand::Invite { username: username_clone.clone(), group_id: group_id_clone };
+                            match guard.request(&host, Some(&token_clone), command).await {
+                                Ok(_) => Message::InviteToGroupResult { 
+                                    success: true, 
+                                    message: format!("Invite successfully sent toa {}!", username_clone) 
+                                },
                                 Err(e) => Message::InviteToGroupResult { 
                                     success: false, 
-                                    message: format!("Error in message sending: {}", e) 
+                                    message: e.message 
                                 },
                             }
                         },
@@ -927,32 +1225,13 @@ impl ChatAppState {
                     return Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            match guard.send_command(&host, format!("/my_group_invites {}", token_clone)).await {
-                                Ok(response) => {
-                                    if response.starts_with("OK: Group invites:") {
-                                        let invites_part = response.trim_start_matches("OK: Group invites:").trim();
-                                        let invites: Vec<(i64, String, String)> = if invites_part.is_empty() {
-                                            vec![]
-                                        } else {
-                                            invites_part.split(" | ").filter_map(|s| {
-                                                let parts: Vec<&str> = s.trim().split(':').collect();
-                                                if parts.len() == 3 {
-                                                    if let Ok(invite_id) = parts[0].parse::<i64>() {
-                                                        Some((invite_id, parts[1].to_string(), parts[2].to_string()))
-                                                    } else {
-                                                        None
-                                                    }
-                                                } else {
-                                                    None
-                                                }
-                                            }).collect()
-                                        };
-                                        Message::MyGroupInvitesLoaded { invites }
-                                    } else {
-                                        Message::MyGroupInvitesLoaded { invites: vec![] }
-                                    }
+                            match guard.request(&host, Some(&token_clone), ServerCommand::MyGroupInvites).await {
+                                Ok(ResponseData::GroupInvites { invites }) => {
+                                    let invites = invites.into_iter().map(|i| (i.id, i.group_name, i.invited_by)).collect();
+                                    Message::MyGroupInvitesLoaded { invites }
                                 }
-                                Err(_) => Message::MyGroupInvitesLoaded { invites: vec![] },
+                                Err(e) if e.code == ErrorCode::SessionExpired => Message::SessionExpired,
+                                _ => Message::MyGroupInvitesLoaded { invites: vec![] },
                             }
                         },
                         |msg| msg,
@@ -973,20 +1252,11 @@ impl ChatAppState {
                     return Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            match guard.send_command(&host, format!("/accept_group_invite {} {}", token_clone, invite_id)).await {
-                                Ok(response) => {
-                                    if response.starts_with("OK:") {
-                                        Message::GroupInviteActionResult { 
-                                            success: true, 
-                                            message: "Invite accepted!".to_string() 
-                                        }
-                                    } else {
-                                        Message::GroupInviteActionResult { 
-                                            success: false, 
-                                            message: response 
-                                        }
-                                    }
-                                }
+                            match guard.request(&host, Some(&token_clone), ServerCommand::AcceptGroupInvite { invite_id }).await {
+                                Ok(_) => Message::GroupInviteActionResult { 
+                                    success: true, 
+                                    message: "Invite accepted!".to_string() 
+                                },
                                 Err(e) => Message::GroupInviteActionResult { 
                                     success: false, 
                                     message: format!("Error in accepting the invite: {}", e) 
@@ -1007,20 +1277,11 @@ impl ChatAppState {
                     return Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            match guard.send_command(&host, format!("/reject_group_invite {} {}", token_clone, invite_id)).await {
-                                Ok(response) => {
-                                    if response.starts_with("OK:") {
-                                        Message::GroupInviteActionResult { 
-                                            success: true, 
-                                            message: "Invito rejected.".to_string() 
-                                        }
-                                    } else {
-                                        Message::GroupInviteActionResult { 
-                                            success: false, 
-                                            message: response 
-                                        }
-                                    }
-                                }
+                            match guard.request(&host, Some(&token_clone), ServerCommand::RejectGroupInvite { invite_id }).await {
+                                Ok(_) => Message::GroupInviteActionResult { 
+                                    success: true, 
+                                    message: "Invito rejected.".to_string() 
+                                },
                                 Err(e) => Message::GroupInviteActionResult { 
                                     success: false, 
                                     message: format!("Error in rejecting the invite: {}", e) 
@@ -1034,33 +1295,31 @@ impl ChatAppState {
             Message::UsersSearchQueryChanged(query) => {
                 self.users_search_query = query;
             }
-            Message::UsersSearch => {
-                // Trigger search based on current query
-                if !self.users_search_query.is_empty() {
-                    let svc = chat_service.clone();
-                    let cfg = crate::server::config::ClientConfig::from_env();
-                    let host = format!("{}:{}", cfg.default_host, cfg.default_port);
-                    let query = self.users_search_query.clone();
-                    // Clone current username so the async block does not borrow &self
-                    let current_username = self.username.clone();
+            // Trigger search based on current query
+            Message::UsersSearch if !self.users_search_query.is_empty() => {
+                let svc = chat_service.clone();
+                let cfg = crate::server::config::ClientConfig::from_env();
+                let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+                let query = self.users_search_query.clone();
+                // Clone current username so the async block does not borrow &self
+                let current_username = self.username.clone();
 
-                    return Command::perform(
-                        async move {
-                            // For now, just return all users and filter client-side
-                            match UsersService::list_all(&svc, &host).await {
-                                Ok(users) => {
-                                    let filtered: Vec<String> = users.into_iter()
-                                        .filter(|u| u.to_lowercase().contains(&query.to_lowercase()))
-                                        .filter(|u| u != &current_username) // Remove current user from search results
-                                        .collect();
-                                    Message::UsersListLoaded { kind: "Search".to_string(), list: filtered }
-                                }
-                                Err(_) => Message::UsersListLoaded { kind: "Search".to_string(), list: vec![] },
+                return Command::perform(
+                    async move {
+                        // For now, just return all users and filter client-side
+                        match UsersService::list_all(&svc, &host).await {
+                            Ok(users) => {
+                                let filtered: Vec<String> = users.into_iter()
+                                    .filter(|u| u.to_lowercase().contains(&query.to_lowercase()))
+                                    .filter(|u| u != &current_username) // Remove current user from search results
+                                    .collect();
+                                Message::UsersListLoaded { kind: "Search".to_string(), list: filtered }
                             }
-                        },
-                        |msg| msg,
-                    );
-                }
+                            Err(_) => Message::UsersListLoaded { kind: "Search".to_string(), list: vec![] },
+                        }
+                    },
+                    |msg| msg,
+                );
             }
             Message::UsersListLoaded { kind: _, list } => {
                 // Filter out current user from all user lists
@@ -1099,104 +1358,341 @@ impl ChatAppState {
             Message::MessageInputChanged(input) => {
                 self.current_message_input = input;
             }
-            Message::SendPrivateMessage { to } => {
-                if !self.current_message_input.trim().is_empty() {
-                    if let Some(token) = &self.session_token {
-                        let svc = chat_service.clone();
-                        let token_clone = token.clone();
-                        let to_clone = to.clone();
-                        let message = self.current_message_input.trim().to_string();
-                        let cfg = crate::server::config::ClientConfig::from_env();
-                        let host = format!("{}:{}", cfg.default_host, cfg.default_port);
-                        
-                        // Create a local message to add immediately to the UI
-                        let local_msg = ChatMessage {
-                            sender: self.username.clone(),
-                            content: message.clone(),
-                            timestamp: chrono::Utc::now().timestamp(),
-                            formatted_time: chrono::Utc::now().format("%H:%M").to_string(),
-                            sent_at: chrono::Utc::now().timestamp(),
-                            is_pending: true,  // This is a temporary local message
-                        };
-                        
-                        // Add message to local cache immediately for instant UI feedback
-                        let messages = self.private_chats.entry(to.clone()).or_insert_with(Vec::new);
-                        messages.push(local_msg);
-                        
-                        // Clear input immediately for better UX
-                        // If we don't have the chat history cached yet, mark it as loading
-                        if !self.private_chats.contains_key(&to) {
-                            self.loading_private_chats.insert(to.clone());
-                        }
-
-                        self.current_message_input.clear();
-                        
-                        return Command::batch([
-                            Command::perform(
-                                async move {
-                                    let mut guard = svc.lock().await;
-                                    let _ = guard.send_private_message(&host, &token_clone, &to_clone, &message).await;
-                                    Message::NoOp  // WebSocket will handle server confirmation
-                                },
-                                |msg| msg,
-                            ),
-                            // Auto-scroll to bottom after sending
-                            scrollable::snap_to(
-                                scrollable::Id::new("messages_scroll"),
-                                scrollable::RelativeOffset::END
-                            )
-                        ]);
-                    }
-                }
+            Message::StartEditMessage { id, content } => {
+                self.replying_to = None;
+                self.editing_message = Some(id);
+                self.current_message_input = content;
             }
-            Message::SendGroupMessage { group_id } => {
-                if !self.current_message_input.trim().is_empty() {
-                    if let Some(token) = &self.session_token {
-                        let svc = chat_service.clone();
-                        let token_clone = token.clone();
-                        let group_id_clone = group_id.clone();
-                        let message = self.current_message_input.trim().to_string();
-                        let cfg = crate::server::config::ClientConfig::from_env();
-                        let host = format!("{}:{}", cfg.default_host, cfg.default_port);
-                        
-                        // Create a local message to add immediately to the UI
-                        let local_msg = ChatMessage {
-                            sender: self.username.clone(),
-                            content: message.clone(),
-                            timestamp: chrono::Utc::now().timestamp(),
-                            formatted_time: chrono::Utc::now().format("%H:%M").to_string(),
-                            sent_at: chrono::Utc::now().timestamp(),
-                            is_pending: true,  // This is a temporary local message
+            Message::CancelEditMessage => {
+                self.editing_message = None;
+                self.current_message_input.clear();
+            }
+            Message::SubmitEditMessage if !self.current_message_input.trim().is_empty() => {
+                if let (Some(id), Some(token)) = (self.editing_message.take(), &self.session_token) {
+                    let svc = chat_service.clone();
+                    let token_clone = token.clone();
+                    let content = self.current_message_input.trim().to_string();
+                    let cfg = crate::server::config::ClientConfig::from_env();
+                    let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+                    self.current_message_input.clear();
+
+                    return Command::perform(
+                        async move {
+                            let mut guard = svc.lock().await;
+                            match guard.edit_message(&host, &token_clone, id, &content).await {
+                                Ok(Some(edited_at)) => Message::MessageEdited { id, content, edited_at },
+                                // Inviata sul WebSocket: l'evento message_edited aggiornerà la chat
+                                Ok(None) => Message::NoOp,
+                                Err(e) => Message::LogError(format!("Modifica non riuscita: {}", e)),
+                            }
+                        },
+                        |msg| msg,
+                    );
+                }
+            }
+            Message::MessageEdited { id, content, edited_at } => {
+                // Gli id sono globali: il messaggio è in una sola chat
+                let _ = self.private_chats.values_mut()
+                    .chain(self.group_chats.values_mut())
+                    .any(|messages| apply_edit(messages, id, &content, edited_at));
+                if let Some(thread) = &mut self.thread {
+                    apply_edit(&mut thread.messages, id, &content, edited_at);
+                }
+            }
+            Message::DeleteMessage { id } => {
+                if let Some(token) = &self.session_token {
+                    let svc = chat_service.clone();
+                    let token_clone = token.clone();
+                    let cfg = crate::server::config::ClientConfig::from_env();
+                    let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+
+                    return Command::perform(
+                        async move {
+                            let mut guard = svc.lock().await;
+                            match guard.delete_message(&host, &token_clone, id).await {
+                                Ok(Some(_)) => Message::MessageDeleted { id },
+                                // Inviata sul WebSocket: l'evento message_deleted aggiornerà la chat
+                                Ok(None) => Message::NoOp,
+                                Err(e) => Message::LogError(format!("Eliminazione non riuscita: {}", e)),
+                            }
+                        },
+                        |msg| msg,
+                    );
+                }
+            }
+            Message::StartReply { id } => {
+                let target = self.private_chats.values()
+                    .chain(self.group_chats.values())
+                    .flatten()
+                    .find(|m| m.id == Some(id));
+                if let Some(msg) = target {
+                    self.replying_to = Some(QuotedMessage {
+                        id,
+                        sender: msg.sender.clone(),
+                        snippet: QuotedMessage::snippet(&msg.content),
+                        deleted: false,
+                    });
+                    // Una risposta sostituisce l'eventuale modifica in corso
+                    if self.editing_message.take().is_some() {
+                        self.current_message_input.clear();
+                    }
+                }
+            }
+            Message::CancelReply => {
+                self.replying_to = None;
+            }
+            Message::OpenThread { id } => {
+                if let Some(token) = &self.session_token {
+                    self.thread = Some(ThreadPanel { message_id: id, messages: Vec::new(), loading: true });
+                    let svc = chat_service.clone();
+                    let token_clone = token.clone();
+                    let cfg = crate::server::config::ClientConfig::from_env();
+                    let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+
+                    return Command::perform(
+                        async move {
+                            let mut guard = svc.lock().await;
+                            match guard.get_thread(&host, &token_clone, id).await {
+                                Ok(messages) => Message::ThreadLoaded { id, messages },
+                                Err(e) => Message::LogError(format!("Impossibile caricare la discussione: {}", e)),
+                            }
+                        },
+                        |msg| msg,
+                    );
+                }
+            }
+            Message::ThreadLoaded { id, messages } => {
+                // Ignora le risposte di una discussione già chiusa o sostituita
+                if let Some(thread) = self.thread.as_mut().filter(|t| t.message_id == id) {
+                    thread.messages = messages;
+                    thread.loading = false;
+                }
+            }
+            Message::CloseThread => {
+                self.thread = None;
+            }
+            Message::ToggleReactionPicker { id } => {
+                self.reaction_picker = if self.reaction_picker == Some(id) { None } else { Some(id) };
+            }
+            Message::ToggleReaction { id, emoji } => {
+                self.reaction_picker = None;
+                if let Some(token) = &self.session_token {
+                    // La stessa emoji già scelta viene tolta, un'altra sostituisce la precedente
+                    let already = self.private_chats.values()
+                        .chain(self.group_chats.values())
+                        .flatten()
+                        .find(|m| m.id == Some(id))
+                        .is_some_and(|m| m.reactions.iter().any(|r| r.emoji == emoji && r.users.contains(&self.username)));
+                    let svc = chat_service.clone();
+                    let token_clone = token.clone();
+                    let cfg = crate::server::config::ClientConfig::from_env();
+                    let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+
+                    return Command::perform(
+                        async move {
+                            let emoji = (!already).then_some(emoji);
+                            let mut guard = svc.lock().await;
+                            match guard.set_reaction(&host, &token_clone, id, emoji.as_deref()).await {
+                                Ok(Some(reactions)) => Message::ReactionsUpdated { id, reactions },
+                                // Inviata sul WebSocket: l'evento reactions_updated aggiornerà la chat
+                                Ok(None) => Message::NoOp,
+                                Err(e) => Message::LogError(format!("Reazione non riuscita: {}", e)),
+                            }
+                        },
+                        |msg| msg,
+                    );
+                }
+            }
+            Message::ReactionsUpdated { id, reactions } => {
+                let _ = self.private_chats.values_mut()
+                    .chain(self.group_chats.values_mut())
+                    .any(|messages| apply_reactions(messages, id, &reactions));
+                if let Some(thread) = &mut self.thread {
+                    apply_reactions(&mut thread.messages, id, &reactions);
+                }
+            }
+            Message::ReceiptsUpdated { receipts } => {
+                for receipt in receipts {
+                    let _ = self.private_chats.values_mut()
+                        .chain(self.group_chats.values_mut())
+                        .any(|messages| apply_receipt(messages, receipt.id, receipt.delivered_to, receipt.read_by));
+                    if let Some(thread) = &mut self.thread {
+                        apply_receipt(&mut thread.messages, receipt.id, receipt.delivered_to, receipt.read_by);
+                    }
+                }
+            }
+            Message::MarkChatRead => {
+                // Chat aperta e ultimo messaggio ricevuto (non nostro) già confermato dal server
+                let (chat_key, messages) = match &self.app_state {
+                    AppState::PrivateChat(with) => (with.clone(), self.private_chats.get(with)),
+                    AppState::GroupChat(group_id, _) => (format!("group_{}", group_id), self.group_chats.get(group_id)),
+                    _ => return Command::none(),
+                };
+                // Il server conferma l'azzeramento con un `inbox_update`
+                if let Some(entry) = self.inbox.get_mut(&chat_key) {
+                    entry.unread = 0;
+                }
+                let Some(up_to) = messages.into_iter().flatten()
+                    .filter(|m| m.sender != self.username)
+                    .filter_map(|m| m.id)
+                    .max() else { return Command::none() };
+                if self.read_up_to.get(&chat_key).is_some_and(|&read| read >= up_to) {
+                    return Command::none();
+                }
+                let Some(token) = self.session_token.clone() else { return Command::none() };
+                self.read_up_to.insert(chat_key.clone(), up_to);
+                let svc = chat_service.clone();
+                let cfg = crate::server::config::ClientConfig::from_env();
+                let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+
+                return Command::perform(
+                    async move {
+                        let mut guard = svc.lock().await;
+                        let result = match chat_key.strip_prefix("group_") {
+                            Some(group_id) => guard.mark_group_read(&host, &token, group_id, Some(up_to)).await,
+                            None => guard.mark_private_read(&host, &token, &chat_key, Some(up_to)).await,
                         };
-                        
-                        // Add message to local cache immediately for instant UI feedback
-                        let messages = self.group_chats.entry(group_id.clone()).or_insert_with(Vec::new);
-                        messages.push(local_msg);
-                        
-                        // Clear input immediately for better UX
-                        // If we don't have the chat history cached yet, mark it as loading
-                        if !self.group_chats.contains_key(&group_id) {
-                            self.loading_group_chats.insert(group_id.clone());
+                        match result {
+                            Ok(_) => Message::NoOp,
+                            Err(e) => Message::LogError(format!("Conferma di lettura non riuscita: {}", e)),
                         }
+                    },
+                    |msg| msg,
+                );
+            }
+            Message::MessageDeleted { id } => {
+                let _ = self.private_chats.values_mut()
+                    .chain(self.group_chats.values_mut())
+                    .any(|messages| apply_delete(messages, id));
+                if let Some(thread) = &mut self.thread {
+                    apply_delete(&mut thread.messages, id);
+                }
+                // Il messaggio in modifica non esiste più
+                if self.editing_message == Some(id) {
+                    self.editing_message = None;
+                    self.current_message_input.clear();
+                }
+            }
+            Message::SendPrivateMessage { to } if !self.current_message_input.trim().is_empty() => {
+                if let Some(token) = &self.session_token {
+                    let svc = chat_service.clone();
+                    let token_clone = token.clone();
+                    let to_clone = to.clone();
+                    let message = self.current_message_input.trim().to_string();
+                    let cfg = crate::server::config::ClientConfig::from_env();
+                    let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+                    
+                    // Create a local message to add immediately to the UI
+                    let client_msg_id = uuid::Uuid::new_v4().to_string();
+                    let reply = self.replying_to.take();
+                    let reply_to = reply.as_ref().map(|q| q.id);
+                    let local_msg = ChatMessage {
+                        id: None,
+                        seq: None,
+                        client_msg_id: Some(client_msg_id.clone()),
+                        sender: self.username.clone(),
+                        content: message.clone(),
+                        timestamp: chrono::Utc::now().timestamp(),
+                        formatted_time: chrono::Utc::now().format("%H:%M").to_string(),
+                        sent_at: chrono::Utc::now().timestamp(),
+                        is_pending: true,  // This is a temporary local message
+                        edited_at: None,
+                        deleted: false,
+                        reactions: Vec::new(),
+                        reply_to: reply.as_ref().map(|q| q.id),
+                        quoted: reply.clone(),
+                        delivered_to: 0,
+                        read_by: 0,
+                    };
+                    
+                    // Add message to local cache immediately for instant UI feedback
+                    let messages = self.private_chats.entry(to.clone()).or_default();
+                    messages.push(local_msg);
+                    
+                    // Clear input immediately for better UX
+                    // If we don't have the chat history cached yet, mark it as loading
+                    if !self.private_chats.contains_key(&to) {
+                        self.loading_private_chats.insert(to.clone());
+                    }
 
-                        self.current_message_input.clear();
-                        
-                        return Command::batch([
-                            Command::perform(
-                                async move {
-                                    let mut guard = svc.lock().await;
-                                    let _ = guard.send_group_message(&host, &token_clone, &group_id_clone, &message).await;
-                                    Message::NoOp  // WebSocket will handle server confirmation
-                                },
-                                |msg| msg,
-                            ),
-                            // Auto-scroll to bottom after sending
-                            scrollable::snap_to(
-                                scrollable::Id::new("group_messages_scroll"),
-                                scrollable::RelativeOffset::END
-                            )
-                        ]);
+                    self.current_message_input.clear();
+                    
+                    return Command::batch([
+                        Command::perform(
+                            async move {
+                                let mut guard = svc.lock().await;
+                                let _ = guard.send_private_message(&host, &token_clone, &to_clone, &message, &client_msg_id, reply_to).await;
+                                Message::NoOp  // WebSocket will handle server confirmation
+                            },
+                            |msg| msg,
+                        ),
+                        // Auto-scroll to bottom after sending
+                        scrollable::snap_to(
+                            scrollable::Id::new("messages_scroll"),
+                            scrollable::RelativeOffset::END
+                        )
+                    ]);
+                }
+            }
+            Message::SendGroupMessage { group_id } if !self.current_message_input.trim().is_empty() => {
+                if let Some(token) = &self.session_token {
+                    let svc = chat_service.clone();
+                    let token_clone = token.clone();
+                    let group_id_clone = group_id.clone();
+                    let message = self.current_message_input.trim().to_string();
+                    let cfg = crate::server::config::ClientConfig::from_env();
+                    let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+                    
+                    // Create a local message to add immediately to the UI
+                    let client_msg_id = uuid::Uuid::new_v4().to_string();
+                    let reply = self.replying_to.take();
+                    let reply_to = reply.as_ref().map(|q| q.id);
+                    let local_msg = ChatMessage {
+                        id: None,
+                        seq: None,
+                        client_msg_id: Some(client_msg_id.clone()),
+                        sender: self.username.clone(),
+                        content: message.clone(),
+                        timestamp: chrono::Utc::now().timestamp(),
+                        formatted_time: chrono::Utc::now().format("%H:%M").to_string(),
+                        sent_at: chrono::Utc::now().timestamp(),
+                        is_pending: true,  // This is a temporary local message
+                        edited_at: None,
+                        deleted: false,
+                        reactions: Vec::new(),
+                        reply_to: reply.as_ref().map(|q| q.id),
+                        quoted: reply.clone(),
+                        delivered_to: 0,
+                        read_by: 0,
+                    };
+                    
+                    // Add message to local cache immediately for instant UI feedback
+                    let messages = self.group_chats.entry(group_id.clone()).or_default();
+                    messages.push(local_msg);
+                    
+                    // Clear input immediately for better UX
+                    // If we don't have the chat history cached yet, mark it as loading
+                    if !self.group_chats.contains_key(&group_id) {
+                        self.loading_group_chats.insert(group_id.clone());
                     }
+
+                    self.current_message_input.clear();
+                    
+                    return Command::batch([
+                        Command::perform(
+                            async move {
+                                let mut guard = svc.lock().await;
+                                let _ = guard.send_group_message(&host, &token_clone, &group_id_clone, &message, &client_msg_id, reply_to).await;
+                                Message::NoOp  // WebSocket will handle server confirmation
+                            },
+                            |msg| msg,
+                        ),
+                        // Auto-scroll to bottom after sending
+                        scrollable::snap_to(
+                            scrollable::Id::new("group_messages_scroll"),
+                            scrollable::RelativeOffset::END
+                        )
+                    ]);
                 }
             }
             Message::LoadGroupMessages { group_id } => {
@@ -1210,14 +1706,12 @@ impl ChatAppState {
                     return Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            match guard.get_group_messages(&host, &token_clone, &group_id_clone).await {
-                                Ok(messages) => Message::GroupMessagesLoaded { group_id: group_id_clone, messages },
-                                Err(e) => {
-                                    if e.to_string().contains("NOT_A_MEMBER") {
-                                        Message::NotAMember { group_id: group_id_clone }
-                                    } else {
-                                        Message::GroupMessagesLoaded { group_id: group_id_clone, messages: vec![] }
-                                    }
+                            match guard.get_group_messages(&host, &token_clone, &group_id_clone, HistoryPage::latest()).await {
+                                Ok((messages, has_more)) => Message::GroupMessagesLoaded { group_id: group_id_clone, messages, has_more },
+                                Err(e) => match error_code(&e) {
+                                    Some(ErrorCode::NotMember) => Message::NotAMember { group_id: group_id_clone },
+                                    Some(ErrorCode::SessionExpired) => Message::SessionExpired,
+                                    _ => Message::GroupMessagesLoaded { group_id: group_id_clone, messages: vec![], has_more: false },
                                 }
                             }
                         },
@@ -1225,17 +1719,21 @@ impl ChatAppState {
                     );
                 }
             }
-            Message::GroupMessagesLoaded { group_id, messages } => {
+            Message::GroupMessagesLoaded { group_id, messages, has_more } => {
                 self.group_chats.insert(group_id.clone(), messages);
                 self.loading_group_chats.remove(&group_id);
+                self.group_paging.insert(group_id.clone(), HistoryPaging { has_older: has_more, loading_older: false });
                 
                 // Auto-scroll to bottom when messages are loaded
                 if let AppState::GroupChat(current_group_id, _) = &self.app_state {
                     if current_group_id == &group_id {
-                        return scrollable::snap_to(
-                            scrollable::Id::new("group_messages_scroll"),
-                            scrollable::RelativeOffset::END
-                        );
+                        return Command::batch([
+                            scrollable::snap_to(
+                                scrollable::Id::new("group_messages_scroll"),
+                                scrollable::RelativeOffset::END
+                            ),
+                            self.update(Message::MarkChatRead, chat_service),
+                        ]);
                     }
                 }
             }
@@ -1250,16 +1748,18 @@ impl ChatAppState {
                     return Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            match guard.get_private_messages(&host, &token_clone, &with_clone).await {
-                                Ok(messages) => Message::PrivateMessagesLoaded { with: with_clone, messages },
-                                Err(_) => Message::PrivateMessagesLoaded { with: with_clone, messages: vec![] },
+                            match guard.get_private_messages(&host, &token_clone, &with_clone, HistoryPage::latest()).await {
+                                Ok((messages, has_more)) => Message::PrivateMessagesLoaded { with: with_clone, messages, has_more },
+                                Err(e) if error_code(&e) == Some(ErrorCode::SessionExpired) => Message::SessionExpired,
+                                Err(_) => Message::PrivateMessagesLoaded { with: with_clone, messages: vec![], has_more: false },
                             }
                         },
                         |msg| msg,
                     );
                 }
             }
-            Message::PrivateMessagesLoaded { with, messages } => {
+            Message::PrivateMessagesLoaded { with, messages, has_more } => {
+                self.private_paging.insert(with.clone(), HistoryPaging { has_older: has_more, loading_older: false });
                 // Track the latest timestamp from HTTP loaded messages
                 if let Some(latest_msg) = messages.iter().max_by_key(|msg| msg.timestamp) {
                     self.last_http_timestamp.insert(with.clone(), latest_msg.timestamp);
@@ -1275,12 +1775,85 @@ impl ChatAppState {
                 // Auto-scroll to bottom when messages are loaded (for recipient)
                 if let AppState::PrivateChat(current_chat) = &self.app_state {
                     if current_chat == &with {
-                        return scrollable::snap_to(
-                            scrollable::Id::new("messages_scroll"),
-                            scrollable::RelativeOffset::END
-                        );
+                        return Command::batch([
+                            scrollable::snap_to(
+                                scrollable::Id::new("messages_scroll"),
+                                scrollable::RelativeOffset::END
+                            ),
+                            self.update(Message::MarkChatRead, chat_service),
+                        ]);
                     }
                 }
+            }
+            Message::LoadOlderPrivateMessages { with } => {
+                let paging = self.private_paging.entry(with.clone()).or_default();
+                let cursor = self.private_chats.get(&with).and_then(|m| oldest_message_id(m));
+                let (Some(token), Some(before)) = (&self.session_token, cursor) else { return Command::none() };
+                if !paging.has_older || paging.loading_older {
+                    return Command::none();
+                }
+                paging.loading_older = true;
+                let svc = chat_service.clone();
+                let token_clone = token.clone();
+                let cfg = crate::server::config::ClientConfig::from_env();
+                let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+                return Command::perform(
+                    async move {
+                        let mut guard = svc.lock().await;
+                        match guard.get_private_messages(&host, &token_clone, &with, HistoryPage::before(before)).await {
+                            Ok((messages, has_more)) => Message::OlderPrivateMessagesLoaded { with, messages, has_more },
+                            Err(e) if error_code(&e) == Some(ErrorCode::SessionExpired) => Message::SessionExpired,
+                            // Riprova al prossimo scroll
+                            Err(_) => Message::OlderPrivateMessagesLoaded { with, messages: vec![], has_more: true },
+                        }
+                    },
+                    |msg| msg,
+                );
+            }
+            Message::OlderPrivateMessagesLoaded { with, messages, has_more } => {
+                self.private_paging.insert(with.clone(), HistoryPaging { has_older: has_more, loading_older: false });
+                let Some(cached) = self.private_chats.get_mut(&with) else { return Command::none() };
+                let added = prepend_older(cached, messages);
+                println!("[APP] 📚 Loaded {} older messages for {} (has_more={})", added, with, has_more);
+                if added > 0 && matches!(&self.app_state, AppState::PrivateChat(current) if current == &with) {
+                    return keep_scroll_position("messages_scroll", added, cached.len());
+                }
+            }
+            Message::LoadOlderGroupMessages { group_id } => {
+                let paging = self.group_paging.entry(group_id.clone()).or_default();
+                let cursor = self.group_chats.get(&group_id).and_then(|m| oldest_message_id(m));
+                let (Some(token), Some(before)) = (&self.session_token, cursor) else { return Command::none() };
+                if !paging.has_older || paging.loading_older {
+                    return Command::none();
+                }
+                paging.loading_older = true;
+                let svc = chat_service.clone();
+                let token_clone = token.clone();
+                let cfg = crate::server::config::ClientConfig::from_env();
+                let host = format!("{}:{}", cfg.default_host, cfg.default_port);
+                return Command::perform(
+                    async move {
+                        let mut guard = svc.lock().await;
+                        match guard.get_group_messages(&host, &token_clone, &group_id, HistoryPage::before(before)).await {
+                            Ok((messages, has_more)) => Message::OlderGroupMessagesLoaded { group_id, messages, has_more },
+                            Err(e) => match error_code(&e) {
+                                Some(ErrorCode::NotMember) => Message::NotAMember { group_id },
+                                Some(ErrorCode::SessionExpired) => Message::SessionExpired,
+                                _ => Message::OlderGroupMessagesLoaded { group_id, messages: vec![], has_more: true },
+                            }
+                        }
+                    },
+                    |msg| msg,
+                );
+            }
+            Message::OlderGroupMessagesLoaded { group_id, messages, has_more } => {
+                self.group_paging.insert(group_id.clone(), HistoryPaging { has_older: has_more, loading_older: false });
+                let Some(cached) = self.group_chats.get_mut(&group_id) else { return Command::none() };
+                let added = prepend_older(cached, messages);
+                println!("[APP] 📚 Loaded {} older group messages for {} (has_more={})", added, group_id, has_more);
+                if added > 0 && matches!(&self.app_state, AppState::GroupChat(current, _) if current == &group_id) {
+                    return keep_scroll_position("group_messages_scroll", added, cached.len());
+                }
             }
              Message::LeaveGroup { group_id: _, group_name } => {
                 let cfg = crate::server::config::ClientConfig::from_env();
@@ -1292,15 +1865,8 @@ impl ChatAppState {
                 return Command::perform(
                     async move {
                         let mut guard = svc.lock().await;
-                        let cmd = format!("/leave_group {} {}", token, group_name_clone);
-                        match guard.send_command(&host, cmd).await {
-                            Ok(response) => {
-                                if response.starts_with("OK:") {
-                                    Message::LeaveGroupResult { success: true, message: format!("Left group '{}'", group_name_clone) }
-                                } else {
-                                    Message::LeaveGroupResult { success: false, message: response }
-                                }
-                            }
+                        match guard.request(&host, Some(&token), ServerCommand::LeaveGroup { group: group_name_clone.clone() }).await {
+                            Ok(_) => Message::LeaveGroupResult { success: true, message: format!("Left group '{}'", group_name_clone) },
                             Err(e) => Message::LeaveGroupResult { success: false, message: format!("Error: {}", e) }
                         }
                     },
@@ -1334,30 +1900,10 @@ impl ChatAppState {
                         Command::perform(
                             async move {
                                 let mut guard = svc.lock().await;
-                                let cmd = format!("/my_groups {}", token);
-                                match guard.send_command(&host, cmd).await {
-                                    Ok(response) => {
-                                        // Parse response: "OK: My groups: id1:name1, id2:name2"
-                                        if response.starts_with("OK: My groups:") {
-                                            let after = response.splitn(3, ':').nth(2).unwrap_or("");
-                                            let groups: Vec<(String, String, usize)> = after
-                                                .split(',')
-                                                .map(|s| s.trim())
-                                                .filter(|s| !s.is_empty())
-                                                .map(|s| {
-                                                    if let Some((id, name)) = s.split_once(':') {
-                                                        (id.to_string(), name.to_string(), 0) // member_count not used
-                                                    } else {
-                                                        (s.to_string(), s.to_string(), 0)
-                                                    }
-                                                })
-                                                .collect();
-                                            Message::MyGroupsLoaded { groups }
-                                        } else {
-                                            Message::MyGroupsLoaded { groups: vec![] }
-                                        }
-                                    },
-                                    Err(_) => Message::MyGroupsLoaded { groups: vec![] },
+                                match guard.request(&host, Some(&token), ServerCommand::MyGroups).await {
+                                    // member_count not used
+                                    Ok(ResponseData::Groups { groups }) => Message::MyGroupsLoaded { groups: groups_to_rows(groups, 0) },
+                                    _ => Message::MyGroupsLoaded { groups: vec![] },
                                 }
                             },
                             |msg| msg
@@ -1412,29 +1958,9 @@ impl ChatAppState {
                         return Command::perform(
                             async move {
                                 let mut guard = svc.lock().await;
-                                let cmd = format!("/my_groups {}", token);
-                                match guard.send_command(&host, cmd).await {
-                                    Ok(response) => {
-                                        if response.starts_with("OK: My groups:") {
-                                            let after = response.splitn(3, ':').nth(2).unwrap_or("");
-                                            let groups: Vec<(String, String, usize)> = after
-                                                .split(',')
-                                                .map(|s| s.trim())
-                                                .filter(|s| !s.is_empty())
-                                                .map(|s| {
-                                                    if let Some((id, name)) = s.split_once(':') {
-                                                        (id.to_string(), name.to_string(), 0)
-                                                    } else {
-                                                        (s.to_string(), s.to_string(), 0)
-                                                    }
-                                                })
-                                                .collect();
-                                            Message::MyGroupsLoaded { groups }
-                                        } else {
-                                            Message::MyGroupsLoaded { groups: vec![] }
-                                        }
-                                    },
-                                    Err(_) => Message::MyGroupsLoaded { groups: vec![] },
+                                match guard.request(&host, Some(&token), ServerCommand::MyGroups).await {
+                                    Ok(ResponseData::Groups { groups }) => Message::MyGroupsLoaded { groups: groups_to_rows(groups, 0) },
+                                    _ => Message::MyGroupsLoaded { groups: vec![] },
                                 }
                             },
                             |msg| msg
@@ -1452,24 +1978,13 @@ impl ChatAppState {
                     return Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            match guard.send_command(&host, format!("/delete_private_messages {} {}", token_clone, with_clone)).await {
-                                Ok(response) => {
-                                    if response.starts_with("OK:") {
-                                        Message::DiscardMessagesResult { 
-                                            success: true, 
-                                            message: response.trim_start_matches("OK:").trim().to_string(),
-                                            username: Some(with_clone),
-                                            group_id: None
-                                        }
-                                    } else {
-                                        Message::DiscardMessagesResult { 
-                                            success: false, 
-                                            message: response,
-                                            username: Some(with_clone),
-                                            group_id: None
-                                        }
-                                    }
-                                }
+                            match guard.request(&host, Some(&token_clone), ServerCommand::DeletePrivateMessages { with: with_clone.clone() }).await {
+                                Ok(response) => Message::DiscardMessagesResult { 
+                                    success: true, 
+                                    message: ack_message(response),
+                                    username: Some(with_clone),
+                                    group_id: None
+                                },
                                 Err(e) => Message::DiscardMessagesResult { 
                                     success: false, 
                                     message: format!("Error: {}", e),
@@ -1493,24 +2008,13 @@ impl ChatAppState {
                     return Command::perform(
                         async move {
                             let mut guard = svc.lock().await;
-                            match guard.send_command(&host, format!("/delete_group_messages {} {}", token_clone, group_id_clone)).await {
-                                Ok(response) => {
-                                    if response.starts_with("OK:") {
-                                        Message::DiscardMessagesResult { 
-                                            success: true, 
-                                            message: response.trim_start_matches("OK:").trim().to_string(),
-                                            username: None,  // For group messages, username is None
-                                            group_id: Some(group_id_clone)
-                                        }
-                                    } else {
-                                        Message::DiscardMessagesResult { 
-                                            success: false, 
-                                            message: response,
-                                            username: None,
-                                            group_id: Some(group_id_clone)
-                                        }
-                                    }
-                                }
+                            match guard.request(&host, Some(&token_clone), ServerCommand::DeleteGroupMessages { group_id: group_id_clone.clone() }).await {
+                                Ok(response) => Message::DiscardMessagesResult { 
+                                    success: true, 
+                                    message: ack_message(response),
+                                    username: None,  // For group messages, username is None
+                                    group_id: Some(group_id_clone)
+                                },
                                 Err(e) => Message::DiscardMessagesResult { 
                                     success: false, 
                                     message: format!("Error: {}", e),
@@ -1560,7 +2064,7 @@ impl ChatAppState {
             }
             Message::NewMessagesReceived { with, messages } => {
                 self.loading_private_chats.remove(&with);
-                self.private_chats.insert(with, messages);
+                append_newer(self.private_chats.entry(with).or_default(), messages);
                 return Command::none();
             }
             Message::NewGroupMessagesReceived { group_id, messages: _ } => {
@@ -1624,12 +2128,15 @@ impl ChatAppState {
                 return Command::none();
             }
             Message::WebSocketMessageReceived(ws_msg) => {
-                match ws_msg {
+                match *ws_msg {
                     crate::client::services::websocket_client::WebSocketMessage::NewMessage(chat_msg) => {
                         println!("[APP] Received WebSocket message from {}: {}", chat_msg.from_user, chat_msg.content);
                         
                         // Convert IncomingChatMessage to ChatMessage
                         let app_msg = ChatMessage {
+                            id: chat_msg.id,
+                            seq: chat_msg.seq,
+                            client_msg_id: chat_msg.client_msg_id.clone(),
                             sender: chat_msg.from_user.clone(),
                             content: chat_msg.content.clone(),
                             timestamp: chat_msg.timestamp,
@@ -1638,7 +2145,21 @@ impl ChatAppState {
                                 .unwrap_or_else(|| "??:??".to_string()),
                             sent_at: chat_msg.timestamp,
                             is_pending: false,  // This is a confirmed server message
+                            edited_at: chat_msg.edited_at,
+                            deleted: false,
+                            reactions: Vec::new(),
+                            reply_to: chat_msg.reply_to,
+                            quoted: chat_msg.quoted.clone(),
+                            delivered_to: 0,
+                            read_by: 0,
                         };
+
+                        // Le risposte alla discussione aperta compaiono anche nel pannello
+                        if let (Some(thread), Some(parent)) = (&mut self.thread, app_msg.reply_to) {
+                            if thread.messages.iter().any(|m| m.id == Some(parent)) {
+                                insert_confirmed(&mut thread.messages, app_msg.clone());
+                            }
+                        }
                         
                         // Determine the chat key (who we're chatting with)
                         let chat_key = if chat_msg.chat_type == "private" {
@@ -1666,15 +2187,27 @@ impl ChatAppState {
                             return Command::none();
                         };
                         
+                        // Chi ha appena inviato ha smesso di scrivere
+                        if let Some(users) = self.typing_users.get_mut(&chat_key) {
+                            users.retain(|u| u != &chat_msg.from_user);
+                        }
+                        
                         // Add message to the appropriate chat (with deduplication)
                         if chat_msg.chat_type == "private" {
                             let messages = self.private_chats.entry(chat_key.clone())
-                                .or_insert_with(Vec::new);
+                                .or_default();
                             
                             // Check if this WebSocket message is newer than the latest HTTP-loaded message
                             let last_http_ts = self.last_http_timestamp.get(&chat_key).copied().unwrap_or(0);
                             
-                            if app_msg.timestamp <= last_http_ts {
+                            if app_msg.id.is_some() {
+                                // Server con id stabili: deduplica e ordine esatti
+                                if insert_confirmed(messages, app_msg) {
+                                    println!("[APP] ✅ Added WebSocket private message #{:?} (seq {:?}) to chat with {}", chat_msg.id, chat_msg.seq, chat_key);
+                                } else {
+                                    println!("[APP] ⚠️ Duplicate WebSocket private message #{:?} for {}", chat_msg.id, chat_key);
+                                }
+                            } else if app_msg.timestamp <= last_http_ts {
                                 println!("[APP] 🚫 Skipping WebSocket message (timestamp {} <= last HTTP timestamp {} for {})", 
                                     app_msg.timestamp, last_http_ts, chat_key);
                             } else {
@@ -1718,64 +2251,166 @@ impl ChatAppState {
                             // Extract just the group_id from "group_groupid" format
                             let group_id = chat_key.strip_prefix("group_").unwrap_or(&chat_key);
                             let messages = self.group_chats.entry(group_id.to_string())
-                                .or_insert_with(Vec::new);
-                            
-                            // Check if there's a pending message to replace first
-                            let replaced_pending = messages.iter_mut().find(|msg| {
-                                msg.is_pending && msg.sender == app_msg.sender && msg.content == app_msg.content
-                            });
+                                .or_default();
                             
-                            if let Some(pending_msg) = replaced_pending {
-                                // Replace pending message with server confirmation
-                                *pending_msg = app_msg;
-                                println!("[APP] 🔄 Replaced pending group message with server confirmation for group {} (timestamp: {})", group_id, chat_msg.timestamp);
+                            if app_msg.id.is_some() {
+                                if insert_confirmed(messages, app_msg) {
+                                    println!("[APP] ✅ Added WebSocket group message #{:?} (seq {:?}) to group {}", chat_msg.id, chat_msg.seq, group_id);
+                                } else {
+                                    println!("[APP] ⚠️ Duplicate WebSocket group message #{:?} for group {}", chat_msg.id, group_id);
+                                }
                             } else {
-                                // Check only for exact timestamp duplicates (network-level duplicates)
-                                let is_exact_duplicate = messages.iter().any(|existing_msg| {
-                                    existing_msg.sender == app_msg.sender &&
-                                    existing_msg.content == app_msg.content &&
-                                    existing_msg.timestamp == app_msg.timestamp  // Only exact timestamp matches are duplicates
+                                // Check if there's a pending message to replace first
+                                let replaced_pending = messages.iter_mut().find(|msg| {
+                                    msg.is_pending && msg.sender == app_msg.sender && msg.content == app_msg.content
                                 });
                                 
-                                if !is_exact_duplicate {
-                                    messages.push(app_msg);
-                                    println!("[APP] ✅ Added WebSocket group message to group {} (timestamp: {})", group_id, chat_msg.timestamp);
+                                if let Some(pending_msg) = replaced_pending {
+                                    // Replace pending message with server confirmation
+                                    *pending_msg = app_msg;
+                                    println!("[APP] 🔄 Replaced pending group message with server confirmation for group {} (timestamp: {})", group_id, chat_msg.timestamp);
                                 } else {
-                                    println!("[APP] ⚠️ Exact duplicate WebSocket group message for group {} (sender: {}, content: {}, timestamp: {})", 
-                                        group_id, chat_msg.from_user, chat_msg.content, chat_msg.timestamp);
+                                    // Check only for exact timestamp duplicates (network-level duplicates)
+                                    let is_exact_duplicate = messages.iter().any(|existing_msg| {
+                                        existing_msg.sender == app_msg.sender &&
+                                        existing_msg.content == app_msg.content &&
+                                        existing_msg.timestamp == app_msg.timestamp  // Only exact timestamp matches are duplicates
+                                    });
+                                    
+                                    if !is_exact_duplicate {
+                                        messages.push(app_msg);
+                                        println!("[APP] ✅ Added WebSocket group message to group {} (timestamp: {})", group_id, chat_msg.timestamp);
+                                    } else {
+                                        println!("[APP] ⚠️ Exact duplicate WebSocket group message for group {} (sender: {}, content: {}, timestamp: {})", 
+                                            group_id, chat_msg.from_user, chat_msg.content, chat_msg.timestamp);
+                                    }
                                 }
                             }
                         }
                         
                         // If we're currently viewing this chat, auto-scroll to bottom to trigger UI update
-                        if chat_msg.chat_type == "private" {
-                            if let AppState::PrivateChat(current_chat) = &self.app_state {
-                                if current_chat == &chat_key {
-                                    // We're currently viewing this private chat - scroll to bottom
-                                    return scrollable::snap_to(
-                                        scrollable::Id::new("messages_scroll"),
-                                        scrollable::RelativeOffset::END
-                                    );
-                                }
+                        let viewing = match &self.app_state {
+                            AppState::PrivateChat(current_chat) => chat_msg.chat_type == "private" && current_chat == &chat_key,
+                            AppState::GroupChat(current_group_id, _) => {
+                                chat_msg.chat_type == "group" && chat_key.strip_prefix("group_") == Some(current_group_id.as_str())
                             }
-                        } else if chat_msg.chat_type == "group" {
-                            if let AppState::GroupChat(current_group_id, _) = &self.app_state {
-                                let group_id = chat_key.strip_prefix("group_").unwrap_or(&chat_key);
-                                if current_group_id == group_id {
-                                    // We're currently viewing this group chat - scroll to bottom
-                                    return scrollable::snap_to(
-                                        scrollable::Id::new("messages_scroll"),
-                                        scrollable::RelativeOffset::END
-                                    );
-                                }
+                            _ => false,
+                        };
+                        if viewing {
+                            let scroll = scrollable::snap_to(
+                                scrollable::Id::new("messages_scroll"),
+                                scrollable::RelativeOffset::END
+                            );
+                            // Chi sta guardando la chat ha letto il nuovo messaggio
+                            if chat_msg.from_user != self.username {
+                                return Command::batch([scroll, self.update(Message::MarkChatRead, chat_service)]);
                             }
+                            return scroll;
                         }
                         
                         // Not viewing this chat currently, just add the message silently
                         return Command::none();
                     }
-                    crate::client::services::websocket_client::WebSocketMessage::UserStatusUpdate { user_id, online } => {
-                        println!("[APP] User {} is now {}", user_id, if online { "online" } else { "offline" });
+                    crate::client::services::websocket_client::WebSocketMessage::MessageEdited(chat_msg) => {
+                        if let (Some(id), Some(edited_at)) = (chat_msg.id, chat_msg.edited_at) {
+                            println!("[APP] Message #{} edited by {}", id, chat_msg.from_user);
+                            return self.update(Message::MessageEdited { id, content: chat_msg.content, edited_at }, chat_service);
+                        }
+                    }
+                    crate::client::services::websocket_client::WebSocketMessage::ReactionsUpdated(chat_msg) => {
+                        if let Some(id) = chat_msg.id {
+                            return self.update(Message::ReactionsUpdated { id, reactions: chat_msg.reactions }, chat_service);
+                        }
+                    }
+                    crate::client::services::websocket_client::WebSocketMessage::ReceiptsUpdated(receipts) => {
+                        return self.update(Message::ReceiptsUpdated { receipts }, chat_service);
+                    }
+                    crate::client::services::websocket_client::WebSocketMessage::InboxUpdated(entry) => {
+                        let chat_key = inbox_key(&entry.chat);
+                        println!("[APP] Inbox: {} has {} unread", chat_key, entry.unread);
+                        self.inbox.insert(chat_key, entry);
+                    }
+                    crate::client::services::websocket_client::WebSocketMessage::Typing(event) => {
+                        let chat_key = match &event.group_id {
+                            Some(group_id) => format!("group_{}", group_id),
+                            None => event.from_user.clone(),
+                        };
+                        let users = self.typing_users.entry(chat_key).or_default();
+                        users.retain(|u| u != &event.from_user);
+                        if event.typing {
+                            users.push(event.from_user);
+                        }
+                    }
+                    crate::client::services::websocket_client::WebSocketMessage::MessageDeleted(chat_msg) => {
+                        if let Some(id) = chat_msg.id {
+                            println!("[APP] Message #{} from {} deleted", id, chat_msg.from_user);
+                            return self.update(Message::MessageDeleted { id }, chat_service);
+                        }
+                    }
+                    crate::client::services::websocket_client::WebSocketMessage::UserStatusUpdate { user_id, presence } => {
+                        println!("[APP] User {} ({}) is now {}", presence.username, user_id, presence.status.as_str());
+                        let online = presence.status != PresenceStatus::Offline;
+                        let username = presence.username.clone();
+                        if let Some(friend) = self.friends_list.iter_mut().find(|f| f.username == username) {
+                            *friend = presence.clone();
+                        }
+                        if let Some(peer) = self.peer_presence.get_mut(&username) {
+                            *peer = presence;
+                        }
+                        // La lista degli utenti online aperta segue le transizioni
+                        if matches!(&self.app_state, AppState::UsersList(kind) if kind == "Online") {
+                            let listed = self.users_search_results.contains(&username);
+                            if online && !listed {
+                                self.users_search_results.push(username);
+                            } else if !online && listed {
+                                self.users_search_results.retain(|u| u != &username);
+                            }
+                        }
+                    }
+                    crate::client::services::websocket_client::WebSocketMessage::SyncCompleted { events, latest_seq } => {
+                        println!("[APP] Caught up to event #{} ({} missed)", latest_seq, events);
+                        if events > 0 {
+                            self.logger.push(LogMessage {
+                                level: LogLevel::Info,
+                                message: format!("Recuperati {} eventi persi durante la disconnessione", events),
+                            });
+                            // Anteprime e non letti delle chat toccate dagli eventi recuperati
+                            return self.update(Message::LoadInbox, chat_service);
+                        }
+                    }
+                    crate::client::services::websocket_client::WebSocketMessage::FriendRequest(request) => {
+                        println!("[APP] Friend request from {}", request.username);
+                        if !self.friend_requests.iter().any(|(username, _)| username == &request.username) {
+                            self.logger.push(LogMessage {
+                                level: LogLevel::Info,
+                                message: format!("Nuova richiesta di amicizia da {}", request.username),
+                            });
+                            self.friend_requests.push((request.username, request.message));
+                        }
+                    }
+                    crate::client::services::websocket_client::WebSocketMessage::GroupInvite(invite) => {
+                        println!("[APP] Invited to group {} by {}", invite.group_name, invite.invited_by);
+                        if !self.my_group_invites.iter().any(|(id, _, _)| *id == invite.id) {
+                            self.logger.push(LogMessage {
+                                level: LogLevel::Info,
+                                message: format!("{} ti ha invitato nel gruppo {}", invite.invited_by, invite.group_name),
+                            });
+                            self.my_group_invites.push((invite.id, invite.group_name, invite.invited_by));
+                        }
+                    }
+                    crate::client::services::websocket_client::WebSocketMessage::ServerError(error) => {
+                        println!("[APP] Server rejected WebSocket message: {}", error);
+                        self.logger.push(LogMessage {
+                            level: LogLevel::Warning,
+                            message: format!("Messaggio non inviato: {}", error),
+                        });
+                    }
+                    crate::client::services::websocket_client::WebSocketMessage::System(notice) => {
+                        println!("[APP] Server notice: {}", notice);
+                        self.logger.push(LogMessage {
+                            level: LogLevel::Warning,
+                            message: notice,
+                        });
                     }
                     crate::client::services::websocket_client::WebSocketMessage::Error(error) => {
                         println!("[APP] WebSocket error: {}", error);
@@ -1812,7 +2447,7 @@ impl ChatAppState {
                         
                         if let Some(ws_message) = guard.try_receive_websocket_message().await {
                             drop(guard);
-                            return Message::WebSocketMessageReceived(ws_message);
+                            return Message::WebSocketMessageReceived(Box::new(ws_message));
                         }
                         
                         drop(guard);
@@ -1,4 +1,5 @@
 use crate::client::gui::views::registration::HostType;
+use crate::common::protocol::{InboxEntry, LastSeenVisibility, PresenceStatus, UserInfo};
 
 #[derive(Debug, Clone)]
 pub enum Message {
@@ -14,6 +15,8 @@ pub enum Message {
     SubmitLoginOrRegister,
     AuthResult { success: bool, message: String, token: Option<String> },
     SessionMissing,
+    // Il server ha rifiutato il token (ErrorCode::SessionExpired)
+    SessionExpired,
     ClearLog,
     LogInfo(String),
     LogSuccess(String),
@@ -54,7 +57,33 @@ pub enum Message {
     MessageInputChanged(String),
     SendPrivateMessage { to: String },
     LoadPrivateMessages { with: String },
-    PrivateMessagesLoaded { with: String, messages: Vec<crate::client::models::app_state::ChatMessage> },
+    PrivateMessagesLoaded { with: String, messages: Vec<crate::client::models::app_state::ChatMessage>, has_more: bool },
+    // Storico paginato: pagina precedente richiesta scorrendo verso l'alto
+    LoadOlderPrivateMessages { with: String },
+    OlderPrivateMessagesLoaded { with: String, messages: Vec<crate::client::models::app_state::ChatMessage>, has_more: bool },
+    // Modifica di un proprio messaggio: il testo va nel campo di input
+    StartEditMessage { id: i64, content: String },
+    CancelEditMessage,
+    SubmitEditMessage,
+    MessageEdited { id: i64, content: String, edited_at: i64 },
+    // Ritiro di un proprio messaggio per tutti
+    DeleteMessage { id: i64 },
+    MessageDeleted { id: i64 },
+    // Reazioni: il selettore si apre su un messaggio, un'emoji già scelta viene tolta
+    ToggleReactionPicker { id: i64 },
+    ToggleReaction { id: i64, emoji: String },
+    ReactionsUpdated { id: i64, reactions: Vec<crate::common::protocol::ReactionCount> },
+    // Conferme di consegna e lettura dei propri messaggi
+    ReceiptsUpdated { receipts: Vec<crate::client::services::websocket_client::MessageReceipt> },
+    // Segna come letti i messaggi ricevuti nella chat aperta
+    MarkChatRead,
+    // Risposte: il messaggio citato accompagna il prossimo invio
+    StartReply { id: i64 },
+    CancelReply,
+    // Pannello della discussione di un messaggio
+    OpenThread { id: i64 },
+    ThreadLoaded { id: i64, messages: Vec<crate::client::models::app_state::ChatMessage> },
+    CloseThread,
     // Real-time message updates
     StartMessagePolling { with: String },
     StopMessagePolling,
@@ -65,7 +94,9 @@ pub enum Message {
     // Group chat messages
     SendGroupMessage { group_id: String },
     LoadGroupMessages { group_id: String },
-    GroupMessagesLoaded { group_id: String, messages: Vec<crate::client::models::app_state::ChatMessage> },
+    GroupMessagesLoaded { group_id: String, messages: Vec<crate::client::models::app_state::ChatMessage>, has_more: bool },
+    LoadOlderGroupMessages { group_id: String },
+    OlderGroupMessagesLoaded { group_id: String, messages: Vec<crate::client::models::app_state::ChatMessage>, has_more: bool },
     // Real-time group message updates
     StartGroupMessagePolling { group_id: String },
     StopGroupMessagePolling,
@@ -100,20 +131,36 @@ pub enum Message {
     // Friend system
     OpenSendFriendRequest,
     OpenViewFriends,
+    OpenInbox,
+    LoadInbox,
+    InboxLoaded { chats: Vec<InboxEntry> },
     SendFriendRequestToUser { username: String, message: String },
     FriendRequestResult { success: bool, message: String },
     // Friend request management
     AcceptFriendRequestFromUser { username: String },
     RejectFriendRequestFromUser { username: String },
-    FriendsLoaded { friends: Vec<String> },
+    FriendsLoaded { friends: Vec<UserInfo> },
     FriendRequestsLoaded { requests: Vec<(String, String)> },
     InviteToGroupResult{success: bool, message: String},
     DiscardMessagesResult { success: bool, message: String, username: Option<String>, group_id: Option<String> },
+    // Presenza: stato scelto, testo di stato e "away" automatico dopo l'inattività
+    LoadPresence,
+    PresenceLoaded { status: PresenceStatus, message: Option<String>, last_seen_visibility: LastSeenVisibility },
+    SetPresence { status: PresenceStatus },
+    StatusMessageInputChanged(String),
+    SubmitStatusMessage,
+    PresenceSet { status: PresenceStatus, message: Option<String> },
+    SetLastSeenVisibility { visibility: LastSeenVisibility },
+    LastSeenVisibilitySet { visibility: LastSeenVisibility },
+    /// Presenza e ultimo accesso dell'interlocutore di una chat privata
+    UserInfoLoaded { user: UserInfo },
+    UserActivity,
+    CheckIdle,
     // WebSocket connection messages
     WebSocketConnected,
     WebSocketError { error: String },
     // Real-time WebSocket messages
-    WebSocketMessageReceived(crate::client::services::websocket_client::WebSocketMessage),
+    WebSocketMessageReceived(Box<crate::client::services::websocket_client::WebSocketMessage>),
     CheckWebSocketMessages,
     // Logout completion
     LogoutCompleted,
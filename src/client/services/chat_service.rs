@@ -1,9 +1,11 @@
-use tokio::net::TcpStream;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::{Duration, timeout};
 use crate::client::services::message_parser;
 use crate::client::services::websocket_client::{WebSocketClient, WebSocketMessage};
+use crate::client::utils::tls::{self, TlsSettings};
+use crate::common::protocol::{Command, HistoryPage, LastSeenVisibility, PresenceStatus, ReactionCount, Request, Response, ResponseData, UserInfo};
+use crate::common::error::{ChatError, ErrorCode};
 
 #[derive(Debug)]
 pub enum CommandType {
@@ -24,6 +26,14 @@ pub struct ChatService {
     pub current_user: Option<String>,
     /// Receiver per messaggi WebSocket
     pub websocket_receiver: Option<mpsc::UnboundedReceiver<WebSocketMessage>>,
+    /// Id dell'ultima richiesta JSON inviata, usato per correlare le risposte
+    pub last_request_id: u64,
+    /// Token già legato dal server alla connessione TCP corrente: le richieste
+    /// successive con lo stesso token lo omettono
+    pub bound_token: Option<String>,
+    /// Impostazioni TLS per la porta comandi; `None` per TCP in chiaro.
+    /// Non viene azzerato da `reset()`: vale per tutte le riconnessioni
+    pub tls: Option<TlsSettings>,
 }
 
 impl ChatService {
@@ -34,8 +44,21 @@ impl ChatService {
             websocket: None,
             current_user: None,
             websocket_receiver: None,
+            last_request_id: 0,
+            bound_token: None,
+            tls: None,
         }
     }
+
+    pub fn set_tls(&mut self, tls: Option<TlsSettings>) {
+        if self.tls.is_some() != tls.is_some() {
+            // Cambia il trasporto: la connessione esistente non è più valida
+            self.tx = None;
+            self._bg = None;
+            self.bound_token = None;
+        }
+        self.tls = tls;
+    }
     
     /// Reset the service by dropping existing connections and background tasks
     pub async fn reset(&mut self) {
@@ -45,6 +68,7 @@ impl ChatService {
         self.websocket = None;
         self.current_user = None;
         self.websocket_receiver = None;
+        self.bound_token = None;
         println!("[CHAT_SERVICE] ✅ Reset completed");
     }
 
@@ -53,10 +77,9 @@ impl ChatService {
         println!("[CHAT_SERVICE] 🚪 Logging out from server");
         
         // Call server logout command first
-        let cmd = format!("/logout {}", session_token);
-        match self.send_command(host, cmd).await {
+        match self.request(host, Some(session_token), Command::Logout).await {
             Ok(response) => {
-                println!("[CHAT_SERVICE] 🚪 Server logout response: {}", response);
+                println!("[CHAT_SERVICE] 🚪 Server logout response: {:?}", response);
             }
             Err(e) => {
                 println!("[CHAT_SERVICE] ⚠️ Server logout failed: {}, continuing with local cleanup", e);
@@ -70,8 +93,10 @@ impl ChatService {
     }
 
     /// Initialize WebSocket connection
-    pub async fn connect_websocket(&mut self, ws_host: &str, ws_port: u16, session_token: &str) -> anyhow::Result<()> {
-        let ws_url = format!("ws://{}:{}", ws_host, ws_port);
+    /// Con `tls` impostato si usa wss:// verificando il server con radici e pin indicati.
+    pub async fn connect_websocket(&mut self, ws_host: &str, ws_port: u16, session_token: &str, tls: Option<TlsSettings>) -> anyhow::Result<()> {
+        let scheme = if tls.is_some() { "wss" } else { "ws" };
+        let ws_url = format!("{}://{}:{}", scheme, ws_host, ws_port);
         println!("[CHAT_SERVICE] 🔌 Starting WebSocket connection to {}", ws_url);
         
         // Reset any existing WebSocket connection
@@ -80,6 +105,9 @@ impl ChatService {
         // Create new WebSocket client
         let mut ws_client = WebSocketClient::new(ws_url.clone());
         ws_client.set_session_token(session_token.to_string());
+        if let Some(settings) = tls {
+            ws_client.set_tls(settings);
+        }
         
         // Get the receiver before connecting
         self.websocket_receiver = ws_client.take_receiver();
@@ -150,8 +178,8 @@ impl ChatService {
         }
 
         let host = host.to_string();
-        let stream = TcpStream::connect(&host).await?;
-        let (reader, writer) = stream.into_split();
+        let tls = self.tls.clone();
+        let (reader, writer) = tls::connect(&host, tls.as_ref()).await?;
         let mut reader = BufReader::new(reader);
         let mut writer = BufWriter::new(writer);
 
@@ -186,9 +214,8 @@ impl ChatService {
                         // write failed -> need to reconnect
                         eprintln!("[CLIENT:SVC] write failed: {}, reconnecting...", e);
                         // perform reconnect
-                        match TcpStream::connect(&host).await {
-                            Ok(s) => {
-                                let (r, w) = s.into_split();
+                        match tls::connect(&host, tls.as_ref()).await {
+                            Ok((r, w)) => {
                                 reader = BufReader::new(r);
                                 writer = BufWriter::new(w);
                                 // retry sending
@@ -203,9 +230,8 @@ impl ChatService {
                     }
                     if let Err(e) = writer.write_all(b"\n").await {
                         eprintln!("[CLIENT:SVC] write newline failed: {}, reconnecting...", e);
-                        match TcpStream::connect(&host).await {
-                            Ok(s) => {
-                                let (r, w) = s.into_split();
+                        match tls::connect(&host, tls.as_ref()).await {
+                            Ok((r, w)) => {
                                 reader = BufReader::new(r);
                                 writer = BufWriter::new(w);
                                 continue;
@@ -218,9 +244,8 @@ impl ChatService {
                     }
                     if let Err(e) = writer.flush().await {
                         eprintln!("[CLIENT:SVC] flush failed: {}, reconnecting...", e);
-                        match TcpStream::connect(&host).await {
-                            Ok(s) => {
-                                let (r, w) = s.into_split();
+                        match tls::connect(&host, tls.as_ref()).await {
+                            Ok((r, w)) => {
                                 reader = BufReader::new(r);
                                 writer = BufWriter::new(w);
                                 continue;
@@ -243,9 +268,8 @@ impl ChatService {
                             Ok(0) => {
                                 // Connection closed by peer. Reconnect and retry.
                                 eprintln!("[CLIENT:SVC] server closed connection, reconnecting...");
-                                match TcpStream::connect(&host).await {
-                                    Ok(s) => {
-                                        let (r, w) = s.into_split();
+                                match tls::connect(&host, tls.as_ref()).await {
+                                    Ok((r, w)) => {
                                         reader = BufReader::new(r);
                                         writer = BufWriter::new(w);
                                         continue;
@@ -293,9 +317,8 @@ impl ChatService {
                             }
                             Err(e) => {
                                 eprintln!("[CLIENT:SVC] read failed: {}, reconnecting...", e);
-                                match TcpStream::connect(&host).await {
-                                    Ok(s) => {
-                                        let (r, w) = s.into_split();
+                                match tls::connect(&host, tls.as_ref()).await {
+                                    Ok((r, w)) => {
                                         reader = BufReader::new(r);
                                         writer = BufWriter::new(w);
                                         continue;
@@ -314,9 +337,8 @@ impl ChatService {
                             Ok(0) => {
                                 // Connection closed by peer. Reconnect and retry sending the same command.
                                 eprintln!("[CLIENT:SVC] server closed connection, reconnecting...");
-                                match TcpStream::connect(&host).await {
-                                    Ok(s) => {
-                                        let (r, w) = s.into_split();
+                                match tls::connect(&host, tls.as_ref()).await {
+                                    Ok((r, w)) => {
                                         reader = BufReader::new(r);
                                         writer = BufWriter::new(w);
                                         // retry send/receive loop
@@ -328,6 +350,10 @@ impl ChatService {
                                     }
                                 }
                             }
+                            Ok(_) if tls.is_none() && tls::is_tls_record(&server_line) => {
+                                let _ = resp_tx.send(format!("ERR: {}", tls::SERVER_REQUIRES_TLS));
+                                break;
+                            }
                             Ok(_) => {
                                 let resp = server_line.trim().to_string();
                                 let _ = resp_tx.send(resp);
@@ -335,9 +361,8 @@ impl ChatService {
                             }
                             Err(e) => {
                                 eprintln!("[CLIENT:SVC] read failed: {}, reconnecting...", e);
-                                match TcpStream::connect(&host).await {
-                                    Ok(s) => {
-                                        let (r, w) = s.into_split();
+                                match tls::connect(&host, tls.as_ref()).await {
+                                    Ok((r, w)) => {
                                         reader = BufReader::new(r);
                                         writer = BufWriter::new(w);
                                         continue;
@@ -388,17 +413,63 @@ impl ChatService {
         }
     }
 
-    // Placeholder methods for later
+    /// Send a typed request using the JSON envelope and wait for its typed response.
+    /// Network failures and undecodable replies are reported as `ErrorCode::Transport`.
+    ///
+    /// Once the server has bound the session to this connection the token is omitted;
+    /// if the connection was silently re-established in the meantime the request is
+    /// retried once with the token, which binds the new connection.
+    pub async fn request(&mut self, host: &str, session_token: Option<&str>, command: Command) -> Result<ResponseData, ChatError> {
+        let omit_token = session_token.is_some() && self.bound_token.as_deref() == session_token;
+        let sent_token = if omit_token { None } else { session_token };
+        let result = match self.send_request(host, sent_token, command.clone()).await {
+            Err(e) if omit_token && e.code == ErrorCode::SessionExpired => {
+                println!("[CHAT_SERVICE] Connection no longer authenticated, resending {} with token", command.name());
+                self.bound_token = None;
+                self.send_request(host, session_token, command).await
+            }
+            other => other,
+        };
+        match &result {
+            Ok(ResponseData::Session { session_token, .. }) => self.bound_token = Some(session_token.clone()),
+            Ok(_) if session_token.is_some() => self.bound_token = session_token.map(|t| t.to_string()),
+            _ => {}
+        }
+        result
+    }
+
+    async fn send_request(&mut self, host: &str, session_token: Option<&str>, command: Command) -> Result<ResponseData, ChatError> {
+        self.last_request_id += 1;
+        let id = self.last_request_id;
+        let name = command.name();
+        let request = Request::new(id, session_token.map(|t| t.to_string()), command);
+        let line = serde_json::to_string(&request)
+            .map_err(|e| ChatError::new(ErrorCode::BadRequest, format!("Failed to encode request: {}", e)))?;
+        let raw = self.send_command(host, line).await
+            .map_err(|e| ChatError::new(ErrorCode::Transport, e.to_string()))?;
+        if let Some(reason) = raw.strip_prefix("ERR: ") {
+            // Errore di trasporto riportato dal task di connessione (riconnessione, TLS)
+            return Err(ChatError::new(ErrorCode::Transport, reason));
+        }
+        let response: Response = serde_json::from_str(&raw)
+            .map_err(|_| ChatError::new(ErrorCode::Transport, format!("Unexpected reply to {}: {}", name, raw)))?;
+        if response.id != id {
+            return Err(ChatError::new(ErrorCode::Transport, format!("Reply id {} does not match request {}", response.id, id)));
+        }
+        response.into_result()
+    }
+
     /// Send a private message using WebSocket if available, fallback to TCP.
-    /// Returns the raw server response.
-    pub async fn send_private_message(&mut self, host: &str, session_token: &str, to: &str, msg: &str) -> anyhow::Result<String> {
+    /// `client_msg_id` (a UUID) travels with every attempt, so the server stores
+    /// the message once even if it is sent again. Returns the server acknowledgement.
+    pub async fn send_private_message(&mut self, host: &str, session_token: &str, to: &str, msg: &str, client_msg_id: &str, reply_to: Option<i64>) -> anyhow::Result<String> {
         // Try WebSocket first if connected
         if let Some(ref websocket) = self.websocket {
             if websocket.is_connected() {
-                match websocket.send_private_message(to, msg).await {
+                match websocket.send_private_message(to, msg, client_msg_id, reply_to).await {
                     Ok(()) => {
                         println!("[CHAT_SERVICE] Message sent via WebSocket to {}", to);
-                        return Ok("OK: Message sent via WebSocket".to_string());
+                        return Ok("Message sent via WebSocket".to_string());
                     }
                     Err(e) => {
                         println!("[CHAT_SERVICE] WebSocket send failed: {}, falling back to TCP", e);
@@ -409,17 +480,23 @@ impl ChatService {
         }
         
         // Fallback to TCP
-        let cmd = format!("/send_private_message {} {} {}", session_token, to, msg);
-        let resp = self.send_command(host, cmd).await?;
-        Ok(resp)
+        let command = Command::SendPrivateMessage { to: to.to_string(), content: msg.to_string(), client_msg_id: Some(client_msg_id.to_string()), reply_to };
+        match self.request(host, Some(session_token), command).await? {
+            ResponseData::MessageSent { message_id, seq, duplicate, .. } if duplicate => Ok(format!("Message already sent (#{} seq {})", message_id, seq)),
+            ResponseData::MessageSent { message_id, seq, .. } => Ok(format!("Message sent (#{} seq {})", message_id, seq)),
+            ResponseData::Ack { message } => Ok(message),
+            other => Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+        }
     }
 
-    /// Retrieve private messages with another user and return them parsed as Vec<String>.
-    pub async fn get_private_messages(&mut self, host: &str, session_token: &str, with: &str) -> anyhow::Result<Vec<crate::client::models::app_state::ChatMessage>> {
-        let cmd = format!("/get_private_messages {} {}", session_token, with);
-        let resp = self.send_multiline_command(host, cmd).await?;
-        
-        println!("[CHAT_SERVICE] Raw response: {}", resp);
+    /// Retrieve a page of private messages with another user as Vec<ChatMessage>,
+    /// together with the server's `has_more` flag for that page.
+    pub async fn get_private_messages(&mut self, host: &str, session_token: &str, with: &str, page: HistoryPage) -> anyhow::Result<(Vec<crate::client::models::app_state::ChatMessage>, bool)> {
+        let command = Command::GetPrivateMessages { with: with.to_string(), page };
+        let (history, has_more) = match self.request(host, Some(session_token), command).await? {
+            ResponseData::Messages { messages, has_more } => (messages, has_more),
+            other => return Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+        };
         
         // For private messages, participants are current user and the other user
         let participants = if let Some(current_user) = &self.current_user {
@@ -428,27 +505,88 @@ impl ChatService {
             vec![with.to_string()]
         };
         
-        let msgs = message_parser::parse_private_messages_with_participants(&resp, &participants)
-            .map_err(|e| anyhow::anyhow!(e))?;
+        let msgs = message_parser::history_to_chat_messages(history, &participants);
         
-        println!("[CHAT_SERVICE] Parsed {} messages", msgs.len());
+        println!("[CHAT_SERVICE] Loaded {} messages (has_more={})", msgs.len(), has_more);
         for (i, msg) in msgs.iter().enumerate() {
             println!("[CHAT_SERVICE] Message {}: {} -> {}", i, msg.sender, msg.content);
         }
         
+        Ok((msgs, has_more))
+    }
+
+    /// Retrieve the whole thread of a message (root and all replies) as Vec<ChatMessage>.
+    pub async fn get_thread(&mut self, host: &str, session_token: &str, message_id: i64) -> anyhow::Result<Vec<crate::client::models::app_state::ChatMessage>> {
+        let command = Command::GetThread { message_id };
+        let history = match self.request(host, Some(session_token), command).await? {
+            ResponseData::Messages { messages, .. } => messages,
+            other => return Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+        };
+        // Il server restituisce il testo già decifrato
+        let msgs = message_parser::history_to_chat_messages(history, &[]);
+        println!("[CHAT_SERVICE] Loaded thread of message #{}: {} messages", message_id, msgs.len());
         Ok(msgs)
     }
 
-    /// Send a group message using WebSocket if available, fallback to TCP.
-    /// Returns the raw server response.
-    pub async fn send_group_message(&mut self, host: &str, session_token: &str, group_id: &str, msg: &str) -> anyhow::Result<String> {
+    /// Mark the messages received in a chat as read, up to `up_to` (all when None).
+    /// Returns how many messages were newly marked.
+    pub async fn mark_private_read(&mut self, host: &str, session_token: &str, with: &str, up_to: Option<i64>) -> anyhow::Result<u32> {
+        let command = Command::MarkPrivateRead { with: with.to_string(), up_to };
+        match self.request(host, Some(session_token), command).await? {
+            ResponseData::MarkedRead { messages } => Ok(messages),
+            other => Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+        }
+    }
+
+    pub async fn mark_group_read(&mut self, host: &str, session_token: &str, group_id: &str, up_to: Option<i64>) -> anyhow::Result<u32> {
+        let command = Command::MarkGroupRead { group_id: group_id.to_string(), up_to };
+        match self.request(host, Some(session_token), command).await? {
+            ResponseData::MarkedRead { messages } => Ok(messages),
+            other => Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+        }
+    }
+
+    /// Imposta la presenza; restituisce il testo di stato salvato dal server.
+    pub async fn set_presence(&mut self, host: &str, session_token: &str, status: PresenceStatus, message: Option<String>) -> anyhow::Result<Option<String>> {
+        match self.request(host, Some(session_token), Command::SetPresence { status, message }).await? {
+            ResponseData::Presence { message, .. } => Ok(message),
+            other => Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+        }
+    }
+
+    pub async fn get_presence(&mut self, host: &str, session_token: &str) -> anyhow::Result<(PresenceStatus, Option<String>, LastSeenVisibility)> {
+        match self.request(host, Some(session_token), Command::GetPresence).await? {
+            ResponseData::Presence { status, message, last_seen_visibility } => Ok((status, message, last_seen_visibility)),
+            other => Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+        }
+    }
+
+    /// Sceglie chi può vedere il nostro ultimo accesso; restituisce l'impostazione salvata.
+    pub async fn set_last_seen_visibility(&mut self, host: &str, session_token: &str, visibility: LastSeenVisibility) -> anyhow::Result<LastSeenVisibility> {
+        match self.request(host, Some(session_token), Command::SetLastSeenVisibility { visibility }).await? {
+            ResponseData::Presence { last_seen_visibility, .. } => Ok(last_seen_visibility),
+            other => Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+        }
+    }
+
+    /// Presenza e ultimo accesso di un utente, come il server ce li mostra.
+    pub async fn get_user(&mut self, host: &str, session_token: &str, username: &str) -> anyhow::Result<UserInfo> {
+        match self.request(host, Some(session_token), Command::GetUser { username: username.to_string() }).await? {
+            ResponseData::User { user } => Ok(user),
+            other => Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+        }
+    }
+
+    /// Send a group message using WebSocket if available, fallback to TCP,
+    /// deduplicated by the server on `client_msg_id`. Returns the server acknowledgement.
+    pub async fn send_group_message(&mut self, host: &str, session_token: &str, group_id: &str, msg: &str, client_msg_id: &str, reply_to: Option<i64>) -> anyhow::Result<String> {
         // Try WebSocket first if connected
         if let Some(ref websocket) = self.websocket {
             if websocket.is_connected() {
-                match websocket.send_group_message(group_id, msg).await {
+                match websocket.send_group_message(group_id, msg, client_msg_id, reply_to).await {
                     Ok(()) => {
                         println!("[CHAT_SERVICE] Group message sent via WebSocket to group {}", group_id);
-                        return Ok("OK: Message sent via WebSocket".to_string());
+                        return Ok("Message sent via WebSocket".to_string());
                     }
                     Err(e) => {
                         println!("[CHAT_SERVICE] WebSocket group send failed: {}, falling back to TCP", e);
@@ -459,9 +597,94 @@ impl ChatService {
         }
         
         // Fallback to TCP
-        let cmd = format!("/send_group_message {} {} {}", session_token, group_id, msg);
-        let resp = self.send_command(host, cmd).await?;
-        Ok(resp)
+        let command = Command::SendGroupMessage { group_id: group_id.to_string(), content: msg.to_string(), client_msg_id: Some(client_msg_id.to_string()), reply_to };
+        match self.request(host, Some(session_token), command).await? {
+            ResponseData::MessageSent { message_id, seq, duplicate, .. } if duplicate => Ok(format!("Message already sent (#{} seq {})", message_id, seq)),
+            ResponseData::MessageSent { message_id, seq, .. } => Ok(format!("Message sent (#{} seq {})", message_id, seq)),
+            ResponseData::Ack { message } => Ok(message),
+            other => Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+        }
+    }
+
+    /// Edit one of our messages using WebSocket if available, fallback to TCP.
+    /// Over WebSocket the outcome arrives later as a `message_edited` event (`None`);
+    /// over TCP the server confirms directly with the edit time.
+    pub async fn edit_message(&mut self, host: &str, session_token: &str, message_id: i64, content: &str) -> anyhow::Result<Option<i64>> {
+        if let Some(ref websocket) = self.websocket {
+            if websocket.is_connected() {
+                match websocket.send_edit_message(message_id, content).await {
+                    Ok(()) => {
+                        println!("[CHAT_SERVICE] Edit of message #{} sent via WebSocket", message_id);
+                        return Ok(None);
+                    }
+                    Err(e) => println!("[CHAT_SERVICE] WebSocket edit failed: {}, falling back to TCP", e),
+                }
+            }
+        }
+
+        let command = Command::EditMessage { message_id, content: content.to_string() };
+        match self.request(host, Some(session_token), command).await? {
+            ResponseData::MessageEdited { edited_at, .. } => Ok(Some(edited_at)),
+            other => Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+        }
+    }
+
+    /// Delete one of our messages for everyone, using WebSocket if available, fallback to TCP.
+    /// Over WebSocket the outcome arrives later as a `message_deleted` event (`None`);
+    /// over TCP the server confirms directly with the deletion time.
+    pub async fn delete_message(&mut self, host: &str, session_token: &str, message_id: i64) -> anyhow::Result<Option<i64>> {
+        if let Some(ref websocket) = self.websocket {
+            if websocket.is_connected() {
+                match websocket.send_delete_message(message_id).await {
+                    Ok(()) => {
+                        println!("[CHAT_SERVICE] Deletion of message #{} sent via WebSocket", message_id);
+                        return Ok(None);
+                    }
+                    Err(e) => println!("[CHAT_SERVICE] WebSocket deletion failed: {}, falling back to TCP", e),
+                }
+            }
+        }
+
+        let command = Command::DeleteMessage { message_id };
+        match self.request(host, Some(session_token), command).await? {
+            ResponseData::MessageDeleted { deleted_at, .. } => Ok(Some(deleted_at)),
+            other => Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+        }
+    }
+
+    /// Set (`Some(emoji)`) or remove (`None`) our reaction to a message, using WebSocket if
+    /// available, fallback to TCP. Over TCP the server replies with the updated reactions.
+    pub async fn set_reaction(&mut self, host: &str, session_token: &str, message_id: i64, emoji: Option<&str>) -> anyhow::Result<Option<Vec<ReactionCount>>> {
+        if let Some(ref websocket) = self.websocket {
+            if websocket.is_connected() {
+                match websocket.send_reaction(message_id, emoji).await {
+                    Ok(()) => {
+                        println!("[CHAT_SERVICE] Reaction to message #{} sent via WebSocket", message_id);
+                        return Ok(None);
+                    }
+                    Err(e) => println!("[CHAT_SERVICE] WebSocket reaction failed: {}, falling back to TCP", e),
+                }
+            }
+        }
+
+        let command = match emoji {
+            Some(emoji) => Command::AddReaction { message_id, emoji: emoji.to_string() },
+            None => Command::RemoveReaction { message_id },
+        };
+        match self.request(host, Some(session_token), command).await? {
+            ResponseData::Reactions { reactions, .. } => Ok(Some(reactions)),
+            other => Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+        }
+    }
+
+    /// Send a typing indicator for a private (`to_user`) or group chat. Typing indicators
+    /// only travel over WebSocket: without a connection they are simply not sent.
+    pub async fn send_typing(&self, to_user: Option<&str>, group_id: Option<&str>, typing: bool) {
+        if let Some(ref websocket) = self.websocket {
+            if let Err(e) = websocket.send_typing(to_user, group_id, typing).await {
+                println!("[CHAT_SERVICE] Typing indicator not sent: {}", e);
+            }
+        }
     }
 
     /// Check for new messages via WebSocket (non-blocking)
@@ -485,29 +708,16 @@ impl ChatService {
 
     /// Get group members for proper message decryption
     pub async fn get_group_members(&mut self, host: &str, session_token: &str, group_id: &str) -> anyhow::Result<Vec<String>> {
-        let cmd = format!("/group_members {} {}", session_token, group_id);
-        let resp = self.send_command(host, cmd).await?;
-        
-        // Parse response format: "OK: Group members: user1, user2, user3"
-        if resp.starts_with("OK: Group members: ") {
-            let members_str = resp.strip_prefix("OK: Group members: ").unwrap_or("");
-            if members_str.is_empty() {
-                Ok(vec![])
-            } else {
-                let members: Vec<String> = members_str
-                    .split(", ")
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect();
-                Ok(members)
+        let command = Command::GroupMembers { group_id: group_id.to_string() };
+        match self.request(host, Some(session_token), command).await {
+            Ok(ResponseData::GroupMembers { members }) => Ok(members),
+            Ok(other) => Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+            // User left the group - keep the typed error so callers can react
+            Err(e) if e.code == ErrorCode::NotMember => Err(e.into()),
+            Err(e) => {
+                println!("[CHAT_SERVICE] Failed to get group members: {}", e);
+                Err(e.into())
             }
-        } else if resp.starts_with("ERR: Not a group member") {
-            // User left the group - return specific error
-            Err(anyhow::anyhow!("NOT_A_MEMBER"))
-        } else {
-            // Other error or unexpected format
-            println!("[CHAT_SERVICE] Failed to get group members: {}", resp);
-            Err(anyhow::anyhow!("Failed to get group members: {}", resp))
         }
     }
 }
@@ -515,18 +725,19 @@ impl ChatService {
 
 
 impl ChatService {
-    /// Retrieve group messages and return them parsed as Vec<ChatMessage>.
-    pub async fn get_group_messages(&mut self, host: &str, session_token: &str, group_id: &str) -> anyhow::Result<Vec<crate::client::models::app_state::ChatMessage>> {
+    /// Retrieve a page of group messages parsed as Vec<ChatMessage>, together with
+    /// the server's `has_more` flag for that page.
+    pub async fn get_group_messages(&mut self, host: &str, session_token: &str, group_id: &str, page: HistoryPage) -> anyhow::Result<(Vec<crate::client::models::app_state::ChatMessage>, bool)> {
         // First get the group members for proper decryption
         let participants = match self.get_group_members(host, session_token, group_id).await {
             Ok(members) => {
                 println!("[CHAT_SERVICE] Got {} members for group {}: {:?}", members.len(), group_id, members);
                 members
             }
-            Err(e) if e.to_string().contains("NOT_A_MEMBER") => {
-                // User is no longer a member of this group
-                println!("[CHAT_SERVICE] User is not a member of group {}, stopping polling", group_id);
-                return Err(anyhow::anyhow!("NOT_A_MEMBER"));
+            Err(e) if matches!(error_code(&e), Some(ErrorCode::NotMember | ErrorCode::SessionExpired)) => {
+                // User is no longer a member of this group (or the session is gone)
+                println!("[CHAT_SERVICE] Cannot read group {}: {}, stopping polling", group_id, e);
+                return Err(e);
             }
             Err(e) => {
                 println!("[CHAT_SERVICE] Failed to get group members for {}: {}, using empty participants", group_id, e);
@@ -535,17 +746,19 @@ impl ChatService {
         };
 
         // Then get the group messages
-        let cmd = format!("/get_group_messages {} {}", session_token, group_id);
-        let resp = self.send_multiline_command(host, cmd).await?;
-        
-        // Check if user is not a member
-        if resp.starts_with("ERR: Not a group member") {
-            return Err(anyhow::anyhow!("NOT_A_MEMBER"));
-        }
+        let command = Command::GetGroupMessages { group_id: group_id.to_string(), page };
+        let (history, has_more) = match self.request(host, Some(session_token), command).await {
+            Ok(ResponseData::Messages { messages, has_more }) => (messages, has_more),
+            Ok(other) => return Err(anyhow::anyhow!("Unexpected response: {:?}", other)),
+            Err(e) => return Err(e.into()),
+        };
         
-        // Parse messages with proper participants for decryption
-        let msgs = message_parser::parse_group_messages_with_participants(&resp, &participants)
-            .map_err(|e| anyhow::anyhow!(e))?;
-        Ok(msgs)
+        // Decrypt messages with proper participants
+        Ok((message_parser::history_to_chat_messages(history, &participants), has_more))
     }
-}
\ No newline at end of file
+}
+
+/// Codice di errore tipizzato trasportato da un `anyhow::Error`, se presente.
+pub fn error_code(e: &anyhow::Error) -> Option<ErrorCode> {
+    e.downcast_ref::<ChatError>().map(|c| c.code)
+}
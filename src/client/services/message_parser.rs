@@ -1,6 +1,7 @@
 // Modulo di parsing messaggi lato client
 use crate::client::models::app_state::ChatMessage;
 use crate::common::crypto::CryptoManager;
+use crate::common::protocol::HistoryMessage;
 use base64::{Engine as _, engine::general_purpose};
 
 /// Attempt to decrypt a message content if it appears to be encrypted JSON
@@ -33,84 +34,34 @@ fn try_decrypt_content(content: &str, participants: &[String]) -> String {
     content.to_string()
 }
 
-/// Parse server `OK: Messages:\n<lines...>` responses into Vec<String>.
-pub fn parse_messages(resp: &str) -> Result<Vec<String>, &'static str> {
-	let trimmed = resp.trim();
-	if !trimmed.starts_with("OK: Messages:") {
-		return Err("unexpected response format");
-	}
-	// Split after the first newline and collect remaining non-empty lines
-	let mut parts = trimmed.splitn(2, '\n');
-	parts.next(); // skip the OK header
-	if let Some(body) = parts.next() {
-		let msgs: Vec<String> = body.lines().map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect();
-		Ok(msgs)
-	} else {
-		Ok(vec![])
-	}
-}
-
-/// Parse private messages from server response into ChatMessage structs with decryption
-pub fn parse_private_messages_with_participants(resp: &str, participants: &[String]) -> Result<Vec<ChatMessage>, &'static str> {
-    let trimmed = resp.trim();
-    if !trimmed.starts_with("OK: Messages:") {
-        return Err("unexpected response format");
-    }
-    
-    let mut parts = trimmed.splitn(2, '\n');
-    parts.next(); // skip the OK header
-    
-    if let Some(body) = parts.next() {
-        let mut messages = Vec::new();
-        
-        for line in body.lines() {
-            let line = line.trim();
-            if line.is_empty() {
-                continue;
-            }
-            
-            // Expected format: [timestamp] sender: message
-            if let Some(bracket_end) = line.find(']') {
-                if line.starts_with('[') {
-                    let timestamp_str = &line[1..bracket_end];
-                    let rest = &line[bracket_end + 1..].trim();
-                    
-                    if let Some(colon_pos) = rest.find(':') {
-                        let sender = rest[..colon_pos].trim().to_string();
-                        let raw_content = rest[colon_pos + 1..].trim().to_string();
-                        
-                        if let Ok(timestamp) = timestamp_str.parse::<i64>() {
-                            let formatted_time = format_timestamp(timestamp);
-                            
-                            // Try to decrypt the content if it's encrypted
-                            let decrypted_content = try_decrypt_content(&raw_content, participants);
-                            
-                            messages.push(ChatMessage {
-                                sender,
-                                content: decrypted_content,
-                                timestamp,
-                                formatted_time,
-                                sent_at: timestamp,
-                                is_pending: false,  // HTTP messages are confirmed by server
-                            });
-                        }
-                    }
-                }
-            }
+/// Convert the typed history returned by the server into ChatMessage structs,
+/// decrypting any content that is still encrypted with the chat key.
+pub fn history_to_chat_messages(history: Vec<HistoryMessage>, participants: &[String]) -> Vec<ChatMessage> {
+    let mut messages: Vec<ChatMessage> = history.into_iter().map(|m| {
+        ChatMessage {
+            // Server più vecchi non inviano l'id (0)
+            id: (m.id > 0).then_some(m.id),
+            seq: (m.seq > 0).then_some(m.seq),
+            client_msg_id: None,
+            sender: m.sender,
+            content: try_decrypt_content(&m.content, participants),
+            timestamp: m.sent_at,
+            formatted_time: format_timestamp(m.sent_at),
+            sent_at: m.sent_at,
+            is_pending: false,  // History messages are confirmed by server
+            edited_at: m.edited_at,
+            deleted: m.deleted,
+            reactions: m.reactions,
+            reply_to: m.reply_to,
+            quoted: m.quoted,
+            delivered_to: m.delivered_to,
+            read_by: m.read_by,
         }
-        
-        // Sort by timestamp to ensure chronological order
-        messages.sort_by_key(|m| m.timestamp);
-        Ok(messages)
-    } else {
-        Ok(vec![])
-    }
-}
-
-/// Parse private messages from server response into ChatMessage structs (legacy version)
-pub fn parse_private_messages(resp: &str) -> Result<Vec<ChatMessage>, &'static str> {
-    // Use empty participants list for backward compatibility
-    parse_private_messages_with_participants(resp, &[])
+    }).collect();
+    
+    // Sort by sequence number (timestamp for servers without it) to ensure chronological order
+    messages.sort_by_key(|m| (m.seq, m.timestamp));
+    messages
 }
 
 pub fn format_timestamp(timestamp: i64) -> String {
@@ -123,65 +74,19 @@ pub fn format_timestamp(timestamp: i64) -> String {
     local_dt.format("%H:%M").to_string()
 }
 
-/// Parse group messages from server response into ChatMessage structs with decryption
-pub fn parse_group_messages_with_participants(resp: &str, participants: &[String]) -> Result<Vec<ChatMessage>, &'static str> {
-    let trimmed = resp.trim();
-    if !trimmed.starts_with("OK: Messages:") {
-        return Err("unexpected response format");
-    }
-    
-    let mut parts = trimmed.splitn(2, '\n');
-    parts.next(); // skip the OK header
-    
-    if let Some(body) = parts.next() {
-        let mut messages = Vec::new();
-        
-        for line in body.lines() {
-            let line = line.trim();
-            if line.is_empty() {
-                continue;
-            }
-            
-            // Expected format: [timestamp] sender_name: message
-            if let Some(bracket_end) = line.find(']') {
-                if line.starts_with('[') {
-                    let timestamp_str = &line[1..bracket_end];
-                    let rest = &line[bracket_end + 1..].trim();
-                    
-                    if let Some(colon_pos) = rest.find(':') {
-                        let sender_name = rest[..colon_pos].trim().to_string();
-                        let raw_content = rest[colon_pos + 1..].trim().to_string();
-                        
-                        if let Ok(timestamp) = timestamp_str.parse::<i64>() {
-                            let formatted_time = format_timestamp(timestamp);
-                            
-                            // Try to decrypt the content if it's encrypted
-                            let decrypted_content = try_decrypt_content(&raw_content, participants);
-                            
-                            messages.push(ChatMessage {
-                                sender: sender_name, // Now shows actual username
-                                content: decrypted_content,
-                                timestamp,
-                                formatted_time,
-                                sent_at: timestamp,
-                                is_pending: false,  // HTTP messages are confirmed by server
-                            });
-                        }
-                    }
-                }
-            }
-        }
-        
-        // Sort by timestamp to ensure chronological order
-        messages.sort_by_key(|m| m.timestamp);
-        Ok(messages)
+/// Ultimo accesso in forma leggibile: "today at 14:05", "yesterday at 09:30" o "03/10/2026 18:20".
+pub fn format_last_seen(timestamp: i64) -> String {
+    use chrono::{DateTime, Utc, Local, TimeZone};
+
+    let dt = Utc.timestamp_opt(timestamp, 0).single().unwrap_or_else(Utc::now);
+    let local_dt: DateTime<Local> = dt.with_timezone(&Local);
+    let today = Local::now().date_naive();
+
+    if local_dt.date_naive() == today {
+        format!("today at {}", local_dt.format("%H:%M"))
+    } else if today.pred_opt() == Some(local_dt.date_naive()) {
+        format!("yesterday at {}", local_dt.format("%H:%M"))
     } else {
-        Ok(vec![])
+        local_dt.format("%d/%m/%Y %H:%M").to_string()
     }
 }
-
-/// Parse group messages from server response into ChatMessage structs (legacy version)
-pub fn parse_group_messages(resp: &str) -> Result<Vec<ChatMessage>, &'static str> {
-    // Use empty participants list for backward compatibility
-    parse_group_messages_with_participants(resp, &[])
-}
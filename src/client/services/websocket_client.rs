@@ -1,8 +1,14 @@
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::{client_async, tungstenite::Message, WebSocketStream};
 use futures_util::{SinkExt, StreamExt};
 use serde::{Serialize, Deserialize};
 use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use crate::client::utils::tls::{self, TlsSettings};
+use crate::common::error::{ChatError, ErrorCode};
+use crate::common::protocol::{FriendRequestInfo, GroupInviteInfo, InboxEntry, PresenceStatus, QuotedMessage, ReactionCount, UserInfo};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthMessage {
@@ -16,6 +22,8 @@ pub struct AuthResponse {
     pub success: bool,
     pub user_id: Option<String>,
     pub error: Option<String>,
+    #[serde(default)]
+    pub code: Option<ErrorCode>,
 }
 
 // Messaggio ricevuto dal WebSocket - rappresenta un nuovo messaggio chat
@@ -28,6 +36,25 @@ pub struct IncomingChatMessage {
     pub group_id: Option<String>, // per messaggi di gruppo  
     pub content: String,
     pub timestamp: i64,
+    /// Id del messaggio e numero di sequenza nella chat (assenti con server meno recenti)
+    #[serde(default)]
+    pub id: Option<i64>,
+    #[serde(default)]
+    pub seq: Option<i64>,
+    /// UUID assegnato dal mittente: permette di confermare il messaggio locale in attesa
+    #[serde(default)]
+    pub client_msg_id: Option<String>,
+    /// Orario della modifica (solo negli eventi `message_edited`)
+    #[serde(default)]
+    pub edited_at: Option<i64>,
+    /// Reazioni aggiornate (solo negli eventi `reactions_updated`)
+    #[serde(default)]
+    pub reactions: Vec<ReactionCount>,
+    /// Messaggio a cui risponde, con il suo estratto
+    #[serde(default)]
+    pub reply_to: Option<i64>,
+    #[serde(default)]
+    pub quoted: Option<QuotedMessage>,
 }
 
 // Messaggio da inviare tramite WebSocket
@@ -39,12 +66,134 @@ pub struct OutgoingChatMessage {
     pub group_id: Option<String>, // per messaggi di gruppo
     pub content: String,
     pub session_token: String,
+    /// UUID del messaggio: il server ignora i nuovi invii con lo stesso id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_msg_id: Option<String>,
+    /// Id del messaggio a cui si risponde
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<i64>,
+}
+
+// Modifica di un proprio messaggio già inviato
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EditMessageFrame {
+    pub message_type: String, // "edit_message"
+    pub message_id: i64,
+    pub content: String,
+}
+
+// Ritiro di un proprio messaggio per tutti i partecipanti
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteMessageFrame {
+    pub message_type: String, // "delete_message"
+    pub message_id: i64,
+}
+
+// Aggiunta (con emoji) o rimozione della propria reazione a un messaggio
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionFrame {
+    pub message_type: String, // "add_reaction" o "remove_reaction"
+    pub message_id: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emoji: Option<String>,
+}
+
+// Inizio o fine della scrittura in una chat privata (`to_user`) o di gruppo (`group_id`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypingFrame {
+    pub message_type: String, // "start_typing" o "stop_typing"
+    pub chat_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+}
+
+/// Un altro partecipante ha iniziato o smesso di scrivere (`message_type: "typing"`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypingEvent {
+    pub chat_type: String,
+    pub from_user: String,
+    #[serde(default)]
+    pub to_user: Option<String>,
+    #[serde(default)]
+    pub group_id: Option<String>,
+    pub typing: bool,
+}
+
+/// Conferme aggiornate di alcuni propri messaggi di una chat (`message_type: "message_receipts"`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiptsEvent {
+    pub receipts: Vec<MessageReceipt>,
+}
+
+/// Destinatari che hanno ricevuto e letto un messaggio.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageReceipt {
+    pub id: i64,
+    pub delivered_to: u32,
+    pub read_by: u32,
+}
+
+// Richiesta degli eventi successivi a `since` (senza `since` solo il cursore attuale),
+// inviata a ogni connessione
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncFrame {
+    pub message_type: String, // "sync"
+    pub since: Option<i64>,
+}
+
+/// Frame inviato al server: il campo `message_type` ne indica il tipo.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum OutgoingFrame {
+    Chat(OutgoingChatMessage),
+    Edit(EditMessageFrame),
+    Delete(DeleteMessageFrame),
+    Reaction(ReactionFrame),
+    Typing(TypingFrame),
+}
+
+impl OutgoingFrame {
+    fn message_type(&self) -> &str {
+        match self {
+            OutgoingFrame::Chat(m) => &m.message_type,
+            OutgoingFrame::Edit(e) => &e.message_type,
+            OutgoingFrame::Delete(d) => &d.message_type,
+            OutgoingFrame::Reaction(r) => &r.message_type,
+            OutgoingFrame::Typing(t) => &t.message_type,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum WebSocketMessage {
     NewMessage(IncomingChatMessage),
-    UserStatusUpdate { user_id: String, online: bool },
+    /// Un messaggio già ricevuto è stato modificato dal mittente
+    MessageEdited(IncomingChatMessage),
+    /// Un messaggio è stato ritirato per tutti (contenuto vuoto)
+    MessageDeleted(IncomingChatMessage),
+    /// Reazioni aggiornate di un messaggio (in `reactions`)
+    ReactionsUpdated(IncomingChatMessage),
+    /// Conferme di consegna e lettura di alcuni propri messaggi
+    ReceiptsUpdated(Vec<MessageReceipt>),
+    /// Indicatore di scrittura di un altro partecipante
+    Typing(TypingEvent),
+    /// Non letti e anteprima aggiornati di una chat (nuovo messaggio, lettura, ritiro)
+    InboxUpdated(InboxEntry),
+    /// Un amico è entrato (prima connessione), uscito (ultima connessione chiusa) o ha
+    /// cambiato stato; `Offline` anche quando diventa invisibile, con l'ultimo accesso se visibile
+    UserStatusUpdate { user_id: String, presence: UserInfo },
+    /// Recupero dopo la connessione concluso: `events` eventi persi rimandati, fino a `latest_seq`
+    SyncCompleted { events: u32, latest_seq: i64 },
+    /// Nuova richiesta di amicizia ricevuta
+    FriendRequest(FriendRequestInfo),
+    /// Invito a un gruppo ricevuto
+    GroupInvite(GroupInviteInfo),
+    /// Errore tipizzato del server su un messaggio inviato (es. `rate_limited`)
+    ServerError(ChatError),
+    /// Avviso del server (es. arresto in corso)
+    System(String),
     Error(String),
 }
 
@@ -52,6 +201,8 @@ pub enum WebSocketMessage {
 pub enum WebSocketError {
     ConnectionFailed(String),
     AuthenticationFailed(String),
+    /// Il server ha raggiunto il limite di connessioni: riprovare più tardi
+    ServerFull(String),
     MessageSendFailed(String),
     Disconnected,
     InvalidMessage(String),
@@ -63,6 +214,7 @@ impl std::fmt::Display for WebSocketError {
         match self {
             WebSocketError::ConnectionFailed(msg) => write!(f, "Connection failed: {}", msg),
             WebSocketError::AuthenticationFailed(msg) => write!(f, "Authentication failed: {}", msg),
+            WebSocketError::ServerFull(msg) => write!(f, "{}", msg),
             WebSocketError::MessageSendFailed(msg) => write!(f, "Message send failed: {}", msg),
             WebSocketError::Disconnected => write!(f, "WebSocket disconnected"),
             WebSocketError::InvalidMessage(msg) => write!(f, "Invalid message: {}", msg),
@@ -73,89 +225,71 @@ impl std::fmt::Display for WebSocketError {
 
 impl std::error::Error for WebSocketError {}
 
-pub struct WebSocketClient {
-    url: String,
-    session_token: Option<String>,
-    connection_retry_attempts: u32,
-    max_retry_attempts: u32,
-    retry_delay: tokio::time::Duration,
-    /// Channel per inviare messaggi ricevuti all'applicazione
-    pub message_sender: Option<mpsc::UnboundedSender<WebSocketMessage>>,
-    /// Receiver per l'applicazione per ricevere i messaggi
-    pub message_receiver: Option<mpsc::UnboundedReceiver<WebSocketMessage>>,
-    /// Sender per inviare messaggi al WebSocket
-    pub outgoing_sender: Option<mpsc::UnboundedSender<OutgoingChatMessage>>,
-}
-
-impl WebSocketClient {
-    pub fn new(url: String) -> Self {
-        let (tx, rx) = mpsc::unbounded_channel();
-        Self {
-            url,
-            session_token: None,
-            connection_retry_attempts: 0,
-            max_retry_attempts: 5,
-            retry_delay: tokio::time::Duration::from_secs(2),
-            message_sender: Some(tx),
-            message_receiver: Some(rx),
-            outgoing_sender: None,
-        }
-    }
+/// Ritardo iniziale e massimo tra due tentativi di riconnessione automatica
+const RECONNECT_DELAY: tokio::time::Duration = tokio::time::Duration::from_secs(2);
+const MAX_RECONNECT_DELAY: tokio::time::Duration = tokio::time::Duration::from_secs(30);
 
-    /// Prende il receiver per l'applicazione - può essere chiamato solo una volta
-    pub fn take_receiver(&mut self) -> Option<mpsc::UnboundedReceiver<WebSocketMessage>> {
-        self.message_receiver.take()
-    }
+/// Flusso sotto il WebSocket: TCP semplice o TLS
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
 
-    pub fn set_session_token(&mut self, token: String) {
-        self.session_token = Some(token);
-    }
+type WsStream = WebSocketStream<Box<dyn Transport>>;
 
-    pub async fn connect_with_auth(&mut self) -> Result<(), WebSocketError> {
-        for attempt in 1..=self.max_retry_attempts {
-            match self.try_connect().await {
-                Ok(outgoing_sender) => {
-                    self.connection_retry_attempts = 0;
-                    self.outgoing_sender = Some(outgoing_sender);
-                    println!("[WS:CLIENT] Successfully connected and authenticated");
-                    return Ok(());
-                }
-                Err(e) => {
-                    self.connection_retry_attempts = attempt;
-                    println!("[WS:CLIENT] Connection attempt {} failed: {}", attempt, e);
-                    
-                    if attempt < self.max_retry_attempts {
-                        println!("[WS:CLIENT] Retrying in {:?}...", self.retry_delay);
-                        tokio::time::sleep(self.retry_delay).await;
-                        // Exponential backoff
-                        self.retry_delay = std::cmp::min(
-                            self.retry_delay * 2,
-                            tokio::time::Duration::from_secs(30)
-                        );
-                    } else {
-                        return Err(e);
-                    }
-                }
-            }
-        }
-        
-        Err(WebSocketError::ConnectionFailed("Max retry attempts exceeded".to_string()))
-    }
+/// Server e credenziali della connessione, usati anche dal task che si riconnette
+#[derive(Clone)]
+struct Endpoint {
+    url: String,
+    session_token: Option<String>,
+    tls: TlsSettings,
+}
 
-    async fn try_connect(&self) -> Result<mpsc::UnboundedSender<OutgoingChatMessage>, WebSocketError> {
+impl Endpoint {
+    /// Apre la connessione (TLS per gli URL wss://) e la autentica
+    async fn connect(&self) -> Result<WsStream, WebSocketError> {
         // Connect to WebSocket
         println!("[WS:CLIENT] Connecting to {}", self.url);
-        let (ws_stream, _) = connect_async(&self.url)
+        let url = url::Url::parse(&self.url)
+            .map_err(|e| WebSocketError::ConnectionFailed(format!("Invalid URL {}: {}", self.url, e)))?;
+
+        let transport: Box<dyn Transport> = if url.scheme() == "wss" {
+            let tls_stream = self.connect_tls(&url).await.map_err(|e| {
+                println!("[WS:CLIENT] TLS connection failed: {}", e);
+                WebSocketError::ConnectionFailed(format!("TLS connection failed: {}", e))
+            })?;
+            Box::new(tls_stream)
+        } else {
+            let host = url.host_str()
+                .ok_or_else(|| WebSocketError::ConnectionFailed(format!("Missing host in {}", self.url)))?;
+            let port = url.port_or_known_default().unwrap_or(80);
+            let tcp = tokio::net::TcpStream::connect((host, port)).await.map_err(|e| {
+                println!("[WS:CLIENT] Connection failed: {}", e);
+                WebSocketError::ConnectionFailed(format!("Failed to connect: {}", e))
+            })?;
+            Box::new(tcp)
+        };
+
+        let (ws_stream, _) = client_async(self.url.as_str(), transport)
             .await
             .map_err(|e| {
                 println!("[WS:CLIENT] Connection failed: {}", e);
                 WebSocketError::ConnectionFailed(format!("Failed to connect: {}", e))
             })?;
 
-        println!("[WS:CLIENT] Connected to {}", self.url);
+        println!("[WS:CLIENT] Connected to {}{}", self.url, if url.scheme() == "wss" { " (TLS)" } else { "" });
+        self.authenticate(ws_stream).await
+    }
 
-        let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    /// Apre la connessione TCP e completa l'handshake TLS verso l'host dell'URL
+    async fn connect_tls(&self, url: &url::Url) -> anyhow::Result<tokio_rustls::client::TlsStream<tokio::net::TcpStream>> {
+        let host = url.host_str().ok_or_else(|| anyhow::anyhow!("Missing host in {}", url))?;
+        let port = url.port_or_known_default().unwrap_or(443);
+        let connector = self.tls.connector()?;
+        let tcp = tokio::net::TcpStream::connect((host, port)).await?;
+        Ok(connector.connect(tls::server_name(host)?, tcp).await?)
+    }
 
+    /// Invia il messaggio di autenticazione e attende la risposta del server
+    async fn authenticate(&self, mut ws_stream: WsStream) -> Result<WsStream, WebSocketError> {
         // Send authentication message
         println!("[WS:CLIENT] Sending authentication message");
         let auth_message = AuthMessage {
@@ -167,7 +301,7 @@ impl WebSocketClient {
         let auth_json = serde_json::to_string(&auth_message)
             .map_err(|e| WebSocketError::AuthenticationFailed(format!("Failed to serialize auth message: {}", e)))?;
 
-        ws_sender
+        ws_stream
             .send(Message::Text(auth_json))
             .await
             .map_err(|e| WebSocketError::AuthenticationFailed(format!("Failed to send auth message: {}", e)))?;
@@ -176,7 +310,7 @@ impl WebSocketClient {
         println!("[WS:CLIENT] Waiting for authentication response");
         let auth_timeout = tokio::time::timeout(
             tokio::time::Duration::from_secs(10),
-            ws_receiver.next()
+            ws_stream.next()
         ).await;
 
         let auth_response = match auth_timeout {
@@ -204,94 +338,240 @@ impl WebSocketClient {
 
         if auth_response.success {
             println!("[WS:CLIENT] Authentication successful for user: {:?}", auth_response.user_id);
-            
-            // Crea channel per messaggi in uscita
-            let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<OutgoingChatMessage>();
-            
-            // Avvia il loop di gestione messaggi in background
-            if let Some(sender) = &self.message_sender {
-                let sender_clone = sender.clone();
-                
-                // Spawn task per gestire messaggi in arrivo
-                tokio::spawn(async move {
-                    Self::handle_incoming_messages(ws_receiver, sender_clone).await;
-                });
-                
-                // Spawn task per gestire messaggi in uscita
-                tokio::spawn(async move {
-                    println!("[WS:CLIENT] Starting outgoing message handler");
-                    while let Some(outgoing_msg) = outgoing_rx.recv().await {
-                        println!("[WS:CLIENT] Received outgoing message: {:?}", outgoing_msg.message_type);
-                        match serde_json::to_string(&outgoing_msg) {
-                            Ok(json) => {
-                                println!("[WS:CLIENT] Sending JSON: {}", json);
-                                if let Err(e) = ws_sender.send(Message::Text(json)).await {
-                                    println!("[WS:CLIENT] Failed to send message: {}", e);
-                                    break;
-                                }
-                                println!("[WS:CLIENT] Message sent successfully");
-                            }
-                            Err(e) => {
-                                println!("[WS:CLIENT] Failed to serialize outgoing message: {}", e);
-                            }
-                        }
-                    }
-                    println!("[WS:CLIENT] Outgoing message handler ended");
-                });
-            }
-            
-            Ok(outgoing_tx)
+            Ok(ws_stream)
         } else {
             let error_msg = auth_response.error.unwrap_or_else(|| "Unknown authentication error".to_string());
+            if auth_response.code == Some(ErrorCode::ServerFull) {
+                println!("[WS:CLIENT] Connection refused: {}", error_msg);
+                return Err(WebSocketError::ServerFull(error_msg));
+            }
             println!("[WS:CLIENT] Authentication failed: {}", error_msg);
             Err(WebSocketError::AuthenticationFailed(error_msg))
         }
     }
+}
 
-   
+pub struct WebSocketClient {
+    url: String,
+    session_token: Option<String>,
+    connection_retry_attempts: u32,
+    max_retry_attempts: u32,
+    retry_delay: tokio::time::Duration,
+    /// Radici e pin usati per verificare il server sugli URL wss://
+    tls: TlsSettings,
+    /// Ultimo evento ricevuto (`sync_seq`, 0 se ancora ignoto): dopo una riconnessione il
+    /// server rimanda quelli successivi
+    sync_cursor: Arc<AtomicI64>,
+    /// Channel per inviare messaggi ricevuti all'applicazione
+    pub message_sender: Option<mpsc::UnboundedSender<WebSocketMessage>>,
+    /// Receiver per l'applicazione per ricevere i messaggi
+    pub message_receiver: Option<mpsc::UnboundedReceiver<WebSocketMessage>>,
+    /// Sender per inviare messaggi al WebSocket
+    pub outgoing_sender: Option<mpsc::UnboundedSender<OutgoingFrame>>,
+}
 
+impl WebSocketClient {
+    pub fn new(url: String) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            url,
+            session_token: None,
+            connection_retry_attempts: 0,
+            max_retry_attempts: 5,
+            retry_delay: tokio::time::Duration::from_secs(2),
+            tls: TlsSettings::default(),
+            sync_cursor: Arc::new(AtomicI64::new(0)),
+            message_sender: Some(tx),
+            message_receiver: Some(rx),
+            outgoing_sender: None,
+        }
+    }
+
+    /// Prende il receiver per l'applicazione - può essere chiamato solo una volta
+    pub fn take_receiver(&mut self) -> Option<mpsc::UnboundedReceiver<WebSocketMessage>> {
+        self.message_receiver.take()
+    }
+
+    pub fn set_session_token(&mut self, token: String) {
+        self.session_token = Some(token);
+    }
+
+    pub fn set_tls(&mut self, tls: TlsSettings) {
+        self.tls = tls;
+    }
 
+    fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            url: self.url.clone(),
+            session_token: self.session_token.clone(),
+            tls: self.tls.clone(),
+        }
+    }
 
+    pub async fn connect_with_auth(&mut self) -> Result<(), WebSocketError> {
+        let endpoint = self.endpoint();
+        for attempt in 1..=self.max_retry_attempts {
+            match endpoint.connect().await {
+                Ok(ws_stream) => {
+                    self.connection_retry_attempts = 0;
+                    // Il canale in uscita sopravvive alle riconnessioni: i frame accodati
+                    // mentre la connessione è giù partono appena torna
+                    let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel::<OutgoingFrame>();
+                    if let Some(sender) = &self.message_sender {
+                        tokio::spawn(Self::run_session(endpoint.clone(), ws_stream, outgoing_rx, sender.clone(), self.sync_cursor.clone()));
+                    }
+                    self.outgoing_sender = Some(outgoing_tx);
+                    println!("[WS:CLIENT] Successfully connected and authenticated");
+                    return Ok(());
+                }
+                Err(e) => {
+                    self.connection_retry_attempts = attempt;
+                    println!("[WS:CLIENT] Connection attempt {} failed: {}", attempt, e);
+                    
+                    if attempt < self.max_retry_attempts {
+                        println!("[WS:CLIENT] Retrying in {:?}...", self.retry_delay);
+                        tokio::time::sleep(self.retry_delay).await;
+                        // Exponential backoff
+                        self.retry_delay = std::cmp::min(
+                            self.retry_delay * 2,
+                            tokio::time::Duration::from_secs(30)
+                        );
+                    } else {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        
+        Err(WebSocketError::ConnectionFailed("Max retry attempts exceeded".to_string()))
+    }
 
-    /// Gestisce i messaggi in arrivo dal WebSocket in background
-    async fn handle_incoming_messages(
-        mut ws_receiver: futures_util::stream::SplitStream<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>,
-        sender: mpsc::UnboundedSender<WebSocketMessage>
+    /// Tiene viva la connessione finché l'applicazione la usa: se cade lo segnala, si
+    /// riconnette e recupera gli eventi persi nel frattempo
+    async fn run_session(
+        endpoint: Endpoint,
+        mut ws_stream: WsStream,
+        mut outgoing_rx: mpsc::UnboundedReceiver<OutgoingFrame>,
+        sender: mpsc::UnboundedSender<WebSocketMessage>,
+        cursor: Arc<AtomicI64>,
     ) {
-        println!("[WS:CLIENT] Starting incoming message handler");
-        while let Some(message) = ws_receiver.next().await {
-            match message {
-                Ok(Message::Text(text)) => {
-                    println!("[WS:CLIENT] Received message: {}", text);
-                    match Self::parse_websocket_message(&text) {
-                        Ok(ws_msg) => {
-                            if let Err(_) = sender.send(ws_msg) {
-                                println!("[WS:CLIENT] Failed to send message to application - receiver dropped");
-                                break;
+        while let Some(reason) = Self::pump(ws_stream, &mut outgoing_rx, &sender, &cursor).await {
+            let _ = sender.send(WebSocketMessage::Error(reason));
+            match Self::reconnect(&endpoint, &sender).await {
+                Some(stream) => ws_stream = stream,
+                None => break,
+            }
+        }
+        println!("[WS:CLIENT] Session ended");
+    }
+
+    /// Riprova con backoff finché l'applicazione è in ascolto; rinuncia se il server rifiuta
+    /// la sessione
+    async fn reconnect(endpoint: &Endpoint, sender: &mpsc::UnboundedSender<WebSocketMessage>) -> Option<WsStream> {
+        let mut delay = RECONNECT_DELAY;
+        while !sender.is_closed() {
+            println!("[WS:CLIENT] Reconnecting in {:?}...", delay);
+            tokio::time::sleep(delay).await;
+            match endpoint.connect().await {
+                Ok(ws_stream) => {
+                    println!("[WS:CLIENT] Reconnected to {}", endpoint.url);
+                    return Some(ws_stream);
+                }
+                Err(WebSocketError::AuthenticationFailed(e)) => {
+                    println!("[WS:CLIENT] Giving up reconnecting: {}", e);
+                    let _ = sender.send(WebSocketMessage::Error(format!("Authentication failed: {}", e)));
+                    return None;
+                }
+                Err(e) => {
+                    println!("[WS:CLIENT] Reconnection failed: {}", e);
+                    delay = std::cmp::min(delay * 2, MAX_RECONNECT_DELAY);
+                }
+            }
+        }
+        None
+    }
+
+    /// Chiede gli eventi successivi al cursore, poi inoltra i frame in uscita al server e
+    /// quelli in arrivo all'applicazione. Restituisce il motivo se la connessione cade,
+    /// `None` se l'applicazione non la usa più.
+    async fn pump(
+        ws_stream: WsStream,
+        outgoing_rx: &mut mpsc::UnboundedReceiver<OutgoingFrame>,
+        sender: &mpsc::UnboundedSender<WebSocketMessage>,
+        cursor: &AtomicI64,
+    ) -> Option<String> {
+        let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+        let since = cursor.load(Ordering::SeqCst);
+        let sync = SyncFrame { message_type: "sync".to_string(), since: (since > 0).then_some(since) };
+        println!("[WS:CLIENT] Requesting events after #{}", since);
+        if let Err(e) = ws_sender.send(Message::Text(serde_json::to_string(&sync).unwrap_or_default())).await {
+            return Some(format!("WebSocket error: {}", e));
+        }
+        // Fino a `sync_complete` gli eventi possono arrivare dal recupero, intrecciati con
+        // quelli nuovi: il cursore avanza solo a recupero finito
+        let mut syncing = true;
+
+        loop {
+            tokio::select! {
+                outgoing = outgoing_rx.recv() => {
+                    let Some(outgoing_msg) = outgoing else {
+                        println!("[WS:CLIENT] Outgoing channel closed, closing connection");
+                        let _ = ws_sender.close().await;
+                        return None;
+                    };
+                    println!("[WS:CLIENT] Received outgoing message: {:?}", outgoing_msg.message_type());
+                    match serde_json::to_string(&outgoing_msg) {
+                        Ok(json) => {
+                            println!("[WS:CLIENT] Sending JSON: {}", json);
+                            if let Err(e) = ws_sender.send(Message::Text(json)).await {
+                                println!("[WS:CLIENT] Failed to send message: {}", e);
+                                return Some(format!("WebSocket error: {}", e));
                             }
+                            println!("[WS:CLIENT] Message sent successfully");
                         }
                         Err(e) => {
-                            println!("[WS:CLIENT] Failed to parse message: {} - Raw: {}", e, text);
-                            let _ = sender.send(WebSocketMessage::Error(format!("Parse error: {}", e)));
+                            println!("[WS:CLIENT] Failed to serialize outgoing message: {}", e);
                         }
                     }
                 }
-                Ok(Message::Close(_)) => {
-                    println!("[WS:CLIENT] WebSocket connection closed by server");
-                    let _ = sender.send(WebSocketMessage::Error("Connection closed".to_string()));
-                    break;
-                }
-                Ok(_) => {
-                    // Ignora altri tipi di messaggio (binary, ping, pong)
-                }
-                Err(e) => {
-                    println!("[WS:CLIENT] WebSocket error: {}", e);
-                    let _ = sender.send(WebSocketMessage::Error(format!("WebSocket error: {}", e)));
-                    break;
+                incoming = ws_receiver.next() => match incoming {
+                    Some(Ok(Message::Text(text))) => {
+                        println!("[WS:CLIENT] Received message: {}", text);
+                        match Self::parse_websocket_message(&text) {
+                            Ok(ws_msg) => {
+                                if let WebSocketMessage::SyncCompleted { latest_seq, .. } = &ws_msg {
+                                    cursor.fetch_max(*latest_seq, Ordering::SeqCst);
+                                    syncing = false;
+                                } else if let Some(seq) = sync_seq(&text).filter(|_| !syncing) {
+                                    cursor.fetch_max(seq, Ordering::SeqCst);
+                                }
+                                if sender.send(ws_msg).is_err() {
+                                    println!("[WS:CLIENT] Failed to send message to application - receiver dropped");
+                                    let _ = ws_sender.close().await;
+                                    return None;
+                                }
+                            }
+                            Err(e) => {
+                                println!("[WS:CLIENT] Failed to parse message: {} - Raw: {}", e, text);
+                                let _ = sender.send(WebSocketMessage::Error(format!("Parse error: {}", e)));
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | None => {
+                        println!("[WS:CLIENT] WebSocket connection closed by server");
+                        return Some("Connection closed".to_string());
+                    }
+                    Some(Ok(_)) => {
+                        // Ignora altri tipi di messaggio (binary, ping, pong)
+                    }
+                    Some(Err(e)) => {
+                        println!("[WS:CLIENT] WebSocket error: {}", e);
+                        return Some(format!("WebSocket error: {}", e));
+                    }
                 }
             }
         }
-        println!("[WS:CLIENT] Message handling loop ended");
     }
 
     /// Parsa un messaggio JSON dal WebSocket
@@ -310,15 +590,88 @@ impl WebSocketClient {
                     .map_err(|e| format!("Failed to parse new_message: {}", e))?;
                 Ok(WebSocketMessage::NewMessage(chat_msg))
             }
+            "message_edited" => {
+                let chat_msg: IncomingChatMessage = serde_json::from_str(text)
+                    .map_err(|e| format!("Failed to parse message_edited: {}", e))?;
+                Ok(WebSocketMessage::MessageEdited(chat_msg))
+            }
+            "message_deleted" => {
+                let chat_msg: IncomingChatMessage = serde_json::from_str(text)
+                    .map_err(|e| format!("Failed to parse message_deleted: {}", e))?;
+                Ok(WebSocketMessage::MessageDeleted(chat_msg))
+            }
+            "reactions_updated" => {
+                let chat_msg: IncomingChatMessage = serde_json::from_str(text)
+                    .map_err(|e| format!("Failed to parse reactions_updated: {}", e))?;
+                Ok(WebSocketMessage::ReactionsUpdated(chat_msg))
+            }
+            "message_receipts" => {
+                let event: ReceiptsEvent = serde_json::from_str(text)
+                    .map_err(|e| format!("Failed to parse message_receipts: {}", e))?;
+                Ok(WebSocketMessage::ReceiptsUpdated(event.receipts))
+            }
+            "inbox_update" => {
+                let entry: InboxEntry = serde_json::from_str(text)
+                    .map_err(|e| format!("Failed to parse inbox_update: {}", e))?;
+                Ok(WebSocketMessage::InboxUpdated(entry))
+            }
+            "typing" => {
+                let event: TypingEvent = serde_json::from_str(text)
+                    .map_err(|e| format!("Failed to parse typing: {}", e))?;
+                Ok(WebSocketMessage::Typing(event))
+            }
+            "UserJoined" | "UserLeft" => {
+                let user_id = generic.get("target")
+                    .and_then(|v| v.as_str())
+                    .ok_or("Missing target in presence message")?
+                    .to_string();
+                let username = generic.get("sender")
+                    .and_then(|v| v.as_str())
+                    .ok_or("Missing sender in presence message")?
+                    .to_string();
+                let status = if message_type == "UserJoined" { PresenceStatus::Online } else { PresenceStatus::Offline };
+                let presence = UserInfo { username, status, status_message: None, last_seen_at: None };
+                Ok(WebSocketMessage::UserStatusUpdate { user_id, presence })
+            }
             "user_status" => {
                 let user_id = generic.get("user_id")
                     .and_then(|v| v.as_str())
                     .ok_or("Missing user_id in user_status message")?
                     .to_string();
-                let online = generic.get("online")
-                    .and_then(|v| v.as_bool())
-                    .ok_or("Missing online field in user_status message")?;
-                Ok(WebSocketMessage::UserStatusUpdate { user_id, online })
+                let presence: UserInfo = serde_json::from_value(generic)
+                    .map_err(|e| format!("Failed to parse user_status: {}", e))?;
+                Ok(WebSocketMessage::UserStatusUpdate { user_id, presence })
+            }
+            "System" => {
+                let content = generic.get("content")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string();
+                Ok(WebSocketMessage::System(content))
+            }
+            "sync_complete" => {
+                let events = generic.get("events")
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or_default() as u32;
+                let latest_seq = generic.get("latest_seq")
+                    .and_then(|v| v.as_i64())
+                    .ok_or("Missing latest_seq in sync_complete message")?;
+                Ok(WebSocketMessage::SyncCompleted { events, latest_seq })
+            }
+            "friend_request" => {
+                let request: FriendRequestInfo = serde_json::from_value(generic)
+                    .map_err(|e| format!("Failed to parse friend_request: {}", e))?;
+                Ok(WebSocketMessage::FriendRequest(request))
+            }
+            "group_invite" => {
+                let invite: GroupInviteInfo = serde_json::from_value(generic)
+                    .map_err(|e| format!("Failed to parse group_invite: {}", e))?;
+                Ok(WebSocketMessage::GroupInvite(invite))
+            }
+            "error" => {
+                let error: ChatError = serde_json::from_value(generic)
+                    .map_err(|e| format!("Failed to parse error: {}", e))?;
+                Ok(WebSocketMessage::ServerError(error))
             }
             _ => {
                 Err(format!("Unknown message type: {}", message_type))
@@ -336,7 +689,7 @@ impl WebSocketClient {
     }
 
     /// Invia un messaggio privato tramite WebSocket
-    pub async fn send_private_message(&self, to_user: &str, content: &str) -> Result<(), WebSocketError> {
+    pub async fn send_private_message(&self, to_user: &str, content: &str, client_msg_id: &str, reply_to: Option<i64>) -> Result<(), WebSocketError> {
         println!("[WS:CLIENT] send_private_message called for user: {}, content: {}", to_user, content);
         
         let session_token = self.session_token.as_ref()
@@ -349,11 +702,13 @@ impl WebSocketClient {
             group_id: None,
             content: content.to_string(),
             session_token: session_token.clone(),
+            client_msg_id: Some(client_msg_id.to_string()),
+            reply_to,
         };
 
         if let Some(sender) = &self.outgoing_sender {
             println!("[WS:CLIENT] Attempting to send message via WebSocket channel");
-            match sender.send(message) {
+            match sender.send(OutgoingFrame::Chat(message)) {
                 Ok(_) => {
                     println!("[WS:CLIENT] Message successfully queued for sending");
                     Ok(())
@@ -370,7 +725,7 @@ impl WebSocketClient {
     }
 
     /// Invia un messaggio di gruppo tramite WebSocket
-    pub async fn send_group_message(&self, group_id: &str, content: &str) -> Result<(), WebSocketError> {
+    pub async fn send_group_message(&self, group_id: &str, content: &str, client_msg_id: &str, reply_to: Option<i64>) -> Result<(), WebSocketError> {
         let session_token = self.session_token.as_ref()
             .ok_or_else(|| WebSocketError::MessageSendFailed("No session token available".to_string()))?;
 
@@ -381,10 +736,12 @@ impl WebSocketClient {
             group_id: Some(group_id.to_string()),
             content: content.to_string(),
             session_token: session_token.clone(),
+            client_msg_id: Some(client_msg_id.to_string()),
+            reply_to,
         };
 
         if let Some(sender) = &self.outgoing_sender {
-            sender.send(message)
+            sender.send(OutgoingFrame::Chat(message))
                 .map_err(|_| WebSocketError::MessageSendFailed("Failed to queue message for sending".to_string()))?;
             Ok(())
         } else {
@@ -392,8 +749,84 @@ impl WebSocketClient {
         }
     }
 
+    /// Invia la modifica di un proprio messaggio; l'esito arriva come evento `message_edited`
+    /// (o come errore del server)
+    pub async fn send_edit_message(&self, message_id: i64, content: &str) -> Result<(), WebSocketError> {
+        let frame = EditMessageFrame {
+            message_type: "edit_message".to_string(),
+            message_id,
+            content: content.to_string(),
+        };
+
+        if let Some(sender) = &self.outgoing_sender {
+            sender.send(OutgoingFrame::Edit(frame))
+                .map_err(|_| WebSocketError::MessageSendFailed("Failed to queue edit for sending".to_string()))?;
+            Ok(())
+        } else {
+            Err(WebSocketError::MessageSendFailed("WebSocket not connected".to_string()))
+        }
+    }
+
+    /// Ritira un proprio messaggio per tutti; l'esito arriva come evento `message_deleted`
+    /// (o come errore del server)
+    pub async fn send_delete_message(&self, message_id: i64) -> Result<(), WebSocketError> {
+        let frame = DeleteMessageFrame {
+            message_type: "delete_message".to_string(),
+            message_id,
+        };
+
+        if let Some(sender) = &self.outgoing_sender {
+            sender.send(OutgoingFrame::Delete(frame))
+                .map_err(|_| WebSocketError::MessageSendFailed("Failed to queue deletion for sending".to_string()))?;
+            Ok(())
+        } else {
+            Err(WebSocketError::MessageSendFailed("WebSocket not connected".to_string()))
+        }
+    }
+
+    /// Imposta (`Some(emoji)`) o rimuove (`None`) la propria reazione a un messaggio;
+    /// le reazioni aggiornate arrivano come evento `reactions_updated`
+    pub async fn send_reaction(&self, message_id: i64, emoji: Option<&str>) -> Result<(), WebSocketError> {
+        let frame = ReactionFrame {
+            message_type: if emoji.is_some() { "add_reaction" } else { "remove_reaction" }.to_string(),
+            message_id,
+            emoji: emoji.map(str::to_string),
+        };
+
+        if let Some(sender) = &self.outgoing_sender {
+            sender.send(OutgoingFrame::Reaction(frame))
+                .map_err(|_| WebSocketError::MessageSendFailed("Failed to queue reaction for sending".to_string()))?;
+            Ok(())
+        } else {
+            Err(WebSocketError::MessageSendFailed("WebSocket not connected".to_string()))
+        }
+    }
+
+    /// Segnala l'inizio o la fine della scrittura in una chat privata o di gruppo
+    pub async fn send_typing(&self, to_user: Option<&str>, group_id: Option<&str>, typing: bool) -> Result<(), WebSocketError> {
+        let frame = TypingFrame {
+            message_type: if typing { "start_typing" } else { "stop_typing" }.to_string(),
+            chat_type: if group_id.is_some() { "group" } else { "private" }.to_string(),
+            to_user: to_user.map(str::to_string),
+            group_id: group_id.map(str::to_string),
+        };
+
+        if let Some(sender) = &self.outgoing_sender {
+            sender.send(OutgoingFrame::Typing(frame))
+                .map_err(|_| WebSocketError::MessageSendFailed("Failed to queue typing indicator for sending".to_string()))?;
+            Ok(())
+        } else {
+            Err(WebSocketError::MessageSendFailed("WebSocket not connected".to_string()))
+        }
+    }
+
     /// Controlla se il WebSocket è connesso e pronto per inviare messaggi
     pub fn is_connected(&self) -> bool {
         self.outgoing_sender.is_some()
     }
 }
+
+/// Numero dell'evento nella coda dell'utente, presente negli eventi che il server conserva
+fn sync_seq(text: &str) -> Option<i64> {
+    serde_json::from_str::<serde_json::Value>(text).ok()?.get("sync_seq")?.as_i64()
+}
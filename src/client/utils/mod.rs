@@ -1,2 +1,3 @@
 pub mod constants;
 pub mod session_store;
+pub mod tls;
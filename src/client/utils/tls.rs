@@ -0,0 +1,187 @@
+// src/client/utils/tls.rs
+// Connettore TLS lato client (porta comandi e wss://)
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use tokio_rustls::TlsConnector;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ServerName};
+
+pub use crate::common::tls::{TlsSettings, TrustRoots};
+
+/// Tempo massimo per connessione e handshake: un server senza TLS non risponde al ClientHello
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type BoxedReader = Box<dyn AsyncRead + Unpin + Send>;
+pub type BoxedWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+impl TrustRoots {
+    fn root_store(&self) -> anyhow::Result<rustls::RootCertStore> {
+        let mut roots = rustls::RootCertStore::empty();
+        match self {
+            TrustRoots::WebPki => {
+                roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                    rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+                }));
+            }
+            TrustRoots::CaBundle(path) => {
+                let file = File::open(path)
+                    .map_err(|e| anyhow::anyhow!("Failed to open CA bundle '{}': {}", path.display(), e))?;
+                let certs = rustls_pemfile::certs(&mut BufReader::new(file))?;
+                let (added, _ignored) = roots.add_parsable_certificates(&certs);
+                if added == 0 {
+                    return Err(anyhow::anyhow!("No valid certificates found in {}", path.display()));
+                }
+                println!("[TLS] Loaded {} trusted certificate(s) from {}", added, path.display());
+            }
+        }
+        Ok(roots)
+    }
+}
+
+impl TlsSettings {
+    pub fn connector(&self) -> anyhow::Result<TlsConnector> {
+        let verifier: Arc<dyn ServerCertVerifier> = match &self.pin_sha256 {
+            None => Arc::new(WebPkiVerifier::new(self.roots.root_store()?, None)),
+            Some(pin) => {
+                let chain = match self.roots {
+                    TrustRoots::CaBundle(_) => Some(WebPkiVerifier::new(self.roots.root_store()?, None)),
+                    TrustRoots::WebPki => None,
+                };
+                Arc::new(PinnedCertVerifier { fingerprint: parse_fingerprint(pin)?, chain })
+            }
+        };
+        let builder = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(verifier);
+        let cfg = match &self.client_cert {
+            Some((cert_path, key_path)) => builder
+                .with_client_auth_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+                .map_err(|e| anyhow::anyhow!("Invalid client certificate: {}", e))?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(TlsConnector::from(Arc::new(cfg)))
+    }
+}
+
+fn load_certs(path: &PathBuf) -> anyhow::Result<Vec<Certificate>> {
+    let file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to open client certificate '{}': {}", path.display(), e))?;
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(file))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("No certificates found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &PathBuf) -> anyhow::Result<rustls::PrivateKey> {
+    let file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to open client key '{}': {}", path.display(), e))?;
+    for item in rustls_pemfile::read_all(&mut BufReader::new(file))? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => {
+                return Ok(rustls::PrivateKey(key));
+            }
+            _ => {}
+        }
+    }
+    Err(anyhow::anyhow!("No private key found in {}", path.display()))
+}
+
+/// Accetta solo il certificato con il fingerprint atteso (e, se presente, una catena valida).
+struct PinnedCertVerifier {
+    fingerprint: [u8; 32],
+    chain: Option<WebPkiVerifier>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual = ring::digest::digest(&ring::digest::SHA256, &end_entity.0);
+        if actual.as_ref() != self.fingerprint {
+            return Err(rustls::Error::General(format!(
+                "server certificate fingerprint mismatch: expected {}, got {}",
+                to_hex(&self.fingerprint),
+                to_hex(actual.as_ref())
+            )));
+        }
+        match &self.chain {
+            Some(chain) => chain.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now),
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+}
+
+fn parse_fingerprint(pin: &str) -> anyhow::Result<[u8; 32]> {
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+    let invalid = || anyhow::anyhow!("Invalid SHA-256 fingerprint '{}': expected 64 hex characters", pin);
+    if hex.len() != 64 {
+        return Err(invalid());
+    }
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(out)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":")
+}
+
+/// Nome con cui verificare il certificato: hostname o indirizzo IP.
+pub fn server_name(host: &str) -> anyhow::Result<ServerName> {
+    ServerName::try_from(host).map_err(|_| anyhow::anyhow!("Invalid TLS server name: {}", host))
+}
+
+/// Apre la connessione al server `host:port`, con TLS se `tls` è impostato.
+pub async fn connect(addr: &str, tls: Option<&TlsSettings>) -> anyhow::Result<(BoxedReader, BoxedWriter)> {
+    let tcp = TcpStream::connect(addr).await?;
+    let Some(settings) = tls else {
+        let (r, w) = tcp.into_split();
+        return Ok((Box::new(r), Box::new(w)));
+    };
+    let host = addr.rsplit_once(':').map(|(h, _)| h).unwrap_or(addr);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let connector = settings.connector()?;
+    let stream = match timeout(HANDSHAKE_TIMEOUT, connector.connect(server_name(host)?, tcp)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            // Un record non TLS o una chiusura durante l'handshake indicano un server senza TLS
+            let hint = match e.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>()) {
+                Some(rustls::Error::InvalidMessage(_)) | None => " (is TLS enabled on the server?)",
+                Some(_) => "",
+            };
+            return Err(anyhow::anyhow!("TLS handshake with {} failed: {}{}", addr, e, hint));
+        }
+        Err(_) => {
+            return Err(anyhow::anyhow!("TLS handshake with {} timed out: the server does not seem to use TLS", addr));
+        }
+    };
+    let (r, w) = tokio::io::split(stream);
+    Ok((Box::new(r), Box::new(w)))
+}
+
+/// Vero se la risposta è un record TLS (tipicamente un alert): il server usa TLS
+/// ma il client si è connesso in chiaro.
+pub fn is_tls_record(reply: &str) -> bool {
+    matches!(reply.as_bytes().first(), Some(0x15) | Some(0x16))
+}
+
+/// Messaggio mostrato quando il server richiede TLS e il client non lo usa.
+pub const SERVER_REQUIRES_TLS: &str = "the server requires TLS: enable CLIENT_TLS or use a tls://host:port address";
@@ -0,0 +1,115 @@
+// src/common/error.rs
+// Errori tipizzati condivisi tra server e client
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Codice di errore stabile restituito dai comandi del server.
+///
+/// Sul filo viene serializzato come stringa snake_case (es. `"session_expired"`);
+/// `number()` fornisce l'equivalente numerico. Entrambi non devono cambiare
+/// tra versioni: i client li usano per reagire senza confrontare i messaggi.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Richiesta non interpretabile (JSON invalido, payload mancante)
+    BadRequest,
+    UnknownCommand,
+    UnsupportedVersion,
+    /// Argomenti non validi (messaggio troppo lungo, id malformato, ...)
+    InvalidInput,
+    /// Token di sessione assente, sconosciuto o scaduto
+    SessionExpired,
+    InvalidCredentials,
+    UserNotFound,
+    GroupNotFound,
+    InviteNotFound,
+    FriendRequestNotFound,
+    MessageNotFound,
+    /// La risorsa esiste già (username, amicizia, invito, membership)
+    AlreadyExists,
+    NotMember,
+    PermissionDenied,
+    RateLimited,
+    /// Errore interno del server; il dettaglio resta nei log del server
+    Internal,
+    /// Limite di connessioni raggiunto (totale o per indirizzo IP): riprovare più tardi
+    ServerFull,
+    /// Il server si sta arrestando e non accetta nuovi comandi
+    ShuttingDown,
+    /// Errore di rete lato client (connessione persa, risposta assente)
+    Transport,
+}
+
+impl ErrorCode {
+    pub fn number(self) -> u16 {
+        match self {
+            ErrorCode::BadRequest => 1000,
+            ErrorCode::UnknownCommand => 1001,
+            ErrorCode::UnsupportedVersion => 1002,
+            ErrorCode::InvalidInput => 1003,
+            ErrorCode::SessionExpired => 2000,
+            ErrorCode::InvalidCredentials => 2001,
+            ErrorCode::UserNotFound => 3000,
+            ErrorCode::GroupNotFound => 3001,
+            ErrorCode::InviteNotFound => 3002,
+            ErrorCode::FriendRequestNotFound => 3003,
+            ErrorCode::MessageNotFound => 3004,
+            ErrorCode::AlreadyExists => 3100,
+            ErrorCode::NotMember => 4000,
+            ErrorCode::PermissionDenied => 4001,
+            ErrorCode::RateLimited => 4290,
+            ErrorCode::Internal => 5000,
+            ErrorCode::ServerFull => 5030,
+            ErrorCode::ShuttingDown => 5031,
+            ErrorCode::Transport => 6000,
+        }
+    }
+}
+
+/// Errore restituito da un comando: codice stabile più messaggio leggibile.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ChatError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+
+    pub fn session_expired() -> Self {
+        Self::new(ErrorCode::SessionExpired, "Invalid or expired session")
+    }
+
+    pub fn user_not_found() -> Self {
+        Self::new(ErrorCode::UserNotFound, "User not found")
+    }
+
+    pub fn group_not_found() -> Self {
+        Self::new(ErrorCode::GroupNotFound, "Group not found")
+    }
+
+    pub fn message_not_found() -> Self {
+        Self::new(ErrorCode::MessageNotFound, "Message not found")
+    }
+
+    pub fn not_member() -> Self {
+        Self::new(ErrorCode::NotMember, "Not a group member")
+    }
+
+    /// Registra il dettaglio nei log e restituisce un errore generico,
+    /// così il testo degli errori sqlx non arriva mai al client.
+    pub fn internal(context: &str, err: impl fmt::Display) -> Self {
+        println!("[ERROR] {}: {}", context, err);
+        Self::new(ErrorCode::Internal, "Internal server error")
+    }
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ChatError {}
@@ -1 +1,4 @@
 pub mod crypto;
+pub mod error;
+pub mod protocol;
+pub mod tls;
@@ -0,0 +1,531 @@
+// Protocollo strutturato client/server.
+//
+// Ogni richiesta è una singola riga JSON con un envelope versionato
+// (`v`, `id`, comando e payload tipizzato); il server risponde con una riga
+// JSON che riporta lo stesso `id` e contiene `data` oppure `error`.
+// Le righe che non iniziano con `{` vengono ancora interpretate dal server
+// come comandi testuali legacy (`/login user pass`, ...).
+use serde::{Deserialize, Serialize};
+use crate::common::error::{ChatError, ErrorCode};
+
+/// Versione corrente dell'envelope. Il server rifiuta versioni più recenti.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Richiesta inviata dal client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub v: u32,
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<String>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+impl Request {
+    pub fn new(id: u64, session_token: Option<String>, command: Command) -> Self {
+        Self { v: PROTOCOL_VERSION, id, session_token, command }
+    }
+}
+
+/// Comandi supportati, con il relativo payload.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", content = "payload", rename_all = "snake_case")]
+pub enum Command {
+    Register { username: String, password: String },
+    Login { username: String, password: String },
+    Logout,
+    ValidateSession,
+    /// Login senza password con il certificato client della connessione (mutual TLS)
+    CertificateLogin,
+    Help,
+    Quit,
+    OnlineUsers,
+    AllUsers,
+    SendFriendRequest {
+        to: String,
+        #[serde(default)]
+        message: String,
+    },
+    AcceptFriendRequest { from: String },
+    RejectFriendRequest { from: String },
+    ListFriends,
+    ReceivedFriendRequests,
+    SentFriendRequests,
+    CreateGroup {
+        name: String,
+        #[serde(default)]
+        participants: Vec<String>,
+    },
+    MyGroups,
+    Invite { username: String, group_id: String },
+    AcceptGroupInvite { invite_id: i64 },
+    RejectGroupInvite { invite_id: i64 },
+    MyGroupInvites,
+    GroupMembers { group_id: String },
+    JoinGroup { group: String },
+    LeaveGroup { group: String },
+    /// `client_msg_id`: UUID scelto dal client; un nuovo invio con lo stesso id
+    /// (es. dopo una riconnessione) restituisce il messaggio già salvato.
+    /// `reply_to`: id del messaggio citato, nella stessa chat
+    SendGroupMessage {
+        group_id: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_msg_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<i64>,
+    },
+    SendPrivateMessage {
+        to: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_msg_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<i64>,
+    },
+    GetGroupMessages {
+        group_id: String,
+        #[serde(default)]
+        page: HistoryPage,
+    },
+    GetPrivateMessages {
+        with: String,
+        #[serde(default)]
+        page: HistoryPage,
+    },
+    /// Nuovo testo di un proprio messaggio, entro la finestra di modifica del server
+    EditMessage { message_id: i64, content: String },
+    /// Ritira un messaggio per tutti (il mittente, o l'amministratore nei gruppi)
+    DeleteMessage { message_id: i64 },
+    /// Reazione a un messaggio: sostituisce quella precedente dello stesso utente
+    AddReaction { message_id: i64, emoji: String },
+    RemoveReaction { message_id: i64 },
+    /// Intera discussione di un messaggio: la radice e tutte le risposte, in ordine
+    GetThread { message_id: i64 },
+    /// Segna come letti i messaggi ricevuti nella chat, fino a `up_to` compreso
+    /// (tutti se assente); i mittenti ricevono la conferma di lettura
+    MarkPrivateRead {
+        with: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        up_to: Option<i64>,
+    },
+    MarkGroupRead {
+        group_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        up_to: Option<i64>,
+    },
+    DeleteGroupMessages { group_id: String },
+    DeletePrivateMessages { with: String },
+    /// Tutte le chat private e di gruppo con i messaggi non letti e l'anteprima dell'ultimo
+    Inbox,
+    /// Stato di presenza scelto dall'utente e testo facoltativo visibile agli amici
+    /// (assente o vuoto per rimuoverlo)
+    SetPresence {
+        status: PresenceStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    /// Presenza scelta e testo di stato dell'utente (es. dopo il login)
+    GetPresence,
+    /// Chi può vedere il proprio ultimo accesso
+    SetLastSeenVisibility { visibility: LastSeenVisibility },
+    /// Presenza e ultimo accesso di un utente, secondo la sua privacy
+    GetUser { username: String },
+    /// Eventi dell'utente successivi al cursore `since` (nuovi messaggi, modifiche, ritiri,
+    /// inviti, richieste di amicizia), per recuperare quelli persi mentre era offline.
+    /// Senza `since` restituisce solo il cursore attuale
+    Sync {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        since: Option<i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<u32>,
+    },
+    /// Qualsiasi nome di comando non riconosciuto
+    #[serde(other)]
+    Unknown,
+}
+
+impl Command {
+    /// Nome del comando, utile per i log (non include mai il payload,
+    /// che può contenere password o testo dei messaggi).
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Register { .. } => "register",
+            Command::Login { .. } => "login",
+            Command::Logout => "logout",
+            Command::ValidateSession => "validate_session",
+            Command::CertificateLogin => "certificate_login",
+            Command::Help => "help",
+            Command::Quit => "quit",
+            Command::OnlineUsers => "online_users",
+            Command::AllUsers => "all_users",
+            Command::SendFriendRequest { .. } => "send_friend_request",
+            Command::AcceptFriendRequest { .. } => "accept_friend_request",
+            Command::RejectFriendRequest { .. } => "reject_friend_request",
+            Command::ListFriends => "list_friends",
+            Command::ReceivedFriendRequests => "received_friend_requests",
+            Command::SentFriendRequests => "sent_friend_requests",
+            Command::CreateGroup { .. } => "create_group",
+            Command::MyGroups => "my_groups",
+            Command::Invite { .. } => "invite",
+            Command::AcceptGroupInvite { .. } => "accept_group_invite",
+            Command::RejectGroupInvite { .. } => "reject_group_invite",
+            Command::MyGroupInvites => "my_group_invites",
+            Command::GroupMembers { .. } => "group_members",
+            Command::JoinGroup { .. } => "join_group",
+            Command::LeaveGroup { .. } => "leave_group",
+            Command::SendGroupMessage { .. } => "send_group_message",
+            Command::SendPrivateMessage { .. } => "send_private_message",
+            Command::GetGroupMessages { .. } => "get_group_messages",
+            Command::GetPrivateMessages { .. } => "get_private_messages",
+            Command::EditMessage { .. } => "edit_message",
+            Command::DeleteMessage { .. } => "delete_message",
+            Command::AddReaction { .. } => "add_reaction",
+            Command::RemoveReaction { .. } => "remove_reaction",
+            Command::GetThread { .. } => "get_thread",
+            Command::MarkPrivateRead { .. } => "mark_private_read",
+            Command::MarkGroupRead { .. } => "mark_group_read",
+            Command::DeleteGroupMessages { .. } => "delete_group_messages",
+            Command::DeletePrivateMessages { .. } => "delete_private_messages",
+            Command::Inbox => "inbox",
+            Command::SetPresence { .. } => "set_presence",
+            Command::GetPresence => "get_presence",
+            Command::SetLastSeenVisibility { .. } => "set_last_seen_visibility",
+            Command::GetUser { .. } => "get_user",
+            Command::Sync { .. } => "sync",
+            Command::Unknown => "unknown",
+        }
+    }
+}
+
+/// Risposta del server: `ok == true` implica `data`, altrimenti `error`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub v: u32,
+    pub id: u64,
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<ResponseData>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ChatError>,
+}
+
+impl Response {
+    pub fn from_result(id: u64, result: Result<ResponseData, ChatError>) -> Self {
+        match result {
+            Ok(data) => Self { v: PROTOCOL_VERSION, id, ok: true, data: Some(data), error: None },
+            Err(error) => Self { v: PROTOCOL_VERSION, id, ok: false, data: None, error: Some(error) },
+        }
+    }
+
+    pub fn into_result(self) -> Result<ResponseData, ChatError> {
+        match (self.ok, self.data, self.error) {
+            (true, Some(data), _) => Ok(data),
+            (_, _, Some(error)) => Err(error),
+            _ => Err(ChatError::new(ErrorCode::BadRequest, "Malformed response")),
+        }
+    }
+}
+
+/// Payload tipizzato delle risposte.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ResponseData {
+    /// Login o registrazione riusciti
+    Session { username: String, session_token: String },
+    /// Sessione esistente ancora valida
+    SessionValid { username: String },
+    /// Conferma generica senza dati
+    Ack { message: String },
+    Help { text: String },
+    OnlineUsers { users: Vec<String> },
+    AllUsers { users: Vec<String> },
+    Friends { friends: Vec<UserInfo> },
+    ReceivedFriendRequests { requests: Vec<FriendRequestInfo> },
+    SentFriendRequests { requests: Vec<FriendRequestInfo> },
+    GroupCreated { group_id: String, name: String },
+    Groups { groups: Vec<GroupInfo> },
+    GroupInvites { invites: Vec<GroupInviteInfo> },
+    GroupMembers { members: Vec<String> },
+    /// Messaggio salvato: id, numero di sequenza nella chat e orario assegnati dal server
+    /// (`duplicate` se il `client_msg_id` era già stato ricevuto: sono i dati dell'originale)
+    MessageSent {
+        message_id: i64,
+        seq: i64,
+        sent_at: i64,
+        #[serde(default)]
+        duplicate: bool,
+    },
+    /// Messaggio modificato: id, sequenza (invariata) e orario della modifica
+    MessageEdited { message_id: i64, seq: i64, edited_at: i64 },
+    /// Messaggio ritirato per tutti i partecipanti
+    MessageDeleted { message_id: i64, seq: i64, deleted_at: i64 },
+    /// Reazioni aggiornate di un messaggio
+    Reactions { message_id: i64, reactions: Vec<ReactionCount> },
+    /// Numero di messaggi appena segnati come letti
+    MarkedRead { messages: u32 },
+    /// Presenza scelta dall'utente (anche `Invisible`), testo di stato e privacy dell'ultimo accesso
+    Presence {
+        status: PresenceStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        #[serde(default)]
+        last_seen_visibility: LastSeenVisibility,
+    },
+    User { user: UserInfo },
+    /// Chat dell'utente, dalla più recente
+    Inbox { chats: Vec<InboxEntry> },
+    /// Eventi persi in ordine di arrivo; `latest_seq` è il cursore da passare come `since`
+    /// alla richiesta successiva (altri eventi da leggere se `has_more`)
+    Events {
+        events: Vec<SyncedEvent>,
+        #[serde(default)]
+        has_more: bool,
+        latest_seq: i64,
+    },
+    /// Pagina dello storico in ordine cronologico; `has_more` indica altri messaggi
+    /// oltre la pagina nella direzione richiesta (più vecchi, o più recenti con `after`)
+    Messages {
+        messages: Vec<HistoryMessage>,
+        #[serde(default)]
+        has_more: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FriendRequestInfo {
+    pub username: String,
+    pub message: String,
+}
+
+/// Stato di presenza. `Invisible` è solo una scelta dell'utente: gli altri lo vedono
+/// `Offline`, che a sua volta non si può scegliere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    #[default]
+    Online,
+    Away,
+    Busy,
+    Invisible,
+    Offline,
+}
+
+impl PresenceStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PresenceStatus::Online => "online",
+            PresenceStatus::Away => "away",
+            PresenceStatus::Busy => "busy",
+            PresenceStatus::Invisible => "invisible",
+            PresenceStatus::Offline => "offline",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "online" => Some(PresenceStatus::Online),
+            "away" => Some(PresenceStatus::Away),
+            "busy" => Some(PresenceStatus::Busy),
+            "invisible" => Some(PresenceStatus::Invisible),
+            "offline" => Some(PresenceStatus::Offline),
+            _ => None,
+        }
+    }
+}
+
+/// Chi può vedere l'ultimo accesso di un utente.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LastSeenVisibility {
+    #[default]
+    Everyone,
+    Friends,
+    Nobody,
+}
+
+impl LastSeenVisibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LastSeenVisibility::Everyone => "everyone",
+            LastSeenVisibility::Friends => "friends",
+            LastSeenVisibility::Nobody => "nobody",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "everyone" => Some(LastSeenVisibility::Everyone),
+            "friends" => Some(LastSeenVisibility::Friends),
+            "nobody" => Some(LastSeenVisibility::Nobody),
+            _ => None,
+        }
+    }
+}
+
+/// Utente con la presenza visibile agli altri (mai `Invisible`). `last_seen_at` solo
+/// se è offline e le sue impostazioni di privacy lo permettono a chi chiede.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub username: String,
+    #[serde(default)]
+    pub status: PresenceStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupInfo {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupInviteInfo {
+    pub id: i64,
+    pub group_name: String,
+    pub invited_by: String,
+}
+
+/// Messaggio dello storico, già decifrato dal server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryMessage {
+    /// Id del messaggio, usato come cursore per la paginazione
+    #[serde(default)]
+    pub id: i64,
+    /// Numero di sequenza nella chat (1, 2, 3, ...): ordine affidabile anche a parità di `sent_at`
+    #[serde(default)]
+    pub seq: i64,
+    pub sender: String,
+    pub content: String,
+    pub sent_at: i64,
+    /// Orario dell'ultima modifica, se il testo è stato modificato
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<i64>,
+    /// Ritirato per tutti: `content` è vuoto, resta solo la posizione nella chat
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<ReactionCount>,
+    /// Messaggio a cui risponde, con un estratto per mostrarne il contesto
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quoted: Option<QuotedMessage>,
+    /// Destinatari a cui il messaggio è stato consegnato e che lo hanno letto
+    /// (nelle chat private al più 1)
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub delivered_to: u32,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub read_by: u32,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+/// Chat privata (con l'altro partecipante) o di gruppo.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "chat_type", rename_all = "snake_case")]
+pub enum ChatRef {
+    Private { with: String },
+    Group { group_id: String, name: String },
+}
+
+/// Una chat nell'inbox: messaggi ricevuti e non ancora letti e anteprima dell'ultimo
+/// messaggio (assente se la chat è vuota o è stata svuotata).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InboxEntry {
+    #[serde(flatten)]
+    pub chat: ChatRef,
+    pub unread: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_message: Option<MessagePreview>,
+}
+
+/// Anteprima di un messaggio: mittente, estratto del testo e orario.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessagePreview {
+    pub id: i64,
+    pub sender: String,
+    pub snippet: String,
+    pub sent_at: i64,
+    /// Il messaggio è stato ritirato (`snippet` vuoto)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+}
+
+/// Evento della coda di un utente: lo stesso JSON inviato sul WebSocket (con `message_type`)
+/// e il suo numero nella coda, che compare anche come `sync_seq` negli eventi live.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncedEvent {
+    pub seq: i64,
+    pub event: serde_json::Value,
+}
+
+/// Estratto del messaggio citato da una risposta.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotedMessage {
+    pub id: i64,
+    pub sender: String,
+    pub snippet: String,
+    /// Il messaggio citato è stato ritirato (`snippet` vuoto)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+}
+
+impl QuotedMessage {
+    /// Lunghezza massima dell'estratto, in caratteri
+    pub const SNIPPET_CHARS: usize = 100;
+
+    /// Estratto di `text`: la prima riga, troncata a `SNIPPET_CHARS` caratteri.
+    pub fn snippet(text: &str) -> String {
+        let line = text.lines().next().unwrap_or_default();
+        match line.char_indices().nth(Self::SNIPPET_CHARS) {
+            Some((end, _)) => format!("{}…", &line[..end]),
+            None if line.len() < text.trim_end().len() => format!("{}…", line),
+            None => line.to_string(),
+        }
+    }
+}
+
+/// Reazioni con la stessa emoji su un messaggio, nell'ordine della prima reazione.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: u32,
+    /// Chi ha reagito, così ogni client riconosce la propria reazione
+    pub users: Vec<String>,
+}
+
+/// Finestra dello storico richiesta: al massimo `limit` messaggi con id minore di
+/// `before` e/o maggiore di `after`. Senza cursori restituisce gli ultimi messaggi.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct HistoryPage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<i64>,
+}
+
+impl HistoryPage {
+    /// Gli ultimi messaggi della chat (dimensione di pagina del server).
+    pub fn latest() -> Self {
+        Self::default()
+    }
+
+    /// I messaggi precedenti a `id`, per caricare lo storico più vecchio.
+    pub fn before(id: i64) -> Self {
+        Self { before: Some(id), ..Self::default() }
+    }
+
+    /// I messaggi successivi a `id`, per recuperare solo le novità.
+    pub fn after(id: i64) -> Self {
+        Self { after: Some(id), ..Self::default() }
+    }
+}
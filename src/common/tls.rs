@@ -0,0 +1,52 @@
+// src/common/tls.rs
+// Impostazioni TLS del client, condivise con la configurazione senza dipendere dal codice client
+use std::path::PathBuf;
+
+/// Certificati radice con cui il client verifica il certificato del server.
+#[derive(Debug, Clone, Default)]
+pub enum TrustRoots {
+    /// Radici pubbliche di Mozilla (webpki-roots)
+    #[default]
+    WebPki,
+    /// Solo i certificati del bundle PEM indicato: CA privata o certificato self-signed
+    CaBundle(PathBuf),
+}
+
+impl TrustRoots {
+    /// Bundle PEM se indicato (es. `TLS_CA_FILE`), altrimenti le radici pubbliche.
+    pub fn from_ca_file(path: Option<&str>) -> Self {
+        match path.map(str::trim).filter(|p| !p.is_empty()) {
+            Some(p) => TrustRoots::CaBundle(PathBuf::from(p)),
+            None => TrustRoots::WebPki,
+        }
+    }
+}
+
+/// Impostazioni TLS del client: radici fidate, eventuale pinning del certificato
+/// ed eventuale certificato client per i server con mutual TLS.
+#[derive(Debug, Clone, Default)]
+pub struct TlsSettings {
+    pub roots: TrustRoots,
+    /// SHA-256 del certificato del server in esadecimale (i `:` sono ammessi).
+    /// Con il pin impostato la catena viene verificata solo se è indicato un bundle CA,
+    /// così un certificato self-signed può essere fidato con il solo fingerprint.
+    pub pin_sha256: Option<String>,
+    /// Certificato e chiave privata (PEM) presentati al server
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+impl TlsSettings {
+    pub fn new(ca_file: Option<&str>, pin_sha256: Option<&str>) -> Self {
+        Self {
+            roots: TrustRoots::from_ca_file(ca_file),
+            pin_sha256: pin_sha256.map(str::trim).filter(|p| !p.is_empty()).map(str::to_string),
+            client_cert: None,
+        }
+    }
+
+    /// Presenta il certificato client indicato (solo se sono presenti sia certificato sia chiave).
+    pub fn with_client_cert(mut self, cert_path: Option<&str>, key_path: Option<&str>) -> Self {
+        self.client_cert = cert_path.zip(key_path).map(|(c, k)| (PathBuf::from(c), PathBuf::from(k)));
+        self
+    }
+}
@@ -1,84 +1,74 @@
 use crate::server::database::Database;
 use crate::server::config::ServerConfig;
+use crate::common::error::{ChatError, ErrorCode};
 use std::sync::Arc;
 use sqlx::Row;
 use argon2::{Argon2, password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString}};
 use rand::RngCore;
 
+/// Sessione appena aperta o verificata: l'utente, il token e la sua scadenza.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub user_id: String,
+    pub token: String,
+    pub expires_at: i64,
+}
 
-/// Logout: elimina la sessione e imposta utente offline
-pub async fn logout(db: Arc<Database>, session_token: &str) -> String {
-    // Trova user_id dalla sessione
-    println!("[AUTH] logout called (token masked)");
-    let row = sqlx::query("SELECT user_id FROM sessions WHERE session_token = ?")
-        .bind(session_token)
-        .fetch_optional(&db.pool)
-        .await;
-    match row {
-        Ok(Some(row)) => {
-            let user_id: String = row.get("user_id");
-            // Invalidate all sessions for this user (logout from all devices) to enforce single-session semantics
-            match sqlx::query("DELETE FROM sessions WHERE user_id = ?")
-                .bind(&user_id)
-                .execute(&db.pool)
-                .await
-            {
-                Ok(r) => println!("[AUTH] Deleted {} session rows for user {}", r.rows_affected(), user_id),
-                Err(e) => println!("[AUTH] Failed deleting sessions for {}: {}", user_id, e),
-            }
-
-            // Force user offline
-            match sqlx::query("UPDATE users SET is_online = 0 WHERE id = ?")
-                .bind(&user_id)
-                .execute(&db.pool)
-                .await
-            {
-                Ok(_) => println!("[AUTH] Set is_online=0 for user {} due to logout", user_id),
-                Err(e) => println!("[AUTH] Failed to set is_online=0 for {}: {}", user_id, e),
-            }
+/// Logout: elimina tutte le sessioni dell'utente e lo imposta offline
+pub async fn logout(db: Arc<Database>, user_id: &str) -> Result<String, ChatError> {
+    println!("[AUTH] logout called for user {}", user_id);
+    // Invalidate all sessions for this user (logout from all devices) to enforce single-session semantics
+    match sqlx::query("DELETE FROM sessions WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&db.pool)
+        .await
+    {
+        Ok(r) => println!("[AUTH] Deleted {} session rows for user {}", r.rows_affected(), user_id),
+        Err(e) => println!("[AUTH] Failed deleting sessions for {}: {}", user_id, e),
+    }
 
-            // Verify state after logout
-            let sess_cnt = sqlx::query("SELECT COUNT(1) as c FROM sessions WHERE user_id = ?")
-                .bind(&user_id)
-                .fetch_one(&db.pool)
-                .await
-                .ok()
-                .and_then(|r| r.try_get::<i64, _>("c").ok())
-                .unwrap_or(-1);
-            let is_online = sqlx::query("SELECT is_online FROM users WHERE id = ?")
-                .bind(&user_id)
-                .fetch_optional(&db.pool)
-                .await
-                .ok()
-                .and_then(|opt| opt.map(|r| r.get::<i64, _>("is_online")))
-                .unwrap_or(-1);
-            println!("[AUTH][DB CHECK] logout completed: sessions_count={} users.is_online={} for user {}", sess_cnt, is_online, user_id);
+    // Force user offline
+    match sqlx::query("UPDATE users SET is_online = 0 WHERE id = ?")
+        .bind(user_id)
+        .execute(&db.pool)
+        .await
+    {
+        Ok(_) => println!("[AUTH] Set is_online=0 for user {} due to logout", user_id),
+        Err(e) => println!("[AUTH] Failed to set is_online=0 for {}: {}", user_id, e),
+    }
 
-            // record logout event
-            let now = chrono::Utc::now().timestamp();
-            match sqlx::query("INSERT INTO session_events (user_id, event_type, created_at) VALUES (?, ?, ?)")
-                .bind(&user_id)
-                .bind("logout")
-                .bind(now)
-                .execute(&db.pool)
-                .await
-            {
-                Ok(_) => println!("[AUTH] Recorded logout event for {}", user_id),
-                Err(e) => println!("[AUTH] Failed to record logout event for {}: {}", user_id, e),
-            }
+    // Verify state after logout
+    let sess_cnt = sqlx::query("SELECT COUNT(1) as c FROM sessions WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_one(&db.pool)
+        .await
+        .ok()
+        .and_then(|r| r.try_get::<i64, _>("c").ok())
+        .unwrap_or(-1);
+    let is_online = sqlx::query("SELECT is_online FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(&db.pool)
+        .await
+        .ok()
+        .and_then(|opt| opt.map(|r| r.get::<i64, _>("is_online")))
+        .unwrap_or(-1);
+    println!("[AUTH][DB CHECK] logout completed: sessions_count={} users.is_online={} for user {}", sess_cnt, is_online, user_id);
 
-            println!("[AUTH] Logout success for user_id={}", user_id);
-            "OK: Logout effettuato".to_string()
-        }
-        Ok(None) => {
-            println!("[AUTH] Logout fallito: sessione non trovata");
-            "ERR: Sessione non trovata".to_string()
-        }
-        Err(e) => {
-            println!("[AUTH] Logout fallito: {}", e);
-            format!("ERR: Logout fallito: {}", e)
-        }
+    // record logout event
+    let now = chrono::Utc::now().timestamp();
+    match sqlx::query("INSERT INTO session_events (user_id, event_type, created_at) VALUES (?, ?, ?)")
+        .bind(user_id)
+        .bind("logout")
+        .bind(now)
+        .execute(&db.pool)
+        .await
+    {
+        Ok(_) => println!("[AUTH] Recorded logout event for {}", user_id),
+        Err(e) => println!("[AUTH] Failed to record logout event for {}: {}", user_id, e),
     }
+
+    println!("[AUTH] Logout success for user_id={}", user_id);
+    Ok("Logout effettuato".to_string())
 }
 
 fn hash_password(password: &str, salt_length: u32) -> String {
@@ -105,7 +95,8 @@ fn generate_session_token() -> String {
     format!("{}-{:x}", uuid, md5::compute(random))
 }
 
-pub async fn register(db: Arc<Database>, username: &str, password: &str, config: &ServerConfig) -> String {
+/// Registra un nuovo utente e restituisce la sessione creata.
+pub async fn register(db: Arc<Database>, username: &str, password: &str, config: &ServerConfig) -> Result<Session, ChatError> {
     println!("[AUTH] Register attempt: {}", username);
     let user_id = uuid::Uuid::new_v4().to_string();
     let created_at = chrono::Utc::now().timestamp();
@@ -124,9 +115,9 @@ pub async fn register(db: Arc<Database>, username: &str, password: &str, config:
                 let err_str = e.to_string();
                 println!("[AUTH] Registration failed for {}: {}", username, err_str);
                 if err_str.to_lowercase().contains("UNIQUE") || err_str.to_lowercase().contains("constraint failed") {
-                    return "ERR: Username already used".to_string();
+                    return Err(ChatError::new(ErrorCode::AlreadyExists, "Username already used"));
                 }
-                return "ERR: Registration failed".to_string();
+                return Err(ChatError::new(ErrorCode::Internal, "Registration failed"));
             }
             sqlx::query("INSERT INTO user_encryption_keys (user_id, public_key, private_key) VALUES (?, '', '')")
                 .bind(&user_id)
@@ -157,19 +148,17 @@ pub async fn register(db: Arc<Database>, username: &str, password: &str, config:
                 .execute(&mut *tx)
                 .await
                 .ok();
-            println!("[AUTH] Created initial session for user {} token={}", user_id, session_token);
+            println!("[AUTH] Created initial session for user {}", user_id);
             tx.commit().await.ok();
             println!("[AUTH] Registered user {} (id={})", username, user_id);
-            format!("OK: Registered as {} SESSION: {}", username, session_token)
-        }
-        Err(e) => {
-            println!("[AUTH] Registration failed for {}: {}", username, e);
-            format!("ERR: Registration failed: {}", e)
+            Ok(Session { user_id, token: session_token, expires_at: expires })
         }
+        Err(e) => Err(ChatError::internal(&format!("[AUTH] Registration failed for {}", username), e)),
     }
 }
 
-pub async fn login(db: Arc<Database>, username: &str, password: &str, config: &ServerConfig) -> String {
+/// Verifica le credenziali e restituisce la nuova sessione.
+pub async fn login(db: Arc<Database>, username: &str, password: &str, config: &ServerConfig) -> Result<Session, ChatError> {
     println!("[AUTH] Login attempt: {}", username);
     let row = sqlx::query("SELECT users.id, password_hash FROM users JOIN auth ON users.id = auth.user_id WHERE username = ?")
         .bind(username)
@@ -180,86 +169,93 @@ pub async fn login(db: Arc<Database>, username: &str, password: &str, config: &S
             let user_id: String = row.get("id");
             let password_hash: String = row.get("password_hash");
             if verify_password(&password_hash, password) {
-                // Begin transaction to ensure atomic single-session semantics
-                match db.pool.begin().await {
-                    Ok(mut tx) => {
-                        // Remove any existing sessions for this user
-                        match sqlx::query("DELETE FROM sessions WHERE user_id = ?")
-                            .bind(&user_id)
-                            .execute(&mut *tx)
-                            .await
-                        {
-                            Ok(r) => println!("[AUTH] Deleted {} old sessions for user {} during login", r.rows_affected(), user_id),
-                            Err(e) => println!("[AUTH] Failed deleting old sessions for {}: {}", user_id, e),
-                        }
-
-                        // Set user online
-                        match sqlx::query("UPDATE users SET is_online = 1 WHERE id = ?")
-                            .bind(&user_id)
-                            .execute(&mut *tx)
-                            .await
-                        {
-                            Ok(_) => println!("[AUTH] Set is_online=1 for user {} (transaction)", user_id),
-                            Err(e) => println!("[AUTH] Failed to set is_online for {}: {}", user_id, e),
-                        }
-
-                        // Create new session token
-                        let session_token = generate_session_token();
-                        let now = chrono::Utc::now().timestamp();
-                        let expires = now + 60*60*24*config.session_expiry_days as i64;
-                        match sqlx::query("INSERT INTO sessions (user_id, session_token, created_at, expires_at) VALUES (?, ?, ?, ?)")
-                            .bind(&user_id)
-                            .bind(&session_token)
-                            .bind(now)
-                            .bind(expires)
-                            .execute(&mut *tx)
-                            .await
-                        {
-                            Ok(_) => println!("[AUTH] Inserted new session for user {} token={}", user_id, session_token),
-                            Err(e) => println!("[AUTH] Failed inserting session for {}: {}", user_id, e),
-                        }
-
-                        // Record login event
-                        let _ = sqlx::query("INSERT INTO session_events (user_id, event_type, created_at) VALUES (?, ?, ?)")
-                            .bind(&user_id)
-                            .bind("login_success")
-                            .bind(now)
-                            .execute(&mut *tx)
-                            .await;
-
-                        // Commit
-                        if let Err(e) = tx.commit().await {
-                            println!("[AUTH] Failed to commit login transaction for {}: {}", user_id, e);
-                            return format!("ERR: Login failed: {}", e);
-                        }
-
-                        println!("[AUTH] Login success for {} (id={})", username, user_id);
-                        format!("OK: Logged in as {} SESSION: {}", username, session_token)
-                    }
-                    Err(e) => {
-                        println!("[AUTH] Failed to start transaction for login {}: {}", username, e);
-                        format!("ERR: Login failed: {}", e)
-                    }
-                }
+                let session = start_session(db, &user_id, config, "login_success").await?;
+                println!("[AUTH] Login success for {} (id={})", username, user_id);
+                Ok(session)
             } else {
                 println!("[AUTH] Login failed for {}: wrong password", username);
-                "ERR: Wrong password".to_string()
+                Err(ChatError::new(ErrorCode::InvalidCredentials, "Wrong password"))
             }
         }
         Ok(None) => {
             println!("[AUTH] Login failed for {}: user not found", username);
-            "ERR: User not found".to_string()
+            Err(ChatError::user_not_found())
         }
-        Err(e) => {
-            println!("[AUTH] Login failed for {}: {}", username, e);
-            format!("ERR: Login failed: {}", e)
+        Err(e) => Err(ChatError::internal(&format!("[AUTH] Login failed for {}", username), e)),
+    }
+}
+
+/// Apre una nuova sessione per l'utente (sessione singola: quelle precedenti
+/// vengono eliminate) e registra `event` in session_events.
+async fn start_session(db: Arc<Database>, user_id: &str, config: &ServerConfig, event: &str) -> Result<Session, ChatError> {
+    // Begin transaction to ensure atomic single-session semantics
+    match db.pool.begin().await {
+        Ok(mut tx) => {
+            // Remove any existing sessions for this user
+            match sqlx::query("DELETE FROM sessions WHERE user_id = ?")
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await
+            {
+                Ok(r) => println!("[AUTH] Deleted {} old sessions for user {} during login", r.rows_affected(), user_id),
+                Err(e) => println!("[AUTH] Failed deleting old sessions for {}: {}", user_id, e),
+            }
+
+            // Set user online
+            match sqlx::query("UPDATE users SET is_online = 1 WHERE id = ?")
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await
+            {
+                Ok(_) => println!("[AUTH] Set is_online=1 for user {} (transaction)", user_id),
+                Err(e) => println!("[AUTH] Failed to set is_online for {}: {}", user_id, e),
+            }
+
+            // Create new session token
+            let session_token = generate_session_token();
+            let now = chrono::Utc::now().timestamp();
+            let expires = now + 60*60*24*config.session_expiry_days as i64;
+            match sqlx::query("INSERT INTO sessions (user_id, session_token, created_at, expires_at) VALUES (?, ?, ?, ?)")
+                .bind(user_id)
+                .bind(&session_token)
+                .bind(now)
+                .bind(expires)
+                .execute(&mut *tx)
+                .await
+            {
+                Ok(_) => println!("[AUTH] Inserted new session for user {}", user_id),
+                Err(e) => println!("[AUTH] Failed inserting session for {}: {}", user_id, e),
+            }
+
+            // Record login event
+            let _ = sqlx::query("INSERT INTO session_events (user_id, event_type, created_at) VALUES (?, ?, ?)")
+                .bind(user_id)
+                .bind(event)
+                .bind(now)
+                .execute(&mut *tx)
+                .await;
+
+            // Commit
+            if let Err(e) = tx.commit().await {
+                return Err(ChatError::internal(&format!("[AUTH] Failed to commit login transaction for {}", user_id), e));
+            }
+
+            Ok(Session { user_id: user_id.to_string(), token: session_token, expires_at: expires })
         }
+        Err(e) => Err(ChatError::internal(&format!("[AUTH] Failed to start transaction for login {}", user_id), e)),
     }
 }
 
-pub async fn validate_session(db: Arc<Database>, session_token: &str) -> Option<String> {
+/// Login senza password di un dispositivo già autenticato dal certificato client (mutual TLS).
+pub async fn certificate_login(db: Arc<Database>, user_id: &str, config: &ServerConfig) -> Result<Session, ChatError> {
+    let session = start_session(db, user_id, config, "login_certificate").await?;
+    println!("[AUTH] Certificate login success for user_id={}", user_id);
+    Ok(session)
+}
+
+pub async fn validate_session(db: Arc<Database>, session_token: &str) -> Option<Session> {
     let now = chrono::Utc::now().timestamp();
-    let row = sqlx::query("SELECT user_id FROM sessions WHERE session_token = ? AND expires_at > ?")
+    let row = sqlx::query("SELECT user_id, expires_at FROM sessions WHERE session_token = ? AND expires_at > ?")
         .bind(session_token)
         .bind(now)
         .fetch_optional(&db.pool)
@@ -268,18 +264,10 @@ pub async fn validate_session(db: Arc<Database>, session_token: &str) -> Option<
     
     if let Some(row) = row {
         let user_id: String = row.get("user_id");
-        println!("[AUTH] validate_session: token {} is valid for user {}", session_token, user_id);
-        
-        // Set user online when session is validated (for auto-login scenarios)
-        let _ = sqlx::query("UPDATE users SET is_online = 1 WHERE id = ?")
-            .bind(&user_id)
-            .execute(&db.pool)
-            .await;
-        println!("[AUTH] Set is_online=1 for user {} due to session validation", user_id);
-        
-        Some(user_id)
+        println!("[AUTH] validate_session: session is valid for user {}", user_id);
+        Some(Session { user_id, token: session_token.to_string(), expires_at: row.get("expires_at") })
     } else {
-        println!("[AUTH] validate_session: token {} is invalid or expired", session_token);
+        println!("[AUTH] validate_session: session token is invalid or expired");
         None
     }
 }
@@ -1,18 +1,31 @@
+use std::collections::HashMap;
 use std::env;
 use crate::common::crypto::CryptoManager;
+use crate::common::tls::TlsSettings;
+use crate::server::rate_limit::RateLimits;
 
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub database_url: String,
-    pub max_clients: usize,
+    pub max_clients: usize, // Connessioni contemporanee (TCP + WebSocket), 0 = nessun limite
+    pub max_connections_per_ip: usize, // Connessioni contemporanee per indirizzo IP, 0 = nessun limite
     pub enable_encryption: bool,
     pub log_level: String,
     pub session_expiry_days: u32,
     pub argon2_salt_length: u32,
     pub max_message_length: usize,
+    pub history_page_size: u32, // Messaggi per pagina dello storico se il client non indica `limit`
+    pub edit_window_secs: u64, // Tempo concesso al mittente per modificare un messaggio (0 = nessun limite)
     pub encryption_master_key: [u8; 32], // Master key for message encryption
+    pub websocket_port: Option<u16>, // Porta WebSocket dedicata; se assente i WebSocket condividono `port`
+    pub tls_client_ca_path: Option<String>, // CA dei certificati client: se impostata il TLS è mutuo
+    pub client_cert_users: HashMap<String, String>, // CN del certificato client -> username
+    pub tls_reload_interval_secs: u64, // Controllo dei file del certificato per la ricarica a caldo (0 = solo SIGHUP)
+    pub rate_limits: RateLimits, // Token bucket per classe di comando (RATE_LIMIT_AUTH/MESSAGES/LOOKUPS)
+    pub shutdown_timeout_secs: u64, // Attesa massima dei comandi in corso durante l'arresto
+    pub sync_retention_days: u32, // Giorni di conservazione degli eventi recuperabili con `sync` (0 = per sempre)
 }
 
 impl ServerConfig {
@@ -40,16 +53,36 @@ impl ServerConfig {
             port: env::var("SERVER_PORT").ok().and_then(|p| p.parse().ok()).unwrap_or(5000),
             database_url: env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:data/ruggine_modulare.db".to_string()),
             max_clients: env::var("MAX_CLIENTS").ok().and_then(|v| v.parse().ok()).unwrap_or(100),
+            max_connections_per_ip: env::var("MAX_CONNECTIONS_PER_IP").ok().and_then(|v| v.parse().ok()).unwrap_or(10),
             enable_encryption: env::var("ENABLE_ENCRYPTION").map(|v| v == "true" || v == "1").unwrap_or(true),
             log_level: env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_string()),
             session_expiry_days: env::var("SESSION_EXPIRY_DAYS").ok().and_then(|v| v.parse().ok()).unwrap_or(7),
             argon2_salt_length: env::var("ARGON2_SALT_LENGTH").ok().and_then(|v| v.parse().ok()).unwrap_or(16),
             max_message_length: env::var("MAX_MESSAGE_LENGTH").ok().and_then(|v| v.parse().ok()).unwrap_or(2048),
+            history_page_size: env::var("HISTORY_PAGE_SIZE").ok().and_then(|v| v.trim().parse().ok()).unwrap_or(50),
+            edit_window_secs: env::var("MESSAGE_EDIT_WINDOW_SECS").ok().and_then(|v| v.trim().parse().ok()).unwrap_or(900),
             encryption_master_key,
+            websocket_port: env::var("SERVER_WEBSOCKET_PORT").ok().and_then(|p| p.trim().parse().ok()),
+            tls_client_ca_path: env::var("TLS_CLIENT_CA_PATH").ok().filter(|p| !p.trim().is_empty()),
+            client_cert_users: parse_cert_users(&env::var("TLS_CLIENT_CERT_USERS").unwrap_or_default()),
+            tls_reload_interval_secs: env::var("TLS_RELOAD_INTERVAL_SECS").ok().and_then(|v| v.trim().parse().ok()).unwrap_or(30),
+            rate_limits: RateLimits::from_env(),
+            shutdown_timeout_secs: env::var("SHUTDOWN_TIMEOUT_SECS").ok().and_then(|v| v.trim().parse().ok()).unwrap_or(10),
+            sync_retention_days: env::var("SYNC_RETENTION_DAYS").ok().and_then(|v| v.trim().parse().ok()).unwrap_or(30),
         }
     }
 }
 
+/// Formato `cn=username` separati da virgola, es. `laptop-anna=anna,kiosk-01=reception`
+fn parse_cert_users(value: &str) -> HashMap<String, String> {
+    value
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(cn, user)| (cn.trim().to_string(), user.trim().to_string()))
+        .filter(|(cn, user)| !cn.is_empty() && !user.is_empty())
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct ClientConfig {
     pub default_host: String,
@@ -57,17 +90,53 @@ pub struct ClientConfig {
     pub public_host: String,
     pub websocket_host: String,
     pub websocket_port: u16,
+    pub use_tls: bool,                 // TLS sulla porta comandi per gli host predefiniti
+    pub websocket_tls: bool,           // Usa wss:// verso il server (default: come use_tls)
+    pub tls_ca_file: Option<String>,   // Bundle PEM delle CA fidate; se assente radici pubbliche
+    pub tls_pin_sha256: Option<String>, // Fingerprint SHA-256 atteso del certificato del server
+    pub tls_client_cert_path: Option<String>, // Certificato client PEM per server con mutual TLS
+    pub tls_client_key_path: Option<String>,
 }
 
 impl ClientConfig {
     pub fn from_env() -> Self {
         dotenvy::dotenv().ok();
+        let default_port = env::var("CLIENT_DEFAULT_PORT").ok().and_then(|p| p.parse().ok()).unwrap_or(5000);
+        let use_tls = env::var("CLIENT_TLS").map(|v| v == "true" || v == "1").unwrap_or(false);
         Self {
             default_host: env::var("CLIENT_DEFAULT_HOST").unwrap_or_else(|_| "127.0.0.1".to_string()),
-            default_port: env::var("CLIENT_DEFAULT_PORT").ok().and_then(|p| p.parse().ok()).unwrap_or(5000),
+            default_port,
             public_host: env::var("CLIENT_PUBLIC_HOST").unwrap_or_else(|_| "remote.example.com".to_string()),
             websocket_host: env::var("WEBSOCKET_HOST").unwrap_or_else(|_| "127.0.0.1".to_string()),
-            websocket_port: env::var("WEBSOCKET_PORT").ok().and_then(|p| p.parse().ok()).unwrap_or(5001),
+            // Di default il server accetta i WebSocket sulla stessa porta del protocollo a righe
+            websocket_port: env::var("WEBSOCKET_PORT").ok().and_then(|p| p.trim().parse().ok()).unwrap_or(default_port),
+            use_tls,
+            websocket_tls: env::var("WEBSOCKET_TLS").map(|v| v == "true" || v == "1").unwrap_or(use_tls),
+            tls_ca_file: env::var("TLS_CA_FILE").ok().filter(|p| !p.trim().is_empty()),
+            tls_pin_sha256: env::var("TLS_PIN_SHA256").ok().filter(|p| !p.trim().is_empty()),
+            tls_client_cert_path: env::var("TLS_CLIENT_CERT_PATH").ok().filter(|p| !p.trim().is_empty()),
+            tls_client_key_path: env::var("TLS_CLIENT_KEY_PATH").ok().filter(|p| !p.trim().is_empty()),
         }
     }
+
+    /// CA bundle, pin e certificato client configurati, usati sia dalla porta comandi sia dal WebSocket
+    pub fn tls_settings(&self) -> TlsSettings {
+        TlsSettings::new(self.tls_ca_file.as_deref(), self.tls_pin_sha256.as_deref())
+            .with_client_cert(self.tls_client_cert_path.as_deref(), self.tls_client_key_path.as_deref())
+    }
+
+    /// Vero se il client può autenticarsi con il certificato (login senza password)
+    pub fn has_client_cert(&self) -> bool {
+        self.use_tls && self.tls_client_cert_path.is_some() && self.tls_client_key_path.is_some()
+    }
+
+    /// Impostazioni TLS per la porta comandi, oppure `None` per TCP in chiaro
+    pub fn command_tls(&self) -> Option<TlsSettings> {
+        self.use_tls.then(|| self.tls_settings())
+    }
+
+    /// Impostazioni TLS per il WebSocket, oppure `None` se il client usa ws:// in chiaro
+    pub fn websocket_tls_settings(&self) -> Option<TlsSettings> {
+        self.websocket_tls.then(|| self.tls_settings())
+    }
 }
@@ -1,8 +1,10 @@
-use crate::server::{database::Database, auth, users, groups, messages, presence::PresenceRegistry, websocket::ChatWebSocketManager};
+use crate::server::{database::Database, auth, users, groups, messages, sync, mtls, limits::ConnectionLimiter, presence::PresenceRegistry, tls_reload::{self, ReloadableAcceptor}, rate_limit::{CommandClass, RateLimiter, RateSubject}, shutdown::Shutdown, websocket::{self, ChatWebSocketManager}};
+use crate::common::protocol::{ChatRef, Command, HistoryMessage, HistoryPage, InboxEntry, LastSeenVisibility, PresenceStatus, ReactionCount, Request, Response, ResponseData, UserInfo, PROTOCOL_VERSION};
+use crate::common::error::{ChatError, ErrorCode};
 use sqlx::Row;
 use crate::server::config::ServerConfig;
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
 use std::fs::File;
 use std::io::BufReader as StdBufReader;
@@ -12,16 +14,23 @@ use tokio_rustls::TlsAcceptor;
 use rustls::{ServerConfig as RustlsConfig};
 use rustls_pemfile::{certs, rsa_private_keys, pkcs8_private_keys};
 
+#[derive(Clone)]
 pub struct Server {
     pub db: Arc<Database>,
     pub config: ServerConfig,
     pub presence: PresenceRegistry,
     pub ws_manager: Option<Arc<ChatWebSocketManager>>,
+    /// Connessioni aperte, condivise con la porta WebSocket dedicata
+    pub limiter: ConnectionLimiter,
+    /// Limiti di frequenza per utente/connessione, condivisi con il gestore WebSocket
+    pub rate_limiter: RateLimiter,
+    /// Arresto controllato: stop agli accept e attesa dei comandi in corso
+    pub shutdown: Shutdown,
 }
 
 impl Server {
-    /// Configure TLS acceptor from environment variables
-    fn setup_tls_acceptor(&self) -> anyhow::Result<Option<TlsAcceptor>> {
+    /// Configure TLS acceptor from environment variables (usata anche dalla ricarica a caldo)
+    pub(crate) fn setup_tls_acceptor(&self) -> anyhow::Result<Option<TlsAcceptor>> {
         if !self.config.enable_encryption {
             println!("[TLS] TLS disabled in configuration");
             return Ok(None);
@@ -40,13 +49,14 @@ impl Server {
         let mut cert_reader = StdBufReader::new(cert_file);
         let cert_chain = certs(&mut cert_reader)?
             .into_iter()
-            .map(|v| rustls::Certificate(v))
+            .map(rustls::Certificate)
             .collect::<Vec<_>>();
 
         if cert_chain.is_empty() {
             return Err(anyhow::anyhow!("No certificates found in {}", cert_path));
         }
         println!("[TLS] Loaded {} certificate(s)", cert_chain.len());
+        tls_reload::log_expiry(&cert_chain[0].0);
 
         let key_file = File::open(&key_path)
             .map_err(|e| anyhow::anyhow!("Failed to open private key file '{}': {}", key_path, e))?;
@@ -66,9 +76,13 @@ impl Server {
         println!("[TLS] Loaded private key");
 
         let priv_key = rustls::PrivateKey(keys.remove(0));
-        let rustls_cfg = RustlsConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
+        let builder = RustlsConfig::builder().with_safe_defaults();
+        // Mutual TLS: con una CA per i client il certificato client è obbligatorio
+        let builder = match &self.config.tls_client_ca_path {
+            Some(ca_path) => builder.with_client_cert_verifier(mtls::client_verifier(ca_path)?),
+            None => builder.with_no_client_auth(),
+        };
+        let rustls_cfg = builder
             .with_single_cert(cert_chain, priv_key)
             .map_err(|e| anyhow::anyhow!("TLS configuration error: {}", e))?;
 
@@ -76,467 +90,859 @@ impl Server {
         Ok(Some(TlsAcceptor::from(std::sync::Arc::new(rustls_cfg))))
     }
 
-    pub async fn run(&self, addr: &str) -> anyhow::Result<()> {
-        let listener = TcpListener::bind(addr).await?;
-        println!("[SERVER] Listening on {}", addr);
-
-        // Setup TLS acceptor if enabled
-        let tls_acceptor = match self.setup_tls_acceptor() {
-            Ok(acceptor) => {
-                if acceptor.is_some() {
-                    println!("[TLS] TLS enabled and configured successfully");
-                } else {
-                    println!("[TLS] TLS disabled");
-                }
-                acceptor
+    /// Acceptor TLS condiviso da porta comandi e WebSocket; `None` se TLS è
+    /// disabilitato o la configurazione fallisce (fallback a TCP in chiaro).
+    /// Se attivo, il certificato viene ricaricato a caldo (vedi `tls_reload`).
+    pub fn tls_acceptor(&self) -> Option<ReloadableAcceptor> {
+        match self.setup_tls_acceptor() {
+            Ok(Some(acceptor)) => {
+                println!("[TLS] TLS enabled and configured successfully");
+                let acceptor = ReloadableAcceptor::new(acceptor);
+                tls_reload::spawn_watcher(self.clone(), acceptor.clone());
+                Some(acceptor)
+            }
+            Ok(None) => {
+                println!("[TLS] TLS disabled");
+                None
             }
             Err(e) => {
                 println!("[TLS] TLS configuration failed: {}", e);
                 println!("[TLS] Falling back to plain TCP");
                 None
             }
-        };
+        }
+    }
+
+    pub async fn run(&self, addr: &str, tls_acceptor: Option<ReloadableAcceptor>) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        println!("[SERVER] Listening on {}", addr);
+
+        if self.shared_port_websocket().is_some() {
+            println!("[SERVER] WebSocket upgrades accepted on {}", addr);
+        }
 
         loop {
-            let (stream, peer) = listener.accept().await?;
+            let (stream, peer) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = self.shutdown.triggered() => {
+                    println!("[SERVER] No longer accepting connections on {}", addr);
+                    return Ok(());
+                }
+            };
             println!("[SERVER] New connection from {}", peer);
-            let db = self.db.clone();
-            let config = self.config.clone();
-            let acceptor = tls_acceptor.clone();
-            let presence = self.presence.clone();
+            let server = self.clone();
+            // Acceptor corrente: dopo una ricarica le nuove connessioni usano il nuovo certificato
+            let acceptor = tls_acceptor.as_ref().map(ReloadableAcceptor::current);
             tokio::spawn(async move {
+                // Il posto resta occupato finché il task gestisce la connessione
+                let slot = server.limiter.try_acquire(peer.ip());
+                let rejection = slot.as_ref().err().cloned();
                 // If TLS is configured, try to accept TLS, otherwise use plain TCP
                 if let Some(acceptor) = acceptor {
                     match acceptor.accept(stream).await {
                         Ok(tls_stream) => {
-                                    if let Err(e) = handle_tls_client(db, config, tls_stream, peer, presence.clone()).await {
-                                        println!("[SERVER] Client error (tls {}) : {}", peer, e);
-                                    }
+                            let cert_user = match rejection {
+                                None => server.client_cert_identity(&tls_stream, peer).await,
+                                Some(_) => None,
+                            };
+                            if let Err(e) = server.serve_connection(tls_stream, peer, true, cert_user, rejection).await {
+                                println!("[SERVER] Client error (tls {}) : {}", peer, e);
+                            }
+                        }
+                        Err(e) => {
+                            println!("[SERVER] TLS accept failed: {}", e);
+                            if let Some(reason) = mtls::handshake_failure(&e) {
+                                mtls::record_failure(&server.db, peer, &reason).await;
+                            }
                         }
-                        Err(e) => println!("[SERVER] TLS accept failed: {}", e),
                     }
-                } else if let Err(e) = handle_client(db, config, stream, peer, presence.clone()).await {
+                } else if let Err(e) = server.serve_connection(stream, peer, false, None, rejection).await {
                     println!("[SERVER] Client error ({}): {}", peer, e);
                 }
             });
         }
     }
 
-    pub async fn handle_command(&self, cmd: &str, args: &[&str]) -> String {
-        println!("[SERVER] Received command: {} {:?}", cmd, args);
-        match cmd {
-            // FRIENDSHIP SYSTEM
-            "/send_friend_request" if args.len() >= 2 => {
-                let session_token = args[0];
-                let to_username = args[1];
-                let message = if args.len() > 2 { args[2..].join(" ") } else { "".to_string() };
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    users::send_friend_request(self.db.clone(), &uid, to_username, &message).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
-                }
+
+    /// Smista una connessione (già decifrata se TLS è attivo): sulla porta condivisa
+    /// una richiesta HTTP Upgrade va al gestore WebSocket, il resto al protocollo a righe.
+    /// Un handshake TLS su un server senza TLS viene chiuso subito, così il client
+    /// riceve un errore chiaro invece di attendere una risposta.
+    /// Con `rejection` impostato (limite di connessioni superato) il client riceve
+    /// l'errore `ServerFull` nel proprio protocollo e la connessione viene chiusa.
+    async fn serve_connection<S>(self, stream: S, peer: std::net::SocketAddr, encrypted: bool, cert_user: Option<mtls::CertIdentity>, rejection: Option<ChatError>) -> anyhow::Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        // I byte letti per il rilevamento restano nel buffer e arrivano comunque al gestore scelto
+        let mut stream = BufReader::new(stream);
+        let first = first_byte(&mut stream).await;
+        if !encrypted && first == Some(TLS_HANDSHAKE_RECORD) {
+            println!("[TLS] {} started a TLS handshake but TLS is disabled on this server; closing", peer);
+            return Ok(());
+        }
+        let ws_manager = self.shared_port_websocket().filter(|_| first == Some(b'G'));
+        if let Some(error) = rejection {
+            println!("[SERVER] Connection from {} rejected: {}", peer, error);
+            if ws_manager.is_none() {
+                return reject_line_client(stream, &error).await;
             }
-            "/accept_friend_request" if args.len() == 2 => {
-                let session_token = args[0];
-                let from_username = args[1];
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    users::accept_friend_request(self.db.clone(), &uid, from_username).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
-                }
+            let ws_stream = tokio_tungstenite::accept_async(stream)
+                .await
+                .map_err(|e| anyhow::anyhow!("WebSocket handshake failed: {}", e))?;
+            return websocket::reject_connection(ws_stream, &error).await;
+        }
+        let Some(ws_manager) = ws_manager else {
+            return handle_client(self, stream, peer, cert_user).await;
+        };
+        println!("[SERVER] {} requested a WebSocket upgrade", peer);
+        let ws_stream = tokio_tungstenite::accept_async(stream)
+            .await
+            .map_err(|e| anyhow::anyhow!("WebSocket handshake failed: {}", e))?;
+        ws_manager.handle_authenticated_connection(ws_stream, self.db.clone(), self.config.clone()).await
+    }
+
+    /// Utente associato al certificato client (solo con mutual TLS attivo).
+    /// Un certificato valido ma non associato a un utente viene registrato come
+    /// rifiuto; la connessione resta comunque utilizzabile con username e password.
+    async fn client_cert_identity(&self, tls_stream: &tokio_rustls::server::TlsStream<tokio::net::TcpStream>, peer: std::net::SocketAddr) -> Option<mtls::CertIdentity> {
+        self.config.tls_client_ca_path.as_ref()?;
+        let cert = tls_stream.get_ref().1.peer_certificates()?.first()?;
+        match mtls::identify(&self.db, &self.config, &cert.0).await {
+            Ok(identity) => {
+                println!("[TLS] {} authenticated by client certificate as {}", peer, identity.username);
+                Some(identity)
             }
-            "/reject_friend_request" if args.len() == 2 => {
-                let session_token = args[0];
-                let from_username = args[1];
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    users::reject_friend_request(self.db.clone(), &uid, from_username).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
-                }
+            Err(reason) => {
+                mtls::record_failure(&self.db, peer, &reason).await;
+                None
             }
-            "/list_friends" if args.len() == 1 => {
-                let session_token = args[0];
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    users::list_friends(self.db.clone(), &uid).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
-                }
+        }
+    }
+
+    /// Gestore WebSocket da servire sulla porta principale, se i WebSocket non hanno una porta dedicata.
+    fn shared_port_websocket(&self) -> Option<Arc<ChatWebSocketManager>> {
+        match self.config.websocket_port {
+            Some(_) => None,
+            None => self.ws_manager.clone(),
+        }
+    }
+
+    /// Risolve il token di sessione nell'id utente, oppure errore se assente/scaduto.
+    /// Su una connessione già autenticata il token può essere omesso: della sessione
+    /// legata si controlla la scadenza senza interrogare il DB.
+    async fn require_session(&self, bound: Option<&BoundSession>, session_token: Option<&str>) -> Result<String, ChatError> {
+        if let Some(b) = bound.filter(|b| session_token.is_none_or(|t| t == b.token)) {
+            if b.expires_at <= chrono::Utc::now().timestamp() {
+                println!("[AUTH] Session bound to this connection for user {} has expired", b.user_id);
+                return Err(ChatError::session_expired());
             }
-            "/received_friend_requests" if args.len() == 1 => {
-                let session_token = args[0];
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    users::received_friend_requests(self.db.clone(), &uid).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
-                }
+            return Ok(b.user_id.clone());
+        }
+        let token = session_token.ok_or_else(ChatError::session_expired)?;
+        auth::validate_session(self.db.clone(), token)
+            .await
+            .map(|session| session.user_id)
+            .ok_or_else(ChatError::session_expired)
+    }
+
+    /// Esegue un comando tipizzato. È il punto d'ingresso unico per entrambi i
+    /// formati di richiesta (envelope JSON e comandi testuali legacy).
+    pub async fn execute(&self, bound: Option<&BoundSession>, session_token: Option<&str>, command: &Command) -> Result<ResponseData, ChatError> {
+        println!("[SERVER] Received command: {}", command.name());
+        match command {
+            Command::Register { username, password } => {
+                auth::register(self.db.clone(), username, password, &self.config).await
+                    .map(|session| ResponseData::Session { username: username.clone(), session_token: session.token })
             }
-            "/sent_friend_requests" if args.len() == 1 => {
-                let session_token = args[0];
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    users::sent_friend_requests(self.db.clone(), &uid).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
+            Command::Login { username, password } => {
+                auth::login(self.db.clone(), username, password, &self.config).await
+                    .map(|session| ResponseData::Session { username: username.clone(), session_token: session.token })
+            }
+            Command::Logout => self.logout(bound, session_token).await.map(|(_, response)| response),
+            // Richiede l'identità TLS della connessione, gestito in dispatch()
+            Command::CertificateLogin => {
+                Err(ChatError::new(ErrorCode::InvalidCredentials, "No client certificate mapped to a user on this connection"))
+            }
+            Command::ValidateSession => {
+                let uid = self.require_session(bound, session_token).await?;
+                // Recupera username
+                let row = sqlx::query("SELECT username FROM users WHERE id = ?")
+                    .bind(&uid)
+                    .fetch_optional(&self.db.pool)
+                    .await;
+                match row {
+                    Ok(Some(r)) => Ok(ResponseData::SessionValid { username: r.get("username") }),
+                    Ok(None) => Err(ChatError::user_not_found()),
+                    Err(e) => Err(ChatError::internal("[AUTH] validate_session lookup failed", e)),
                 }
             }
             // SYSTEM
-            "/help" => {
-                users::help().await
-            }
-            "/quit" => {
-                "OK: Disconnected".to_string()
-            }
-            "/logout" if args.len() == 1 => {
-                // args[0] = session_token
-                let token = args[0];
-                // attempt to resolve user_id first so we can kick presence after logout
-                if let Some(uid) = auth::validate_session(self.db.clone(), token).await {
-                    println!("[AUTH] Handling /logout for user {} (token masked)", uid);
-                    
-                    // Disconnect WebSocket connections for this user BEFORE logout
-                    if let Some(ws_manager) = &self.ws_manager {
-                        ws_manager.disconnect_user(&uid).await;
-                    }
-                    
-                    let res = auth::logout(self.db.clone(), token).await;
-                    // After logout, query DB to report current sessions count and is_online state for debugging
-                    let sess_cnt = sqlx::query("SELECT COUNT(1) as c FROM sessions WHERE user_id = ?")
-                        .bind(&uid)
-                        .fetch_one(&self.db.pool)
-                        .await
-                        .ok()
-                        .and_then(|r| r.try_get::<i64, _>("c").ok())
-                        .unwrap_or(-1);
-                    let is_online = sqlx::query("SELECT is_online FROM users WHERE id = ?")
-                        .bind(&uid)
-                        .fetch_optional(&self.db.pool)
-                        .await
-                        .ok()
-                        .and_then(|opt| opt.map(|r| r.get::<i64, _>("is_online")))
-                        .unwrap_or(-1);
-                    println!("[AUTH][DB CHECK] after logout: sessions_count={} users.is_online={} for user {}", sess_cnt, is_online, uid);
-                    let kicked = self.presence.kick_all(&uid).await;
-                    println!("[AUTH] Logout triggered kick for user {} (kicked={})", uid, kicked);
-                    res
-                } else {
-                    // session not valid/expired, still call logout for consistent response
-                    println!("[AUTH] /logout called with invalid/expired token (raw token masked)");
-                    let res = auth::logout(self.db.clone(), token).await;
-                    // Can't resolve uid to run presence.kick_all; return result but also attempt to log token outcome
-                    println!("[AUTH] /logout completed for unknown token, result={}", res);
-                    res
-                }
+            Command::Help => Ok(ResponseData::Help { text: users::help().await }),
+            Command::Quit => Ok(ResponseData::Ack { message: "Disconnected".to_string() }),
+            Command::AllUsers => {
+                users::list_all(self.db.clone(), None).await
+                    .map(|users| ResponseData::AllUsers { users })
             }
-            "/validate_session" if args.len() == 1 => {
-                let session_token = args[0];
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    // Recupera username
-                    let row = sqlx::query("SELECT username FROM users WHERE id = ?")
-                        .bind(&uid)
-                        .fetch_optional(&self.db.pool)
-                        .await;
-                    if let Ok(Some(r)) = row {
-                        let username: String = r.get("username");
-                        format!("OK: {}", username)
-                    } else {
-                        "ERR: User not found".to_string()
-                    }
-                } else {
-                    "ERR: Invalid or expired session".to_string()
+            Command::Unknown => Err(ChatError::new(ErrorCode::UnknownCommand, "Unknown or invalid command")),
+            // Tutti gli altri comandi richiedono una sessione valida
+            command => {
+                let uid = self.require_session(bound, session_token).await?;
+                self.rate_limiter.check(RateSubject::User(uid.clone()), CommandClass::of(command))?;
+                self.execute_authenticated(&uid, command).await
+            }
+        }
+    }
+
+    async fn execute_authenticated(&self, uid: &str, command: &Command) -> Result<ResponseData, ChatError> {
+        let db = self.db.clone();
+        match command {
+            // FRIENDSHIP SYSTEM
+            Command::SendFriendRequest { to, message } => {
+                let ack = users::send_friend_request(db, uid, to, message).await?;
+                if let Some(ws_manager) = &self.ws_manager {
+                    ws_manager.notify_friend_request(&self.db, uid, to).await;
                 }
+                Ok(ResponseData::Ack { message: ack })
             }
-            "/register" if args.len() == 2 => {
-                auth::register(self.db.clone(), args[0], args[1], &self.config).await
+            Command::AcceptFriendRequest { from } => {
+                users::accept_friend_request(db, uid, from).await.map(|message| ResponseData::Ack { message })
             }
-            "/login" if args.len() == 2 => {
-                auth::login(self.db.clone(), args[0], args[1], &self.config).await
+            Command::RejectFriendRequest { from } => {
+                users::reject_friend_request(db, uid, from).await.map(|message| ResponseData::Ack { message })
             }
-            "/online_users" if args.len() == 1 => {
-                let session_token = args[0];
-                users::list_online_excluding_self(self.db.clone(), session_token).await
+            Command::ListFriends => {
+                users::list_friends(db, uid).await.map(|friends| ResponseData::Friends { friends })
             }
-            "/all_users" => {
-                let exclude = None;
-                users::list_all(self.db.clone(), exclude).await
+            Command::ReceivedFriendRequests => {
+                users::received_friend_requests(db, uid).await.map(|requests| ResponseData::ReceivedFriendRequests { requests })
             }
-            "/create_group" if args.len() >= 2 => {
-                let session_token = args[0];
-                let group_name = args[1];
-                let participants = if args.len() > 2 { Some(args[2]) } else { None };
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    groups::create_group_with_participants(self.db.clone(), &uid, group_name, participants).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
-                }
+            Command::SentFriendRequests => {
+                users::sent_friend_requests(db, uid).await.map(|requests| ResponseData::SentFriendRequests { requests })
             }
-            "/my_groups" if args.len() == 1 => {
-                let session_token = args[0];
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    groups::my_groups(self.db.clone(), &uid).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
+            Command::OnlineUsers => {
+                users::list_online_excluding_self(db, uid).await.map(|users| ResponseData::OnlineUsers { users })
+            }
+            Command::SetPresence { status, message } => {
+                users::set_presence(db.clone(), uid, *status, message.as_deref()).await?;
+                if let Some(ws_manager) = &self.ws_manager {
+                    ws_manager.notify_presence(&self.db, uid).await;
                 }
+                own_presence(db, uid).await
             }
-            "/invite" if args.len() == 3 => {
-                let session_token = args[0];
-                let username = args[1];
-                let group_id = args[2];
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    groups::invite_user_to_group(self.db.clone(), &uid, username, group_id).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
+            Command::GetPresence => own_presence(db, uid).await,
+            Command::SetLastSeenVisibility { visibility } => {
+                users::set_last_seen_visibility(db.clone(), uid, *visibility).await?;
+                own_presence(db, uid).await
+            }
+            Command::GetUser { username } => {
+                users::get_user(db, uid, username).await.map(|user| ResponseData::User { user })
+            }
+            // GROUPS
+            Command::CreateGroup { name, participants } => {
+                let group_id = groups::create_group_with_participants(db, uid, name, participants).await?;
+                for participant in participants {
+                    self.notify_group_invite(&group_id, participant.trim()).await;
                 }
+                Ok(ResponseData::GroupCreated { group_id, name: name.clone() })
             }
-            "/accept_group_invite" if args.len() >= 2 => {
-                let session_token = args[0];
-                let invite_id = args[1];
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    groups::accept_invite(self.db.clone(), &uid, invite_id).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
+            Command::MyGroups => {
+                groups::my_groups(db, uid).await.map(|groups| ResponseData::Groups { groups })
+            }
+            Command::Invite { username, group_id } => {
+                let ack = groups::invite_user_to_group(db, uid, username, group_id).await?;
+                self.notify_group_invite(group_id, username).await;
+                Ok(ResponseData::Ack { message: ack })
+            }
+            Command::AcceptGroupInvite { invite_id } => {
+                groups::accept_invite(db, uid, *invite_id).await.map(|message| ResponseData::Ack { message })
+            }
+            Command::RejectGroupInvite { invite_id } => {
+                groups::reject_invite(db, uid, *invite_id).await.map(|message| ResponseData::Ack { message })
+            }
+            Command::MyGroupInvites => {
+                groups::my_invites(db, uid).await.map(|invites| ResponseData::GroupInvites { invites })
+            }
+            Command::GroupMembers { group_id } => {
+                groups::get_group_members(db, group_id).await.map(|members| ResponseData::GroupMembers { members })
+            }
+            Command::JoinGroup { group } => {
+                groups::join_group(db, uid, group).await.map(|message| ResponseData::Ack { message })
+            }
+            Command::LeaveGroup { group } => {
+                groups::leave_group(db, uid, group).await.map(|message| ResponseData::Ack { message })
+            }
+            // MESSAGGI
+            Command::SendGroupMessage { group_id, content, client_msg_id, reply_to } => {
+                let m = messages::send_group_message(db, uid, group_id, content, client_msg_id.as_deref(), *reply_to, &self.config).await?;
+                if let Some(ws_manager) = &self.ws_manager {
+                    ws_manager.notify_new_message(&self.db, &m, &self.config).await;
                 }
+                Ok(ResponseData::MessageSent { message_id: m.id, seq: m.seq, sent_at: m.sent_at, duplicate: m.duplicate })
             }
-            "/reject_group_invite" if args.len() >= 2 => {
-                let session_token = args[0];
-                let invite_id = args[1];
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    groups::reject_invite(self.db.clone(), &uid, invite_id).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
+            Command::SendPrivateMessage { to, content, client_msg_id, reply_to } => {
+                let m = messages::send_private_message(db, uid, to, content, client_msg_id.as_deref(), *reply_to, &self.config).await?;
+                if let Some(ws_manager) = &self.ws_manager {
+                    ws_manager.notify_new_message(&self.db, &m, &self.config).await;
                 }
+                Ok(ResponseData::MessageSent { message_id: m.id, seq: m.seq, sent_at: m.sent_at, duplicate: m.duplicate })
             }
-            "/my_group_invites" if args.len() == 1 => {
-                let session_token = args[0];
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    groups::my_invites(self.db.clone(), &uid).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
+            Command::GetGroupMessages { group_id, page } => {
+                let (messages, has_more) = messages::get_group_messages(db, uid, group_id, page, &self.config).await?;
+                self.mark_history_delivered(uid, &messages).await;
+                Ok(ResponseData::Messages { messages, has_more })
+            }
+            Command::GetPrivateMessages { with, page } => {
+                let (messages, has_more) = messages::get_private_messages(db, uid, with, page, &self.config).await?;
+                self.mark_history_delivered(uid, &messages).await;
+                Ok(ResponseData::Messages { messages, has_more })
+            }
+            Command::GetThread { message_id } => {
+                messages::get_thread(db, uid, *message_id, &self.config).await
+                    .map(|(messages, has_more)| ResponseData::Messages { messages, has_more })
+            }
+            Command::EditMessage { message_id, content } => {
+                let edited = messages::edit_message(db, uid, *message_id, content, &self.config).await?;
+                if let Some(ws_manager) = &self.ws_manager {
+                    ws_manager.notify_message_edited(&self.db, &edited).await;
                 }
+                Ok(ResponseData::MessageEdited { message_id: edited.id, seq: edited.seq, edited_at: edited.edited_at })
             }
-            "/group_members" if args.len() == 2 => {
-                let session_token = args[0];
-                let group_id = args[1];
-                if let Some(_uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    groups::get_group_members(self.db.clone(), group_id).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
+            Command::DeleteMessage { message_id } => {
+                let deleted = messages::delete_message(db, uid, *message_id).await?;
+                if let Some(ws_manager) = &self.ws_manager {
+                    ws_manager.notify_message_deleted(&self.db, &deleted, &self.config).await;
                 }
+                Ok(ResponseData::MessageDeleted { message_id: deleted.id, seq: deleted.seq, deleted_at: deleted.deleted_at })
             }
-            "/join_group" if args.len() == 2 => {
-                let session_token = args[0];
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    groups::join_group(self.db.clone(), &uid, args[1]).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
+            Command::AddReaction { message_id, emoji } => {
+                let update = messages::add_reaction(db, uid, *message_id, emoji).await?;
+                if let Some(ws_manager) = &self.ws_manager {
+                    ws_manager.notify_reactions_updated(&self.db, &update).await;
                 }
+                Ok(ResponseData::Reactions { message_id: update.id, reactions: update.reactions })
             }
-            "/leave_group" if args.len() == 2 => {
-                let session_token = args[0];
-                if let Some(uid) = auth::validate_session(self.db.clone(), session_token).await {
-                    groups::leave_group(self.db.clone(), &uid, args[1]).await
-                } else {
-                    "ERR: Invalid or expired session".to_string()
+            Command::RemoveReaction { message_id } => {
+                let update = messages::remove_reaction(db, uid, *message_id).await?;
+                if let Some(ws_manager) = &self.ws_manager {
+                    ws_manager.notify_reactions_updated(&self.db, &update).await;
                 }
+                Ok(ResponseData::Reactions { message_id: update.id, reactions: update.reactions })
             }
-            // MESSAGGI
-            "/send_group_message" if args.len() >= 3 => {
-                let session_token = args[0];
-                let group_name = args[1];
-                let message = &args[2..].join(" ");
-                messages::send_group_message(self.db.clone(), session_token, group_name, message, &self.config).await
+            Command::MarkPrivateRead { with, up_to } => {
+                let (chat, updates) = messages::mark_private_read(db, uid, with, *up_to).await?;
+                self.notify_receipts(&updates).await;
+                self.notify_chat_read(uid, &chat).await;
+                Ok(ResponseData::MarkedRead { messages: updates.len() as u32 })
             }
-            "/send_private_message"  if args.len() >= 3 => {
-                let session_token = args[0];
-                let to_username = args[1];
-                let message = &args[2..].join(" ");
-                messages::send_private_message(self.db.clone(), session_token, to_username, message, &self.config).await
+            Command::MarkGroupRead { group_id, up_to } => {
+                let (chat, updates) = messages::mark_group_read(db, uid, group_id, *up_to).await?;
+                self.notify_receipts(&updates).await;
+                self.notify_chat_read(uid, &chat).await;
+                Ok(ResponseData::MarkedRead { messages: updates.len() as u32 })
             }
-            "/get_group_messages" if args.len() == 2 => {
-                let session_token = args[0];
-                let group_name = args[1];
-                messages::get_group_messages(self.db.clone(), session_token, group_name, &self.config).await
+            Command::DeleteGroupMessages { group_id } => {
+                messages::delete_group_messages(db, uid, group_id).await.map(|message| ResponseData::Ack { message })
             }
-            "/get_private_messages" if args.len() == 2 => {
-                let session_token = args[0];
-                let other_username = args[1];
-                messages::get_private_messages(self.db.clone(), session_token, other_username, &self.config).await
+            Command::DeletePrivateMessages { with } => {
+                messages::delete_private_messages(db, uid, with).await.map(|message| ResponseData::Ack { message })
             }
-            "/delete_group_messages" if args.len() == 2 => {
-                let session_token = args[0];
-                let group_id = args[1];
-                messages::delete_group_messages(self.db.clone(), session_token, group_id).await
+            Command::Inbox => {
+                messages::inbox(db, uid, &self.config).await.map(|chats| ResponseData::Inbox { chats })
             }
-            "/delete_private_messages" if args.len() == 2 => {
-                let session_token = args[0];
-                let other_username = args[1];
-                messages::delete_private_messages(self.db.clone(), session_token, other_username).await
+            Command::Sync { since, limit } => {
+                sync::events_since(&db, uid, *since, *limit, &self.config).await
+                    .map(|(events, has_more, latest_seq)| ResponseData::Events { events, has_more, latest_seq })
             }
-            _ => "ERR: Unknown or invalid command".to_string(),
+            // Comandi senza sessione, gestiti in execute()
+            _ => Err(ChatError::new(ErrorCode::UnknownCommand, "Unknown or invalid command")),
         }
     }
-}
 
-async fn handle_client(db: Arc<Database>, config: ServerConfig, stream: TcpStream, peer: std::net::SocketAddr, presence: PresenceRegistry) -> anyhow::Result<()> {
-    let (reader, writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
-    let mut writer = BufWriter::new(writer);
-    let mut line = String::new();
-    let mut kick_rx: Option<tokio::sync::oneshot::Receiver<()>> = None;
-    let mut registered_user: Option<String> = None;
-    let mut registered_token: Option<String> = None;
-    loop {
-        line.clear();
-        if let Some(rx) = &mut kick_rx {
-            tokio::select! {
-                biased;
-                _ = rx => {
-                    if let Some(uid) = &registered_user {
-                        println!("[AUTH] User {} kicked out due to login from another device", uid);
-                    } else {
-                        println!("[SERVER] Client was kicked out");
-                    }
-                    break;
-                }
-                res = reader.read_line(&mut line) => {
-                    let n = res?;
-                    if n == 0 {
-                        println!("[SERVER] Client disconnected: {}", peer);
-                        break;
-                    }
-                }
+    /// I messaggi altrui di una pagina dello storico risultano consegnati a chi la legge.
+    /// Un errore qui non fa fallire la lettura dello storico.
+    async fn mark_history_delivered(&self, uid: &str, page: &[HistoryMessage]) {
+        let (Some(first), Some(last)) = (page.first(), page.last()) else { return };
+        match messages::mark_delivered(&self.db, uid, first.id, last.id).await {
+            Ok(updates) => self.notify_receipts(&updates).await,
+            Err(e) => println!("[MSG] Could not mark history as delivered to {}: {}", uid, e.message),
+        }
+    }
+
+    async fn notify_receipts(&self, updates: &[messages::ReceiptUpdate]) {
+        if let Some(ws_manager) = &self.ws_manager {
+            ws_manager.notify_receipts(&self.db, updates).await;
+        }
+    }
+
+    async fn notify_chat_read(&self, uid: &str, chat: &messages::MessageChat) {
+        if let Some(ws_manager) = &self.ws_manager {
+            ws_manager.notify_chat_read(&self.db, uid, chat, &self.config).await;
+        }
+    }
+
+    async fn notify_group_invite(&self, group_id: &str, username: &str) {
+        if let Some(ws_manager) = &self.ws_manager {
+            ws_manager.notify_group_invite(&self.db, group_id, username).await;
+        }
+    }
+
+    /// Revoca tutte le sessioni dell'utente e restituisce il suo id con la risposta.
+    async fn logout(&self, bound: Option<&BoundSession>, session_token: Option<&str>) -> Result<(String, ResponseData), ChatError> {
+        let uid = self.require_session(bound, session_token).await?;
+        println!("[AUTH] Handling /logout for user {}", uid);
+
+        // Disconnect WebSocket connections for this user BEFORE logout
+        if let Some(ws_manager) = &self.ws_manager {
+            ws_manager.disconnect_user(&uid).await;
+        }
+
+        let res = auth::logout(self.db.clone(), &uid).await;
+        let kicked = self.presence.kick_all(&uid).await;
+        println!("[AUTH] Logout triggered kick for user {} (kicked={})", uid, kicked);
+        res.map(|message| (uid, ResponseData::Ack { message }))
+    }
+
+    /// Compatibilità con il vecchio formato testuale (`/comando arg1 arg2 ...`):
+    /// il comando viene convertito nella forma tipizzata, eseguito, e la risposta
+    /// viene resa nel formato `OK: ...` / `ERR: ...` atteso dai client esistenti.
+    pub async fn handle_command(&self, cmd: &str, args: &[&str]) -> String {
+        match parse_legacy(cmd, args) {
+            Some((token, command)) => {
+                let result = self.execute(None, token, &command).await;
+                legacy_reply(&command, &result)
             }
-        } else {
-            let n = reader.read_line(&mut line).await?;
-            if n == 0 {
-                println!("[SERVER] Client disconnected: {}", peer);
-                break;
+            None => "ERR: Unknown or invalid command".to_string(),
+        }
+    }
+
+    /// Come `execute`, ma con l'identità del certificato client della connessione.
+    /// I comandi senza sessione (login, comandi pubblici) sono limitati qui per
+    /// indirizzo o connessione; quelli autenticati per utente in `execute`.
+    /// Restituisce anche l'effetto del comando sulla sessione legata alla connessione.
+    async fn dispatch(&self, peer: std::net::SocketAddr, bound: Option<&BoundSession>, cert_user: Option<&mtls::CertIdentity>, session_token: Option<&str>, command: &Command) -> (Result<ResponseData, ChatError>, Option<SessionChange>) {
+        let class = CommandClass::of(command);
+        if class == CommandClass::Auth || is_public(command) {
+            if let Err(e) = self.rate_limiter.check(RateSubject::unauthenticated(class, peer), class) {
+                return (Err(e), None);
             }
         }
-    let trimmed = line.trim();
-    // Raw incoming line logger for diagnostics
-    println!("[CONN:RAW] [{}] Raw line received: '{}'", peer, trimmed);
-        if trimmed.is_empty() { continue; }
-        let mut parts = trimmed.split_whitespace();
-        let cmd = parts.next().unwrap_or("");
-        let args: Vec<&str> = parts.collect();
-        println!("[CONN] [{}] Cmd='{}' Args={:?}", peer, cmd, args);
-        let server = Server { db: db.clone(), config: config.clone(), presence: presence.clone(), ws_manager: None };
-        let response = server.handle_command(cmd, &args).await;
-        println!("[CONN] [{}] Response: {}", peer, response);
-        // If the client just validated an existing session, register presence so
-        // we treat this connection as an active one (preserve session row for auto-login
-        // but reflect presence in is_online).
-        if cmd == "/validate_session" && args.len() == 1 && response.starts_with("OK:") {
-            let token = args[0];
-            println!("[CONN] [{}] /validate_session returned OK for token {} — registering presence", peer, token);
-            if let Some(uid) = auth::validate_session(db.clone(), token).await {
-                // Do not kick existing sessions on validate; just register this connection
-                let rx = presence.register(&uid).await;
-                println!("[CONN] [{}] Registered presence receiver for user {} (via validate_session)", peer, uid);
-                // set is_online = 1 when a connection registers
-                let _ = sqlx::query("UPDATE users SET is_online = 1 WHERE id = ?")
-                    .bind(&uid)
-                    .execute(&db.pool)
-                    .await;
-                println!("[DB] Set is_online=1 for user {} due to validate_session", uid);
-                kick_rx = Some(rx);
-                registered_user = Some(uid.clone());
-                registered_token = Some(token.to_string());
-            } else {
-                println!("[CONN] [{}] validate_session token {} became invalid during registration", peer, token);
-            }
-        }
-        if response.contains("SESSION:") {
-            if let Some(line) = response.lines().find(|l| l.contains("SESSION:")) {
-                if let Some(tok) = line.split("SESSION:").nth(1) {
-                    let token = tok.trim();
-                    println!("[CONN] [{}] Detected SESSION token: {}", peer, token);
-                    if let Some(uid) = auth::validate_session(db.clone(), token).await {
-                        println!("[CONN] [{}] Token maps to user_id={}", peer, uid);
-                        // kick previous sessions for this user and record event
-                        let kicked = presence.kick_all(&uid).await;
-                        if kicked > 0 {
-                            println!("[AUTH] User {} kicked out due to login from another device (kicked={})", uid, kicked);
-                            let now = chrono::Utc::now().timestamp();
-                            let res = sqlx::query("INSERT INTO session_events (user_id, event_type, created_at) VALUES (?, ?, ?)")
-                                .bind(&uid)
-                                .bind("kicked_out")
-                                .bind(now)
-                                .execute(&db.pool)
-                                .await;
-                            println!("[DB] Inserted kicked_out event for {} result={:?}", uid, res);
-                        } else {
-                            println!("[AUTH] No previous sessions to kick for {}", uid);
-                        }
-                        let rx = presence.register(&uid).await;
-                        println!("[CONN] [{}] Registered presence receiver for user {}", peer, uid);
-                        // set is_online = 1 when a connection registers
-                        let _ = sqlx::query("UPDATE users SET is_online = 1 WHERE id = ?")
-                            .bind(&uid)
-                            .execute(&db.pool)
-                            .await;
-                        println!("[DB] Set is_online=1 for user {} due to active connection", uid);
-                        kick_rx = Some(rx);
-                        registered_user = Some(uid.clone());
-                        registered_token = Some(token.to_string());
+        let (username, opened) = match (command, cert_user) {
+            (Command::Register { username, password }, _) => {
+                println!("[SERVER] Received command: {}", command.name());
+                (username.clone(), auth::register(self.db.clone(), username, password, &self.config).await)
+            }
+            (Command::Login { username, password }, _) => {
+                println!("[SERVER] Received command: {}", command.name());
+                (username.clone(), auth::login(self.db.clone(), username, password, &self.config).await)
+            }
+            (Command::CertificateLogin, Some(identity)) => {
+                println!("[SERVER] Received command: {}", command.name());
+                (identity.username.clone(), auth::certificate_login(self.db.clone(), &identity.user_id, &self.config).await)
+            }
+            (Command::Logout, _) => {
+                println!("[SERVER] Received command: {}", command.name());
+                return match self.logout(bound, session_token).await {
+                    Ok((uid, response)) => {
+                        let released = bound.is_some_and(|b| b.user_id == uid).then_some(SessionChange::Release);
+                        (Ok(response), released)
                     }
-                }
+                    Err(e) => (Err(e), None),
+                };
             }
+            _ => return self.execute_and_bind(bound, session_token, command).await,
+        };
+        match opened {
+            Ok(session) => {
+                let response = ResponseData::Session { username, session_token: session.token.clone() };
+                (Ok(response), Some(SessionChange::Bind(session, true)))
+            }
+            Err(e) => (Err(e), None),
         }
-        writer.write_all(response.as_bytes()).await?;
-        writer.write_all(b"\n").await?;
-        writer.flush().await?;
     }
-    if let Some(uid) = registered_user {
-        println!("[CONN] [{}] Connection for user {} ending; cleaning up", peer, uid);
-        presence.unregister_one(&uid).await;
-        // If no more active connections, set is_online = 0 (preserve session row for auto-login)
-        let remaining = presence.count(&uid).await;
-        if remaining == 0 {
-            let _ = sqlx::query("UPDATE users SET is_online = 0 WHERE id = ?")
-                .bind(&uid)
-                .execute(&db.pool)
-                .await;
-            println!("[DB] Set is_online=0 for user {} because no active connections remain", uid);
-        } else {
-            println!("[CONN] [{}] {} active connections remain for user {}, leaving is_online=1", peer, remaining, uid);
-        }
-        if let Some(tok) = registered_token {
-            println!("[CONN] [{}] Preserving session token {} for user {} to allow auto-login on reconnect", peer, tok, uid);
+
+    /// Un comando autenticato con un token diverso da quello della connessione (es.
+    /// validate_session dopo una riconnessione) verifica il token una sola volta e, se
+    /// riesce, lega quella sessione alla connessione.
+    async fn execute_and_bind(&self, bound: Option<&BoundSession>, session_token: Option<&str>, command: &Command) -> (Result<ResponseData, ChatError>, Option<SessionChange>) {
+        let other_token = session_token.filter(|t| !is_public(command) && bound.is_none_or(|b| b.token != *t));
+        let Some(token) = other_token else {
+            return (self.execute(bound, session_token, command).await, None);
+        };
+        let Some(session) = auth::validate_session(self.db.clone(), token).await else {
+            println!("[SERVER] Received command: {}", command.name());
+            return (Err(ChatError::session_expired()), None);
+        };
+        let result = self.execute(Some(&session), session_token, command).await;
+        let activated = result.is_ok().then_some(SessionChange::Bind(session, false));
+        (result, activated)
+    }
+
+    /// Elabora una riga ricevuta dal client, in formato JSON o legacy.
+    async fn process_line(&self, line: &str, peer: std::net::SocketAddr, bound: Option<&BoundSession>, cert_user: Option<&mtls::CertIdentity>) -> LineOutcome {
+        if line.starts_with('{') {
+            let request = match decode_request(line) {
+                Ok(request) => request,
+                Err((id, error)) => {
+                    return LineOutcome { reply: encode_response(Response::from_result(id, Err(error))), session: None };
+                }
+            };
+            let token = request.session_token.as_deref();
+            let (result, session) = self.dispatch(peer, bound, cert_user, token, &request.command).await;
+            LineOutcome { reply: encode_response(Response::from_result(request.id, result)), session }
         } else {
-            println!("[CONN] [{}] No session token associated with this connection", peer);
+            let mut parts = line.split_whitespace();
+            let cmd = parts.next().unwrap_or("");
+            let args = legacy_args(cmd, parts.collect(), bound);
+            match parse_legacy(cmd, &args) {
+                Some((token, command)) => {
+                    let (result, session) = self.dispatch(peer, bound, cert_user, token, &command).await;
+                    LineOutcome { reply: legacy_reply(&command, &result), session }
+                }
+                None => LineOutcome { reply: "ERR: Unknown or invalid command".to_string(), session: None },
+            }
         }
-        let now = chrono::Utc::now().timestamp();
-        let res = sqlx::query("INSERT INTO session_events (user_id, event_type, created_at) VALUES (?, ?, ?)")
-            .bind(&uid)
-            .bind("quit")
-            .bind(now)
-            .execute(&db.pool)
-            .await;
-        println!("[DB] Inserted quit event for {} result={:?}", uid, res);
     }
+}
+
+/// Risposta di errore a una riga che non verrà eseguita, nel formato della richiesta.
+fn error_reply(line: &str, error: &ChatError) -> String {
+    if line.starts_with('{') {
+        let id = decode_request(line).map(|r| r.id).unwrap_or_else(|(id, _)| id);
+        encode_response(Response::from_result(id, Err(error.clone())))
+    } else {
+        format!("ERR: {}", error.message)
+    }
+}
+
+/// Tempo concesso a un client rifiutato per inviare la prima richiesta
+const REJECT_READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Risponde alla prima richiesta di un client rifiutato con `error`, nel formato
+/// usato dal client (JSON con lo stesso id, oppure legacy `ERR:`), poi chiude.
+async fn reject_line_client<S>(mut stream: BufReader<S>, error: &ChatError) -> anyhow::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let mut line = String::new();
+    let _ = tokio::time::timeout(REJECT_READ_TIMEOUT, stream.read_line(&mut line)).await;
+    let reply = error_reply(line.trim(), error);
+    let stream = stream.get_mut();
+    stream.write_all(reply.as_bytes()).await?;
+    stream.write_all(b"\n").await?;
+    stream.shutdown().await?;
     Ok(())
 }
 
-// TLS stream handling: keep the same protocol logic but using the TLS stream types
-async fn handle_tls_client<S>(db: Arc<Database>, config: ServerConfig, stream: S, peer: std::net::SocketAddr, presence: PresenceRegistry) -> anyhow::Result<()>
+/// Primo byte del record TLS ClientHello
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+/// Primo byte inviato dal client, senza consumarlo: `G` per l'handshake WebSocket
+/// (`GET`), `/` o `{` per i comandi a righe, 0x16 per un handshake TLS.
+async fn first_byte<S: tokio::io::AsyncRead + Unpin>(stream: &mut BufReader<S>) -> Option<u8> {
+    stream.fill_buf().await.ok().and_then(|buf| buf.first().copied())
+}
+
+/// Sessione associata a una connessione dopo un login o una validazione riusciti:
+/// i comandi successivi possono omettere il token finché la sessione non scade.
+pub type BoundSession = auth::Session;
+
+/// Effetto di una riga sulla sessione legata alla connessione.
+enum SessionChange {
+    /// Sessione da legare, con `true` se è un nuovo login (che disconnette le altre
+    /// sessioni dello stesso utente) e `false` per il primo comando autenticato con un token
+    Bind(BoundSession, bool),
+    /// Logout dell'utente della connessione: le sue sessioni sono state revocate
+    Release,
+}
+
+/// Risultato dell'elaborazione di una riga: risposta da inviare ed eventuale
+/// cambio della sessione legata alla connessione.
+struct LineOutcome {
+    reply: String,
+    session: Option<SessionChange>,
+}
+
+/// Presenza scelta dall'utente, restituita dai comandi che la leggono o la modificano.
+async fn own_presence(db: Arc<Database>, uid: &str) -> Result<ResponseData, ChatError> {
+    let (status, message, last_seen_visibility) = users::own_presence(db, uid).await?;
+    Ok(ResponseData::Presence { status, message, last_seen_visibility })
+}
+
+/// Comandi che non richiedono (né validano) una sessione.
+fn is_public(command: &Command) -> bool {
+    matches!(command, Command::Register { .. } | Command::Login { .. } | Command::CertificateLogin | Command::Logout | Command::Help | Command::Quit | Command::AllUsers | Command::Unknown)
+}
+
+/// Nei comandi legacy il token occupa il primo argomento ed è obbligatorio solo se alla
+/// connessione non è legata una sessione. Su una connessione autenticata il primo argomento
+/// è il token solo se coincide con quello della sessione legata, oppure se il comando non è
+/// valido senza token (es. `/validate_session <altro token>`); altrimenti viene inserito un
+/// segnaposto e gli argomenti restano tutti al comando, anche se hanno la forma di un token.
+fn legacy_args<'a>(cmd: &str, args: Vec<&'a str>, bound: Option<&BoundSession>) -> Vec<&'a str> {
+    const TOKENLESS: [&str; 6] = ["/register", "/login", "/cert_login", "/help", "/quit", "/all_users"];
+    let Some(bound) = bound else { return args };
+    if TOKENLESS.contains(&cmd) || args.first() == Some(&bound.token.as_str()) {
+        return args;
+    }
+    let mut with_placeholder = vec![""];
+    with_placeholder.extend(args.iter().copied());
+    if parse_legacy(cmd, &with_placeholder).is_some() {
+        with_placeholder
+    } else {
+        args
+    }
+}
+
+fn decode_request(line: &str) -> Result<Request, (u64, ChatError)> {
+    let value: serde_json::Value = serde_json::from_str(line)
+        .map_err(|e| (0, ChatError::new(ErrorCode::BadRequest, format!("Invalid JSON: {}", e))))?;
+    let id = value.get("id").and_then(|v| v.as_u64()).unwrap_or(0);
+    match value.get("v").and_then(|v| v.as_u64()) {
+        None => return Err((id, ChatError::new(ErrorCode::BadRequest, "Missing protocol version"))),
+        Some(v) if v > PROTOCOL_VERSION as u64 => {
+            return Err((id, ChatError::new(
+                ErrorCode::UnsupportedVersion,
+                format!("Unsupported protocol version {} (server supports up to {})", v, PROTOCOL_VERSION),
+            )));
+        }
+        Some(_) => {}
+    }
+    serde_json::from_value::<Request>(value)
+        .map_err(|e| (id, ChatError::new(ErrorCode::BadRequest, format!("Invalid request: {}", e))))
+}
+
+fn encode_response(response: Response) -> String {
+    serde_json::to_string(&response).unwrap_or_else(|e| {
+        println!("[SERVER] Failed to serialize response: {}", e);
+        format!(r#"{{"v":{},"id":{},"ok":false,"error":{{"code":"internal","message":"Internal server error"}}}}"#, PROTOCOL_VERSION, response.id)
+    })
+}
+
+/// Converte un comando testuale legacy nel comando tipizzato e nel token di sessione.
+/// Restituisce `None` se il comando è sconosciuto o ha un numero errato di argomenti.
+fn parse_legacy<'a>(cmd: &str, args: &[&'a str]) -> Option<(Option<&'a str>, Command)> {
+    let token = args.first().copied().filter(|t| !t.is_empty());
+    let arg = |i: usize| args[i].to_string();
+    let command = match cmd {
+        // FRIENDSHIP SYSTEM
+        "/send_friend_request" if args.len() >= 2 => Command::SendFriendRequest { to: arg(1), message: args[2..].join(" ") },
+        "/accept_friend_request" if args.len() == 2 => Command::AcceptFriendRequest { from: arg(1) },
+        "/reject_friend_request" if args.len() == 2 => Command::RejectFriendRequest { from: arg(1) },
+        "/list_friends" if args.len() == 1 => Command::ListFriends,
+        "/received_friend_requests" if args.len() == 1 => Command::ReceivedFriendRequests,
+        "/sent_friend_requests" if args.len() == 1 => Command::SentFriendRequests,
+        // SYSTEM
+        "/help" => return Some((None, Command::Help)),
+        "/cert_login" => return Some((None, Command::CertificateLogin)),
+        "/quit" => return Some((None, Command::Quit)),
+        "/logout" if args.len() == 1 => Command::Logout,
+        "/validate_session" if args.len() == 1 => Command::ValidateSession,
+        "/register" if args.len() == 2 => return Some((None, Command::Register { username: arg(0), password: arg(1) })),
+        "/login" if args.len() == 2 => return Some((None, Command::Login { username: arg(0), password: arg(1) })),
+        "/online_users" if args.len() == 1 => Command::OnlineUsers,
+        "/set_presence" if args.len() >= 2 => Command::SetPresence {
+            status: PresenceStatus::parse(args[1])?,
+            message: Some(args[2..].join(" ")).filter(|m| !m.is_empty()),
+        },
+        "/get_presence" if args.len() == 1 => Command::GetPresence,
+        "/last_seen_visibility" if args.len() == 2 => Command::SetLastSeenVisibility { visibility: LastSeenVisibility::parse(args[1])? },
+        "/get_user" if args.len() == 2 => Command::GetUser { username: arg(1) },
+        "/all_users" => return Some((None, Command::AllUsers)),
+        // GROUPS
+        "/create_group" if args.len() >= 2 => {
+            let participants = args.get(2)
+                .map(|p| p.split(',').map(|u| u.trim().to_string()).filter(|u| !u.is_empty()).collect())
+                .unwrap_or_default();
+            Command::CreateGroup { name: arg(1), participants }
+        }
+        "/my_groups" if args.len() == 1 => Command::MyGroups,
+        "/invite" if args.len() == 3 => Command::Invite { username: arg(1), group_id: arg(2) },
+        "/accept_group_invite" if args.len() >= 2 => Command::AcceptGroupInvite { invite_id: args[1].parse().ok()? },
+        "/reject_group_invite" if args.len() >= 2 => Command::RejectGroupInvite { invite_id: args[1].parse().ok()? },
+        "/my_group_invites" if args.len() == 1 => Command::MyGroupInvites,
+        "/group_members" if args.len() == 2 => Command::GroupMembers { group_id: arg(1) },
+        "/join_group" if args.len() == 2 => Command::JoinGroup { group: arg(1) },
+        "/leave_group" if args.len() == 2 => Command::LeaveGroup { group: arg(1) },
+        // MESSAGGI
+        "/send_group_message" if args.len() >= 3 => Command::SendGroupMessage { group_id: arg(1), content: args[2..].join(" "), client_msg_id: None, reply_to: None },
+        "/send_private_message" if args.len() >= 3 => Command::SendPrivateMessage { to: arg(1), content: args[2..].join(" "), client_msg_id: None, reply_to: None },
+        "/reply_group_message" if args.len() >= 4 => Command::SendGroupMessage { group_id: arg(1), content: args[3..].join(" "), client_msg_id: None, reply_to: Some(args[2].parse().ok()?) },
+        "/reply_private_message" if args.len() >= 4 => Command::SendPrivateMessage { to: arg(1), content: args[3..].join(" "), client_msg_id: None, reply_to: Some(args[2].parse().ok()?) },
+        "/get_thread" if args.len() == 2 => Command::GetThread { message_id: args[1].parse().ok()? },
+        "/get_group_messages" if (2..=4).contains(&args.len()) => Command::GetGroupMessages { group_id: arg(1), page: parse_legacy_page(&args[2..])? },
+        "/get_private_messages" if (2..=4).contains(&args.len()) => Command::GetPrivateMessages { with: arg(1), page: parse_legacy_page(&args[2..])? },
+        "/edit_message" if args.len() >= 3 => Command::EditMessage { message_id: args[1].parse().ok()?, content: args[2..].join(" ") },
+        "/delete_message" if args.len() == 2 => Command::DeleteMessage { message_id: args[1].parse().ok()? },
+        "/react" if args.len() == 3 => Command::AddReaction { message_id: args[1].parse().ok()?, emoji: arg(2) },
+        "/unreact" if args.len() == 2 => Command::RemoveReaction { message_id: args[1].parse().ok()? },
+        "/mark_private_read" if (2..=3).contains(&args.len()) => Command::MarkPrivateRead { with: arg(1), up_to: parse_legacy_up_to(&args[2..])? },
+        "/mark_group_read" if (2..=3).contains(&args.len()) => Command::MarkGroupRead { group_id: arg(1), up_to: parse_legacy_up_to(&args[2..])? },
+        "/delete_group_messages" if args.len() == 2 => Command::DeleteGroupMessages { group_id: arg(1) },
+        "/delete_private_messages" if args.len() == 2 => Command::DeletePrivateMessages { with: arg(1) },
+        "/inbox" if args.len() == 1 => Command::Inbox,
+        "/sync" if (1..=2).contains(&args.len()) => Command::Sync { since: parse_legacy_up_to(&args[1..])?, limit: None },
+        _ => return None,
+    };
+    Some((token, command))
+}
+
+/// Argomenti opzionali della paginazione legacy: `[limit] [before=<id>|after=<id>]`.
+fn parse_legacy_page(args: &[&str]) -> Option<HistoryPage> {
+    let mut page = HistoryPage::latest();
+    for arg in args {
+        match arg.split_once('=') {
+            Some(("before", id)) => page.before = Some(id.parse().ok()?),
+            Some(("after", id)) => page.after = Some(id.parse().ok()?),
+            None => page.limit = Some(arg.parse().ok()?),
+            _ => return None,
+        }
+    }
+    Some(page)
+}
+
+/// Limite opzionale delle conferme di lettura legacy: `[up_to]`.
+fn parse_legacy_up_to(args: &[&str]) -> Option<Option<i64>> {
+    match args.first() {
+        Some(id) => Some(Some(id.parse().ok()?)),
+        None => Some(None),
+    }
+}
+
+/// Reazioni nel formato legacy: `👍 2, ❤️ 1` (o `none`).
+fn legacy_reactions(reactions: &[ReactionCount]) -> String {
+    if reactions.is_empty() {
+        return "none".to_string();
+    }
+    reactions.iter().map(|r| format!("{} {}", r.emoji, r.count)).collect::<Vec<_>>().join(", ")
+}
+
+/// Utente nel formato legacy: `alice (busy: In riunione)` o `bob (offline, last seen 1700000000)`.
+fn legacy_user(user: &UserInfo) -> String {
+    match (&user.status_message, user.last_seen_at) {
+        (Some(message), _) => format!("{} ({}: {})", user.username, user.status.as_str(), message),
+        (None, Some(last_seen)) => format!("{} ({}, last seen {})", user.username, user.status.as_str(), last_seen),
+        (None, None) => format!("{} ({})", user.username, user.status.as_str()),
+    }
+}
+
+/// Chat dell'inbox nel formato legacy: `alice (2 unread): alice: ciao` o `group Amici (0 unread)`.
+fn legacy_inbox_entry(entry: &InboxEntry) -> String {
+    let chat = match &entry.chat {
+        ChatRef::Private { with } => with.clone(),
+        ChatRef::Group { name, .. } => format!("group {}", name),
+    };
+    match &entry.last_message {
+        Some(last) if last.deleted => format!("{} ({} unread): {}: [deleted]", chat, entry.unread, last.sender),
+        Some(last) => format!("{} ({} unread): {}: {}", chat, entry.unread, last.sender, last.snippet),
+        None => format!("{} ({} unread)", chat, entry.unread),
+    }
+}
+
+/// Rende il risultato di un comando nel formato testuale legacy.
+fn legacy_reply(command: &Command, result: &Result<ResponseData, ChatError>) -> String {
+    let data = match result {
+        Ok(data) => data,
+        Err(e) => return format!("ERR: {}", e.message),
+    };
+    match data {
+        ResponseData::Session { username, session_token } => match command {
+            Command::Register { .. } => format!("OK: Registered as {} SESSION: {}", username, session_token),
+            _ => format!("OK: Logged in as {} SESSION: {}", username, session_token),
+        },
+        ResponseData::SessionValid { username } => format!("OK: {}", username),
+        ResponseData::Ack { message } => format!("OK: {}", message),
+        ResponseData::MessageSent { message_id, seq, .. } => format!("OK: Message sent (#{} seq {})", message_id, seq),
+        ResponseData::MessageEdited { message_id, .. } => format!("OK: Message #{} edited", message_id),
+        ResponseData::MessageDeleted { message_id, .. } => format!("OK: Message #{} deleted for everyone", message_id),
+        ResponseData::Reactions { message_id, reactions } => {
+            format!("OK: Reactions on message #{}: {}", message_id, legacy_reactions(reactions))
+        }
+        ResponseData::MarkedRead { messages } => format!("OK: {} messages marked as read", messages),
+        ResponseData::Inbox { chats } => {
+            let chats: Vec<String> = chats.iter().map(legacy_inbox_entry).collect();
+            format!("OK: Inbox: {}", chats.join("; "))
+        }
+        ResponseData::Events { events, has_more, latest_seq } => {
+            let lines: Vec<String> = events.iter().map(|e| format!("#{} {}", e.seq, e.event)).collect();
+            let more = if *has_more { " (more available)" } else { "" };
+            format!("OK: Events up to #{}{}:\n{}", latest_seq, more, lines.join("\n"))
+        }
+        ResponseData::Help { text } => text.clone(),
+        ResponseData::OnlineUsers { users } => format!("OK: Online users: {}", users.join(", ")),
+        ResponseData::AllUsers { users } => format!("OK: All users: {}", users.join(", ")),
+        ResponseData::Friends { friends } => {
+            let friends: Vec<String> = friends.iter().map(legacy_user).collect();
+            format!("OK: Friends: {}", friends.join(", "))
+        }
+        ResponseData::User { user } => format!("OK: {}", legacy_user(user)),
+        ResponseData::Presence { status, message, last_seen_visibility } => {
+            let message = message.as_ref().map(|m| format!(" ({})", m)).unwrap_or_default();
+            format!("OK: Presence: {}{}, last seen visible to {}", status.as_str(), message, last_seen_visibility.as_str())
+        }
+        ResponseData::ReceivedFriendRequests { requests } => {
+            let reqs: Vec<String> = requests.iter().map(|r| format!("{}: {}", r.username, r.message)).collect();
+            format!("OK: Richieste ricevute: {}", reqs.join(" | "))
+        }
+        ResponseData::SentFriendRequests { requests } => {
+            let reqs: Vec<String> = requests.iter().map(|r| format!("{}: {}", r.username, r.message)).collect();
+            format!("OK: Richieste inviate: {}", reqs.join(" | "))
+        }
+        ResponseData::GroupCreated { group_id, name } => format!("OK: Group '{}' created with ID: {}", name, group_id),
+        ResponseData::Groups { groups } => {
+            let groups: Vec<String> = groups.iter().map(|g| format!("{}:{}", g.id, g.name)).collect();
+            format!("OK: My groups: {}", groups.join(", "))
+        }
+        ResponseData::GroupInvites { invites } => {
+            let invites: Vec<String> = invites.iter().map(|i| format!("{}:{}:{}", i.id, i.group_name, i.invited_by)).collect();
+            format!("OK: Group invites: {}", invites.join(" | "))
+        }
+        ResponseData::GroupMembers { members } => format!("OK: Group members: {}", members.join(", ")),
+        ResponseData::Messages { messages, has_more } => {
+            let lines: Vec<String> = messages.iter().map(|m| {
+                let reply = m.reply_to.map(|id| format!("(reply to #{}) ", id)).unwrap_or_default();
+                let line = match (m.deleted, m.edited_at) {
+                    (true, _) => format!("#{} seq {} [{}] {}: {}(deleted)", m.id, m.seq, m.sent_at, m.sender, reply),
+                    (false, Some(_)) => format!("#{} seq {} [{}] {}: {}{} (edited)", m.id, m.seq, m.sent_at, m.sender, reply, m.content),
+                    (false, None) => format!("#{} seq {} [{}] {}: {}{}", m.id, m.seq, m.sent_at, m.sender, reply, m.content),
+                };
+                let line = if m.reactions.is_empty() {
+                    line
+                } else {
+                    format!("{} [{}]", line, legacy_reactions(&m.reactions))
+                };
+                match (m.delivered_to, m.read_by) {
+                    (0, 0) => line,
+                    (delivered, read) => format!("{} (delivered {}, read {})", line, delivered, read),
+                }
+            }).collect();
+            let more = if *has_more { " (more available)" } else { "" };
+            format!("OK: Messages{}:\n{}", more, lines.join("\n"))
+        }
+    }
+}
+
+async fn handle_client<S>(server: Server, stream: S, peer: std::net::SocketAddr, cert_user: Option<mtls::CertIdentity>) -> anyhow::Result<()>
 where
     S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
 {
+    let db = server.db.clone();
+    let presence = server.presence.clone();
     let (reader, writer) = tokio::io::split(stream);
     let mut reader = BufReader::new(reader);
     let mut writer = BufWriter::new(writer);
     let mut line = String::new();
     let mut kick_rx: Option<tokio::sync::oneshot::Receiver<()>> = None;
-    let mut registered_user: Option<String> = None;
-    let mut registered_token: Option<String> = None;
+    // Utente autenticato su questa connessione (mai loggare il token)
+    let mut bound: Option<BoundSession> = None;
     loop {
         line.clear();
         if let Some(rx) = &mut kick_rx {
             tokio::select! {
                 biased;
                 _ = rx => {
-                    if let Some(uid) = &registered_user {
-                        println!("[AUTH] User {} kicked out due to login from another device", uid);
+                    if let Some(b) = &bound {
+                        println!("[AUTH] User {} kicked out due to login from another device", b.user_id);
                     } else {
                         println!("[SERVER] Client was kicked out");
                     }
@@ -557,66 +963,80 @@ where
                 break;
             }
         }
-    let trimmed = line.trim();
-    // Raw incoming line logger for diagnostics (TLS)
-    println!("[CONN:RAW] [{}] TLS Raw line received: '{}'", peer, trimmed);
-    if trimmed.is_empty() { continue; }
-        let mut parts = trimmed.split_whitespace();
-        let cmd = parts.next().unwrap_or("");
-        let args: Vec<&str> = parts.collect();
-        let server = Server { db: db.clone(), config: config.clone(), presence: presence.clone(), ws_manager: None };
-        let response = server.handle_command(cmd, &args).await;
-        // If the client just validated an existing session, register presence so
-        // we treat this TLS connection as an active one (preserve session row for auto-login
-        // but reflect presence in is_online).
-        if cmd == "/validate_session" && args.len() == 1 && response.starts_with("OK:") {
-            let token = args[0];
-            println!("[CONN] [{}] TLS /validate_session returned OK for token {} — registering presence", peer, token);
-            if let Some(uid) = auth::validate_session(db.clone(), token).await {
-                let rx = presence.register(&uid).await;
-                println!("[CONN] [{}] TLS Registered presence receiver for user {} (via validate_session)", peer, uid);
-                let _ = sqlx::query("UPDATE users SET is_online = 1 WHERE id = ?")
-                    .bind(&uid)
-                    .execute(&db.pool)
-                    .await;
-                println!("[DB] TLS Set is_online=1 for user {} due to validate_session", uid);
-                kick_rx = Some(rx);
-                registered_user = Some(uid.clone());
-                registered_token = Some(token.to_string());
-            } else {
-                println!("[CONN] [{}] TLS validate_session token {} became invalid during registration", peer, token);
-            }
-        }
-        if response.contains("SESSION:") {
-            if let Some(line) = response.lines().find(|l| l.contains("SESSION:")) {
-                if let Some(tok) = line.split("SESSION:").nth(1) {
-                    let token = tok.trim();
-                    if let Some(uid) = auth::validate_session(db.clone(), token).await {
+        let trimmed = line.trim();
+        if trimmed.is_empty() { continue; }
+        if server.shutdown.is_triggered() {
+            // Nessun nuovo comando durante l'arresto: il client può riconnettersi più tardi
+            let error = ChatError::new(ErrorCode::ShuttingDown, "Server is shutting down");
+            writer.write_all(error_reply(trimmed, &error).as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+            writer.flush().await?;
+            break;
+        }
+        // Il comando, le scritture di sessione e la risposta vengono completati anche durante l'arresto
+        let _in_flight = server.shutdown.begin_command();
+        let outcome = server.process_line(trimmed, peer, bound.as_ref(), cert_user.as_ref()).await;
+        println!("[CONN] [{}] Response sent ({} bytes)", peer, outcome.reply.len());
+        // Login/registrazione o primo comando autenticato: lega la sessione alla
+        // connessione e registra la presence (is_online = 1) una sola volta.
+        match outcome.session {
+            Some(SessionChange::Bind(session, fresh_login)) => {
+                let uid = session.user_id.clone();
+                let same_user = bound.as_ref().map(|b| b.user_id == uid).unwrap_or(false);
+                if same_user && !fresh_login {
+                    // Stesso utente, nuovo token: nessuna nuova registrazione
+                    bound = Some(session);
+                } else {
+                    if let Some(previous) = bound.take() {
+                        // La connessione cambia sessione: rilascia la presence precedente
+                        // (il receiver viene sostituito più sotto)
+                        presence.unregister_one(&previous.user_id).await;
+                    }
+                    if fresh_login {
+                        // kick previous sessions for this user and record event
                         let kicked = presence.kick_all(&uid).await;
                         if kicked > 0 {
-                            println!("[AUTH] User {} kicked out due to login from another device", uid);
+                            println!("[AUTH] User {} kicked out due to login from another device (kicked={})", uid, kicked);
                             let now = chrono::Utc::now().timestamp();
-                            let _ = sqlx::query("INSERT INTO session_events (user_id, event_type, created_at) VALUES (?, ?, ?)")
+                            let res = sqlx::query("INSERT INTO session_events (user_id, event_type, created_at) VALUES (?, ?, ?)")
                                 .bind(&uid)
                                 .bind("kicked_out")
                                 .bind(now)
                                 .execute(&db.pool)
                                 .await;
+                            println!("[DB] Inserted kicked_out event for {} result={:?}", uid, res);
+                        } else {
+                            println!("[AUTH] No previous sessions to kick for {}", uid);
                         }
-                        let rx = presence.register(&uid).await;
-                        kick_rx = Some(rx);
-                        registered_user = Some(uid.clone());
-                        registered_token = Some(token.to_string());
                     }
+                    let rx = presence.register(&uid).await;
+                    println!("[CONN] [{}] Connection bound to user {}", peer, uid);
+                    let _ = sqlx::query("UPDATE users SET is_online = 1 WHERE id = ?")
+                        .bind(&uid)
+                        .execute(&db.pool)
+                        .await;
+                    println!("[DB] Set is_online=1 for user {} due to active connection", uid);
+                    kick_rx = Some(rx);
+                    bound = Some(session);
+                }
+            }
+            Some(SessionChange::Release) => {
+                // Il logout ha già rimosso la connessione dalla presence: resta aperta
+                // senza sessione, per un nuovo login
+                if let Some(previous) = bound.take() {
+                    println!("[CONN] [{}] Connection released by logout of user {}", peer, previous.user_id);
                 }
+                kick_rx = None;
             }
+            None => {}
         }
-        writer.write_all(response.as_bytes()).await?;
+        writer.write_all(outcome.reply.as_bytes()).await?;
         writer.write_all(b"\n").await?;
         writer.flush().await?;
     }
-    if let Some(uid) = registered_user {
-        println!("[CONN] [{}] TLS connection for user {} ending; cleaning up", peer, uid);
+    if let Some(session) = bound {
+        let uid = session.user_id;
+        println!("[CONN] [{}] Connection for user {} ending; cleaning up", peer, uid);
         presence.unregister_one(&uid).await;
         // If no more active connections, set is_online = 0 (preserve session row for auto-login)
         let remaining = presence.count(&uid).await;
@@ -625,15 +1045,11 @@ where
                 .bind(&uid)
                 .execute(&db.pool)
                 .await;
-            println!("[DB] TLS Set is_online=0 for user {} because no active connections remain", uid);
-        } else {
-            println!("[CONN] [{}] TLS {} active connections remain for user {}, leaving is_online=1", peer, remaining, uid);
-        }
-        if let Some(tok) = registered_token {
-            println!("[CONN] [{}] TLS preserving session token {} for user {} to allow auto-login on reconnect", peer, tok, uid);
+            println!("[DB] Set is_online=0 for user {} because no active connections remain", uid);
         } else {
-            println!("[CONN] [{}] TLS No session token associated with this connection", peer);
+            println!("[CONN] [{}] {} active connections remain for user {}, leaving is_online=1", peer, remaining, uid);
         }
+        println!("[CONN] [{}] Preserving session for user {} to allow auto-login on reconnect", peer, uid);
         let now = chrono::Utc::now().timestamp();
         let res = sqlx::query("INSERT INTO session_events (user_id, event_type, created_at) VALUES (?, ?, ?)")
             .bind(&uid)
@@ -641,7 +1057,78 @@ where
             .bind(now)
             .execute(&db.pool)
             .await;
-        println!("[DB] TLS Inserted quit event for {} result={:?}", uid, res);
+        println!("[DB] Inserted quit event for {} result={:?}", uid, res);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUND_TOKEN: &str = "0b5c3a4e-8f1d-4c2a-9e7b-6d5f4a3b2c1d-00112233445566778899aabbccddeeff";
+    /// Argomento con la forma di un token di sessione ma diverso da quello legato.
+    const TOKEN_LIKE: &str = "7e6d5c4b-3a29-4817-8e6f-5d4c3b2a1908-ffeeddccbbaa99887766554433221100";
+
+    fn bound() -> BoundSession {
+        BoundSession { user_id: "alice-id".to_string(), token: BOUND_TOKEN.to_string(), expires_at: i64::MAX }
+    }
+
+    fn parse(line: &str, bound: Option<&BoundSession>) -> Option<(Option<String>, Command)> {
+        let mut parts = line.split_whitespace();
+        let cmd = parts.next().unwrap_or("");
+        let args = legacy_args(cmd, parts.collect(), bound);
+        parse_legacy(cmd, &args).map(|(token, command)| (token.map(str::to_string), command))
+    }
+
+    fn private_message(to: &str, content: &str) -> Command {
+        Command::SendPrivateMessage { to: to.to_string(), content: content.to_string(), client_msg_id: None, reply_to: None }
+    }
+
+    #[test]
+    fn unbound_connection_takes_token_from_first_argument() {
+        let line = format!("/send_private_message {} bob hi", BOUND_TOKEN);
+        assert_eq!(parse(&line, None), Some((Some(BOUND_TOKEN.to_string()), private_message("bob", "hi"))));
+        assert_eq!(parse("/list_friends", None), None);
+    }
+
+    #[test]
+    fn bound_connection_without_token() {
+        assert_eq!(parse("/send_private_message bob hi", Some(&bound())), Some((None, private_message("bob", "hi"))));
+        assert_eq!(parse("/list_friends", Some(&bound())), Some((None, Command::ListFriends)));
+    }
+
+    #[test]
+    fn bound_connection_with_its_own_token() {
+        let line = format!("/send_private_message {} bob hi", BOUND_TOKEN);
+        assert_eq!(parse(&line, Some(&bound())), Some((Some(BOUND_TOKEN.to_string()), private_message("bob", "hi"))));
+    }
+
+    #[test]
+    fn token_like_payload_is_not_stripped() {
+        let bound = bound();
+        let line = format!("/send_private_message bob {}", TOKEN_LIKE);
+        assert_eq!(parse(&line, Some(&bound)), Some((None, private_message("bob", TOKEN_LIKE))));
+        let line = format!("/send_group_message {} hello", TOKEN_LIKE);
+        assert_eq!(
+            parse(&line, Some(&bound)),
+            Some((None, Command::SendGroupMessage { group_id: TOKEN_LIKE.to_string(), content: "hello".to_string(), client_msg_id: None, reply_to: None })),
+        );
+        let line = format!("/send_private_message {} bob {}", BOUND_TOKEN, TOKEN_LIKE);
+        assert_eq!(parse(&line, None), Some((Some(BOUND_TOKEN.to_string()), private_message("bob", TOKEN_LIKE))));
+    }
+
+    #[test]
+    fn other_token_is_kept_when_required_by_argument_count() {
+        let line = format!("/validate_session {}", TOKEN_LIKE);
+        assert_eq!(parse(&line, Some(&bound())), Some((Some(TOKEN_LIKE.to_string()), Command::ValidateSession)));
+    }
+
+    #[test]
+    fn tokenless_commands_are_unchanged() {
+        assert_eq!(
+            parse("/login alice secret", Some(&bound())),
+            Some((None, Command::Login { username: "alice".to_string(), password: "secret".to_string() })),
+        );
+    }
+}
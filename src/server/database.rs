@@ -10,17 +10,16 @@ impl Database {
         println!("🔗 Attempting to connect to database: {}", database_url);
         
         // Extract file path from database URL to create directory if needed
-        let file_path = if database_url.starts_with("sqlite://") {
+        let file_path = if let Some(path_part) = database_url.strip_prefix("sqlite://") {
             // Remove "sqlite://" prefix and any query parameters
-            let path_part = &database_url[9..];
             if let Some(query_pos) = path_part.find('?') {
                 &path_part[..query_pos]
             } else {
                 path_part
             }
-        } else if database_url.starts_with("sqlite:") {
+        } else if let Some(path_part) = database_url.strip_prefix("sqlite:") {
             // Remove "sqlite:" prefix
-            &database_url[7..]
+            path_part
         } else {
             database_url
         };
@@ -75,6 +74,11 @@ impl Database {
             );
         "#).execute(&self.pool).await?;
 
+        // Presenza scelta dall'utente (online, away, busy, invisible) e testo di stato;
+        // is_online resta il solo stato di connessione
+        self.add_column_if_missing("users", "presence", "TEXT NOT NULL DEFAULT 'online'").await?;
+        self.add_column_if_missing("users", "status_message", "TEXT").await?;
+
         // User encryption keys
         sqlx::query(r#"
             CREATE TABLE IF NOT EXISTS user_encryption_keys (
@@ -112,6 +116,82 @@ impl Database {
                 sent_at INTEGER NOT NULL
             );
         "#).execute(&self.pool).await?;
+        // Paginazione dello storico per chat con cursore sull'id
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_encrypted_messages_chat ON encrypted_messages (chat_id, id)")
+            .execute(&self.pool).await?;
+        // Numero di sequenza per chat (1, 2, 3, ...), assegnato all'inserimento
+        self.add_column_if_missing("encrypted_messages", "seq", "INTEGER").await?;
+        let numbered = sqlx::query(r#"
+            UPDATE encrypted_messages SET seq = (
+                SELECT COUNT(*) FROM encrypted_messages AS e
+                WHERE e.chat_id = encrypted_messages.chat_id AND e.id <= encrypted_messages.id
+            ) WHERE seq IS NULL
+        "#).execute(&self.pool).await?.rows_affected();
+        if numbered > 0 {
+            println!("🗄️ Assigned sequence numbers to {} existing message(s)", numbered);
+        }
+        sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS idx_encrypted_messages_seq ON encrypted_messages (chat_id, seq)")
+            .execute(&self.pool).await?;
+        // Id scelto dal client per rendere idempotenti i nuovi invii dello stesso messaggio
+        self.add_column_if_missing("encrypted_messages", "client_msg_id", "TEXT").await?;
+        sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS idx_encrypted_messages_client_id ON encrypted_messages (sender_id, client_msg_id) WHERE client_msg_id IS NOT NULL")
+            .execute(&self.pool).await?;
+        // Ultima modifica del testo; le versioni precedenti restano in message_edits
+        self.add_column_if_missing("encrypted_messages", "edited_at", "INTEGER").await?;
+
+        // Ritiro per tutti: il testo viene svuotato (tombstone) e restano chi e quando
+        self.add_column_if_missing("encrypted_messages", "deleted_at", "INTEGER").await?;
+        self.add_column_if_missing("encrypted_messages", "deleted_by", "TEXT").await?;
+
+        // Risposte: id del messaggio citato, per ricostruire le discussioni
+        self.add_column_if_missing("encrypted_messages", "reply_to", "INTEGER").await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_encrypted_messages_reply_to ON encrypted_messages (reply_to) WHERE reply_to IS NOT NULL")
+            .execute(&self.pool).await?;
+
+        // Message edits: testo (cifrato) sostituito da ogni modifica, per audit
+        sqlx::query(r#"
+            CREATE TABLE IF NOT EXISTS message_edits (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_id INTEGER NOT NULL,
+                message TEXT NOT NULL,
+                edited_at INTEGER NOT NULL
+            );
+        "#).execute(&self.pool).await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_message_edits_message ON message_edits (message_id, id)")
+            .execute(&self.pool).await?;
+
+        // Message reactions: una reazione (emoji) per utente su ciascun messaggio
+        sqlx::query(r#"
+            CREATE TABLE IF NOT EXISTS message_reactions (
+                message_id INTEGER NOT NULL,
+                user_id TEXT NOT NULL,
+                emoji TEXT NOT NULL,
+                reacted_at INTEGER NOT NULL,
+                PRIMARY KEY (message_id, user_id)
+            );
+        "#).execute(&self.pool).await?;
+
+        // Conferme di consegna e lettura: una riga per destinatario, creata alla consegna
+        sqlx::query(r#"
+            CREATE TABLE IF NOT EXISTS message_receipts (
+                message_id INTEGER NOT NULL,
+                user_id TEXT NOT NULL,
+                delivered_at INTEGER NOT NULL,
+                read_at INTEGER,
+                PRIMARY KEY (message_id, user_id)
+            );
+        "#).execute(&self.pool).await?;
+
+        // Puntatore di lettura per utente e chat: i messaggi altrui oltre `last_read_id` sono non letti
+        sqlx::query(r#"
+            CREATE TABLE IF NOT EXISTS chat_reads (
+                user_id TEXT NOT NULL,
+                chat_id TEXT NOT NULL,
+                last_read_id INTEGER NOT NULL,
+                read_at INTEGER NOT NULL,
+                PRIMARY KEY (user_id, chat_id)
+            );
+        "#).execute(&self.pool).await?;
 
         // Friend requests
         sqlx::query(r#"
@@ -194,7 +274,58 @@ impl Database {
                 created_at INTEGER NOT NULL
             );
         "#).execute(&self.pool).await?;
+        // Dettaglio opzionale (es. motivo del rifiuto di un certificato client)
+        self.add_column_if_missing("session_events", "detail", "TEXT").await?;
+
+        // Ultimo accesso: aggiornato da ogni evento di sessione dell'utente (login, logout,
+        // quit, kicked_out) e dalla chiusura dell'ultimo WebSocket; chi può vederlo è una
+        // scelta dell'utente (everyone, friends, nobody)
+        self.add_column_if_missing("users", "last_seen_at", "INTEGER").await?;
+        self.add_column_if_missing("users", "last_seen_visibility", "TEXT NOT NULL DEFAULT 'everyone'").await?;
+        sqlx::query(r#"
+            CREATE TRIGGER IF NOT EXISTS session_events_last_seen
+            AFTER INSERT ON session_events
+            WHEN NEW.user_id != ''
+            BEGIN
+                UPDATE users SET last_seen_at = MAX(COALESCE(last_seen_at, 0), NEW.created_at) WHERE id = NEW.user_id;
+            END;
+        "#).execute(&self.pool).await?;
+        sqlx::query("UPDATE users SET last_seen_at = (SELECT MAX(created_at) FROM session_events WHERE user_id = users.id) WHERE last_seen_at IS NULL")
+            .execute(&self.pool).await?;
+
+        // Coda degli eventi di ciascun utente (nuovi messaggi, modifiche, ritiri, reazioni,
+        // inviti, richieste di amicizia): l'id è il cursore con cui un client riconnesso
+        // recupera quelli persi. Solo tipo e riferimenti: il contenuto viene ricostruito al
+        // momento del recupero.
+        sqlx::query(r#"
+            CREATE TABLE IF NOT EXISTS user_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                chat_id TEXT,
+                target_id INTEGER NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+        "#).execute(&self.pool).await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_user_events_user ON user_events(user_id, id)")
+            .execute(&self.pool).await?;
 
         Ok(())
     }
+
+    /// Aggiunge una colonna ai database creati con una versione precedente dello schema.
+    async fn add_column_if_missing(&self, table: &str, column: &str, definition: &str) -> Result<(), sqlx::Error> {
+        let exists: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?")
+            .bind(table)
+            .bind(column)
+            .fetch_one(&self.pool)
+            .await?;
+        if exists == 0 {
+            sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+                .execute(&self.pool)
+                .await?;
+            println!("🗄️ Added column {}.{}", table, column);
+        }
+        Ok(())
+    }
 }
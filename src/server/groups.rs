@@ -1,8 +1,11 @@
 use crate::server::database::Database;
+use crate::common::protocol::{GroupInfo, GroupInviteInfo};
+use crate::common::error::{ChatError, ErrorCode};
 use std::sync::Arc;
 use sqlx::Row;
 
-pub async fn create_group(db: Arc<Database>, user_id: &str, group_name: &str) -> String {
+/// Crea un gruppo con il solo creatore come membro e ne restituisce l'id.
+pub async fn create_group(db: Arc<Database>, user_id: &str, group_name: &str) -> Result<String, ChatError> {
     println!("[GROUPS] Create group '{}' by user {}", group_name, user_id);
     let group_id = uuid::Uuid::new_v4().to_string();
     let created_at = chrono::Utc::now().timestamp();
@@ -17,8 +20,7 @@ pub async fn create_group(db: Arc<Database>, user_id: &str, group_name: &str) ->
                 .execute(&mut *tx)
                 .await;
             if let Err(e) = res {
-                println!("[GROUPS] Error creating group: {}", e);
-                return format!("ERR: Could not create group: {}", e);
+                return Err(ChatError::internal("[GROUPS] Error creating group", e));
             }
             let res2 = sqlx::query("INSERT INTO group_members (group_id, user_id, joined_at) VALUES (?, ?, ?)")
                 .bind(&group_id)
@@ -27,21 +29,18 @@ pub async fn create_group(db: Arc<Database>, user_id: &str, group_name: &str) ->
                 .execute(&mut *tx)
                 .await;
             if let Err(e) = res2 {
-                println!("[GROUPS] Error adding creator as member: {}", e);
-                return format!("ERR: Could not add creator as member: {}", e);
+                return Err(ChatError::internal("[GROUPS] Error adding creator as member", e));
             }
             tx.commit().await.ok();
             println!("[GROUPS] Group '{}' created with id {}", group_name, group_id);
-            format!("OK: Group '{}' created", group_name)
-        }
-        Err(e) => {
-            println!("[GROUPS] Error starting transaction: {}", e);
-            format!("ERR: Could not create group: {}", e)
+            Ok(group_id)
         }
+        Err(e) => Err(ChatError::internal("[GROUPS] Error starting transaction", e)),
     }
 }
 
-pub async fn create_group_with_participants(db: Arc<Database>, user_id: &str, group_name: &str, participants: Option<&str>) -> String {
+/// Crea un gruppo e invia un invito a ciascun partecipante indicato; restituisce l'id del gruppo.
+pub async fn create_group_with_participants(db: Arc<Database>, user_id: &str, group_name: &str, participants: &[String]) -> Result<String, ChatError> {
     println!("[GROUPS] Create group '{}' by user {} with participants: {:?}", group_name, user_id, participants);
     let group_id = uuid::Uuid::new_v4().to_string();
     let created_at = chrono::Utc::now().timestamp();
@@ -57,8 +56,7 @@ pub async fn create_group_with_participants(db: Arc<Database>, user_id: &str, gr
                 .execute(&mut *tx)
                 .await;
             if let Err(e) = res {
-                println!("[GROUPS] Error creating group: {}", e);
-                return format!("ERR: Could not create group: {}", e);
+                return Err(ChatError::internal("[GROUPS] Error creating group", e));
             }
             
             // Add creator as member
@@ -69,48 +67,41 @@ pub async fn create_group_with_participants(db: Arc<Database>, user_id: &str, gr
                 .execute(&mut *tx)
                 .await;
             if let Err(e) = res2 {
-                println!("[GROUPS] Error adding creator as member: {}", e);
-                return format!("ERR: Could not add creator as member: {}", e);
+                return Err(ChatError::internal("[GROUPS] Error adding creator as member", e));
             }
             
             // Send invites to participants if provided (don't add them directly)
-            if let Some(participants_str) = participants {
-                let participant_usernames: Vec<&str> = participants_str.split(',').collect();
-                for username in participant_usernames {
-                    let username = username.trim();
-                    if !username.is_empty() && username != user_id {
-                        // Get user_id from username
-                        if let Ok(Some(row)) = sqlx::query("SELECT id FROM users WHERE username = ?")
-                            .bind(username)
-                            .fetch_optional(&mut *tx)
-                            .await
-                        {
-                            let participant_id: String = row.get("id");
-                            // Create invite instead of adding directly to group
-                            let _ = sqlx::query("INSERT INTO group_invites (group_id, invited_user_id, invited_by, created_at, status) VALUES (?, ?, ?, ?, 'pending')")
-                                .bind(&group_id)
-                                .bind(&participant_id)
-                                .bind(user_id)
-                                .bind(created_at)
-                                .execute(&mut *tx)
-                                .await;
-                            println!("[GROUPS] Sent invite to participant {} for group {}", username, group_id);
-                        }
+            for username in participants {
+                let username = username.trim();
+                if !username.is_empty() && username != user_id {
+                    // Get user_id from username
+                    if let Ok(Some(row)) = sqlx::query("SELECT id FROM users WHERE username = ?")
+                        .bind(username)
+                        .fetch_optional(&mut *tx)
+                        .await
+                    {
+                        let participant_id: String = row.get("id");
+                        // Create invite instead of adding directly to group
+                        let _ = sqlx::query("INSERT INTO group_invites (group_id, invited_user_id, invited_by, created_at, status) VALUES (?, ?, ?, ?, 'pending')")
+                            .bind(&group_id)
+                            .bind(&participant_id)
+                            .bind(user_id)
+                            .bind(created_at)
+                            .execute(&mut *tx)
+                            .await;
+                        println!("[GROUPS] Sent invite to participant {} for group {}", username, group_id);
                     }
                 }
             }
             
             tx.commit().await.ok();
             println!("[GROUPS] Group '{}' created with id {}", group_name, group_id);
-            format!("OK: Group '{}' created with ID: {}", group_name, group_id)
-        }
-        Err(e) => {
-            println!("[GROUPS] Error starting transaction: {}", e);
-            format!("ERR: Could not create group: {}", e)
+            Ok(group_id)
         }
+        Err(e) => Err(ChatError::internal("[GROUPS] Error starting transaction", e)),
     }
 }
-pub async fn my_groups(db: Arc<Database>, user_id: &str) -> String {
+pub async fn my_groups(db: Arc<Database>, user_id: &str) -> Result<Vec<GroupInfo>, ChatError> {
     println!("[GROUPS] List groups for user {}", user_id);
     let rows = sqlx::query("SELECT g.id, g.name FROM groups g JOIN group_members m ON g.id = m.group_id WHERE m.user_id = ?")
         .bind(user_id)
@@ -118,17 +109,13 @@ pub async fn my_groups(db: Arc<Database>, user_id: &str) -> String {
         .await;
     match rows {
         Ok(rows) => {
-            let groups: Vec<String> = rows.iter().map(|r| format!("{}:{}", r.get::<String,_>("id"), r.get::<String,_>("name"))).collect();
-            format!("OK: My groups: {}", groups.join(", "))
-        }
-        Err(e) => {
-            println!("[GROUPS] Error listing groups: {}", e);
-            format!("ERR: {}", e)
+            Ok(rows.iter().map(|r| GroupInfo { id: r.get::<String,_>("id"), name: r.get::<String,_>("name") }).collect())
         }
+        Err(e) => Err(ChatError::internal("[GROUPS] Error listing groups", e)),
     }
 }
 
-pub async fn invite_user_to_group(db: Arc<Database>, from_user_id: &str, to_username: &str, group_id: &str) -> String {
+pub async fn invite_user_to_group(db: Arc<Database>, from_user_id: &str, to_username: &str, group_id: &str) -> Result<String, ChatError> {
     println!("[GROUPS] Invite {} to group '{}' by {}", to_username, group_id, from_user_id);
     
     // Verify group exists
@@ -136,8 +123,8 @@ pub async fn invite_user_to_group(db: Arc<Database>, from_user_id: &str, to_user
         .bind(group_id)
         .fetch_optional(&db.pool)
         .await;
-    if group_row.is_err() || group_row.unwrap().is_none() {
-        return "ERR: Group not found".to_string();
+    if !matches!(group_row, Ok(Some(_))) {
+        return Err(ChatError::group_not_found());
     }
     
     // Get user_id from username
@@ -147,7 +134,7 @@ pub async fn invite_user_to_group(db: Arc<Database>, from_user_id: &str, to_user
         .await;
     let to_user_id = match user_row {
         Ok(Some(row)) => row.get::<String,_>("id"),
-        _ => return "ERR: User not found".to_string(),
+        _ => return Err(ChatError::user_not_found()),
     };
     
     // Verify that from_user is member of the group
@@ -160,7 +147,7 @@ pub async fn invite_user_to_group(db: Arc<Database>, from_user_id: &str, to_user
         .flatten()
         .is_some();
     if !is_member {
-        return "ERR: Only group members can invite".to_string();
+        return Err(ChatError::new(ErrorCode::NotMember, "Only group members can invite"));
     }
     
     // Check if user is already a member
@@ -173,7 +160,7 @@ pub async fn invite_user_to_group(db: Arc<Database>, from_user_id: &str, to_user
         .flatten()
         .is_some();
     if already_member {
-        return "ERR: User is already a member of this group".to_string();
+        return Err(ChatError::new(ErrorCode::AlreadyExists, "User is already a member of this group"));
     }
     
     // Check if there's already a pending invite
@@ -186,7 +173,7 @@ pub async fn invite_user_to_group(db: Arc<Database>, from_user_id: &str, to_user
         .flatten()
         .is_some();
     if existing_invite {
-        return "ERR: User already has a pending invite to this group".to_string();
+        return Err(ChatError::new(ErrorCode::AlreadyExists, "User already has a pending invite to this group"));
     }
     
     // Create group invite
@@ -201,16 +188,24 @@ pub async fn invite_user_to_group(db: Arc<Database>, from_user_id: &str, to_user
     match res {
         Ok(_) => {
             println!("[GROUPS] Invite sent to {} for group {}", to_username, group_id);
-            format!("OK: Invite sent to {} successfully", to_username)
-        }
-        Err(e) => {
-            println!("[GROUPS] Error sending invite: {}", e);
-            format!("ERR: Could not send invite: {}", e)
+            Ok(format!("Invite sent to {} successfully", to_username))
         }
+        Err(e) => Err(ChatError::internal("[GROUPS] Error sending invite", e)),
     }
 }
 
-pub async fn get_group_members(db: Arc<Database>, group_id: &str) -> String {
+/// Il creatore del gruppo ne è l'amministratore (può rimuovere i messaggi degli altri).
+pub async fn is_group_admin(db: &Database, group_id: &str, user_id: &str) -> Result<bool, ChatError> {
+    sqlx::query("SELECT 1 FROM groups WHERE id = ? AND created_by = ?")
+        .bind(group_id)
+        .bind(user_id)
+        .fetch_optional(&db.pool)
+        .await
+        .map(|row| row.is_some())
+        .map_err(|e| ChatError::internal("[GROUPS] Error checking group admin", e))
+}
+
+pub async fn get_group_members(db: Arc<Database>, group_id: &str) -> Result<Vec<String>, ChatError> {
     println!("[GROUPS] Get members for group {}", group_id);
     let rows = sqlx::query("SELECT u.username FROM group_members gm JOIN users u ON gm.user_id = u.id WHERE gm.group_id = ?")
         .bind(group_id)
@@ -218,17 +213,13 @@ pub async fn get_group_members(db: Arc<Database>, group_id: &str) -> String {
         .await;
     match rows {
         Ok(rows) => {
-            let members: Vec<String> = rows.iter().map(|r| r.get::<String,_>("username")).collect();
-            format!("OK: Group members: {}", members.join(", "))
-        }
-        Err(e) => {
-            println!("[GROUPS] Error getting group members: {}", e);
-            format!("ERR: {}", e)
+            Ok(rows.iter().map(|r| r.get::<String,_>("username")).collect())
         }
+        Err(e) => Err(ChatError::internal("[GROUPS] Error getting group members", e)),
     }
 }
 
-pub async fn my_invites(db: Arc<Database>, user_id: &str) -> String {
+pub async fn my_invites(db: Arc<Database>, user_id: &str) -> Result<Vec<GroupInviteInfo>, ChatError> {
     println!("[GROUPS] List invites for user {}", user_id);
     let rows = sqlx::query("SELECT gi.id, g.name as group_name, u.username as invited_by FROM group_invites gi JOIN groups g ON gi.group_id = g.id JOIN users u ON gi.invited_by = u.id WHERE gi.invited_user_id = ? AND gi.status = 'pending'")
         .bind(user_id)
@@ -236,26 +227,50 @@ pub async fn my_invites(db: Arc<Database>, user_id: &str) -> String {
         .await;
     match rows {
         Ok(rows) => {
-            let invites: Vec<String> = rows.iter().map(|r| {
-                format!("{}:{}:{}", 
-                    r.get::<i64,_>("id"), 
-                    r.get::<String,_>("group_name"), 
-                    r.get::<String,_>("invited_by")
-                )
+            let mut invites: Vec<GroupInviteInfo> = rows.iter().map(|r| GroupInviteInfo {
+                id: r.get::<i64,_>("id"),
+                group_name: r.get::<String,_>("group_name"),
+                invited_by: r.get::<String,_>("invited_by"),
             }).collect();
-            // Remove duplicates by converting to HashSet and back
-            let unique_invites: std::collections::HashSet<String> = invites.into_iter().collect();
-            let unique_vec: Vec<String> = unique_invites.into_iter().collect();
-            format!("OK: Group invites: {}", unique_vec.join(" | "))
-        }
-        Err(e) => {
-            println!("[GROUPS] Error listing invites: {}", e);
-            format!("ERR: {}", e)
+            // Remove duplicates (same invite id joined twice)
+            invites.sort_by_key(|i| i.id);
+            invites.dedup_by_key(|i| i.id);
+            Ok(invites)
         }
+        Err(e) => Err(ChatError::internal("[GROUPS] Error listing invites", e)),
     }
 }
 
-pub async fn accept_invite(db: Arc<Database>, user_id: &str, invite_id: &str) -> String {
+/// Invito in attesa di `username` nel gruppo, con l'id dell'invitato (per notificarglielo).
+pub async fn pending_invite(db: &Database, group_id: &str, username: &str) -> Result<Option<(String, GroupInviteInfo)>, ChatError> {
+    let row = sqlx::query("SELECT gi.id, gi.invited_user_id, g.name as group_name, u.username as invited_by FROM group_invites gi JOIN groups g ON gi.group_id = g.id JOIN users u ON gi.invited_by = u.id JOIN users t ON gi.invited_user_id = t.id WHERE gi.group_id = ? AND t.username = ? AND gi.status = 'pending' ORDER BY gi.id DESC LIMIT 1")
+        .bind(group_id)
+        .bind(username)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[GROUPS] Error loading invite", e))?;
+    Ok(row.map(|r| (r.get::<String,_>("invited_user_id"), GroupInviteInfo {
+        id: r.get::<i64,_>("id"),
+        group_name: r.get::<String,_>("group_name"),
+        invited_by: r.get::<String,_>("invited_by"),
+    })))
+}
+
+/// Invito ancora in attesa, con il gruppo e l'id dell'invitato.
+pub async fn pending_invite_by_id(db: &Database, invite_id: i64) -> Result<Option<(String, String, GroupInviteInfo)>, ChatError> {
+    let row = sqlx::query("SELECT gi.id, gi.group_id, gi.invited_user_id, g.name as group_name, u.username as invited_by FROM group_invites gi JOIN groups g ON gi.group_id = g.id JOIN users u ON gi.invited_by = u.id WHERE gi.id = ? AND gi.status = 'pending'")
+        .bind(invite_id)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[GROUPS] Error loading invite", e))?;
+    Ok(row.map(|r| (r.get::<String,_>("group_id"), r.get::<String,_>("invited_user_id"), GroupInviteInfo {
+        id: r.get::<i64,_>("id"),
+        group_name: r.get::<String,_>("group_name"),
+        invited_by: r.get::<String,_>("invited_by"),
+    })))
+}
+
+pub async fn accept_invite(db: Arc<Database>, user_id: &str, invite_id: i64) -> Result<String, ChatError> {
     println!("[GROUPS] Accept invite {} by user {}", invite_id, user_id);
     // Trova invito
     let row = sqlx::query("SELECT group_id FROM group_invites WHERE id = ? AND invited_user_id = ? AND status = 'pending'")
@@ -265,15 +280,15 @@ pub async fn accept_invite(db: Arc<Database>, user_id: &str, invite_id: &str) ->
         .await;
     let group_id = match row {
         Ok(Some(row)) => row.get::<String,_>("group_id"),
-        _ => return "ERR: Invite not found or already handled".to_string(),
+        _ => return Err(ChatError::new(ErrorCode::InviteNotFound, "Invite not found or already handled")),
     };
     // Aggiorna invito
     let res = sqlx::query("UPDATE group_invites SET status = 'accepted' WHERE id = ?")
         .bind(invite_id)
         .execute(&db.pool)
         .await;
-    if res.is_err() {
-        return "ERR: Could not update invite".to_string();
+    if let Err(e) = res {
+        return Err(ChatError::internal("[GROUPS] Error updating invite", e));
     }
     // Aggiungi a group_members
     let joined_at = chrono::Utc::now().timestamp();
@@ -286,16 +301,13 @@ pub async fn accept_invite(db: Arc<Database>, user_id: &str, invite_id: &str) ->
     match res2 {
         Ok(_) => {
             println!("[GROUPS] User {} joined group {} via invite", user_id, group_id);
-            "OK: Invite accepted".to_string()
-        }
-        Err(e) => {
-            println!("[GROUPS] Error adding member: {}", e);
-            format!("ERR: Could not join group: {}", e)
+            Ok("Invite accepted".to_string())
         }
+        Err(e) => Err(ChatError::internal("[GROUPS] Error adding member", e)),
     }
 }
 
-pub async fn reject_invite(db: Arc<Database>, user_id: &str, invite_id: &str) -> String {
+pub async fn reject_invite(db: Arc<Database>, user_id: &str, invite_id: i64) -> Result<String, ChatError> {
     println!("[GROUPS] Reject invite {} by user {}", invite_id, user_id);
     let res = sqlx::query("UPDATE group_invites SET status = 'rejected' WHERE id = ? AND invited_user_id = ? AND status = 'pending'")
         .bind(invite_id)
@@ -305,16 +317,14 @@ pub async fn reject_invite(db: Arc<Database>, user_id: &str, invite_id: &str) ->
     match res {
         Ok(r) if r.rows_affected() > 0 => {
             println!("[GROUPS] Invite {} rejected by user {}", invite_id, user_id);
-            "OK: Invite rejected".to_string()
-        }
-        _ => {
-            println!("[GROUPS] Error rejecting invite {} by user {}", invite_id, user_id);
-            "ERR: Could not reject invite".to_string()
+            Ok("Invite rejected".to_string())
         }
+        Ok(_) => Err(ChatError::new(ErrorCode::InviteNotFound, "Invite not found or already handled")),
+        Err(e) => Err(ChatError::internal("[GROUPS] Error rejecting invite", e)),
     }
 }
 
-pub async fn join_group(db: Arc<Database>, user_id: &str, group_name: &str) -> String {
+pub async fn join_group(db: Arc<Database>, user_id: &str, group_name: &str) -> Result<String, ChatError> {
     println!("[GROUPS] User {} joins group '{}'", user_id, group_name);
     // Trova group_id
     let group_row = sqlx::query("SELECT id FROM groups WHERE name = ?")
@@ -323,7 +333,7 @@ pub async fn join_group(db: Arc<Database>, user_id: &str, group_name: &str) -> S
         .await;
     let group_id = match group_row {
         Ok(Some(row)) => row.get::<String,_>("id"),
-        _ => return "ERR: Group not found".to_string(),
+        _ => return Err(ChatError::group_not_found()),
     };
     // Aggiungi a group_members
     let joined_at = chrono::Utc::now().timestamp();
@@ -336,16 +346,13 @@ pub async fn join_group(db: Arc<Database>, user_id: &str, group_name: &str) -> S
     match res {
         Ok(_) => {
             println!("[GROUPS] User {} joined group {}", user_id, group_id);
-            "OK: Joined group".to_string()
-        }
-        Err(e) => {
-            println!("[GROUPS] Error joining group: {}", e);
-            format!("ERR: Could not join group: {}", e)
+            Ok("Joined group".to_string())
         }
+        Err(e) => Err(ChatError::internal("[GROUPS] Error joining group", e)),
     }
 }
 
-pub async fn leave_group(db: Arc<Database>, user_id: &str, group_ident: &str) -> String {
+pub async fn leave_group(db: Arc<Database>, user_id: &str, group_ident: &str) -> Result<String, ChatError> {
     println!("[GROUPS] User {} leaves group '{}'", user_id, group_ident);
     // Try to resolve the provided identifier as a group id first, then fall back to name
     let group_row_by_id = sqlx::query("SELECT id FROM groups WHERE id = ?")
@@ -381,7 +388,7 @@ pub async fn leave_group(db: Arc<Database>, user_id: &str, group_ident: &str) ->
                             println!("[GROUPS] Resolved group name '{}' to id {} (global lookup)", group_ident, gid);
                             gid
                         }
-                        _ => return "ERR: Group not found".to_string(),
+                        _ => return Err(ChatError::group_not_found()),
                     }
                 }
             }
@@ -396,11 +403,8 @@ pub async fn leave_group(db: Arc<Database>, user_id: &str, group_ident: &str) ->
     match res {
         Ok(_) => {
             println!("[GROUPS] User {} left group {}", user_id, group_id);
-            "OK: Left group".to_string()
-        }
-        Err(e) => {
-            println!("[GROUPS] Error leaving group: {}", e);
-            format!("ERR: Could not leave group: {}", e)
+            Ok("Left group".to_string())
         }
+        Err(e) => Err(ChatError::internal("[GROUPS] Error leaving group", e)),
     }
 }
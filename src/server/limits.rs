@@ -0,0 +1,142 @@
+// src/server/limits.rs
+// Limite di connessioni contemporanee (totale e per indirizzo IP), condiviso da TCP e WebSocket
+use crate::common::error::{ChatError, ErrorCode};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Contatori delle connessioni aperte. Un limite a 0 significa "nessun limite".
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    counts: Arc<Mutex<Counts>>,
+    max_total: usize,
+    max_per_ip: usize,
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    rejected: u64,
+}
+
+/// Posto occupato da una connessione: viene liberato quando esce di scope.
+pub struct ConnectionSlot {
+    limiter: ConnectionLimiter,
+    ip: IpAddr,
+}
+
+/// Fotografia dei contatori per il log delle prestazioni.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionStats {
+    pub active: usize,
+    pub max_total: usize,
+    pub distinct_ips: usize,
+    pub busiest_ip: usize,
+    pub rejected: u64,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_total: usize, max_per_ip: usize) -> Self {
+        Self { counts: Arc::new(Mutex::new(Counts::default())), max_total, max_per_ip }
+    }
+
+    /// Registra una nuova connessione da `ip`, o restituisce `ServerFull` se supera un limite.
+    pub fn try_acquire(&self, ip: IpAddr) -> Result<ConnectionSlot, ChatError> {
+        let mut counts = self.lock();
+        let from_ip = counts.per_ip.get(&ip).copied().unwrap_or(0);
+        let error = if self.max_total > 0 && counts.total >= self.max_total {
+            Some(format!("Server full: {} connections already open, try again later", counts.total))
+        } else if self.max_per_ip > 0 && from_ip >= self.max_per_ip {
+            Some(format!("Too many connections from {} (limit {}), try again later", ip, self.max_per_ip))
+        } else {
+            None
+        };
+        if let Some(message) = error {
+            counts.rejected += 1;
+            return Err(ChatError::new(ErrorCode::ServerFull, message));
+        }
+        counts.total += 1;
+        *counts.per_ip.entry(ip).or_insert(0) += 1;
+        Ok(ConnectionSlot { limiter: self.clone(), ip })
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        let counts = self.lock();
+        ConnectionStats {
+            active: counts.total,
+            max_total: self.max_total,
+            distinct_ips: counts.per_ip.len(),
+            busiest_ip: counts.per_ip.values().copied().max().unwrap_or(0),
+            rejected: counts.rejected,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Counts> {
+        self.counts.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.lock();
+        counts.total = counts.total.saturating_sub(1);
+        if let Some(n) = counts.per_ip.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn total_limit() {
+        let limiter = ConnectionLimiter::new(2, 0);
+        let _a = limiter.try_acquire(ip(1)).unwrap();
+        let _b = limiter.try_acquire(ip(2)).unwrap();
+        let error = limiter.try_acquire(ip(3)).err().unwrap();
+        assert_eq!(error.code, ErrorCode::ServerFull);
+        assert_eq!(limiter.stats().active, 2);
+        assert_eq!(limiter.stats().rejected, 1);
+    }
+
+    #[test]
+    fn per_ip_limit() {
+        let limiter = ConnectionLimiter::new(0, 2);
+        let _a = limiter.try_acquire(ip(1)).unwrap();
+        let _b = limiter.try_acquire(ip(1)).unwrap();
+        assert_eq!(limiter.try_acquire(ip(1)).err().unwrap().code, ErrorCode::ServerFull);
+        // Gli altri indirizzi non ne risentono
+        let _c = limiter.try_acquire(ip(2)).unwrap();
+        let stats = limiter.stats();
+        assert_eq!((stats.active, stats.distinct_ips, stats.busiest_ip, stats.rejected), (3, 2, 2, 1));
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let limiter = ConnectionLimiter::new(0, 0);
+        let slots: Vec<_> = (0..500).map(|_| limiter.try_acquire(ip(1)).unwrap()).collect();
+        assert_eq!(limiter.stats().active, slots.len());
+        assert_eq!(limiter.stats().rejected, 0);
+    }
+
+    #[test]
+    fn slot_released_on_drop() {
+        let limiter = ConnectionLimiter::new(1, 1);
+        let slot = limiter.try_acquire(ip(1)).unwrap();
+        assert!(limiter.try_acquire(ip(1)).is_err());
+        drop(slot);
+        let stats = limiter.stats();
+        assert_eq!((stats.active, stats.distinct_ips), (0, 0));
+        assert!(limiter.try_acquire(ip(1)).is_ok());
+    }
+}
@@ -5,6 +5,12 @@ use ruggine_modulare::server::websocket::ChatWebSocketManager;
 use ruggine_modulare::utils::performance;
 use std::sync::Arc;
 use tokio::net::TcpListener;
+use ruggine_modulare::server::tls_reload::ReloadableAcceptor;
+use ruggine_modulare::server::limits::ConnectionLimiter;
+use ruggine_modulare::server::rate_limit::RateLimiter;
+use ruggine_modulare::server::shutdown::{self, Shutdown};
+use ruggine_modulare::server::{mtls, websocket};
+use ruggine_modulare::server::sync;
 use log::{info, error};
 
 #[tokio::main]
@@ -34,45 +40,82 @@ async fn main() -> anyhow::Result<()> {
     })?;
     info!("✅ Database migrations completed successfully");
     
+    // Limiti di frequenza condivisi da porta comandi e WebSocket (bucket per utente)
+    let rate_limiter = RateLimiter::new(config.rate_limits.clone());
+    // Arresto controllato su SIGINT/SIGTERM
+    let shutdown = Shutdown::new();
+
     // Initialize WebSocket manager with Redis
     let redis_url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost:6379".to_string());
-    let ws_manager = Arc::new(ChatWebSocketManager::new(&redis_url).await?);
+    let ws_manager = Arc::new(ChatWebSocketManager::new(&redis_url, rate_limiter.clone(), shutdown.clone()).await?);
     
     // Start Redis subscriber for cross-instance messaging
     ws_manager.start_redis_subscriber().await?;
     
     let presence = ruggine_modulare::server::presence::PresenceRegistry::new();
-    let mut server = Server { 
+    // Limiti condivisi da porta comandi e porta WebSocket dedicata
+    let limiter = ConnectionLimiter::new(config.max_clients, config.max_connections_per_ip);
+    info!("Connection limits: {} total, {} per IP (0 = unlimited)", config.max_clients, config.max_connections_per_ip);
+    let server = Server { 
         db: database.clone(), 
         config: config.clone(), 
         presence,
         ws_manager: Some(ws_manager.clone()),
+        limiter: limiter.clone(),
+        rate_limiter,
+        shutdown: shutdown.clone(),
     };
 
     // Start performance logger in background
     let perf_log_path = std::env::var("PERFORMANCE_LOG_PATH")
         .unwrap_or_else(|_| "data/ruggine_performance.log".to_string());
     let perf_db = database.clone();
+    let perf_limiter = limiter.clone();
     tokio::spawn(async move {
         info!("📊 Starting performance logger - logging every 120 seconds to: {}", perf_log_path);
-        performance::start_performance_logger(perf_db, &perf_log_path).await;
+        performance::start_performance_logger(perf_db, perf_limiter, &perf_log_path).await;
     });
 
-    // Start WebSocket server on a different port
-    let ws_port = config.port + 1; // WebSocket su porta +1 rispetto al server principale
-    let ws_host = config.host.clone();
-    let ws_manager_clone = ws_manager.clone();
-    let database_clone = database.clone();
-    let config_clone = config.clone();
+    // Eventi recuperabili con `sync`: eliminati dopo SYNC_RETENTION_DAYS giorni
+    sync::spawn_pruner(database.clone(), config.sync_retention_days);
+
+    // Lo stesso acceptor TLS protegge porta comandi e WebSocket (wss://)
+    let tls_acceptor = server.tls_acceptor();
+
+    // WebSocket: di default sulla stessa porta (rilevamento HTTP Upgrade in Server::run),
+    // oppure su una porta dedicata se SERVER_WEBSOCKET_PORT è impostata
+    if let Some(ws_port) = config.websocket_port {
+        let ws_host = config.host.clone();
+        let ws_manager_clone = ws_manager.clone();
+        let database_clone = database.clone();
+        let config_clone = config.clone();
+        let ws_tls = tls_acceptor.clone();
+        let ws_limiter = limiter.clone();
+        let ws_shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = start_websocket_server(&format!("{}:{}", ws_host, ws_port), ws_manager_clone, database_clone, config_clone, ws_tls, ws_limiter, ws_shutdown).await {
+                error!("WebSocket server error: {}", e);
+            }
+        });
+        let scheme = if tls_acceptor.is_some() { "wss" } else { "ws" };
+        info!("WebSocket server started on {}://{}:{}", scheme, config.host, ws_port);
+    } else {
+        info!("WebSocket connections share port {}", config.port);
+    }
+
+    let signal_shutdown = shutdown.clone();
     tokio::spawn(async move {
-        if let Err(e) = start_websocket_server(&format!("{}:{}", ws_host, ws_port), ws_manager_clone, database_clone, config_clone).await {
-            error!("WebSocket server error: {}", e);
-        }
+        let signal = shutdown::wait_for_signal().await;
+        info!("🛑 Received {}, shutting down (send it again to force exit)", signal);
+        signal_shutdown.trigger();
+        shutdown::wait_for_signal().await;
+        error!("Forced exit before shutdown completed");
+        std::process::exit(1);
     });
 
-    info!("WebSocket server started on {}:{}", config.host, ws_port);
-
-    server.run(&format!("{}:{}", config.host, config.port)).await?;
+    server.run(&format!("{}:{}", config.host, config.port), tls_acceptor).await?;
+    shutdown::finish(&server, std::time::Duration::from_secs(config.shutdown_timeout_secs)).await;
+    info!("✅ Server stopped");
     Ok(())
 }
 
@@ -80,28 +123,54 @@ async fn start_websocket_server(
     addr: &str, 
     ws_manager: Arc<ChatWebSocketManager>,
     database: Arc<Database>,
-    config: ServerConfig
+    config: ServerConfig,
+    tls_acceptor: Option<ReloadableAcceptor>,
+    limiter: ConnectionLimiter,
+    shutdown: Shutdown,
 ) -> anyhow::Result<()> {
     let listener = TcpListener::bind(addr).await?;
     info!("WebSocket server listening on {}", addr);
 
-    while let Ok((stream, addr)) = listener.accept().await {
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            _ = shutdown.triggered() => {
+                info!("WebSocket server no longer accepting connections on {}", addr);
+                break;
+            }
+        };
         info!("New WebSocket connection from {}", addr);
         let ws_manager = ws_manager.clone();
         let database = database.clone();
         let config = config.clone();
+        let tls_acceptor = tls_acceptor.as_ref().map(ReloadableAcceptor::current);
+        let limiter = limiter.clone();
         
         tokio::spawn(async move {
-            match tokio_tungstenite::accept_async(stream).await {
-                Ok(ws_stream) => {
-                    // Usa l'autenticazione corretta invece di user_id fittizio
-                    if let Err(e) = ws_manager.handle_authenticated_connection(ws_stream, database, config).await {
-                        error!("Error handling WebSocket connection: {}", e);
+            // Il posto resta occupato finché la connessione è aperta
+            let slot = limiter.try_acquire(addr.ip());
+            let rejection = slot.as_ref().err().cloned();
+            if let Some(e) = &rejection {
+                error!("WebSocket connection from {} rejected: {}", addr, e);
+            }
+            let result = match tls_acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(tls_stream) => accept_websocket(tls_stream, ws_manager, database, config, rejection).await,
+                    Err(e) => {
+                        error!("TLS handshake failed for WebSocket client {}: {}", addr, e);
+                        if let Some(reason) = mtls::handshake_failure(&e) {
+                            mtls::record_failure(&database, addr, &reason).await;
+                        }
+                        return;
                     }
-                }
-                Err(e) => {
-                    error!("Error during WebSocket handshake: {}", e);
-                }
+                },
+                None => accept_websocket(stream, ws_manager, database, config, rejection).await,
+            };
+            if let Err(e) = result {
+                error!("Error handling WebSocket connection: {}", e);
             }
         });
     }
@@ -109,4 +178,22 @@ async fn start_websocket_server(
     Ok(())
 }
 
-
+async fn accept_websocket<S>(
+    stream: S,
+    ws_manager: Arc<ChatWebSocketManager>,
+    database: Arc<Database>,
+    config: ServerConfig,
+    rejection: Option<ruggine_modulare::common::error::ChatError>,
+) -> anyhow::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let ws_stream = tokio_tungstenite::accept_async(stream)
+        .await
+        .map_err(|e| anyhow::anyhow!("Error during WebSocket handshake: {}", e))?;
+    if let Some(error) = rejection {
+        return websocket::reject_connection(ws_stream, &error).await;
+    }
+    // Usa l'autenticazione corretta invece di user_id fittizio
+    ws_manager.handle_authenticated_connection(ws_stream, database, config).await
+}
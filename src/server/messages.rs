@@ -1,11 +1,178 @@
-use crate::server::{database::Database, auth};
+use crate::server::database::Database;
 use std::sync::Arc;
 use sqlx::Row;
 use base64::{Engine as _, engine::general_purpose};
 use serde_json;
 
 use crate::server::config::ServerConfig;
+use crate::server::groups;
 use crate::common::crypto::CryptoManager;
+use crate::common::protocol::{ChatRef, HistoryMessage, HistoryPage, InboxEntry, MessagePreview, QuotedMessage, ReactionCount};
+use crate::common::error::{ChatError, ErrorCode};
+
+/// Massimo numero di messaggi per pagina dello storico, qualunque `limit` chieda il client
+pub const MAX_HISTORY_PAGE: u32 = 200;
+
+/// Lunghezza massima dell'id scelto dal client (un UUID ne occupa 36)
+const MAX_CLIENT_MSG_ID_LENGTH: usize = 64;
+
+/// Lunghezza massima (in byte) di una reazione: basta per le emoji composte
+const MAX_REACTION_LENGTH: usize = 32;
+
+/// Messaggio salvato: id globale, numero di sequenza nella chat e orario.
+/// `duplicate` indica un nuovo invio di un messaggio già salvato (stesso `client_msg_id`).
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub chat_id: String,
+    pub seq: i64,
+    pub sent_at: i64,
+    pub duplicate: bool,
+}
+
+/// Chat di un messaggio già salvato: i membri (id utente) determinano la chiave di
+/// cifratura della chat e sono i destinatari degli eventi live.
+#[derive(Debug, Clone)]
+pub struct MessageChat {
+    pub chat_id: String,
+    /// Gruppo della chat, `None` per le chat private
+    pub group_id: Option<String>,
+    pub member_ids: Vec<String>,
+}
+
+/// Messaggio modificato, con il nuovo testo in chiaro da inoltrare ai client connessi.
+#[derive(Debug, Clone)]
+pub struct EditedMessage {
+    pub id: i64,
+    pub seq: i64,
+    pub sender_id: String,
+    pub content: String,
+    pub sent_at: i64,
+    pub edited_at: i64,
+    pub chat: MessageChat,
+}
+
+/// Messaggio ritirato per tutti: al posto del testo resta una tombstone.
+#[derive(Debug, Clone)]
+pub struct DeletedMessage {
+    pub id: i64,
+    pub seq: i64,
+    pub sender_id: String,
+    /// Il mittente, o l'amministratore del gruppo che lo ha rimosso
+    pub deleted_by: String,
+    pub sent_at: i64,
+    pub deleted_at: i64,
+    pub chat: MessageChat,
+}
+
+/// Reazioni di un messaggio dopo un'aggiunta o una rimozione.
+#[derive(Debug, Clone)]
+pub struct ReactionUpdate {
+    pub id: i64,
+    pub seq: i64,
+    pub sender_id: String,
+    pub sent_at: i64,
+    pub reactions: Vec<ReactionCount>,
+    pub chat: MessageChat,
+}
+
+/// Messaggio salvato, con il testo in chiaro attuale e l'estratto dell'eventuale citazione:
+/// da inoltrare ai membri della chat quando non è stato inviato dal WebSocket, o per
+/// ricostruire gli eventi recuperati con `sync`.
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    pub sender_id: String,
+    pub client_msg_id: Option<String>,
+    pub message: HistoryMessage,
+    pub chat: MessageChat,
+    /// Ritiro per tutti: quando e da chi
+    pub deleted_at: Option<i64>,
+    pub deleted_by: Option<String>,
+}
+
+/// Conferme di un messaggio dopo una consegna o una lettura, da inoltrare al mittente.
+#[derive(Debug, Clone)]
+pub struct ReceiptUpdate {
+    pub id: i64,
+    pub seq: i64,
+    pub sender_id: String,
+    pub sent_at: i64,
+    pub delivered_to: u32,
+    pub read_by: u32,
+    pub chat: MessageChat,
+}
+
+fn check_client_msg_id(client_msg_id: Option<&str>) -> Result<(), ChatError> {
+    match client_msg_id {
+        Some(id) if id.is_empty() || id.len() > MAX_CLIENT_MSG_ID_LENGTH => Err(ChatError::new(
+            ErrorCode::InvalidInput,
+            format!("client_msg_id must be 1-{} characters", MAX_CLIENT_MSG_ID_LENGTH),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Messaggio già salvato da `sender_id` con questo `client_msg_id`, se esiste.
+async fn find_by_client_msg_id(db: &Database, sender_id: &str, client_msg_id: &str) -> Result<Option<StoredMessage>, sqlx::Error> {
+    let row = sqlx::query("SELECT id, chat_id, seq, sent_at FROM encrypted_messages WHERE sender_id = ? AND client_msg_id = ?")
+        .bind(sender_id)
+        .bind(client_msg_id)
+        .fetch_optional(&db.pool)
+        .await?;
+    Ok(row.map(|r| StoredMessage { id: r.get("id"), chat_id: r.get("chat_id"), seq: r.get("seq"), sent_at: r.get("sent_at"), duplicate: true }))
+}
+
+/// Salva un messaggio (già cifrato) assegnando il numero di sequenza successivo della chat.
+/// Il calcolo avviene nella stessa istruzione dell'inserimento, quindi due invii
+/// concorrenti non possono ottenere lo stesso numero. Se un invio concorrente con lo
+/// stesso `client_msg_id` è arrivato prima, restituisce quel messaggio.
+async fn store_message(db: &Database, chat_id: &str, sender_id: &str, stored_text: &str, client_msg_id: Option<&str>, reply_to: Option<i64>) -> Result<StoredMessage, sqlx::Error> {
+    let res = sqlx::query(r#"
+        INSERT INTO encrypted_messages (chat_id, sender_id, message, sent_at, seq, client_msg_id, reply_to)
+        SELECT ?, ?, ?, ?, COALESCE(MAX(seq), 0) + 1, ?, ? FROM encrypted_messages WHERE chat_id = ?
+        RETURNING id, seq, sent_at
+    "#)
+        .bind(chat_id)
+        .bind(sender_id)
+        .bind(stored_text)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(client_msg_id)
+        .bind(reply_to)
+        .bind(chat_id)
+        .fetch_one(&db.pool)
+        .await;
+    match (res, client_msg_id) {
+        (Ok(row), _) => Ok(StoredMessage { id: row.get("id"), chat_id: chat_id.to_string(), seq: row.get("seq"), sent_at: row.get("sent_at"), duplicate: false }),
+        (Err(e), Some(client_msg_id)) if e.as_database_error().is_some_and(|d| d.is_unique_violation()) => {
+            find_by_client_msg_id(db, sender_id, client_msg_id).await?.ok_or(e)
+        }
+        (Err(e), _) => Err(e),
+    }
+}
+
+/// Invio già ricevuto con lo stesso `client_msg_id`: non va salvato di nuovo.
+async fn already_stored(db: &Database, sender_id: &str, client_msg_id: Option<&str>) -> Result<Option<StoredMessage>, ChatError> {
+    let Some(client_msg_id) = client_msg_id else { return Ok(None) };
+    let found = find_by_client_msg_id(db, sender_id, client_msg_id).await
+        .map_err(|e| ChatError::internal("[MSG] Error looking up client message id", e))?;
+    if let Some(stored) = &found {
+        println!("[MSG] Duplicate send of {} by {}: returning message #{}", client_msg_id, sender_id, stored.id);
+    }
+    Ok(found)
+}
+
+/// Una risposta può citare solo un messaggio non ritirato della stessa chat.
+async fn check_reply_to(db: &Database, chat_id: &str, reply_to: Option<i64>) -> Result<(), ChatError> {
+    let Some(parent_id) = reply_to else { return Ok(()) };
+    sqlx::query("SELECT 1 FROM encrypted_messages WHERE id = ? AND chat_id = ? AND deleted_at IS NULL")
+        .bind(parent_id)
+        .bind(chat_id)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error loading replied message", e))?
+        .map(|_| ())
+        .ok_or_else(ChatError::message_not_found)
+}
 
 /// Encrypts a message for storage in the database
 fn encrypt_message_for_storage(message: &str, chat_participants: &[String], config: &ServerConfig) -> Result<String, String> {
@@ -68,14 +235,11 @@ fn decrypt_message_from_storage(encrypted_data: &str, chat_participants: &[Strin
     }
 }
 
-pub async fn send_group_message(db: Arc<Database>, session_token: &str, group_name: &str, message: &str, config: &ServerConfig) -> String {
+pub async fn send_group_message(db: Arc<Database>, user_id: &str, group_name: &str, message: &str, client_msg_id: Option<&str>, reply_to: Option<i64>, config: &ServerConfig) -> Result<StoredMessage, ChatError> {
     if message.len() > config.max_message_length {
-        return format!("ERR: Message too long (max {} chars)", config.max_message_length);
+        return Err(ChatError::new(ErrorCode::InvalidInput, format!("Message too long (max {} chars)", config.max_message_length)));
     }
-    let user_id = match auth::validate_session(db.clone(), session_token).await {
-        Some(uid) => uid,
-        None => return "ERR: Invalid session".to_string(),
-    };
+    check_client_msg_id(client_msg_id)?;
     // group_name is actually group_id in this context
     let group_row = sqlx::query("SELECT id FROM groups WHERE id = ?")
         .bind(group_name)
@@ -83,19 +247,24 @@ pub async fn send_group_message(db: Arc<Database>, session_token: &str, group_na
         .await;
     let group_id = match group_row {
         Ok(Some(row)) => row.get::<String,_>("id"),
-        _ => return "ERR: Group not found".to_string(),
+        _ => return Err(ChatError::group_not_found()),
     };
     let is_member = sqlx::query("SELECT 1 FROM group_members WHERE group_id = ? AND user_id = ?")
         .bind(&group_id)
-        .bind(&user_id)
+        .bind(user_id)
         .fetch_optional(&db.pool)
         .await
         .ok()
         .flatten()
         .is_some();
     if !is_member {
-        return "ERR: Not a group member".to_string();
+        return Err(ChatError::not_member());
+    }
+    if let Some(stored) = already_stored(&db, user_id, client_msg_id).await? {
+        return Ok(stored);
     }
+    let chat_id = format!("group:{}", group_id);
+    check_reply_to(&db, &chat_id, reply_to).await?;
     
     // Get all group members for encryption key generation
     let members_rows = sqlx::query("SELECT user_id FROM group_members WHERE group_id = ?")
@@ -104,90 +273,628 @@ pub async fn send_group_message(db: Arc<Database>, session_token: &str, group_na
         .await;
     let group_members = match members_rows {
         Ok(rows) => rows.iter().map(|r| r.get::<String, _>("user_id")).collect::<Vec<String>>(),
-        Err(e) => {
-            println!("[MSG] Error getting group members: {}", e);
-            return "ERR: Failed to get group members".to_string();
-        }
+        Err(e) => return Err(ChatError::internal("[MSG] Error getting group members", e)),
     };
     
     // Encrypt the message before storing
     let encrypted_message = match encrypt_message_for_storage(message, &group_members, config) {
         Ok(encrypted) => encrypted,
-        Err(e) => return format!("ERR: Encryption failed: {}", e),
+        Err(e) => return Err(ChatError::internal("[MSG] Encryption failed", e)),
     };
     
-    let sent_at = chrono::Utc::now().timestamp();
-    let chat_id = format!("group:{}", group_id);
-    let res = sqlx::query("INSERT INTO encrypted_messages (chat_id, sender_id, message, sent_at) VALUES (?, ?, ?, ?)")
-        .bind(&chat_id)
-        .bind(&user_id)
-        .bind(&encrypted_message)
-        .bind(sent_at)
-        .execute(&db.pool)
-        .await;
-    match res {
-        Ok(_) => {
-            println!("[MSG] Group message sent to {} by {}", group_name, user_id);
-            "OK: Message sent".to_string()
-        }
-        Err(e) => {
-            println!("[MSG] Error sending group message: {}", e);
-            format!("ERR: {}", e)
+    match store_message(&db, &chat_id, user_id, &encrypted_message, client_msg_id, reply_to).await {
+        Ok(stored) => {
+            println!("[MSG] Group message #{} (seq {}) sent to {} by {}", stored.id, stored.seq, group_name, user_id);
+            Ok(stored)
         }
+        Err(e) => Err(ChatError::internal("[MSG] Error sending group message", e)),
     }
 }
 
-pub async fn send_private_message(db: Arc<Database>, session_token: &str, to_username: &str, message: &str, config: &ServerConfig) -> String {
+pub async fn send_private_message(db: Arc<Database>, user_id: &str, to_username: &str, message: &str, client_msg_id: Option<&str>, reply_to: Option<i64>, config: &ServerConfig) -> Result<StoredMessage, ChatError> {
     if message.len() > config.max_message_length {
-        return format!("ERR: Message too long (max {} chars)", config.max_message_length);
+        return Err(ChatError::new(ErrorCode::InvalidInput, format!("Message too long (max {} chars)", config.max_message_length)));
     }
-    let user_id = match auth::validate_session(db.clone(), session_token).await {
-        Some(uid) => uid,
-        None => return "ERR: Invalid session".to_string(),
-    };
+    check_client_msg_id(client_msg_id)?;
     let to_row = sqlx::query("SELECT id FROM users WHERE username = ?")
         .bind(to_username)
         .fetch_optional(&db.pool)
         .await;
     let to_id = match to_row {
         Ok(Some(row)) => row.get::<String,_>("id"),
-        _ => return "ERR: User not found".to_string(),
+        _ => return Err(ChatError::user_not_found()),
     };
-    let mut ids = vec![user_id.clone(), to_id.clone()];
+    let mut ids = vec![user_id.to_string(), to_id.clone()];
     ids.sort();
     let chat_id = format!("private:{}-{}", ids[0], ids[1]);
+    if let Some(stored) = already_stored(&db, user_id, client_msg_id).await? {
+        return Ok(stored);
+    }
+    check_reply_to(&db, &chat_id, reply_to).await?;
     
     // Encrypt the message before storing
     let encrypted_message = match encrypt_message_for_storage(message, &ids, config) {
         Ok(encrypted) => encrypted,
-        Err(e) => return format!("ERR: Encryption failed: {}", e),
+        Err(e) => return Err(ChatError::internal("[MSG] Encryption failed", e)),
     };
     
-    let sent_at = chrono::Utc::now().timestamp();
-    let res = sqlx::query("INSERT INTO encrypted_messages (chat_id, sender_id, message, sent_at) VALUES (?, ?, ?, ?)")
-        .bind(&chat_id)
-        .bind(&user_id)
+    match store_message(&db, &chat_id, user_id, &encrypted_message, client_msg_id, reply_to).await {
+        Ok(stored) => {
+            println!("[MSG] Private message #{} (seq {}) sent to {} by {}", stored.id, stored.seq, to_username, user_id);
+            Ok(stored)
+        }
+        Err(e) => Err(ChatError::internal("[MSG] Error sending private message", e)),
+    }
+}
+
+/// Risolve la chat di un messaggio dal suo `chat_id` (`group:<id>` o `private:<id>-<id>`).
+/// Per le chat private l'altro partecipante si ricava togliendo il mittente, dato che
+/// gli id utente contengono a loro volta dei trattini.
+async fn message_chat(db: &Database, chat_id: &str, sender_id: &str) -> Result<MessageChat, ChatError> {
+    if let Some(group_id) = chat_id.strip_prefix("group:") {
+        let rows = sqlx::query("SELECT user_id FROM group_members WHERE group_id = ?")
+            .bind(group_id)
+            .fetch_all(&db.pool)
+            .await
+            .map_err(|e| ChatError::internal("[MSG] Error getting group members", e))?;
+        let member_ids = rows.iter().map(|r| r.get::<String, _>("user_id")).collect();
+        return Ok(MessageChat { chat_id: chat_id.to_string(), group_id: Some(group_id.to_string()), member_ids });
+    }
+    let other = chat_id
+        .strip_prefix("private:")
+        .and_then(|pair| {
+            pair.strip_prefix(&format!("{}-", sender_id))
+                .or_else(|| pair.strip_suffix(&format!("-{}", sender_id)))
+        })
+        .ok_or_else(|| ChatError::internal("[MSG] Unrecognized chat id", chat_id))?;
+    let mut member_ids = vec![sender_id.to_string(), other.to_string()];
+    member_ids.sort();
+    Ok(MessageChat { chat_id: chat_id.to_string(), group_id: None, member_ids })
+}
+
+/// Messaggio non ritirato, con la sua chat. Per chi non partecipa alla chat il
+/// messaggio risulta inesistente.
+async fn load_message(db: &Database, user_id: &str, message_id: i64) -> Result<(sqlx::sqlite::SqliteRow, MessageChat), ChatError> {
+    let row = sqlx::query("SELECT chat_id, sender_id, message, sent_at, seq FROM encrypted_messages WHERE id = ? AND deleted_at IS NULL")
+        .bind(message_id)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error loading message", e))?
+        .ok_or_else(ChatError::message_not_found)?;
+    let chat = message_chat(db, row.get("chat_id"), row.get("sender_id")).await?;
+    if !chat.member_ids.iter().any(|m| m == user_id) {
+        return Err(ChatError::message_not_found());
+    }
+    Ok((row, chat))
+}
+
+/// Ritira un messaggio per tutti i partecipanti: può farlo il mittente o, nei gruppi,
+/// l'amministratore. Il testo cifrato e le versioni precedenti vengono eliminati.
+pub async fn delete_message(db: Arc<Database>, user_id: &str, message_id: i64) -> Result<DeletedMessage, ChatError> {
+    let (row, chat) = load_message(&db, user_id, message_id).await?;
+    let sender_id: String = row.get("sender_id");
+    if sender_id != user_id {
+        let is_admin = match &chat.group_id {
+            Some(group_id) => groups::is_group_admin(&db, group_id, user_id).await?,
+            None => false,
+        };
+        if !is_admin {
+            return Err(ChatError::new(ErrorCode::PermissionDenied, "Only the sender or a group admin can delete this message"));
+        }
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let mut tx = db.pool.begin().await
+        .map_err(|e| ChatError::internal("[MSG] Error starting delete transaction", e))?;
+    sqlx::query("UPDATE encrypted_messages SET message = '', deleted_at = ?, deleted_by = ? WHERE id = ?")
+        .bind(now)
+        .bind(user_id)
+        .bind(message_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error deleting message", e))?;
+    sqlx::query("DELETE FROM message_edits WHERE message_id = ?")
+        .bind(message_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error deleting previous message versions", e))?;
+    sqlx::query("DELETE FROM message_reactions WHERE message_id = ?")
+        .bind(message_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error deleting message reactions", e))?;
+    sqlx::query("DELETE FROM message_receipts WHERE message_id = ?")
+        .bind(message_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error deleting message receipts", e))?;
+    tx.commit().await
+        .map_err(|e| ChatError::internal("[MSG] Error committing message deletion", e))?;
+
+    println!("[MSG] Message #{} in {} deleted for everyone by {}", message_id, chat.chat_id, user_id);
+    Ok(DeletedMessage {
+        id: message_id,
+        seq: row.get("seq"),
+        sender_id,
+        deleted_by: user_id.to_string(),
+        sent_at: row.get("sent_at"),
+        deleted_at: now,
+        chat,
+    })
+}
+
+/// Modifica il testo di un messaggio: solo il mittente, entro `edit_window_secs` dall'invio.
+/// Il nuovo testo è cifrato con la chiave attuale della chat e il precedente viene
+/// conservato in `message_edits`.
+pub async fn edit_message(db: Arc<Database>, user_id: &str, message_id: i64, content: &str, config: &ServerConfig) -> Result<EditedMessage, ChatError> {
+    if content.trim().is_empty() {
+        return Err(ChatError::new(ErrorCode::InvalidInput, "Message cannot be empty"));
+    }
+    if content.len() > config.max_message_length {
+        return Err(ChatError::new(ErrorCode::InvalidInput, format!("Message too long (max {} chars)", config.max_message_length)));
+    }
+    let (row, chat) = load_message(&db, user_id, message_id).await?;
+    let sender_id: String = row.get("sender_id");
+    if sender_id != user_id {
+        return Err(ChatError::new(ErrorCode::PermissionDenied, "You can only edit your own messages"));
+    }
+    let sent_at: i64 = row.get("sent_at");
+    let now = chrono::Utc::now().timestamp();
+    if config.edit_window_secs > 0 && now - sent_at > config.edit_window_secs as i64 {
+        return Err(ChatError::new(ErrorCode::PermissionDenied, format!("Messages can only be edited within {} seconds", config.edit_window_secs)));
+    }
+
+    let encrypted_message = encrypt_message_for_storage(content, &chat.member_ids, config)
+        .map_err(|e| ChatError::internal("[MSG] Encryption failed", e))?;
+    let previous: String = row.get("message");
+    let mut tx = db.pool.begin().await
+        .map_err(|e| ChatError::internal("[MSG] Error starting edit transaction", e))?;
+    sqlx::query("INSERT INTO message_edits (message_id, message, edited_at) VALUES (?, ?, ?)")
+        .bind(message_id)
+        .bind(&previous)
+        .bind(now)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error saving previous message version", e))?;
+    sqlx::query("UPDATE encrypted_messages SET message = ?, edited_at = ? WHERE id = ?")
         .bind(&encrypted_message)
-        .bind(sent_at)
+        .bind(now)
+        .bind(message_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error updating message", e))?;
+    tx.commit().await
+        .map_err(|e| ChatError::internal("[MSG] Error committing message edit", e))?;
+
+    println!("[MSG] Message #{} in {} edited by {}", message_id, chat.chat_id, user_id);
+    Ok(EditedMessage {
+        id: message_id,
+        seq: row.get("seq"),
+        sender_id,
+        content: content.to_string(),
+        sent_at,
+        edited_at: now,
+        chat,
+    })
+}
+
+/// Aggiunge (o sostituisce) la reazione dell'utente a un messaggio della chat.
+pub async fn add_reaction(db: Arc<Database>, user_id: &str, message_id: i64, emoji: &str) -> Result<ReactionUpdate, ChatError> {
+    let emoji = emoji.trim();
+    if emoji.is_empty() || emoji.len() > MAX_REACTION_LENGTH || emoji.contains(char::is_whitespace) {
+        return Err(ChatError::new(ErrorCode::InvalidInput, format!("Reaction must be a single emoji (max {} bytes)", MAX_REACTION_LENGTH)));
+    }
+    let (row, chat) = load_message(&db, user_id, message_id).await?;
+    sqlx::query(
+        "INSERT INTO message_reactions (message_id, user_id, emoji, reacted_at) VALUES (?, ?, ?, ?)
+         ON CONFLICT (message_id, user_id) DO UPDATE SET emoji = excluded.emoji, reacted_at = excluded.reacted_at",
+    )
+        .bind(message_id)
+        .bind(user_id)
+        .bind(emoji)
+        .bind(chrono::Utc::now().timestamp())
         .execute(&db.pool)
-        .await;
-    match res {
-        Ok(_) => {
-            println!("[MSG] Private message sent to {} by {}", to_username, user_id);
-            "OK: Message sent".to_string()
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error saving reaction", e))?;
+    println!("[MSG] {} reacted {} to message #{}", user_id, emoji, message_id);
+    reaction_update(&db, message_id, &row, chat).await
+}
+
+/// Rimuove la reazione dell'utente a un messaggio (nessun errore se non c'era).
+pub async fn remove_reaction(db: Arc<Database>, user_id: &str, message_id: i64) -> Result<ReactionUpdate, ChatError> {
+    let (row, chat) = load_message(&db, user_id, message_id).await?;
+    sqlx::query("DELETE FROM message_reactions WHERE message_id = ? AND user_id = ?")
+        .bind(message_id)
+        .bind(user_id)
+        .execute(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error removing reaction", e))?;
+    println!("[MSG] {} removed their reaction to message #{}", user_id, message_id);
+    reaction_update(&db, message_id, &row, chat).await
+}
+
+async fn reaction_update(db: &Database, message_id: i64, row: &sqlx::sqlite::SqliteRow, chat: MessageChat) -> Result<ReactionUpdate, ChatError> {
+    let reactions = chat_reactions(db, &chat.chat_id, message_id, message_id).await
+        .map_err(|e| ChatError::internal("[MSG] Error loading reactions", e))?
+        .remove(&message_id)
+        .unwrap_or_default();
+    Ok(ReactionUpdate {
+        id: message_id,
+        seq: row.get("seq"),
+        sender_id: row.get("sender_id"),
+        sent_at: row.get("sent_at"),
+        reactions,
+        chat,
+    })
+}
+
+/// Reazioni ai messaggi di `chat_id` con id tra `first` e `last`, raggruppate per emoji.
+async fn chat_reactions(db: &Database, chat_id: &str, first: i64, last: i64) -> Result<std::collections::HashMap<i64, Vec<ReactionCount>>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT r.message_id, r.emoji, COALESCE(u.username, r.user_id) AS username
+         FROM message_reactions r
+         JOIN encrypted_messages m ON m.id = r.message_id
+         LEFT JOIN users u ON u.id = r.user_id
+         WHERE m.chat_id = ? AND r.message_id BETWEEN ? AND ?
+         ORDER BY r.reacted_at, r.rowid",
+    )
+        .bind(chat_id)
+        .bind(first)
+        .bind(last)
+        .fetch_all(&db.pool)
+        .await?;
+    let mut by_message: std::collections::HashMap<i64, Vec<ReactionCount>> = std::collections::HashMap::new();
+    for r in rows {
+        let reactions = by_message.entry(r.get("message_id")).or_default();
+        let emoji: String = r.get("emoji");
+        let username: String = r.get("username");
+        match reactions.iter_mut().find(|c| c.emoji == emoji) {
+            Some(count) => {
+                count.count += 1;
+                count.users.push(username);
+            }
+            None => reactions.push(ReactionCount { emoji, count: 1, users: vec![username] }),
         }
-        Err(e) => {
-            println!("[MSG] Error sending private message: {}", e);
-            format!("ERR: {}", e)
+    }
+    Ok(by_message)
+}
+
+/// Completa una pagina dello storico con le reazioni di ciascun messaggio.
+async fn attach_reactions(db: &Database, chat_id: &str, msgs: &mut [HistoryMessage]) -> Result<(), ChatError> {
+    let (Some(first), Some(last)) = (msgs.iter().map(|m| m.id).min(), msgs.iter().map(|m| m.id).max()) else {
+        return Ok(());
+    };
+    let mut reactions = chat_reactions(db, chat_id, first, last).await
+        .map_err(|e| ChatError::internal("[MSG] Error loading reactions", e))?;
+    for msg in msgs.iter_mut() {
+        msg.reactions = reactions.remove(&msg.id).unwrap_or_default();
+    }
+    Ok(())
+}
+
+/// Segna come consegnati a `user_id` i messaggi altrui con id tra `first` e `last`, nella
+/// chat del messaggio `last`. Il chiamante ha già verificato che l'utente vi partecipi.
+/// Restituisce le conferme aggiornate dei soli messaggi consegnati adesso.
+pub async fn mark_delivered(db: &Database, user_id: &str, first: i64, last: i64) -> Result<Vec<ReceiptUpdate>, ChatError> {
+    let now = chrono::Utc::now().timestamp();
+    let rows = sqlx::query(
+        "INSERT INTO message_receipts (message_id, user_id, delivered_at)
+         SELECT id, ?, ? FROM encrypted_messages
+         WHERE chat_id = (SELECT chat_id FROM encrypted_messages WHERE id = ?)
+           AND id BETWEEN ? AND ? AND sender_id != ? AND deleted_at IS NULL
+         ON CONFLICT (message_id, user_id) DO NOTHING
+         RETURNING message_id",
+    )
+        .bind(user_id)
+        .bind(now)
+        .bind(last)
+        .bind(first)
+        .bind(last)
+        .bind(user_id)
+        .fetch_all(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error marking messages delivered", e))?;
+    let ids: Vec<i64> = rows.iter().map(|r| r.get("message_id")).collect();
+    receipt_updates(db, &ids).await
+}
+
+/// Segna come letti i messaggi ricevuti nella chat privata con `with`, fino a `up_to`.
+/// Restituisce la chat e le conferme da inoltrare ai mittenti.
+pub async fn mark_private_read(db: Arc<Database>, user_id: &str, with: &str, up_to: Option<i64>) -> Result<(MessageChat, Vec<ReceiptUpdate>), ChatError> {
+    let other_id = sqlx::query("SELECT id FROM users WHERE username = ?")
+        .bind(with)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error looking up user", e))?
+        .ok_or_else(ChatError::user_not_found)?
+        .get::<String, _>("id");
+    let mut ids = vec![user_id.to_string(), other_id];
+    ids.sort();
+    let chat = MessageChat { chat_id: format!("private:{}-{}", ids[0], ids[1]), group_id: None, member_ids: ids };
+    let updates = mark_read(&db, user_id, &chat.chat_id, up_to).await?;
+    Ok((chat, updates))
+}
+
+/// Segna come letti i messaggi ricevuti nel gruppo, fino a `up_to`.
+pub async fn mark_group_read(db: Arc<Database>, user_id: &str, group_id: &str, up_to: Option<i64>) -> Result<(MessageChat, Vec<ReceiptUpdate>), ChatError> {
+    let is_member = sqlx::query("SELECT 1 FROM group_members WHERE group_id = ? AND user_id = ?")
+        .bind(group_id)
+        .bind(user_id)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error checking group membership", e))?
+        .is_some();
+    if !is_member {
+        return Err(ChatError::not_member());
+    }
+    let chat = message_chat(&db, &format!("group:{}", group_id), user_id).await?;
+    let updates = mark_read(&db, user_id, &chat.chat_id, up_to).await?;
+    Ok((chat, updates))
+}
+
+/// Un messaggio letto risulta anche consegnato: la riga viene creata se mancava.
+/// Il puntatore di lettura della chat avanza fino all'ultimo messaggio letto.
+async fn mark_read(db: &Database, user_id: &str, chat_id: &str, up_to: Option<i64>) -> Result<Vec<ReceiptUpdate>, ChatError> {
+    let now = chrono::Utc::now().timestamp();
+    sqlx::query(
+        "INSERT INTO chat_reads (user_id, chat_id, last_read_id, read_at)
+         SELECT ?, chat_id, MAX(id), ? FROM encrypted_messages
+         WHERE chat_id = ? AND id <= ?
+         GROUP BY chat_id
+         ON CONFLICT (user_id, chat_id) DO UPDATE SET
+             last_read_id = MAX(chat_reads.last_read_id, excluded.last_read_id),
+             read_at = excluded.read_at",
+    )
+        .bind(user_id)
+        .bind(now)
+        .bind(chat_id)
+        .bind(up_to.unwrap_or(i64::MAX))
+        .execute(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error updating read pointer", e))?;
+    let rows = sqlx::query(
+        "INSERT INTO message_receipts (message_id, user_id, delivered_at, read_at)
+         SELECT id, ?, ?, ? FROM encrypted_messages
+         WHERE chat_id = ? AND id <= ? AND sender_id != ? AND deleted_at IS NULL
+         ON CONFLICT (message_id, user_id) DO UPDATE SET read_at = excluded.read_at
+         WHERE message_receipts.read_at IS NULL
+         RETURNING message_id",
+    )
+        .bind(user_id)
+        .bind(now)
+        .bind(now)
+        .bind(chat_id)
+        .bind(up_to.unwrap_or(i64::MAX))
+        .bind(user_id)
+        .fetch_all(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error marking messages read", e))?;
+    let ids: Vec<i64> = rows.iter().map(|r| r.get("message_id")).collect();
+    if !ids.is_empty() {
+        println!("[MSG] {} messages in {} read by {}", ids.len(), chat_id, user_id);
+    }
+    receipt_updates(db, &ids).await
+}
+
+/// Conteggi aggiornati dei messaggi indicati, calcolati con un'unica query raggruppata.
+async fn receipt_updates(db: &Database, message_ids: &[i64]) -> Result<Vec<ReceiptUpdate>, ChatError> {
+    if message_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let rows = sqlx::query(
+        "SELECT m.id, m.chat_id, m.sender_id, m.seq, m.sent_at,
+                COUNT(r.delivered_at) AS delivered_to, COUNT(r.read_at) AS read_by
+         FROM encrypted_messages m
+         LEFT JOIN message_receipts r ON r.message_id = m.id
+         WHERE m.id IN (SELECT value FROM json_each(?))
+         GROUP BY m.id
+         ORDER BY m.id",
+    )
+        .bind(serde_json::to_string(message_ids).unwrap_or_default())
+        .fetch_all(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error loading message receipts", e))?;
+    let mut chats: std::collections::HashMap<String, MessageChat> = std::collections::HashMap::new();
+    let mut updates = Vec::with_capacity(rows.len());
+    for row in rows {
+        let chat_id: String = row.get("chat_id");
+        let sender_id: String = row.get("sender_id");
+        let chat = match chats.get(&chat_id) {
+            Some(chat) => chat.clone(),
+            None => {
+                let chat = message_chat(db, &chat_id, &sender_id).await?;
+                chats.insert(chat_id, chat.clone());
+                chat
+            }
+        };
+        updates.push(ReceiptUpdate {
+            id: row.get("id"),
+            seq: row.get("seq"),
+            sender_id,
+            sent_at: row.get("sent_at"),
+            delivered_to: row.get::<i64, _>("delivered_to") as u32,
+            read_by: row.get::<i64, _>("read_by") as u32,
+            chat,
+        });
+    }
+    Ok(updates)
+}
+
+/// Chat di un messaggio, con i membri attuali.
+pub async fn chat_of_message(db: &Database, message_id: i64) -> Result<MessageChat, ChatError> {
+    let row = sqlx::query("SELECT chat_id, sender_id FROM encrypted_messages WHERE id = ?")
+        .bind(message_id)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error loading message", e))?
+        .ok_or_else(ChatError::message_not_found)?;
+    message_chat(db, row.get("chat_id"), row.get("sender_id")).await
+}
+
+/// Messaggio salvato con il suo testo in chiaro, per l'evento `new_message`.
+pub async fn sent_message(db: &Database, message_id: i64, config: &ServerConfig) -> Result<SentMessage, ChatError> {
+    let row = sqlx::query("SELECT id, seq, chat_id, sender_id, message, sent_at, edited_at, deleted_at, deleted_by, reply_to, client_msg_id FROM encrypted_messages WHERE id = ?")
+        .bind(message_id)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error loading message", e))?
+        .ok_or_else(ChatError::message_not_found)?;
+    let sender_id: String = row.get("sender_id");
+    let chat = message_chat(db, row.get("chat_id"), &sender_id).await?;
+    let message = history_messages(db, &chat, std::slice::from_ref(&row), config).await?
+        .pop()
+        .ok_or_else(ChatError::message_not_found)?;
+    Ok(SentMessage {
+        sender_id,
+        client_msg_id: row.get("client_msg_id"),
+        message,
+        chat,
+        deleted_at: row.get("deleted_at"),
+        deleted_by: row.get("deleted_by"),
+    })
+}
+
+/// Messaggio di un evento in coda per `user_id`, nello stato attuale. `None` se non esiste
+/// più, se l'utente non è più nella chat o se l'ha svuotata dopo l'invio.
+pub async fn queued_message(db: &Database, user_id: &str, message_id: i64, config: &ServerConfig) -> Result<Option<SentMessage>, ChatError> {
+    let sent = match sent_message(db, message_id, config).await {
+        Ok(sent) => sent,
+        Err(e) if e.code == ErrorCode::MessageNotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if !sent.chat.member_ids.iter().any(|m| m == user_id) {
+        return Ok(None);
+    }
+    if cleared_at(db, user_id, &sent.chat.chat_id).await.is_some_and(|cleared| sent.message.sent_at <= cleared) {
+        return Ok(None);
+    }
+    Ok(Some(sent))
+}
+
+/// Messaggi altrui, non ritirati, oltre il puntatore di lettura dell'utente e successivi
+/// all'eventuale svuotamento della chat.
+async fn unread_count(db: &Database, user_id: &str, chat_id: &str) -> Result<u32, ChatError> {
+    let deleted_at = cleared_at(db, user_id, chat_id).await;
+    let row = sqlx::query(
+        "SELECT COUNT(*) AS unread FROM encrypted_messages
+         WHERE chat_id = ? AND sender_id != ? AND deleted_at IS NULL AND sent_at > ?
+           AND id > COALESCE((SELECT last_read_id FROM chat_reads WHERE user_id = ? AND chat_id = ?), 0)",
+    )
+        .bind(chat_id)
+        .bind(user_id)
+        .bind(deleted_at.unwrap_or(i64::MIN))
+        .bind(user_id)
+        .bind(chat_id)
+        .fetch_one(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error counting unread messages", e))?;
+    Ok(row.get::<i64, _>("unread") as u32)
+}
+
+/// Voce dell'inbox di `user_id` per una chat di cui fa parte: non letti e anteprima
+/// dell'ultimo messaggio che gli viene ancora mostrato.
+pub async fn inbox_entry(db: &Database, user_id: &str, chat: &MessageChat, config: &ServerConfig) -> Result<InboxEntry, ChatError> {
+    let chat_ref = match &chat.group_id {
+        Some(group_id) => {
+            let name = sqlx::query("SELECT name FROM groups WHERE id = ?")
+                .bind(group_id)
+                .fetch_optional(&db.pool)
+                .await
+                .map_err(|e| ChatError::internal("[MSG] Error loading group", e))?
+                .map(|row| row.get::<String, _>("name"))
+                .unwrap_or_else(|| group_id.clone());
+            ChatRef::Group { group_id: group_id.clone(), name }
+        }
+        None => {
+            let other = chat.member_ids.iter().find(|m| *m != user_id).map(String::as_str).unwrap_or(user_id);
+            let mut names = std::collections::HashMap::new();
+            ChatRef::Private { with: sender_name(db, &mut names, other).await }
+        }
+    };
+    let deleted_at = cleared_at(db, user_id, &chat.chat_id).await;
+    let last = sqlx::query("SELECT id, sender_id, message, sent_at, deleted_at FROM encrypted_messages WHERE chat_id = ? AND sent_at > ? ORDER BY id DESC LIMIT 1")
+        .bind(&chat.chat_id)
+        .bind(deleted_at.unwrap_or(i64::MIN))
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error loading last message", e))?;
+    let last_message = match last {
+        Some(row) => {
+            let mut names = std::collections::HashMap::new();
+            Some(MessagePreview {
+                id: row.get("id"),
+                sender: sender_name(db, &mut names, row.get("sender_id")).await,
+                snippet: QuotedMessage::snippet(&clear_text(chat, &row, config)),
+                sent_at: row.get("sent_at"),
+                deleted: row.get::<Option<i64>, _>("deleted_at").is_some(),
+            })
+        }
+        None => None,
+    };
+    Ok(InboxEntry { chat: chat_ref, unread: unread_count(db, user_id, &chat.chat_id).await?, last_message })
+}
+
+/// Tutte le chat dell'utente, dalla più recente: i gruppi di cui è membro (anche vuoti)
+/// e le chat private con almeno un messaggio ancora visibile.
+pub async fn inbox(db: Arc<Database>, user_id: &str, config: &ServerConfig) -> Result<Vec<InboxEntry>, ChatError> {
+    let mut chat_ids: Vec<String> = sqlx::query("SELECT group_id FROM group_members WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_all(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error loading groups", e))?
+        .iter()
+        .map(|row| format!("group:{}", row.get::<String, _>("group_id")))
+        .collect();
+    let private = sqlx::query("SELECT DISTINCT chat_id FROM encrypted_messages WHERE chat_id LIKE 'private:' || ? || '-%' OR chat_id LIKE 'private:%-' || ?")
+        .bind(user_id)
+        .bind(user_id)
+        .fetch_all(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error loading private chats", e))?;
+    chat_ids.extend(private.iter().map(|row| row.get::<String, _>("chat_id")));
+
+    let mut entries = Vec::with_capacity(chat_ids.len());
+    for chat_id in chat_ids {
+        let chat = message_chat(&db, &chat_id, user_id).await?;
+        let entry = inbox_entry(&db, user_id, &chat, config).await?;
+        if chat.group_id.is_some() || entry.last_message.is_some() {
+            entries.push(entry);
         }
     }
+    entries.sort_by_key(|e| std::cmp::Reverse(e.last_message.as_ref().map(|m| m.id)));
+    println!("[MSG] Inbox of {}: {} chat(s), {} unread", user_id, entries.len(), entries.iter().map(|e| e.unread).sum::<u32>());
+    Ok(entries)
 }
 
-pub async fn get_group_messages(db: Arc<Database>, session_token: &str, group_name: &str, config: &ServerConfig) -> String {
-    let user_id = match auth::validate_session(db.clone(), session_token).await {
-        Some(uid) => uid,
-        None => return "ERR: Invalid session".to_string(),
+/// Completa una pagina dello storico con il numero di destinatari che hanno ricevuto
+/// e letto ciascun messaggio.
+async fn attach_receipts(db: &Database, chat_id: &str, msgs: &mut [HistoryMessage]) -> Result<(), ChatError> {
+    let (Some(first), Some(last)) = (msgs.iter().map(|m| m.id).min(), msgs.iter().map(|m| m.id).max()) else {
+        return Ok(());
     };
+    let rows = sqlx::query(
+        "SELECT r.message_id, COUNT(r.delivered_at) AS delivered_to, COUNT(r.read_at) AS read_by
+         FROM message_receipts r
+         JOIN encrypted_messages m ON m.id = r.message_id
+         WHERE m.chat_id = ? AND r.message_id BETWEEN ? AND ?
+         GROUP BY r.message_id",
+    )
+        .bind(chat_id)
+        .bind(first)
+        .bind(last)
+        .fetch_all(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error loading message receipts", e))?;
+    let counts: std::collections::HashMap<i64, (u32, u32)> = rows.iter()
+        .map(|r| (r.get("message_id"), (r.get::<i64, _>("delivered_to") as u32, r.get::<i64, _>("read_by") as u32)))
+        .collect();
+    for msg in msgs.iter_mut() {
+        (msg.delivered_to, msg.read_by) = counts.get(&msg.id).copied().unwrap_or_default();
+    }
+    Ok(())
+}
+
+pub async fn get_group_messages(db: Arc<Database>, user_id: &str, group_name: &str, page: &HistoryPage, config: &ServerConfig) -> Result<(Vec<HistoryMessage>, bool), ChatError> {
     // group_name is actually group_id in this context
     let group_row = sqlx::query("SELECT id FROM groups WHERE id = ?")
         .bind(group_name)
@@ -195,92 +902,216 @@ pub async fn get_group_messages(db: Arc<Database>, session_token: &str, group_na
         .await;
     let group_id = match group_row {
         Ok(Some(row)) => row.get::<String,_>("id"),
-        _ => return "ERR: Group not found".to_string(),
+        _ => return Err(ChatError::group_not_found()),
     };
     let is_member = sqlx::query("SELECT 1 FROM group_members WHERE group_id = ? AND user_id = ?")
         .bind(&group_id)
-        .bind(&user_id)
+        .bind(user_id)
         .fetch_optional(&db.pool)
         .await
         .ok()
         .flatten()
         .is_some();
     if !is_member {
-        return "ERR: Not a group member".to_string();
+        return Err(ChatError::not_member());
     }
     let chat_id = format!("group:{}", group_id);
     
-    // Check if user has deleted this chat and get the deletion timestamp
-    let deleted_at = sqlx::query("SELECT deleted_at FROM deleted_chats WHERE user_id = ? AND chat_id = ?")
-        .bind(&user_id)
-        .bind(&chat_id)
+    let deleted_at = cleared_at(&db, user_id, &chat_id).await;
+    let (rows, has_more) = fetch_history_page(&db, &chat_id, deleted_at, page, config).await
+        .map_err(|e| ChatError::internal("[MSG] Error getting group messages", e))?;
+    // I membri attuali determinano la chiave più recente della chat
+    let chat = message_chat(&db, &chat_id, user_id).await?;
+    let msgs = history_messages(&db, &chat, &rows, config).await?;
+    Ok((msgs, has_more))
+}
+
+/// Momento in cui l'utente ha svuotato la chat: i messaggi precedenti non gli vengono più mostrati.
+async fn cleared_at(db: &Database, user_id: &str, chat_id: &str) -> Option<i64> {
+    sqlx::query("SELECT deleted_at FROM deleted_chats WHERE user_id = ? AND chat_id = ?")
+        .bind(user_id)
+        .bind(chat_id)
         .fetch_optional(&db.pool)
         .await
         .ok()
         .flatten()
-        .map(|row| row.get::<i64, _>("deleted_at"));
-    
-    let rows = sqlx::query("SELECT sender_id, message, sent_at FROM encrypted_messages WHERE chat_id = ? ORDER BY sent_at ASC")
-        .bind(&chat_id)
-        .fetch_all(&db.pool)
-        .await;
-    match rows {
-        Ok(rows) => {
-            // Get current group members for the latest key
-            let current_members_rows = sqlx::query("SELECT user_id FROM group_members WHERE group_id = ?")
-                .bind(&group_id)
-                .fetch_all(&db.pool)
-                .await;
-            let current_members: Vec<String> = match current_members_rows {
-                Ok(rows) => rows.iter().map(|r| r.get::<String, _>("user_id")).collect::<Vec<String>>(),
-                Err(_) => vec![],
-            };
-
-            // Get all historical member combinations for decryption fallback
-            let all_members_rows = sqlx::query("SELECT DISTINCT user_id FROM group_members WHERE group_id = ?")
-                .bind(&group_id)
-                .fetch_all(&db.pool)
-                .await;
-            let all_historical_members: Vec<String> = match all_members_rows {
-                Ok(rows) => rows.iter().map(|r| r.get::<String, _>("user_id")).collect::<Vec<String>>(),
-                Err(_) => vec![],
-            };
-
-            let mut msgs: Vec<String> = Vec::with_capacity(rows.len());
-            for r in rows.iter() {
-                let sender_id: String = r.get("sender_id");
-                // Per i gruppi, converti sender_id in username
-                let sender_name = if let Ok(Some(user_row)) = sqlx::query("SELECT username FROM users WHERE id = ?")
-                    .bind(&sender_id)
-                    .fetch_optional(&db.pool)
-                    .await
-                {
-                    user_row.get::<String, _>("username")
-                } else {
-                    sender_id.clone() // fallback to ID if username not found
-                };
-                let msg: String = r.get("message");
-                let ts: i64 = r.get("sent_at");
-                
-                // Filter out messages before deletion timestamp if user deleted this chat
-                if let Some(deleted_timestamp) = deleted_at {
-                    if ts <= deleted_timestamp {
-                        continue; // Skip this message
-                    }
-                }
-                
-                // Try multiple decryption strategies for historical messages
-                let clear = decrypt_group_message_with_fallback(&msg, &current_members, &all_historical_members, &sender_id, config);
-                
-                msgs.push(format!("[{}] {}: {}", ts, sender_name, clear));
+        .map(|row| row.get::<i64, _>("deleted_at"))
+}
+
+/// Testo in chiaro di un messaggio della chat (vuoto se ritirato per tutti).
+fn clear_text(chat: &MessageChat, row: &sqlx::sqlite::SqliteRow, config: &ServerConfig) -> String {
+    if row.get::<Option<i64>, _>("deleted_at").is_some() {
+        return String::new();
+    }
+    let msg: String = row.get("message");
+    match &chat.group_id {
+        // Try multiple decryption strategies for historical messages
+        Some(_) => decrypt_group_message_with_fallback(&msg, &chat.member_ids, &chat.member_ids, row.get("sender_id"), config),
+        None => decrypt_message_from_storage(&msg, &chat.member_ids, config).unwrap_or_else(|_| "[DECRYPTION FAILED]".to_string()),
+    }
+}
+
+/// Username dei mittenti, letti una sola volta per pagina.
+async fn sender_name(db: &Database, names: &mut std::collections::HashMap<String, String>, sender_id: &str) -> String {
+    if let Some(name) = names.get(sender_id) {
+        return name.clone();
+    }
+    let name = sqlx::query("SELECT username FROM users WHERE id = ?")
+        .bind(sender_id)
+        .fetch_optional(&db.pool)
+        .await
+        .ok()
+        .flatten()
+        .map(|row| row.get::<String, _>("username"))
+        .unwrap_or_else(|| sender_id.to_string()); // fallback to ID if username not found
+    names.insert(sender_id.to_string(), name.clone());
+    name
+}
+
+/// Converte le righe di `encrypted_messages` di una chat in messaggi dello storico, con
+/// testo in chiaro, reazioni, conferme e l'estratto dei messaggi citati.
+async fn history_messages(db: &Database, chat: &MessageChat, rows: &[sqlx::sqlite::SqliteRow], config: &ServerConfig) -> Result<Vec<HistoryMessage>, ChatError> {
+    let mut names = std::collections::HashMap::new();
+    let mut msgs: Vec<HistoryMessage> = Vec::with_capacity(rows.len());
+    for r in rows {
+        let sender_id: String = r.get("sender_id");
+        msgs.push(HistoryMessage {
+            id: r.get("id"),
+            seq: r.get("seq"),
+            sender: sender_name(db, &mut names, &sender_id).await,
+            content: clear_text(chat, r, config),
+            sent_at: r.get("sent_at"),
+            edited_at: r.get("edited_at"),
+            deleted: r.get::<Option<i64>, _>("deleted_at").is_some(),
+            reactions: Vec::new(),
+            reply_to: r.get("reply_to"),
+            quoted: None,
+            delivered_to: 0,
+            read_by: 0,
+        });
+    }
+    attach_reactions(db, &chat.chat_id, &mut msgs).await?;
+    attach_receipts(db, &chat.chat_id, &mut msgs).await?;
+    attach_quotes(db, chat, &mut msgs, &mut names, config).await?;
+    Ok(msgs)
+}
+
+/// Completa le risposte con l'estratto del messaggio citato: dalla pagina stessa se
+/// presente, altrimenti leggendolo dalla chat.
+async fn attach_quotes(db: &Database, chat: &MessageChat, msgs: &mut [HistoryMessage], names: &mut std::collections::HashMap<String, String>, config: &ServerConfig) -> Result<(), ChatError> {
+    let mut quotes: std::collections::HashMap<i64, QuotedMessage> = msgs.iter()
+        .map(|m| (m.id, QuotedMessage { id: m.id, sender: m.sender.clone(), snippet: QuotedMessage::snippet(&m.content), deleted: m.deleted }))
+        .collect();
+    for msg in msgs.iter_mut() {
+        let Some(parent_id) = msg.reply_to else { continue };
+        if let std::collections::hash_map::Entry::Vacant(entry) = quotes.entry(parent_id) {
+            if let Some(quote) = load_quote(db, chat, parent_id, names, config).await? {
+                entry.insert(quote);
             }
-            format!("OK: Messages:\n{}", msgs.join("\n"))
-        }
-        Err(e) => {
-            println!("[MSG] Error getting group messages: {}", e);
-            format!("ERR: {}", e)
         }
+        msg.quoted = quotes.get(&parent_id).cloned();
     }
+    Ok(())
+}
+
+async fn load_quote(db: &Database, chat: &MessageChat, message_id: i64, names: &mut std::collections::HashMap<String, String>, config: &ServerConfig) -> Result<Option<QuotedMessage>, ChatError> {
+    let row = sqlx::query("SELECT id, sender_id, message, deleted_at FROM encrypted_messages WHERE id = ? AND chat_id = ?")
+        .bind(message_id)
+        .bind(&chat.chat_id)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error loading quoted message", e))?;
+    let Some(row) = row else { return Ok(None) };
+    Ok(Some(QuotedMessage {
+        id: message_id,
+        sender: sender_name(db, names, row.get("sender_id")).await,
+        snippet: QuotedMessage::snippet(&clear_text(chat, &row, config)),
+        deleted: row.get::<Option<i64>, _>("deleted_at").is_some(),
+    }))
+}
+
+/// Estratto di un messaggio visibile all'utente, da allegare agli eventi delle risposte.
+pub async fn quoted_message(db: &Database, user_id: &str, message_id: i64, config: &ServerConfig) -> Option<QuotedMessage> {
+    let (_, chat) = load_message(db, user_id, message_id).await.ok()?;
+    load_quote(db, &chat, message_id, &mut std::collections::HashMap::new(), config).await.ok().flatten()
+}
+
+/// Discussione di un messaggio: risale alla radice e restituisce la radice con tutte le
+/// risposte (anche indirette) in ordine cronologico, al massimo `MAX_HISTORY_PAGE`.
+pub async fn get_thread(db: Arc<Database>, user_id: &str, message_id: i64, config: &ServerConfig) -> Result<(Vec<HistoryMessage>, bool), ChatError> {
+    let (_, chat) = load_message(&db, user_id, message_id).await?;
+    let root_id: i64 = sqlx::query(r#"
+        WITH RECURSIVE up(id, reply_to) AS (
+            SELECT id, reply_to FROM encrypted_messages WHERE id = ?
+            UNION
+            SELECT m.id, m.reply_to FROM encrypted_messages m JOIN up ON m.id = up.reply_to WHERE m.chat_id = ?
+        )
+        SELECT MIN(id) AS id FROM up
+    "#)
+        .bind(message_id)
+        .bind(&chat.chat_id)
+        .fetch_one(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error finding thread root", e))?
+        .get("id");
+    let mut rows = sqlx::query(r#"
+        WITH RECURSIVE thread(id) AS (
+            SELECT ?
+            UNION
+            SELECT m.id FROM encrypted_messages m JOIN thread t ON m.reply_to = t.id WHERE m.chat_id = ?
+        )
+        SELECT id, seq, sender_id, message, sent_at, edited_at, deleted_at, reply_to FROM encrypted_messages
+        WHERE id IN (SELECT id FROM thread) AND sent_at > ?
+        ORDER BY id ASC LIMIT ?
+    "#)
+        .bind(root_id)
+        .bind(&chat.chat_id)
+        .bind(cleared_at(&db, user_id, &chat.chat_id).await.unwrap_or(i64::MIN))
+        .bind(MAX_HISTORY_PAGE as i64 + 1)
+        .fetch_all(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[MSG] Error loading thread", e))?;
+    let has_more = rows.len() > MAX_HISTORY_PAGE as usize;
+    rows.truncate(MAX_HISTORY_PAGE as usize);
+    println!("[MSG] Thread of message #{} (root #{}): {} message(s)", message_id, root_id, rows.len());
+    let msgs = history_messages(&db, &chat, &rows, config).await?;
+    Ok((msgs, has_more))
+}
+
+/// Legge una pagina dello storico di `chat_id` in ordine cronologico, escludendo i messaggi
+/// anteriori alla cancellazione della chat da parte dell'utente. Restituisce anche se
+/// esistono altri messaggi oltre la pagina nella direzione richiesta.
+async fn fetch_history_page(
+    db: &Database,
+    chat_id: &str,
+    deleted_at: Option<i64>,
+    page: &HistoryPage,
+    config: &ServerConfig,
+) -> Result<(Vec<sqlx::sqlite::SqliteRow>, bool), sqlx::Error> {
+    let limit = page.limit.unwrap_or(config.history_page_size).clamp(1, MAX_HISTORY_PAGE) as usize;
+    // Solo `after`: si avanza verso i messaggi più recenti; altrimenti si parte dal più recente
+    let forward = page.after.is_some() && page.before.is_none();
+    let sql = if forward {
+        "SELECT id, seq, sender_id, message, sent_at, edited_at, deleted_at, reply_to FROM encrypted_messages WHERE chat_id = ? AND sent_at > ? AND id > ? AND id < ? ORDER BY id ASC LIMIT ?"
+    } else {
+        "SELECT id, seq, sender_id, message, sent_at, edited_at, deleted_at, reply_to FROM encrypted_messages WHERE chat_id = ? AND sent_at > ? AND id > ? AND id < ? ORDER BY id DESC LIMIT ?"
+    };
+    let mut rows = sqlx::query(sql)
+        .bind(chat_id)
+        .bind(deleted_at.unwrap_or(i64::MIN))
+        .bind(page.after.unwrap_or(0))
+        .bind(page.before.unwrap_or(i64::MAX))
+        // Una riga in più per sapere se la pagina è l'ultima
+        .bind(limit as i64 + 1)
+        .fetch_all(&db.pool)
+        .await?;
+    let has_more = rows.len() > limit;
+    rows.truncate(limit);
+    if !forward {
+        rows.reverse();
+    }
+    println!("[MSG] History page for {}: {} message(s), has_more={}", chat_id, rows.len(), has_more);
+    Ok((rows, has_more))
 }
 
 /// Try multiple decryption strategies for group messages
@@ -372,100 +1203,43 @@ fn generate_combinations_recursive(
     }
 }
 
-pub async fn get_private_messages(db: Arc<Database>, session_token: &str, other_username: &str, config: &ServerConfig) -> String {
-    let user_id = match auth::validate_session(db.clone(), session_token).await {
-        Some(uid) => uid,
-        None => return "ERR: Invalid session".to_string(),
-    };
-    
-    // Ottieni anche il nostro username per i messaggi
-    let my_username = match sqlx::query("SELECT username FROM users WHERE id = ?")
-        .bind(&user_id)
-        .fetch_optional(&db.pool)
-        .await
-    {
-        Ok(Some(row)) => row.get::<String,_>("username"),
-        _ => "Unknown".to_string(),
-    };
-    
+pub async fn get_private_messages(db: Arc<Database>, user_id: &str, other_username: &str, page: &HistoryPage, config: &ServerConfig) -> Result<(Vec<HistoryMessage>, bool), ChatError> {
+
+
     let to_row = sqlx::query("SELECT id FROM users WHERE username = ?")
         .bind(other_username)
         .fetch_optional(&db.pool)
         .await;
     let to_id = match to_row {
         Ok(Some(row)) => row.get::<String,_>("id"),
-        _ => return "ERR: User not found".to_string(),
+        _ => return Err(ChatError::user_not_found()),
     };
-    let mut ids = vec![user_id.clone(), to_id.clone()];
+    let mut ids = vec![user_id.to_string(), to_id.clone()];
     ids.sort();
     let chat_id = format!("private:{}-{}", ids[0], ids[1]);
     
-    // Check if user has deleted this chat and get the deletion timestamp
-    let deleted_at = sqlx::query("SELECT deleted_at FROM deleted_chats WHERE user_id = ? AND chat_id = ?")
-        .bind(&user_id)
-        .bind(&chat_id)
-        .fetch_optional(&db.pool)
-        .await
-        .ok()
-        .flatten()
-        .map(|row| row.get::<i64, _>("deleted_at"));
+    let deleted_at = cleared_at(&db, user_id, &chat_id).await;
     
     // Force WAL checkpoint to ensure we see the latest messages from WebSocket connections
     let _ = sqlx::query("PRAGMA wal_checkpoint;")
         .execute(&db.pool)
         .await;
     
-    let rows = sqlx::query("SELECT sender_id, message, sent_at FROM encrypted_messages WHERE chat_id = ? ORDER BY sent_at ASC")
-        .bind(&chat_id)
-        .fetch_all(&db.pool)
-        .await;
-    match rows {
-        Ok(rows) => {
-            let msgs: Vec<String> = rows.iter().filter_map(|r| {
-                let sender: String = r.get("sender_id");
-                // Converti sender_id in username
-                let sender_name = if sender == user_id {
-                    my_username.clone()
-                } else {
-                    other_username.to_string()
-                };
-                let msg: String = r.get("message");
-                let ts: i64 = r.get("sent_at");
-                
-                // Filter out messages before deletion timestamp if user deleted this chat
-                if let Some(deleted_timestamp) = deleted_at {
-                    if ts <= deleted_timestamp {
-                        return None; // Skip this message
-                    }
-                }
-                
-                // For private chats the participants are the two user ids we already computed in `ids`
-                let clear = match decrypt_message_from_storage(&msg, &ids, config) {
-                    Ok(s) => s,
-                    Err(_) => "[DECRYPTION FAILED]".to_string(),
-                };
-                format!("[{}] {}: {}", ts, sender_name, clear).into()
-            }).collect();
-            format!("OK: Messages:\n{}", msgs.join("\n"))
-        }
-        Err(e) => {
-            println!("[MSG] Error getting private messages: {}", e);
-            format!("ERR: {}", e)
-        }
-    }
+    let (rows, has_more) = fetch_history_page(&db, &chat_id, deleted_at, page, config).await
+        .map_err(|e| ChatError::internal("[MSG] Error getting private messages", e))?;
+    // For private chats the participants are the two user ids we already computed in `ids`
+    let chat = MessageChat { chat_id, group_id: None, member_ids: ids };
+    let msgs = history_messages(&db, &chat, &rows, config).await?;
+    Ok((msgs, has_more))
 }
 
-pub async fn delete_group_messages(db: Arc<Database>, session_token: &str, group_id: &str) -> String {
-    let user_id = match auth::validate_session(db.clone(), session_token).await {
-        Some(uid) => uid,
-        None => return "ERR: Invalid session".to_string(),
-    };
-    
+pub async fn delete_group_messages(db: Arc<Database>, user_id: &str, group_id: &str) -> Result<String, ChatError> {
+
     // Insert into deleted_chats table to track user-specific deletion
     let now = chrono::Utc::now().timestamp();
     let chat_id = format!("group:{}", group_id);
     let res = sqlx::query("INSERT OR REPLACE INTO deleted_chats (user_id, chat_id, deleted_at) VALUES (?, ?, ?)")
-        .bind(&user_id)
+        .bind(user_id)
         .bind(&chat_id)
         .bind(now)
         .execute(&db.pool)
@@ -474,37 +1248,30 @@ pub async fn delete_group_messages(db: Arc<Database>, session_token: &str, group
     match res {
         Ok(_) => {
             println!("[MSG] Marked group messages as deleted for user {} in group {}", user_id, group_id);
-            "OK: Messages discarded for you only".to_string()
-        }
-        Err(e) => {
-            println!("[MSG] Error marking group messages as deleted: {}", e);
-            format!("ERR: {}", e)
+            Ok("Messages discarded for you only".to_string())
         }
+        Err(e) => Err(ChatError::internal("[MSG] Error marking group messages as deleted", e)),
     }
 }
     
 
-pub async fn delete_private_messages(db: Arc<Database>, session_token: &str, other_username: &str) -> String {
-    let user_id = match auth::validate_session(db.clone(), session_token).await {
-        Some(uid) => uid,
-        None => return "ERR: Invalid session".to_string(),
-    };
+pub async fn delete_private_messages(db: Arc<Database>, user_id: &str, other_username: &str) -> Result<String, ChatError> {
     let to_row = sqlx::query("SELECT id FROM users WHERE username = ?")
         .bind(other_username)
         .fetch_optional(&db.pool)
         .await;
     let to_id = match to_row {
         Ok(Some(row)) => row.get::<String,_>("id"),
-        _ => return "ERR: User not found".to_string(),
+        _ => return Err(ChatError::user_not_found()),
     };
-    let mut ids = [user_id.clone(), to_id.clone()];
+    let mut ids = [user_id.to_string(), to_id.clone()];
     ids.sort();
     let chat_id = format!("private:{}-{}", ids[0], ids[1]);
     
     // Insert into deleted_chats table to track user-specific deletion
     let now = chrono::Utc::now().timestamp();
     let res = sqlx::query("INSERT OR REPLACE INTO deleted_chats (user_id, chat_id, deleted_at) VALUES (?, ?, ?)")
-        .bind(&user_id)
+        .bind(user_id)
         .bind(&chat_id)
         .bind(now)
         .execute(&db.pool)
@@ -513,11 +1280,8 @@ pub async fn delete_private_messages(db: Arc<Database>, session_token: &str, oth
     match res {
         Ok(_) => {
             println!("[MSG] Marked private messages as deleted for user {} with {}", user_id, other_username);
-            "OK: Messages discarded for you only".to_string()
-        }
-        Err(e) => {
-            println!("[MSG] Error marking private messages as deleted: {}", e);
-            format!("ERR: {}", e)
+            Ok("Messages discarded for you only".to_string())
         }
+        Err(e) => Err(ChatError::internal("[MSG] Error marking private messages as deleted", e)),
     }
 }
@@ -8,4 +8,11 @@ pub mod groups;
 pub mod messages;
 pub mod presence;
 pub mod websocket;
+pub mod typing;
+pub mod sync;
 pub mod redis_cache;
+pub mod mtls;
+pub mod limits;
+pub mod rate_limit;
+pub mod shutdown;
+pub mod tls_reload;
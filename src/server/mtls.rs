@@ -0,0 +1,135 @@
+// src/server/mtls.rs
+// Autenticazione dei dispositivi tramite certificato client (mutual TLS)
+use crate::server::{config::ServerConfig, database::Database};
+use rustls::server::{AllowAnyAuthenticatedClient, ClientCertVerifier};
+use sqlx::Row;
+use std::fs::File;
+use std::io::BufReader as StdBufReader;
+use std::sync::Arc;
+
+/// Utente a cui è associato il certificato client di una connessione.
+#[derive(Debug, Clone)]
+pub struct CertIdentity {
+    pub user_id: String,
+    pub username: String,
+}
+
+/// Verificatore che accetta solo certificati client firmati dalla CA indicata.
+pub fn client_verifier(ca_path: &str) -> anyhow::Result<Arc<dyn ClientCertVerifier>> {
+    let file = File::open(ca_path)
+        .map_err(|e| anyhow::anyhow!("Failed to open client CA file '{}': {}", ca_path, e))?;
+    let certs = rustls_pemfile::certs(&mut StdBufReader::new(file))?;
+    let mut roots = rustls::RootCertStore::empty();
+    let (added, _ignored) = roots.add_parsable_certificates(&certs);
+    if added == 0 {
+        return Err(anyhow::anyhow!("No valid CA certificates found in {}", ca_path));
+    }
+    println!("[TLS] Client certificates required, signed by {} ({} CA certificate(s))", ca_path, added);
+    Ok(AllowAnyAuthenticatedClient::new(roots).boxed())
+}
+
+/// Risolve il certificato client (già verificato dalla CA) nell'utente configurato in
+/// `ServerConfig::client_cert_users`. In caso di errore restituisce il motivo.
+pub async fn identify(db: &Database, config: &ServerConfig, cert_der: &[u8]) -> Result<CertIdentity, String> {
+    let cn = subject_common_name(cert_der)?;
+    let username = config
+        .client_cert_users
+        .get(&cn)
+        .ok_or_else(|| format!("subject CN={} is not mapped to a user", cn))?;
+    let row = sqlx::query("SELECT id FROM users WHERE username = ?")
+        .bind(username)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| format!("database error: {}", e))?;
+    match row {
+        Some(r) => Ok(CertIdentity { user_id: r.get("id"), username: username.clone() }),
+        None => Err(format!("subject CN={} maps to unknown user {}", cn, username)),
+    }
+}
+
+/// Registra in `session_events` un certificato client rifiutato. L'utente non è noto:
+/// `user_id` resta vuoto e il motivo va in `detail`.
+pub async fn record_failure(db: &Database, peer: std::net::SocketAddr, reason: &str) {
+    println!("[TLS] Client certificate rejected for {}: {}", peer, reason);
+    let res = sqlx::query("INSERT INTO session_events (user_id, event_type, created_at, detail) VALUES ('', 'client_cert_rejected', ?, ?)")
+        .bind(chrono::Utc::now().timestamp())
+        .bind(format!("{}: {}", peer, reason))
+        .execute(&db.pool)
+        .await;
+    if let Err(e) = res {
+        println!("[DB] Failed to record client_cert_rejected event: {}", e);
+    }
+}
+
+/// Motivo del fallimento se l'handshake TLS è stato rifiutato per il certificato client.
+pub fn handshake_failure(err: &std::io::Error) -> Option<String> {
+    match err.get_ref()?.downcast_ref::<rustls::Error>()? {
+        e @ (rustls::Error::NoCertificatesPresented | rustls::Error::InvalidCertificate(_)) => Some(e.to_string()),
+        _ => None,
+    }
+}
+
+/// Common name del subject di un certificato X.509 in DER.
+///
+/// Il subject deve contenere esattamente un CN, cercato in tutti gli RDN (anche quelli
+/// multi-valore come `OU=Devices+CN=bob`): con più CN il certificato è ambiguo e viene
+/// rifiutato invece di sceglierne uno. Il subjectAltName non è considerato perché
+/// `client_cert_users` associa gli utenti al CN.
+pub fn subject_common_name(cert_der: &[u8]) -> Result<String, String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert_der)
+        .map_err(|e| format!("invalid certificate: {}", e))?;
+    let mut names = cert.subject().iter_common_name();
+    let cn = names.next().ok_or_else(|| "certificate subject has no common name".to_string())?;
+    if names.next().is_some() {
+        return Err("certificate subject has more than one common name".to_string());
+    }
+    cn.as_str()
+        .map(str::to_string)
+        .map_err(|e| format!("unreadable subject common name: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Certificato di prova con subject `O=Ruggine, CN=alice-laptop`.
+    const CERT_WITH_CN: &str = include_str!("testdata/client_cn.pem");
+    /// Certificato di prova con subject `O=Ruggine, OU=Devices`, senza CN.
+    const CERT_WITHOUT_CN: &str = include_str!("testdata/client_no_cn.pem");
+    /// Certificato di prova con subject `O=Ruggine, CN=alice-laptop, CN=mallory-phone`.
+    const CERT_TWO_CN: &str = include_str!("testdata/client_two_cn.pem");
+    /// Certificato di prova con subject `O=Ruggine, OU=Devices + CN=bob-tablet`.
+    const CERT_MULTIVALUED_RDN: &str = include_str!("testdata/client_multivalued_rdn.pem");
+
+    fn der(pem: &str) -> Vec<u8> {
+        rustls_pemfile::certs(&mut pem.as_bytes()).unwrap().remove(0)
+    }
+
+    #[test]
+    fn common_name_of_valid_certificate() {
+        assert_eq!(subject_common_name(&der(CERT_WITH_CN)).as_deref(), Ok("alice-laptop"));
+    }
+
+    #[test]
+    fn subject_without_common_name() {
+        assert!(subject_common_name(&der(CERT_WITHOUT_CN)).is_err());
+    }
+
+    #[test]
+    fn common_name_in_multivalued_rdn() {
+        assert_eq!(subject_common_name(&der(CERT_MULTIVALUED_RDN)).as_deref(), Ok("bob-tablet"));
+    }
+
+    #[test]
+    fn multiple_common_names_are_rejected() {
+        assert!(subject_common_name(&der(CERT_TWO_CN)).is_err());
+    }
+
+    #[test]
+    fn truncated_certificate_is_rejected() {
+        let cert = der(CERT_WITH_CN);
+        for len in [0, 1, 2, 4, cert.len() / 2, cert.len() - 1] {
+            assert!(subject_common_name(&cert[..len]).is_err(), "truncated to {} bytes", len);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+// src/server/rate_limit.rs
+// Limiti di frequenza (token bucket) per classe di comando, applicati ai comandi
+// a righe e ai messaggi WebSocket
+use crate::common::error::{ChatError, ErrorCode};
+use crate::common::protocol::Command;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Oltre questo numero di bucket quelli già pieni (inattivi) vengono rimossi
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Classe di comando con un proprio limite.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommandClass {
+    /// Login, registrazione e validazione della sessione
+    Auth,
+    /// Invio, modifica e ritiro di messaggi, reazioni, richieste di amicizia e inviti
+    Message,
+    /// Tutti gli altri comandi (liste, storico, gestione gruppi)
+    Lookup,
+}
+
+impl CommandClass {
+    pub fn of(command: &Command) -> Self {
+        match command {
+            Command::Register { .. } | Command::Login { .. } | Command::CertificateLogin | Command::ValidateSession => CommandClass::Auth,
+            Command::SendPrivateMessage { .. }
+            | Command::SendGroupMessage { .. }
+            | Command::EditMessage { .. }
+            | Command::DeleteMessage { .. }
+            | Command::AddReaction { .. }
+            | Command::RemoveReaction { .. }
+            | Command::SendFriendRequest { .. }
+            | Command::Invite { .. } => CommandClass::Message,
+            _ => CommandClass::Lookup,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            CommandClass::Auth => "authentication attempts",
+            CommandClass::Message => "messages",
+            CommandClass::Lookup => "requests",
+        }
+    }
+}
+
+/// Chi consuma i token: l'utente autenticato (condiviso tra TCP e WebSocket),
+/// la singola connessione, o l'indirizzo IP per i tentativi di autenticazione.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RateSubject {
+    User(String),
+    Connection(SocketAddr),
+    Address(IpAddr),
+}
+
+impl RateSubject {
+    /// Soggetto per i comandi eseguiti senza sessione: i tentativi di autenticazione
+    /// contano per indirizzo IP (riconnettersi non azzera il limite), gli altri per connessione.
+    pub fn unauthenticated(class: CommandClass, peer: SocketAddr) -> Self {
+        match class {
+            CommandClass::Auth => RateSubject::Address(peer.ip()),
+            _ => RateSubject::Connection(peer),
+        }
+    }
+}
+
+/// `burst` richieste ogni `per_secs` secondi (formato `N/S`, es. `20/10`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateSpec {
+    pub burst: u32,
+    pub per_secs: u32,
+}
+
+impl RateSpec {
+    /// `None` per `0`, `off` o un valore non valido: nessun limite.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (burst, per_secs) = value.trim().split_once('/')?;
+        let spec = RateSpec { burst: burst.trim().parse().ok()?, per_secs: per_secs.trim().parse().ok()? };
+        (spec.burst > 0 && spec.per_secs > 0).then_some(spec)
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.burst as f64 / self.per_secs as f64
+    }
+}
+
+/// Limiti configurati per classe; `None` disattiva il limite.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub auth: Option<RateSpec>,
+    pub message: Option<RateSpec>,
+    pub lookup: Option<RateSpec>,
+}
+
+impl RateLimits {
+    pub fn from_env() -> Self {
+        let spec = |var: &str, default: &str| RateSpec::parse(&std::env::var(var).unwrap_or_else(|_| default.to_string()));
+        Self {
+            auth: spec("RATE_LIMIT_AUTH", "10/60"),
+            message: spec("RATE_LIMIT_MESSAGES", "20/10"),
+            lookup: spec("RATE_LIMIT_LOOKUPS", "60/10"),
+        }
+    }
+
+    fn spec(&self, class: CommandClass) -> Option<RateSpec> {
+        match class {
+            CommandClass::Auth => self.auth,
+            CommandClass::Message => self.message,
+            CommandClass::Lookup => self.lookup,
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, spec: RateSpec, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * spec.refill_per_sec()).min(spec.burst as f64);
+        self.updated = now;
+    }
+}
+
+/// Bucket condivisi da porta comandi e WebSocket.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Arc<Mutex<HashMap<(RateSubject, CommandClass), TokenBucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self { limits, buckets: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Consuma un token; se il bucket è vuoto restituisce `RateLimited` con l'attesa suggerita.
+    pub fn check(&self, subject: RateSubject, class: CommandClass) -> Result<(), ChatError> {
+        let Some(spec) = self.limits.spec(class) else { return Ok(()) };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|(_, class), bucket| match self.limits.spec(*class) {
+                Some(spec) => {
+                    bucket.refill(spec, now);
+                    bucket.tokens < spec.burst as f64
+                }
+                None => false,
+            });
+        }
+        let bucket = buckets
+            .entry((subject, class))
+            .or_insert(TokenBucket { tokens: spec.burst as f64, updated: now });
+        bucket.refill(spec, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let retry_after = (1.0 - bucket.tokens) / spec.refill_per_sec();
+        Err(ChatError::new(
+            ErrorCode::RateLimited,
+            format!("Too many {} (limit {} every {}s), retry in {:.1}s", class.description(), spec.burst, spec.per_secs, retry_after),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const PEER: &str = "127.0.0.1:40000";
+
+    fn limiter(spec: Option<RateSpec>) -> RateLimiter {
+        RateLimiter::new(RateLimits { auth: None, message: spec, lookup: spec })
+    }
+
+    #[test]
+    fn parse_valid_specs() {
+        assert_eq!(RateSpec::parse("20/10"), Some(RateSpec { burst: 20, per_secs: 10 }));
+        assert_eq!(RateSpec::parse(" 5 / 60 "), Some(RateSpec { burst: 5, per_secs: 60 }));
+    }
+
+    #[test]
+    fn parse_malformed_specs() {
+        for value in ["", "off", "0", "20", "20/", "/10", "x/10", "20/y", "-1/10", "0/10", "10/0", "1/2/3", "1.5/10"] {
+            assert_eq!(RateSpec::parse(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn refill_over_elapsed_time() {
+        let spec = RateSpec { burst: 10, per_secs: 5 };
+        let start = Instant::now();
+        let mut bucket = TokenBucket { tokens: 0.0, updated: start };
+        bucket.refill(spec, start + Duration::from_millis(1500));
+        assert!((bucket.tokens - 3.0).abs() < 1e-9, "tokens = {}", bucket.tokens);
+        bucket.refill(spec, start + Duration::from_secs(2));
+        assert!((bucket.tokens - 4.0).abs() < 1e-9, "tokens = {}", bucket.tokens);
+    }
+
+    #[test]
+    fn refill_is_capped_at_burst() {
+        let spec = RateSpec { burst: 10, per_secs: 5 };
+        let start = Instant::now();
+        let mut bucket = TokenBucket { tokens: 9.5, updated: start };
+        bucket.refill(spec, start + Duration::from_secs(3600));
+        assert_eq!(bucket.tokens, 10.0);
+    }
+
+    #[test]
+    fn burst_then_rate_limited() {
+        let limiter = limiter(Some(RateSpec { burst: 3, per_secs: 3600 }));
+        let user = || RateSubject::User("alice".to_string());
+        for _ in 0..3 {
+            assert!(limiter.check(user(), CommandClass::Message).is_ok());
+        }
+        let error = limiter.check(user(), CommandClass::Message).unwrap_err();
+        assert_eq!(error.code, ErrorCode::RateLimited);
+        // Altri utenti e altre classi hanno bucket separati
+        assert!(limiter.check(RateSubject::User("bob".to_string()), CommandClass::Message).is_ok());
+        assert!(limiter.check(user(), CommandClass::Lookup).is_ok());
+    }
+
+    #[test]
+    fn disabled_class_is_unlimited() {
+        let limiter = limiter(None);
+        let subject = RateSubject::unauthenticated(CommandClass::Message, PEER.parse().unwrap());
+        for _ in 0..1000 {
+            assert!(limiter.check(subject.clone(), CommandClass::Message).is_ok());
+        }
+    }
+}
@@ -0,0 +1,154 @@
+// src/server/shutdown.rs
+// Arresto controllato: stop alle nuove connessioni, avviso ai client WebSocket,
+// attesa dei comandi in corso e pulizia dello stato nel database
+use crate::server::connection::Server;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{watch, Notify};
+use tokio::time::{timeout, Duration};
+
+/// Segnale di arresto condiviso e contatore dei comandi in esecuzione.
+#[derive(Clone)]
+pub struct Shutdown {
+    signal: Arc<watch::Sender<bool>>,
+    in_flight: Arc<InFlight>,
+}
+
+#[derive(Default)]
+struct InFlight {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+/// Comando in esecuzione: l'arresto lo attende finché il guard non esce di scope.
+pub struct CommandGuard {
+    in_flight: Arc<InFlight>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (signal, _) = watch::channel(false);
+        Self { signal: Arc::new(signal), in_flight: Arc::new(InFlight::default()) }
+    }
+
+    pub fn trigger(&self) {
+        self.signal.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.signal.borrow()
+    }
+
+    /// Si completa quando l'arresto è stato richiesto.
+    pub async fn triggered(&self) {
+        let mut rx = self.signal.subscribe();
+        let _ = rx.wait_for(|stopping| *stopping).await;
+    }
+
+    pub fn begin_command(&self) -> CommandGuard {
+        self.in_flight.count.fetch_add(1, Ordering::SeqCst);
+        CommandGuard { in_flight: self.in_flight.clone() }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.count.load(Ordering::SeqCst)
+    }
+
+    /// Attende la fine dei comandi in corso; `false` se la scadenza arriva prima.
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        let wait = async {
+            loop {
+                let idle = self.in_flight.idle.notified();
+                if self.in_flight() == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        };
+        timeout(deadline, wait).await.is_ok()
+    }
+}
+
+impl Drop for CommandGuard {
+    fn drop(&mut self) {
+        if self.in_flight.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.in_flight.idle.notify_waiters();
+        }
+    }
+}
+
+/// Attende SIGINT (Ctrl+C) o, su Unix, SIGTERM.
+pub async fn wait_for_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+                _ = terminate.recv() => "SIGTERM",
+            },
+            Err(e) => {
+                println!("[SHUTDOWN] Cannot listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl+C"
+    }
+}
+
+/// Completa l'arresto dopo che `Server::run` ha smesso di accettare connessioni:
+/// avvisa i client WebSocket, attende i comandi in corso fino a `deadline`,
+/// toglie da Redis le connessioni dell'istanza, segna offline gli utenti connessi,
+/// registra l'evento e chiude il pool SQLite.
+pub async fn finish(server: &Server, deadline: Duration) {
+    if let Some(ws_manager) = &server.ws_manager {
+        let notified = ws_manager.broadcast_shutdown().await;
+        println!("[SHUTDOWN] Notified {} WebSocket client(s)", notified);
+    }
+
+    let pending = server.shutdown.in_flight();
+    if pending > 0 {
+        println!("[SHUTDOWN] Waiting up to {}s for {} command(s) in progress", deadline.as_secs(), pending);
+    }
+    let drained = server.shutdown.drain(deadline).await;
+    let abandoned = server.shutdown.in_flight();
+    if !drained {
+        println!("[SHUTDOWN] Deadline reached with {} command(s) still running", abandoned);
+    }
+    if let Some(ws_manager) = &server.ws_manager {
+        ws_manager.clear_presence().await;
+    }
+
+    let db = &server.db;
+    let now = chrono::Utc::now().timestamp();
+    let offline = match sqlx::query("UPDATE users SET is_online = 0, last_seen_at = ? WHERE is_online = 1").bind(now).execute(&db.pool).await {
+        Ok(res) => res.rows_affected(),
+        Err(e) => {
+            println!("[SHUTDOWN] Failed to mark users offline: {}", e);
+            0
+        }
+    };
+    println!("[DB] Set is_online=0 for {} user(s) due to server shutdown", offline);
+
+    let detail = format!("{} user(s) marked offline, {} command(s) abandoned", offline, abandoned);
+    let res = sqlx::query("INSERT INTO session_events (user_id, event_type, created_at, detail) VALUES ('', 'server_shutdown', ?, ?)")
+        .bind(now)
+        .bind(&detail)
+        .execute(&db.pool)
+        .await;
+    println!("[DB] Inserted server_shutdown event result={:?}", res.map(|r| r.rows_affected()));
+
+    db.pool.close().await;
+    println!("[SHUTDOWN] Database pool closed");
+}
@@ -0,0 +1,142 @@
+// src/server/sync.rs
+// Coda degli eventi di ciascun utente: nuovi messaggi, modifiche, ritiri, reazioni, inviti
+// ai gruppi e richieste di amicizia vengono accodati per ogni destinatario, così chi era
+// offline (o ha perso la connessione) li recupera con `sync` invece di riscaricare gli
+// storici. In coda restano solo i riferimenti: i testi restano cifrati in `encrypted_messages`.
+use crate::server::config::ServerConfig;
+use crate::server::database::Database;
+use crate::server::websocket;
+use crate::common::error::ChatError;
+use crate::common::protocol::SyncedEvent;
+use sqlx::Row;
+use std::sync::Arc;
+use tokio::time::{interval, Duration};
+
+/// Massimo numero di eventi per richiesta, qualunque `limit` chieda il client
+pub const MAX_SYNC_PAGE: u32 = 500;
+
+/// Eventi per richiesta se il client non indica `limit`
+const DEFAULT_SYNC_PAGE: u32 = 200;
+
+/// Intervallo tra due pulizie degli eventi più vecchi della conservazione configurata
+const PRUNE_INTERVAL_SECS: u64 = 60 * 60;
+
+/// Evento da accodare: solo il tipo e i riferimenti, mai il contenuto, che al recupero
+/// viene ricostruito dallo stato attuale (testo modificato, messaggi ritirati, inviti
+/// ancora in attesa).
+#[derive(Debug, Clone)]
+pub struct EventRef {
+    /// `message_type` dell'evento
+    pub kind: &'static str,
+    /// Chat del messaggio; assente per richieste di amicizia e inviti
+    pub chat_id: Option<String>,
+    /// Id del messaggio, della richiesta di amicizia o dell'invito
+    pub target_id: i64,
+}
+
+impl EventRef {
+    pub fn message(kind: &'static str, chat_id: &str, message_id: i64) -> Self {
+        Self { kind, chat_id: Some(chat_id.to_string()), target_id: message_id }
+    }
+
+    pub fn friend_request(request_id: i64) -> Self {
+        Self { kind: "friend_request", chat_id: None, target_id: request_id }
+    }
+
+    pub fn group_invite(invite_id: i64) -> Self {
+        Self { kind: "group_invite", chat_id: None, target_id: invite_id }
+    }
+}
+
+/// Accoda il riferimento all'evento per ciascun destinatario e restituisce, per ognuno, la
+/// copia di `event` da consegnare subito con il suo `sync_seq`.
+pub async fn record(db: &Database, recipients: &[String], target: &EventRef, event: &serde_json::Value) -> Result<Vec<(String, serde_json::Value)>, ChatError> {
+    let created_at = chrono::Utc::now().timestamp();
+    let mut tx = db.pool.begin().await
+        .map_err(|e| ChatError::internal("[SYNC] Error starting transaction", e))?;
+    let mut recorded = Vec::with_capacity(recipients.len());
+    for user_id in recipients {
+        let seq: i64 = sqlx::query_scalar("INSERT INTO user_events (user_id, kind, chat_id, target_id, created_at) VALUES (?, ?, ?, ?, ?) RETURNING id")
+            .bind(user_id)
+            .bind(target.kind)
+            .bind(&target.chat_id)
+            .bind(target.target_id)
+            .bind(created_at)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| ChatError::internal("[SYNC] Error recording event", e))?;
+        let mut copy = event.clone();
+        copy["sync_seq"] = seq.into();
+        recorded.push((user_id.clone(), copy));
+    }
+    tx.commit().await.map_err(|e| ChatError::internal("[SYNC] Error recording event", e))?;
+    Ok(recorded)
+}
+
+/// Eventi dell'utente con numero maggiore di `since`, al più `limit`, in ordine di arrivo,
+/// ricostruiti dallo stato attuale; quelli non più pertinenti (messaggio ritirato dopo
+/// l'invio, invito già gestito, ...) vengono saltati. Restituisce anche se ne restano
+/// altri e il cursore per la richiesta successiva; senza `since` nessun evento, solo il
+/// cursore attuale.
+pub async fn events_since(db: &Database, user_id: &str, since: Option<i64>, limit: Option<u32>, config: &ServerConfig) -> Result<(Vec<SyncedEvent>, bool, i64), ChatError> {
+    let Some(since) = since else {
+        let latest: i64 = sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM user_events WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_one(&db.pool)
+            .await
+            .map_err(|e| ChatError::internal("[SYNC] Error reading event cursor", e))?;
+        return Ok((Vec::new(), false, latest));
+    };
+    let limit = limit.unwrap_or(DEFAULT_SYNC_PAGE).clamp(1, MAX_SYNC_PAGE) as usize;
+    let mut rows = sqlx::query("SELECT id, kind, target_id FROM user_events WHERE user_id = ? AND id > ? ORDER BY id ASC LIMIT ?")
+        .bind(user_id)
+        .bind(since)
+        // Una riga in più per sapere se ne restano altri
+        .bind(limit as i64 + 1)
+        .fetch_all(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[SYNC] Error reading events", e))?;
+    let has_more = rows.len() > limit;
+    rows.truncate(limit);
+    let mut events = Vec::with_capacity(rows.len());
+    for row in &rows {
+        let seq: i64 = row.get("id");
+        let kind: String = row.get("kind");
+        if let Some(mut event) = websocket::queued_event(db, user_id, &kind, row.get("target_id"), config).await? {
+            event["sync_seq"] = seq.into();
+            events.push(SyncedEvent { seq, event });
+        }
+    }
+    let latest_seq = rows.last().map(|row| row.get("id")).unwrap_or(since);
+    println!("[SYNC] {} event(s) for {} after #{} ({} queued), has_more={}", events.len(), user_id, since, rows.len(), has_more);
+    Ok((events, has_more, latest_seq))
+}
+
+/// Elimina gli eventi più vecchi di `retention_days` giorni.
+pub async fn prune(db: &Database, retention_days: u32) -> Result<u64, ChatError> {
+    let cutoff = chrono::Utc::now().timestamp() - 60 * 60 * 24 * retention_days as i64;
+    sqlx::query("DELETE FROM user_events WHERE created_at < ?")
+        .bind(cutoff)
+        .execute(&db.pool)
+        .await
+        .map(|res| res.rows_affected())
+        .map_err(|e| ChatError::internal("[SYNC] Error pruning events", e))
+}
+
+/// Pulizia periodica della coda (ogni ora); con `retention_days` a 0 gli eventi restano.
+pub fn spawn_pruner(db: Arc<Database>, retention_days: u32) {
+    if retention_days == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(PRUNE_INTERVAL_SECS));
+        loop {
+            ticker.tick().await;
+            match prune(&db, retention_days).await {
+                Ok(0) => {}
+                Ok(pruned) => println!("[SYNC] Pruned {} event(s) older than {} days", pruned, retention_days),
+                Err(e) => println!("[SYNC] Failed to prune events: {}", e.message),
+            }
+        }
+    });
+}
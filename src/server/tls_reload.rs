@@ -0,0 +1,165 @@
+// src/server/tls_reload.rs
+// Ricarica a caldo dei certificati TLS: le nuove connessioni usano il nuovo acceptor,
+// quelle già aperte restano sulla sessione TLS negoziata in precedenza
+use crate::server::connection::Server;
+use chrono::{DateTime, Utc};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::time::{interval, Duration};
+use tokio_rustls::TlsAcceptor;
+
+/// Acceptor TLS sostituibile a runtime, condiviso da porta comandi e WebSocket.
+#[derive(Clone)]
+pub struct ReloadableAcceptor {
+    inner: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl ReloadableAcceptor {
+    pub fn new(acceptor: TlsAcceptor) -> Self {
+        Self { inner: Arc::new(RwLock::new(acceptor)) }
+    }
+
+    /// Acceptor da usare per una connessione appena accettata
+    pub fn current(&self) -> TlsAcceptor {
+        self.inner.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn replace(&self, acceptor: TlsAcceptor) {
+        *self.inner.write().unwrap_or_else(|e| e.into_inner()) = acceptor;
+    }
+}
+
+/// Avvia il ricaricamento automatico: controllo periodico di `TLS_CERT_PATH`/`TLS_KEY_PATH`
+/// (ogni `tls_reload_interval_secs`, 0 = disattivato) e, su Unix, ricarica su SIGHUP.
+pub fn spawn_watcher(server: Server, acceptor: ReloadableAcceptor) {
+    let every = server.config.tls_reload_interval_secs;
+    if every > 0 {
+        let server = server.clone();
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            println!("[TLS] Watching certificate files for changes every {}s", every);
+            let mut last = cert_files_stamp();
+            let mut ticker = interval(Duration::from_secs(every));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let stamp = cert_files_stamp();
+                if stamp != last {
+                    last = stamp;
+                    reload(&server, &acceptor, "certificate files changed");
+                }
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("[TLS] Cannot listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            reload(&server, &acceptor, "SIGHUP");
+        }
+    });
+}
+
+fn reload(server: &Server, acceptor: &ReloadableAcceptor, reason: &str) {
+    println!("[TLS] Reloading certificate ({})", reason);
+    match server.setup_tls_acceptor() {
+        Ok(Some(new_acceptor)) => {
+            acceptor.replace(new_acceptor);
+            println!("[TLS] Certificate reloaded: new connections use the new certificate");
+        }
+        Ok(None) => println!("[TLS] TLS disabled in configuration, keeping the current certificate"),
+        Err(e) => println!("[TLS] Reload failed, keeping the current certificate: {}", e),
+    }
+}
+
+/// Data di modifica e dimensione dei file PEM: cambia quando il certificato viene sostituito
+fn cert_files_stamp() -> Vec<Option<(SystemTime, u64)>> {
+    ["TLS_CERT_PATH", "TLS_KEY_PATH"]
+        .iter()
+        .map(|var| {
+            let path = std::env::var(var).ok()?;
+            let meta = std::fs::metadata(path).ok()?;
+            Some((meta.modified().ok()?, meta.len()))
+        })
+        .collect()
+}
+
+/// Registra la scadenza del certificato del server (avviso se scaduto o vicino alla scadenza)
+pub fn log_expiry(cert_der: &[u8]) {
+    let Some(not_after) = certificate_not_after(cert_der) else {
+        println!("[TLS] Could not read the certificate expiry date");
+        return;
+    };
+    let days_left = (not_after - Utc::now()).num_days();
+    if not_after <= Utc::now() {
+        println!("[TLS] WARNING: certificate EXPIRED on {}", not_after.format("%Y-%m-%d %H:%M:%S UTC"));
+    } else if days_left < 14 {
+        println!("[TLS] WARNING: certificate expires on {} ({} days left)", not_after.format("%Y-%m-%d %H:%M:%S UTC"), days_left);
+    } else {
+        println!("[TLS] Certificate valid until {} ({} days left)", not_after.format("%Y-%m-%d %H:%M:%S UTC"), days_left);
+    }
+}
+
+/// Campo notAfter della validity di un certificato X.509 in DER.
+pub fn certificate_not_after(cert_der: &[u8]) -> Option<DateTime<Utc>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert_der).ok()?;
+    DateTime::from_timestamp(cert.validity().not_after.timestamp(), 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// notAfter 2035-01-01 00:00:00 in UTCTime.
+    const CERT_UTC_TIME: &str = include_str!("testdata/client_cn.pem");
+    /// notAfter 2052-03-01 12:00:00 in GeneralizedTime (anni dal 2050 in poi).
+    const CERT_GENERALIZED_TIME: &str = include_str!("testdata/client_no_cn.pem");
+
+    fn der(pem: &str) -> Vec<u8> {
+        rustls_pemfile::certs(&mut pem.as_bytes()).unwrap().remove(0)
+    }
+
+    /// Sostituisce la prima occorrenza di `from` nel DER con `to` (stessa lunghezza).
+    fn patched(cert: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+        let at = cert.windows(from.len()).position(|w| w == from).unwrap();
+        let mut cert = cert.to_vec();
+        cert[at..at + to.len()].copy_from_slice(to);
+        cert
+    }
+
+    #[test]
+    fn utc_time_not_after() {
+        assert_eq!(certificate_not_after(&der(CERT_UTC_TIME)), Some(Utc.with_ymd_and_hms(2035, 1, 1, 0, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn utc_time_before_2000() {
+        let cert = patched(&der(CERT_UTC_TIME), b"350101000000Z", b"991231235959Z");
+        assert_eq!(certificate_not_after(&cert), Some(Utc.with_ymd_and_hms(1999, 12, 31, 23, 59, 59).unwrap()));
+    }
+
+    #[test]
+    fn generalized_time_not_after() {
+        assert_eq!(certificate_not_after(&der(CERT_GENERALIZED_TIME)), Some(Utc.with_ymd_and_hms(2052, 3, 1, 12, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn malformed_certificates() {
+        let cert = der(CERT_UTC_TIME);
+        assert_eq!(certificate_not_after(&[]), None);
+        assert_eq!(certificate_not_after(b"not a certificate"), None);
+        assert_eq!(certificate_not_after(&cert[..cert.len() / 3]), None);
+        // data non valida e tipo diverso da UTCTime/GeneralizedTime
+        assert_eq!(certificate_not_after(&patched(&cert, b"350101000000Z", b"351301000000Z")), None);
+        assert_eq!(certificate_not_after(&patched(&cert, b"\x17\x0d350101", b"\x04\x0d350101")), None);
+    }
+}
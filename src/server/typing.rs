@@ -0,0 +1,85 @@
+// src/server/typing.rs
+// Indicatori di scrittura: inoltrati ai partecipanti della chat tramite l'EventRelay
+// (quindi anche alle altre istanze) e mai salvati nel database
+use crate::server::websocket::{EventRelay, UserId};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Un `start_typing` non rinnovato scade dopo questo intervallo: i destinatari ricevono
+/// lo stop anche se il client non lo invia (chiusura improvvisa, rete persa).
+/// I client lo rinnovano mentre l'utente continua a scrivere.
+pub const TYPING_EXPIRY_SECS: u64 = 6;
+
+/// Scrittura in corso in una chat: i destinatari e l'evento da ripetere con `typing: false`.
+struct Typing {
+    started: Instant,
+    recipients: Vec<UserId>,
+    event: serde_json::Value,
+}
+
+/// Chi sta scrivendo in quale chat (`private:<user_id>` o `group:<id>`).
+#[derive(Clone)]
+pub struct TypingTracker {
+    relay: EventRelay,
+    active: Arc<Mutex<HashMap<(UserId, String), Typing>>>,
+}
+
+impl TypingTracker {
+    pub fn new(relay: EventRelay) -> Self {
+        Self { relay, active: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Inoltra l'inizio della scrittura (`event` con `typing: true`) e ne programma la scadenza.
+    /// Un nuovo start sulla stessa chat rinnova la scadenza.
+    pub async fn start(&self, user_id: &str, chat_key: String, recipients: Vec<UserId>, event: serde_json::Value) {
+        let key = (user_id.to_string(), chat_key);
+        let started = Instant::now();
+        self.active.lock().await.insert(key.clone(), Typing { started, recipients: recipients.clone(), event: event.clone() });
+        let delivered = self.relay.send(&recipients, &event).await;
+        println!("[WS:TYPING] {} typing in {} (delivered locally to {}/{})", key.0, key.1, delivered, recipients.len());
+
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(TYPING_EXPIRY_SECS)).await;
+            // Solo se nel frattempo non è arrivato un altro start o uno stop
+            let expired = {
+                let mut active = tracker.active.lock().await;
+                match active.get(&key) {
+                    Some(typing) if typing.started == started => active.remove(&key),
+                    _ => None,
+                }
+            };
+            if let Some(typing) = expired {
+                println!("[WS:TYPING] Typing of {} in {} expired", key.0, key.1);
+                tracker.send_stop(typing).await;
+            }
+        });
+    }
+
+    pub async fn stop(&self, user_id: &str, chat_key: &str) {
+        let stopped = self.active.lock().await.remove(&(user_id.to_string(), chat_key.to_string()));
+        if let Some(typing) = stopped {
+            self.send_stop(typing).await;
+        }
+    }
+
+    /// Ferma tutte le scritture in corso di un utente (es. alla disconnessione).
+    pub async fn stop_all(&self, user_id: &str) {
+        let stopped: Vec<Typing> = {
+            let mut active = self.active.lock().await;
+            let keys: Vec<_> = active.keys().filter(|(user, _)| user == user_id).cloned().collect();
+            keys.iter().filter_map(|key| active.remove(key)).collect()
+        };
+        for typing in stopped {
+            self.send_stop(typing).await;
+        }
+    }
+
+    async fn send_stop(&self, typing: Typing) {
+        let mut event = typing.event;
+        event["typing"] = false.into();
+        self.relay.send(&typing.recipients, &event).await;
+    }
+}
@@ -1,6 +1,16 @@
+use crate::server::database::Database;
+use crate::common::protocol::{FriendRequestInfo, LastSeenVisibility, PresenceStatus, UserInfo};
+use crate::common::error::{ChatError, ErrorCode};
+use std::sync::Arc;
+use sqlx::Row;
+use sqlx::sqlite::SqliteRow;
 use chrono::Utc;
+
+/// Lunghezza massima del testo di stato, in caratteri
+const MAX_STATUS_MESSAGE_LENGTH: usize = 140;
+
 // FRIENDSHIP SYSTEM
-pub async fn send_friend_request(db: Arc<Database>, from_user_id: &str, to_username: &str, message: &str) -> String {
+pub async fn send_friend_request(db: Arc<Database>, from_user_id: &str, to_username: &str, message: &str) -> Result<String, ChatError> {
     // Trova l'id del destinatario
     let row = sqlx::query("SELECT id FROM users WHERE username = ?")
         .bind(to_username)
@@ -8,8 +18,8 @@ pub async fn send_friend_request(db: Arc<Database>, from_user_id: &str, to_usern
         .await;
     let to_user_id = match row {
         Ok(Some(r)) => r.get::<String,_>("id"),
-        Ok(None) => return "ERR: Destinatario non trovato".to_string(),
-        Err(e) => return format!("ERR: DB error: {}", e),
+        Ok(None) => return Err(ChatError::new(ErrorCode::UserNotFound, "Destinatario non trovato")),
+        Err(e) => return Err(ChatError::internal("[USERS] DB error", e)),
     };
     
     // Controlla se sono già amici
@@ -21,7 +31,7 @@ pub async fn send_friend_request(db: Arc<Database>, from_user_id: &str, to_usern
         .fetch_optional(&db.pool)
         .await;
     if let Ok(Some(_)) = friendship_check {
-        return "ERR: Siete già amici".to_string();
+        return Err(ChatError::new(ErrorCode::AlreadyExists, "Siete già amici"));
     }
     
     
@@ -32,7 +42,7 @@ pub async fn send_friend_request(db: Arc<Database>, from_user_id: &str, to_usern
         .fetch_optional(&db.pool)
         .await;
     if let Ok(Some(_)) = check {
-        return "ERR: Richiesta già inviata".to_string();
+        return Err(ChatError::new(ErrorCode::AlreadyExists, "Richiesta già inviata"));
     }
     // Inserisci la richiesta
     let now = Utc::now().timestamp();
@@ -44,12 +54,12 @@ pub async fn send_friend_request(db: Arc<Database>, from_user_id: &str, to_usern
         .execute(&db.pool)
         .await;
     match res {
-        Ok(_) => "OK: Richiesta inviata".to_string(),
-        Err(e) => format!("ERR: DB error: {}", e),
+        Ok(_) => Ok("Richiesta inviata".to_string()),
+        Err(e) => Err(ChatError::internal("[USERS] DB error", e)),
     }
 }
 
-pub async fn accept_friend_request(db: Arc<Database>, to_user_id: &str, from_username: &str) -> String {
+pub async fn accept_friend_request(db: Arc<Database>, to_user_id: &str, from_username: &str) -> Result<String, ChatError> {
     // Trova l'id del mittente
     let row = sqlx::query("SELECT id FROM users WHERE username = ?")
         .bind(from_username)
@@ -57,8 +67,8 @@ pub async fn accept_friend_request(db: Arc<Database>, to_user_id: &str, from_use
         .await;
     let from_user_id = match row {
         Ok(Some(r)) => r.get::<String,_>("id"),
-        Ok(None) => return "ERR: Mittente non trovato".to_string(),
-        Err(e) => return format!("ERR: DB error: {}", e),
+        Ok(None) => return Err(ChatError::new(ErrorCode::UserNotFound, "Mittente non trovato")),
+        Err(e) => return Err(ChatError::internal("[USERS] DB error", e)),
     };
     // Aggiorna la richiesta
     let res = sqlx::query("UPDATE friend_requests SET status = 'accepted' WHERE from_user_id = ? AND to_user_id = ? AND status = 'pending'")
@@ -66,8 +76,12 @@ pub async fn accept_friend_request(db: Arc<Database>, to_user_id: &str, from_use
         .bind(to_user_id)
         .execute(&db.pool)
         .await;
-    if let Err(e) = res {
-        return format!("ERR: DB error: {}", e);
+    match res {
+        Ok(r) if r.rows_affected() == 0 => {
+            return Err(ChatError::new(ErrorCode::FriendRequestNotFound, "Nessuna richiesta pendente"));
+        }
+        Ok(_) => {}
+        Err(e) => return Err(ChatError::internal("[USERS] DB error", e)),
     }
     // Crea la friendship
     let now = Utc::now().timestamp();
@@ -78,12 +92,12 @@ pub async fn accept_friend_request(db: Arc<Database>, to_user_id: &str, from_use
         .execute(&db.pool)
         .await;
     match res2 {
-        Ok(_) => "OK: Amicizia accettata".to_string(),
-        Err(e) => format!("ERR: DB error: {}", e),
+        Ok(_) => Ok("Amicizia accettata".to_string()),
+        Err(e) => Err(ChatError::internal("[USERS] DB error", e)),
     }
 }
 
-pub async fn reject_friend_request(db: Arc<Database>, to_user_id: &str, from_username: &str) -> String {
+pub async fn reject_friend_request(db: Arc<Database>, to_user_id: &str, from_username: &str) -> Result<String, ChatError> {
     // Trova l'id del mittente
     let row = sqlx::query("SELECT id FROM users WHERE username = ?")
         .bind(from_username)
@@ -91,8 +105,8 @@ pub async fn reject_friend_request(db: Arc<Database>, to_user_id: &str, from_use
         .await;
     let from_user_id = match row {
         Ok(Some(r)) => r.get::<String,_>("id"),
-        Ok(None) => return "ERR: Mittente non trovato".to_string(),
-        Err(e) => return format!("ERR: DB error: {}", e),
+        Ok(None) => return Err(ChatError::new(ErrorCode::UserNotFound, "Mittente non trovato")),
+        Err(e) => return Err(ChatError::internal("[USERS] DB error", e)),
     };
     // Aggiorna la richiesta
     let res = sqlx::query("UPDATE friend_requests SET status = 'rejected' WHERE from_user_id = ? AND to_user_id = ? AND status = 'pending'")
@@ -101,52 +115,183 @@ pub async fn reject_friend_request(db: Arc<Database>, to_user_id: &str, from_use
         .execute(&db.pool)
         .await;
     match res {
-        Ok(_) => "OK: Richiesta rifiutata".to_string(),
-        Err(e) => format!("ERR: DB error: {}", e),
+        Ok(r) if r.rows_affected() == 0 => Err(ChatError::new(ErrorCode::FriendRequestNotFound, "Nessuna richiesta pendente")),
+        Ok(_) => Ok("Richiesta rifiutata".to_string()),
+        Err(e) => Err(ChatError::internal("[USERS] DB error", e)),
     }
 }
 
-pub async fn list_friends(db: Arc<Database>, user_id: &str) -> String {
-    let rows = sqlx::query("SELECT u.username FROM friendships f JOIN users u ON (u.id = f.user1_id OR u.id = f.user2_id) WHERE (f.user1_id = ? OR f.user2_id = ?) AND u.id != ?")
+/// Colonne lette da `visible_user` (tabella `users` con alias `u`).
+const USER_INFO_COLUMNS: &str = "u.username, u.is_online, u.presence, u.status_message, u.last_seen_at, u.last_seen_visibility";
+
+pub async fn list_friends(db: Arc<Database>, user_id: &str) -> Result<Vec<UserInfo>, ChatError> {
+    let rows = sqlx::query(&format!("SELECT {} FROM friendships f JOIN users u ON (u.id = f.user1_id OR u.id = f.user2_id) WHERE (f.user1_id = ? OR f.user2_id = ?) AND u.id != ?", USER_INFO_COLUMNS))
         .bind(user_id)
         .bind(user_id)
         .bind(user_id)
         .fetch_all(&db.pool)
         .await;
     match rows {
-        Ok(rows) => {
-            let friends: Vec<String> = rows.iter().map(|r| r.get::<String,_>("username")).collect();
-            format!("OK: Friends: {}", friends.join(", "))
-        }
-        Err(e) => format!("ERR: DB error: {}", e),
+        Ok(rows) => Ok(rows.iter().map(|r| visible_user(r, true)).collect()),
+        Err(e) => Err(ChatError::internal("[USERS] DB error", e)),
+    }
+}
+
+/// Presenza come la vedono gli altri: offline se l'utente non è connesso o è invisibile,
+/// e in quel caso senza testo di stato ma con l'ultimo accesso, se la sua privacy lo
+/// mostra a chi chiede (`is_friend`: chi chiede è suo amico).
+fn visible_user(row: &SqliteRow, is_friend: bool) -> UserInfo {
+    let username = row.get("username");
+    let chosen = PresenceStatus::parse(&row.get::<String,_>("presence")).unwrap_or_default();
+    let offline = row.get::<i64,_>("is_online") == 0 || matches!(chosen, PresenceStatus::Invisible | PresenceStatus::Offline);
+    if !offline {
+        return UserInfo { username, status: chosen, status_message: row.get("status_message"), last_seen_at: None };
+    }
+    let last_seen_at = match LastSeenVisibility::parse(&row.get::<String,_>("last_seen_visibility")).unwrap_or_default() {
+        LastSeenVisibility::Everyone => row.get("last_seen_at"),
+        LastSeenVisibility::Friends if is_friend => row.get("last_seen_at"),
+        _ => None,
+    };
+    UserInfo { username, status: PresenceStatus::Offline, status_message: None, last_seen_at }
+}
+
+/// Presenza di un utente: quella visibile agli amici e quella scelta (che può essere `Invisible`).
+pub async fn user_presence(db: Arc<Database>, user_id: &str) -> Result<(UserInfo, PresenceStatus), ChatError> {
+    let row = sqlx::query(&format!("SELECT {} FROM users u WHERE u.id = ?", USER_INFO_COLUMNS))
+        .bind(user_id)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[USERS] DB error", e))?
+        .ok_or_else(ChatError::user_not_found)?;
+    let chosen = PresenceStatus::parse(&row.get::<String,_>("presence")).unwrap_or_default();
+    Ok((visible_user(&row, true), chosen))
+}
+
+/// Presenza e ultimo accesso di un utente come li vede `viewer_id`.
+pub async fn get_user(db: Arc<Database>, viewer_id: &str, username: &str) -> Result<UserInfo, ChatError> {
+    let row = sqlx::query(&format!(
+        "SELECT {}, u.id = ? OR EXISTS (SELECT 1 FROM friendships f WHERE (f.user1_id = u.id AND f.user2_id = ?) OR (f.user1_id = ? AND f.user2_id = u.id)) AS is_friend FROM users u WHERE u.username = ?",
+        USER_INFO_COLUMNS
+    ))
+        .bind(viewer_id)
+        .bind(viewer_id)
+        .bind(viewer_id)
+        .bind(username)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[USERS] DB error", e))?
+        .ok_or_else(ChatError::user_not_found)?;
+    Ok(visible_user(&row, row.get::<bool,_>("is_friend")))
+}
+
+/// Presenza scelta dall'utente, testo di stato e privacy dell'ultimo accesso.
+pub async fn own_presence(db: Arc<Database>, user_id: &str) -> Result<(PresenceStatus, Option<String>, LastSeenVisibility), ChatError> {
+    let row = sqlx::query("SELECT presence, status_message, last_seen_visibility FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[USERS] DB error", e))?
+        .ok_or_else(ChatError::user_not_found)?;
+    let chosen = PresenceStatus::parse(&row.get::<String,_>("presence")).unwrap_or_default();
+    let visibility = LastSeenVisibility::parse(&row.get::<String,_>("last_seen_visibility")).unwrap_or_default();
+    Ok((chosen, row.get("status_message"), visibility))
+}
+
+/// Imposta lo stato scelto dall'utente e il testo di stato (rimosso se assente o vuoto).
+pub async fn set_presence(db: Arc<Database>, user_id: &str, status: PresenceStatus, message: Option<&str>) -> Result<(), ChatError> {
+    if status == PresenceStatus::Offline {
+        return Err(ChatError::new(ErrorCode::InvalidInput, "Presence must be online, away, busy or invisible"));
+    }
+    let message = message.map(str::trim).filter(|m| !m.is_empty());
+    if message.is_some_and(|m| m.chars().count() > MAX_STATUS_MESSAGE_LENGTH) {
+        return Err(ChatError::new(ErrorCode::InvalidInput, format!("Status message too long (max {} characters)", MAX_STATUS_MESSAGE_LENGTH)));
     }
+    sqlx::query("UPDATE users SET presence = ?, status_message = ? WHERE id = ?")
+        .bind(status.as_str())
+        .bind(message)
+        .bind(user_id)
+        .execute(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[USERS] Failed to set presence", e))?;
+    println!("[USERS] User {} set presence to {}", user_id, status.as_str());
+    Ok(())
 }
 
-pub async fn received_friend_requests(db: Arc<Database>, user_id: &str) -> String {
+pub async fn set_last_seen_visibility(db: Arc<Database>, user_id: &str, visibility: LastSeenVisibility) -> Result<(), ChatError> {
+    sqlx::query("UPDATE users SET last_seen_visibility = ? WHERE id = ?")
+        .bind(visibility.as_str())
+        .bind(user_id)
+        .execute(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[USERS] Failed to set last seen visibility", e))?;
+    println!("[USERS] User {} shows last seen to {}", user_id, visibility.as_str());
+    Ok(())
+}
+
+/// Id degli amici di un utente (destinatari degli aggiornamenti di presenza).
+pub async fn friend_ids(db: Arc<Database>, user_id: &str) -> Result<Vec<String>, ChatError> {
+    let rows = sqlx::query("SELECT CASE WHEN user1_id = ? THEN user2_id ELSE user1_id END AS friend_id FROM friendships WHERE user1_id = ? OR user2_id = ?")
+        .bind(user_id)
+        .bind(user_id)
+        .bind(user_id)
+        .fetch_all(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[USERS] DB error", e))?;
+    Ok(rows.iter().map(|r| r.get::<String,_>("friend_id")).collect())
+}
+
+pub async fn received_friend_requests(db: Arc<Database>, user_id: &str) -> Result<Vec<FriendRequestInfo>, ChatError> {
     let rows = sqlx::query("SELECT u.username, fr.message FROM friend_requests fr JOIN users u ON fr.from_user_id = u.id WHERE fr.to_user_id = ? AND fr.status = 'pending'")
         .bind(user_id)
         .fetch_all(&db.pool)
         .await;
     match rows {
         Ok(rows) => {
-            let reqs: Vec<String> = rows.iter().map(|r| format!("{}: {}", r.get::<String,_>("username"), r.get::<String,_>("message"))).collect();
-            format!("OK: Richieste ricevute: {}", reqs.join(" | "))
+            Ok(rows.iter().map(|r| FriendRequestInfo {
+                username: r.get::<String,_>("username"),
+                message: r.get::<Option<String>,_>("message").unwrap_or_default(),
+            }).collect())
         }
-        Err(e) => format!("ERR: DB error: {}", e),
+        Err(e) => Err(ChatError::internal("[USERS] DB error", e)),
     }
 }
 
-pub async fn sent_friend_requests(db: Arc<Database>, user_id: &str) -> String {
+/// Id dell'ultima richiesta di amicizia in attesa da `from_user_id` a `to_username`.
+pub async fn pending_friend_request_id(db: &Database, from_user_id: &str, to_username: &str) -> Result<Option<i64>, ChatError> {
+    sqlx::query_scalar("SELECT fr.id FROM friend_requests fr JOIN users u ON fr.to_user_id = u.id WHERE fr.from_user_id = ? AND u.username = ? AND fr.status = 'pending' ORDER BY fr.id DESC LIMIT 1")
+        .bind(from_user_id)
+        .bind(to_username)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[USERS] DB error", e))
+}
+
+/// Richiesta di amicizia ancora in attesa, con l'id del destinatario.
+pub async fn pending_friend_request(db: &Database, request_id: i64) -> Result<Option<(String, FriendRequestInfo)>, ChatError> {
+    let row = sqlx::query("SELECT fr.to_user_id, u.username, fr.message FROM friend_requests fr JOIN users u ON fr.from_user_id = u.id WHERE fr.id = ? AND fr.status = 'pending'")
+        .bind(request_id)
+        .fetch_optional(&db.pool)
+        .await
+        .map_err(|e| ChatError::internal("[USERS] DB error", e))?;
+    Ok(row.map(|r| (r.get::<String,_>("to_user_id"), FriendRequestInfo {
+        username: r.get::<String,_>("username"),
+        message: r.get::<Option<String>,_>("message").unwrap_or_default(),
+    })))
+}
+
+pub async fn sent_friend_requests(db: Arc<Database>, user_id: &str) -> Result<Vec<FriendRequestInfo>, ChatError> {
     let rows = sqlx::query("SELECT u.username, fr.message FROM friend_requests fr JOIN users u ON fr.to_user_id = u.id WHERE fr.from_user_id = ? AND fr.status = 'pending'")
         .bind(user_id)
         .fetch_all(&db.pool)
         .await;
     match rows {
         Ok(rows) => {
-            let reqs: Vec<String> = rows.iter().map(|r| format!("{}: {}", r.get::<String,_>("username"), r.get::<String,_>("message"))).collect();
-            format!("OK: Richieste inviate: {}", reqs.join(" | "))
+            Ok(rows.iter().map(|r| FriendRequestInfo {
+                username: r.get::<String,_>("username"),
+                message: r.get::<Option<String>,_>("message").unwrap_or_default(),
+            }).collect())
         }
-        Err(e) => format!("ERR: DB error: {}", e),
+        Err(e) => Err(ChatError::internal("[USERS] DB error", e)),
     }
 }
 
@@ -155,6 +300,7 @@ pub async fn help() -> String {
     let help = "Comandi disponibili:\n\
     /register <username> <password>\n\
     /login <username> <password>\n\
+    /cert_login\n\
     /logout\n\
     /users\n\
     /all_users\n\
@@ -168,50 +314,36 @@ pub async fn help() -> String {
     /quit\n";
     help.to_string()
 }
-use crate::server::database::Database;
-use std::sync::Arc;
-use sqlx::Row;
-
-pub async fn list_online(db: Arc<Database>) -> String {
+pub async fn list_online(db: Arc<Database>) -> Result<Vec<String>, ChatError> {
     println!("[USERS] Listing online users");
-    let rows = sqlx::query("SELECT username FROM users WHERE is_online = 1")
+    let rows = sqlx::query("SELECT username FROM users WHERE is_online = 1 AND presence != 'invisible'")
         .fetch_all(&db.pool)
         .await;
     match rows {
         Ok(rows) => {
-            let users: Vec<String> = rows.iter().map(|r| r.get::<String,_>("username")).collect();
-            format!("OK: Online users: {}", users.join(", "))
-        }
-        Err(e) => {
-            println!("[USERS] Error listing online users: {}", e);
-            format!("ERR: {}", e)
+            Ok(rows.iter().map(|r| r.get::<String,_>("username")).collect())
         }
+        Err(e) => Err(ChatError::internal("[USERS] Error listing online users", e)),
     }
 }
 
-pub async fn list_online_excluding_self(db: Arc<Database>, session_token: &str) -> String {
+pub async fn list_online_excluding_self(db: Arc<Database>, current_user_id: &str) -> Result<Vec<String>, ChatError> {
     println!("[USERS] Listing online users excluding current user");
     
-    // First validate session and get current user ID
-    let current_user_id = match crate::server::auth::validate_session(db.clone(), session_token).await {
-        Some(uid) => uid,
-        None => return "ERR: Invalid or expired session".to_string(),
-    };
-    
     // Get current user's username
     let current_username = match sqlx::query("SELECT username FROM users WHERE id = ?")
-        .bind(&current_user_id)
+        .bind(current_user_id)
         .fetch_optional(&db.pool)
         .await
     {
         Ok(Some(row)) => row.get::<String,_>("username"),
-        Ok(None) => return "ERR: User not found".to_string(),
-        Err(e) => return format!("ERR: Database error: {}", e),
+        Ok(None) => return Err(ChatError::user_not_found()),
+        Err(e) => return Err(ChatError::internal("[USERS] DB error", e)),
     };
     
-    // Get all online users except current user
-    let rows = sqlx::query("SELECT username FROM users WHERE is_online = 1 AND id != ?")
-        .bind(&current_user_id)
+    // Get all online users except current user (gli invisibili risultano offline)
+    let rows = sqlx::query("SELECT username FROM users WHERE is_online = 1 AND presence != 'invisible' AND id != ?")
+        .bind(current_user_id)
         .fetch_all(&db.pool)
         .await;
     
@@ -219,16 +351,13 @@ pub async fn list_online_excluding_self(db: Arc<Database>, session_token: &str)
         Ok(rows) => {
             let users: Vec<String> = rows.iter().map(|r| r.get::<String,_>("username")).collect();
             println!("[USERS] Found {} online users excluding {}", users.len(), current_username);
-            format!("OK: Online users: {}", users.join(", "))
-        }
-        Err(e) => {
-            println!("[USERS] Error listing online users: {}", e);
-            format!("ERR: {}", e)
+            Ok(users)
         }
+        Err(e) => Err(ChatError::internal("[USERS] Error listing online users", e)),
     }
 }
 
-pub async fn list_all(db: Arc<Database>, exclude_username: Option<&str>) -> String {
+pub async fn list_all(db: Arc<Database>, exclude_username: Option<&str>) -> Result<Vec<String>, ChatError> {
     println!("[USERS] Listing all users");
     let rows = sqlx::query("SELECT username FROM users")
         .fetch_all(&db.pool)
@@ -239,11 +368,8 @@ pub async fn list_all(db: Arc<Database>, exclude_username: Option<&str>) -> Stri
             if let Some(exclude) = exclude_username {
                 users.retain(|u| u != exclude);
             }
-            format!("OK: All users: {}", users.join(", "))
-        }
-        Err(e) => {
-            println!("[USERS] Error listing all users: {}", e);
-            format!("ERR: {}", e)
+            Ok(users)
         }
+        Err(e) => Err(ChatError::internal("[USERS] Error listing all users", e)),
     }
 }
@@ -1,13 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::{Mutex, broadcast};
 use tokio_tungstenite::{WebSocketStream, tungstenite::Message};
 use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 use redis::aio::ConnectionManager;
 use crate::server::database::Database;
-use crate::server::messages;
+use crate::server::{groups, messages, sync};
+use crate::common::error::{ChatError, ErrorCode};
+use crate::common::protocol::{FriendRequestInfo, GroupInviteInfo, PresenceStatus, UserInfo};
+use crate::server::rate_limit::{CommandClass, RateLimiter, RateSubject};
+use crate::server::shutdown::Shutdown;
+use crate::server::typing::TypingTracker;
 use sqlx::Row;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,6 +24,56 @@ pub struct OutgoingChatMessage {
     pub group_id: Option<String>, // per messaggi di gruppo
     pub content: String,
     pub session_token: String,
+    /// UUID del client per la deduplicazione dei nuovi invii
+    #[serde(default)]
+    pub client_msg_id: Option<String>,
+    /// Messaggio citato dalla risposta
+    #[serde(default)]
+    pub reply_to: Option<i64>,
+}
+
+/// Modifica di un messaggio inviata dal client (`message_type: "edit_message"`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EditMessageFrame {
+    pub message_type: String,
+    pub message_id: i64,
+    pub content: String,
+}
+
+/// Ritiro di un messaggio per tutti (`message_type: "delete_message"`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteMessageFrame {
+    pub message_type: String,
+    pub message_id: i64,
+}
+
+/// Reazione a un messaggio (`message_type: "add_reaction"` o `"remove_reaction"`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionFrame {
+    pub message_type: String,
+    pub message_id: i64,
+    #[serde(default)]
+    pub emoji: Option<String>,
+}
+
+/// Richiesta degli eventi persi dopo il cursore `since` (`message_type: "sync"`); senza
+/// `since` il server risponde solo con il cursore attuale
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncFrame {
+    pub message_type: String,
+    #[serde(default)]
+    pub since: Option<i64>,
+}
+
+/// Inizio o fine della scrittura in una chat (`message_type: "start_typing"` o `"stop_typing"`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypingFrame {
+    pub message_type: String,
+    pub chat_type: String,
+    #[serde(default)]
+    pub to_user: Option<String>,
+    #[serde(default)]
+    pub group_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,6 +98,9 @@ pub struct AuthResponse {
     pub success: bool,
     pub user_id: Option<String>,
     pub error: Option<String>,
+    /// Codice tipizzato dell'errore (es. `server_full`), assente per i rifiuti di autenticazione
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -54,6 +113,648 @@ pub enum MessageType {
     System,
 }
 
+/// Rifiuta una connessione oltre il limite: risposta di autenticazione fallita con il
+/// codice dell'errore, poi chiusura con 1013 (Try Again Later).
+pub async fn reject_connection<S>(ws_stream: WebSocketStream<S>, error: &ChatError) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+    let (mut ws_sender, _ws_receiver) = ws_stream.split();
+    let response = AuthResponse {
+        message_type: "auth_response".to_string(),
+        success: false,
+        user_id: None,
+        error: Some(error.message.clone()),
+        code: Some(error.code),
+    };
+    ws_sender.send(Message::Text(serde_json::to_string(&response)?)).await?;
+    let close = CloseFrame { code: CloseCode::Again, reason: "server full".into() };
+    ws_sender.send(Message::Close(Some(close))).await?;
+    Ok(())
+}
+
+/// Errore di un comando inviato sul WebSocket (es. `rate_limited`).
+fn error_frame(error: &ChatError) -> Message {
+    let frame = serde_json::json!({
+        "message_type": "error",
+        "code": error.code,
+        "message": error.message,
+    });
+    Message::Text(frame.to_string())
+}
+
+/// Username di un utente, o l'id stesso se non è più presente.
+async fn username_of(db: &Database, user_id: &str) -> String {
+    match sqlx::query("SELECT username FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(&db.pool)
+        .await
+    {
+        Ok(Some(row)) => row.get("username"),
+        _ => user_id.to_string(),
+    }
+}
+
+/// Evento su un messaggio già salvato, con gli stessi campi di `new_message` (mittente e
+/// destinatario o gruppo) così i client trovano la chat giusta; `fields` completa l'evento.
+async fn message_event(db: &Database, message_type: &str, sender_id: &str, chat: &messages::MessageChat, fields: serde_json::Value) -> serde_json::Value {
+    let mut event = fields;
+    event["message_type"] = message_type.into();
+    event["from_user"] = username_of(db, sender_id).await.into();
+    match &chat.group_id {
+        Some(group_id) => {
+            event["chat_type"] = "group".into();
+            event["group_id"] = group_id.clone().into();
+        }
+        None => {
+            let other = chat.member_ids.iter().find(|m| *m != sender_id).map(String::as_str).unwrap_or(sender_id);
+            event["chat_type"] = "private".into();
+            event["to_user"] = username_of(db, other).await.into();
+        }
+    }
+    event
+}
+
+/// Messaggio salvato fuori dal WebSocket, nello stesso formato degli invii via WebSocket.
+async fn new_message_event(db: &Database, sent: &messages::SentMessage) -> serde_json::Value {
+    let fields = serde_json::json!({
+        "content": sent.message.content,
+        "timestamp": sent.message.sent_at,
+        "id": sent.message.id,
+        "seq": sent.message.seq,
+        "edited_at": sent.message.edited_at,
+        "client_msg_id": sent.client_msg_id,
+        "reply_to": sent.message.reply_to,
+        "quoted": sent.message.quoted,
+    });
+    message_event(db, "new_message", &sent.sender_id, &sent.chat, fields).await
+}
+
+async fn message_edited_event(db: &Database, edited: &messages::EditedMessage) -> serde_json::Value {
+    let fields = serde_json::json!({
+        "content": edited.content,
+        "timestamp": edited.sent_at,
+        "id": edited.id,
+        "seq": edited.seq,
+        "edited_at": edited.edited_at,
+    });
+    message_event(db, "message_edited", &edited.sender_id, &edited.chat, fields).await
+}
+
+async fn message_deleted_event(db: &Database, deleted: &messages::DeletedMessage) -> serde_json::Value {
+    let fields = serde_json::json!({
+        "content": "",
+        "timestamp": deleted.sent_at,
+        "id": deleted.id,
+        "seq": deleted.seq,
+        "deleted_at": deleted.deleted_at,
+        "deleted_by": username_of(db, &deleted.deleted_by).await,
+    });
+    message_event(db, "message_deleted", &deleted.sender_id, &deleted.chat, fields).await
+}
+
+async fn reactions_updated_event(db: &Database, update: &messages::ReactionUpdate) -> serde_json::Value {
+    let fields = serde_json::json!({
+        "content": "",
+        "timestamp": update.sent_at,
+        "id": update.id,
+        "seq": update.seq,
+        "reactions": update.reactions,
+    });
+    message_event(db, "reactions_updated", &update.sender_id, &update.chat, fields).await
+}
+
+/// Conferme aggiornate di più messaggi dello stesso mittente nella stessa chat, in un
+/// unico evento.
+async fn receipts_event(db: &Database, sender_id: &str, chat: &messages::MessageChat, updates: &[&messages::ReceiptUpdate]) -> serde_json::Value {
+    let receipts: Vec<serde_json::Value> = updates.iter()
+        .map(|u| serde_json::json!({
+            "id": u.id,
+            "seq": u.seq,
+            "delivered_to": u.delivered_to,
+            "read_by": u.read_by,
+        }))
+        .collect();
+    message_event(db, "message_receipts", sender_id, chat, serde_json::json!({ "receipts": receipts })).await
+}
+
+fn friend_request_event(request: &FriendRequestInfo) -> serde_json::Value {
+    let mut event = serde_json::to_value(request).unwrap_or_default();
+    event["message_type"] = "friend_request".into();
+    event
+}
+
+fn group_invite_event(group_id: &str, invite: &GroupInviteInfo) -> serde_json::Value {
+    let mut event = serde_json::to_value(invite).unwrap_or_default();
+    event["message_type"] = "group_invite".into();
+    event["group_id"] = group_id.into();
+    event
+}
+
+/// Evento in coda per `user_id`, ricostruito dallo stato attuale per il `sync`: testo
+/// corrente dei messaggi (cifrato a riposo) e nessun contenuto per quelli ritirati.
+/// `None` se non c'è più nulla da recuperare (messaggio ritirato o chat svuotata,
+/// richiesta o invito già gestiti).
+pub async fn queued_event(db: &Database, user_id: &str, kind: &str, target_id: i64, config: &crate::server::config::ServerConfig) -> Result<Option<serde_json::Value>, ChatError> {
+    match kind {
+        "friend_request" => {
+            let request = crate::server::users::pending_friend_request(db, target_id).await?;
+            return Ok(request.filter(|(to_user_id, _)| to_user_id == user_id).map(|(_, request)| friend_request_event(&request)));
+        }
+        "group_invite" => {
+            let invite = groups::pending_invite_by_id(db, target_id).await?;
+            return Ok(invite.filter(|(_, invited, _)| invited == user_id).map(|(group_id, _, invite)| group_invite_event(&group_id, &invite)));
+        }
+        _ => {}
+    }
+    let Some(sent) = messages::queued_message(db, user_id, target_id, config).await? else {
+        return Ok(None);
+    };
+    let event = match (kind, sent.deleted_at) {
+        ("message_deleted", Some(deleted_at)) => {
+            let deleted = messages::DeletedMessage {
+                id: sent.message.id,
+                seq: sent.message.seq,
+                deleted_by: sent.deleted_by.clone().unwrap_or_else(|| sent.sender_id.clone()),
+                sender_id: sent.sender_id,
+                sent_at: sent.message.sent_at,
+                deleted_at,
+                chat: sent.chat,
+            };
+            message_deleted_event(db, &deleted).await
+        }
+        // Ritirato dopo l'evento: basta il ritiro, a sua volta in coda
+        (_, Some(_)) => return Ok(None),
+        ("new_message", None) => new_message_event(db, &sent).await,
+        ("message_edited", None) => {
+            let Some(edited_at) = sent.message.edited_at else { return Ok(None) };
+            let edited = messages::EditedMessage {
+                id: sent.message.id,
+                seq: sent.message.seq,
+                sender_id: sent.sender_id,
+                content: sent.message.content,
+                sent_at: sent.message.sent_at,
+                edited_at,
+                chat: sent.chat,
+            };
+            message_edited_event(db, &edited).await
+        }
+        ("reactions_updated", None) => {
+            let update = messages::ReactionUpdate {
+                id: sent.message.id,
+                seq: sent.message.seq,
+                sender_id: sent.sender_id,
+                sent_at: sent.message.sent_at,
+                reactions: sent.message.reactions,
+                chat: sent.chat,
+            };
+            reactions_updated_event(db, &update).await
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(event))
+}
+
+/// Inoltra le conferme di consegna e lettura ai soli mittenti dei messaggi, un evento
+/// per mittente e chat, anche se connessi ad altre istanze.
+async fn deliver_receipts(db: &Database, relay: &EventRelay, updates: &[messages::ReceiptUpdate]) {
+    let mut batches: Vec<(&str, &str, Vec<&messages::ReceiptUpdate>)> = Vec::new();
+    for update in updates {
+        match batches.iter_mut().find(|(sender, chat, _)| *sender == update.sender_id && *chat == update.chat.chat_id) {
+            Some((_, _, batch)) => batch.push(update),
+            None => batches.push((&update.sender_id, &update.chat.chat_id, vec![update])),
+        }
+    }
+    for (sender_id, _, batch) in batches {
+        let event = receipts_event(db, sender_id, &batch[0].chat, &batch).await;
+        relay.send(&[sender_id.to_string()], &event).await;
+    }
+}
+
+/// Segna un messaggio come consegnato ai destinatari a cui il WebSocket lo ha appena
+/// passato e invia al mittente lo stato finale.
+async fn mark_handed_over(db: &Database, relay: &EventRelay, message_id: i64, recipients: &[String]) {
+    let mut last_update = None;
+    for recipient in recipients {
+        match messages::mark_delivered(db, recipient, message_id, message_id).await {
+            Ok(updates) => last_update = updates.into_iter().last().or(last_update),
+            Err(e) => println!("[WS:ERROR] Could not mark message #{} delivered to {}: {}", message_id, recipient, e.message),
+        }
+    }
+    if let Some(update) = last_update {
+        deliver_receipts(db, relay, &[update]).await;
+    }
+}
+
+/// Aggiorna la voce dell'inbox della chat (non letti e anteprima) per ciascuno degli
+/// utenti indicati, anche se connessi ad altre istanze.
+async fn announce_inbox(db: &Database, relay: &EventRelay, chat: &messages::MessageChat, user_ids: &[UserId], config: &crate::server::config::ServerConfig) {
+    for user_id in user_ids {
+        let entry = match messages::inbox_entry(db, user_id, chat, config).await {
+            Ok(entry) => entry,
+            Err(e) => {
+                println!("[WS:INBOX] Failed to load inbox entry of {} for {}: {}", user_id, chat.chat_id, e.message);
+                continue;
+            }
+        };
+        let Ok(mut event) = serde_json::to_value(&entry) else { continue };
+        event["message_type"] = "inbox_update".into();
+        relay.send(std::slice::from_ref(user_id), &event).await;
+    }
+    println!("[WS:INBOX] Inbox of {} updated for {} member(s)", chat.chat_id, user_ids.len());
+}
+
+/// Dopo un nuovo messaggio: non letti dei destinatari e anteprima per tutti i membri.
+async fn announce_new_message_inbox(db: &Database, relay: &EventRelay, stored: &messages::StoredMessage, config: &crate::server::config::ServerConfig) {
+    if stored.duplicate {
+        return;
+    }
+    match messages::chat_of_message(db, stored.id).await {
+        Ok(chat) => announce_inbox(db, relay, &chat, &chat.member_ids, config).await,
+        Err(e) => println!("[WS:INBOX] Could not resolve chat of message #{}: {}", stored.id, e.message),
+    }
+}
+
+/// Consegna a ciascun destinatario la propria copia dell'evento, anche se connesso ad
+/// altre istanze. Restituisce i destinatari a cui l'ha consegnata questa istanza.
+async fn deliver_each(relay: &EventRelay, events: Vec<(UserId, serde_json::Value)>) -> Vec<UserId> {
+    let mut delivered = Vec::new();
+    for (user_id, event) in events {
+        if relay.send(std::slice::from_ref(&user_id), &event).await > 0 {
+            delivered.push(user_id);
+        }
+    }
+    delivered
+}
+
+/// Accoda `target` per i destinatari (ognuno con il proprio `sync_seq`) e consegna `event`
+/// a chi è connesso; chi è offline lo recupera con `sync`. Se il salvataggio fallisce
+/// l'evento viene comunque consegnato, senza `sync_seq`.
+async fn deliver_recorded(db: &Database, relay: &EventRelay, recipients: &[UserId], target: &sync::EventRef, event: &serde_json::Value) -> Vec<UserId> {
+    let events = match sync::record(db, recipients, target, event).await {
+        Ok(events) => events,
+        Err(e) => {
+            println!("[WS:SYNC] Could not queue {} for {} recipient(s): {}", event["message_type"], recipients.len(), e.message);
+            recipients.iter().map(|user_id| (user_id.clone(), event.clone())).collect()
+        }
+    };
+    deliver_each(relay, events).await
+}
+
+/// Invia sul WebSocket gli eventi dell'utente successivi a `since`, una pagina alla volta,
+/// seguiti da `sync_complete` con il numero di eventi e il nuovo cursore.
+async fn replay_events(db: &Database, user_id: &str, since: Option<i64>, sender: &tokio::sync::mpsc::UnboundedSender<Message>, config: &crate::server::config::ServerConfig) {
+    let mut cursor = since;
+    let mut replayed = 0;
+    let latest_seq = loop {
+        match sync::events_since(db, user_id, cursor, Some(sync::MAX_SYNC_PAGE), config).await {
+            Ok((events, has_more, latest_seq)) => {
+                replayed += events.len();
+                for synced in events {
+                    let _ = sender.send(Message::Text(synced.event.to_string()));
+                }
+                if !has_more {
+                    break latest_seq;
+                }
+                cursor = Some(latest_seq);
+            }
+            Err(e) => {
+                println!("[WS:SYNC] Sync of {} failed: {}", user_id, e.message);
+                let _ = sender.send(error_frame(&e));
+                return;
+            }
+        }
+    };
+    let complete = serde_json::json!({
+        "message_type": "sync_complete",
+        "events": replayed,
+        "latest_seq": latest_seq,
+    });
+    let _ = sender.send(Message::Text(complete.to_string()));
+    println!("[WS:SYNC] Replayed {} event(s) to {} since {:?} (cursor #{})", replayed, user_id, since, latest_seq);
+}
+
+/// Chiave della chat, destinatari ed evento (con `typing: true`) di un indicatore di
+/// scrittura: l'altro partecipante della chat privata o gli altri membri del gruppo.
+async fn typing_target(db: &Database, user_id: &str, frame: &TypingFrame) -> Result<(String, Vec<UserId>, serde_json::Value), ChatError> {
+    let from_user = username_of(db, user_id).await;
+    match (frame.chat_type.as_str(), &frame.to_user, &frame.group_id) {
+        ("private", Some(to_user), _) => {
+            let to_id: String = sqlx::query("SELECT id FROM users WHERE username = ?")
+                .bind(to_user)
+                .fetch_optional(&db.pool)
+                .await
+                .map_err(|e| ChatError::internal("[WS:TYPING] Error looking up user", e))?
+                .ok_or_else(ChatError::user_not_found)?
+                .get("id");
+            let event = serde_json::json!({
+                "message_type": "typing",
+                "chat_type": "private",
+                "from_user": from_user,
+                "to_user": to_user,
+                "typing": true,
+            });
+            Ok((format!("private:{}", to_id), vec![to_id], event))
+        }
+        ("group", _, Some(group_id)) => {
+            let rows = sqlx::query("SELECT user_id FROM group_members WHERE group_id = ?")
+                .bind(group_id)
+                .fetch_all(&db.pool)
+                .await
+                .map_err(|e| ChatError::internal("[WS:TYPING] Error getting group members", e))?;
+            let members: Vec<UserId> = rows.iter().map(|r| r.get("user_id")).collect();
+            if !members.iter().any(|m| m == user_id) {
+                return Err(ChatError::not_member());
+            }
+            let event = serde_json::json!({
+                "message_type": "typing",
+                "chat_type": "group",
+                "from_user": from_user,
+                "group_id": group_id,
+                "typing": true,
+            });
+            let recipients = members.into_iter().filter(|m| m != user_id).collect();
+            Ok((format!("group:{}", group_id), recipients, event))
+        }
+        _ => Err(ChatError::new(ErrorCode::InvalidInput, "Typing frame needs to_user or group_id")),
+    }
+}
+
+/// Canale Redis degli eventi destinati a utenti specifici
+pub const RELAY_CHANNEL: &str = "relay";
+
+/// Evento per utenti specifici pubblicato su Redis: ogni istanza lo consegna ai
+/// destinatari connessi a lei, tranne quella che l'ha originato.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RelayedEvent {
+    origin: String,
+    recipients: Vec<UserId>,
+    event: serde_json::Value,
+}
+
+/// Consegna di eventi effimeri (non salvati nel database) agli utenti connessi a
+/// qualsiasi istanza del server.
+#[derive(Clone)]
+pub struct EventRelay {
+    instance_id: String,
+    connections: Arc<Mutex<HashMap<ClientId, WebSocketConnection>>>,
+    user_connections: Arc<Mutex<HashMap<UserId, HashSet<ClientId>>>>,
+    redis_manager: Arc<Mutex<ConnectionManager>>,
+}
+
+impl EventRelay {
+    /// Consegna l'evento ai destinatari connessi a questa istanza e lo pubblica per le altre.
+    pub async fn send(&self, recipients: &[UserId], event: &serde_json::Value) -> usize {
+        let delivered = deliver_to_users(&self.connections, &self.user_connections, recipients, event).await;
+        let relayed = RelayedEvent { origin: self.instance_id.clone(), recipients: recipients.to_vec(), event: event.clone() };
+        let serialized = serde_json::to_string(&relayed).unwrap_or_default();
+        let mut redis_conn = self.redis_manager.lock().await;
+        let published: Result<(), _> = redis::cmd("PUBLISH")
+            .arg(RELAY_CHANNEL)
+            .arg(&serialized)
+            .query_async(&mut *redis_conn)
+            .await;
+        if let Err(e) = published {
+            println!("[WS:REDIS] Failed to publish relayed event: {}", e);
+        }
+        delivered
+    }
+
+    /// Evento ricevuto da Redis: lo consegna se proviene da un'altra istanza.
+    async fn receive(&self, payload: &str) {
+        let Ok(relayed) = serde_json::from_str::<RelayedEvent>(payload) else {
+            println!("[WS:REDIS] Invalid relayed event: {}", payload);
+            return;
+        };
+        if relayed.origin != self.instance_id {
+            deliver_to_users(&self.connections, &self.user_connections, &relayed.recipients, &relayed.event).await;
+        }
+    }
+}
+
+/// Ogni istanza rinnova la propria registrazione in Redis con questo intervallo...
+pub const PRESENCE_HEARTBEAT_SECS: u64 = 10;
+/// ...e le connessioni di un'istanza che non la rinnova da più di così (crash, SIGKILL)
+/// non contano più per la presenza.
+pub const PRESENCE_TTL_SECS: u64 = 30;
+
+/// Istanze che hanno registrato connessioni WebSocket.
+const PRESENCE_INSTANCES: &str = "presence:instances";
+
+/// Chiave con scadenza rinnovata dal battito dell'istanza.
+fn presence_alive_key(instance_id: &str) -> String {
+    format!("presence:alive:{}", instance_id)
+}
+
+/// Hash user_id -> numero di WebSocket aperti dall'utente su un'istanza.
+fn presence_connections_key(instance_id: &str) -> String {
+    format!("presence:conns:{}", instance_id)
+}
+
+/// Connessioni WebSocket di questa istanza registrate in Redis, per sapere se un utente
+/// è connesso ad almeno un'istanza viva. Il contributo di ogni istanza è separato: un
+/// arresto lo cancella e un crash lo fa ignorare alla scadenza del battito.
+#[derive(Clone)]
+struct PresenceCounters {
+    instance_id: String,
+    redis_manager: Arc<Mutex<ConnectionManager>>,
+}
+
+impl PresenceCounters {
+    /// Registra l'istanza e ne rinnova il battito finché il server è attivo.
+    async fn start_heartbeat(&self) {
+        self.heartbeat().await;
+        let counters = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(PRESENCE_HEARTBEAT_SECS));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                counters.heartbeat().await;
+            }
+        });
+    }
+
+    async fn heartbeat(&self) {
+        let mut redis_conn = self.redis_manager.lock().await;
+        let alive: Result<(), _> = redis::cmd("SET")
+            .arg(presence_alive_key(&self.instance_id))
+            .arg(1)
+            .arg("EX")
+            .arg(PRESENCE_TTL_SECS)
+            .query_async(&mut *redis_conn)
+            .await;
+        let registered: Result<(), _> = redis::cmd("SADD")
+            .arg(PRESENCE_INSTANCES)
+            .arg(&self.instance_id)
+            .query_async(&mut *redis_conn)
+            .await;
+        if let Err(e) = alive.and(registered) {
+            println!("[WS:PRESENCE] Failed to renew instance heartbeat: {}", e);
+        }
+    }
+
+    /// Aggiorna le connessioni dell'utente su questa istanza e dice se la connessione
+    /// aperta (`online`) o chiusa è una transizione: la prima aperta o l'ultima chiusa su
+    /// tutte le istanze vive. Se Redis non risponde vale `local_transition`, calcolato su
+    /// questa istanza.
+    async fn transition(&self, user_id: &str, online: bool, local_transition: bool) -> bool {
+        let mut redis_conn = self.redis_manager.lock().await;
+        let key = presence_connections_key(&self.instance_id);
+        let count: Result<i64, _> = redis::cmd("HINCRBY")
+            .arg(&key)
+            .arg(user_id)
+            .arg(if online { 1 } else { -1 })
+            .query_async(&mut *redis_conn)
+            .await;
+        let count = match count {
+            Ok(count) => count,
+            Err(e) => {
+                println!("[WS:PRESENCE] Redis counter unavailable for {}: {}", user_id, e);
+                return local_transition;
+            }
+        };
+        if count <= 0 {
+            // Sotto zero se Redis è stato riavviato con connessioni aperte: si riparte da zero
+            let _: Result<(), _> = redis::cmd("HDEL").arg(&key).arg(user_id).query_async(&mut *redis_conn).await;
+        }
+        let local_edge = if online { count == 1 } else { count <= 0 };
+        if !local_edge {
+            return false;
+        }
+        match self.connected_elsewhere(&mut redis_conn, user_id).await {
+            Ok(elsewhere) => !elsewhere,
+            Err(e) => {
+                println!("[WS:PRESENCE] Could not check other instances for {}: {}", user_id, e);
+                local_transition
+            }
+        }
+    }
+
+    /// L'utente ha connessioni su un'altra istanza viva. Le istanze senza battito vengono
+    /// rimosse insieme alle loro connessioni.
+    async fn connected_elsewhere(&self, redis_conn: &mut ConnectionManager, user_id: &str) -> redis::RedisResult<bool> {
+        let instances: Vec<String> = redis::cmd("SMEMBERS").arg(PRESENCE_INSTANCES).query_async(redis_conn).await?;
+        for instance_id in instances.iter().filter(|id| **id != self.instance_id) {
+            let alive: bool = redis::cmd("EXISTS").arg(presence_alive_key(instance_id)).query_async(redis_conn).await?;
+            if !alive {
+                println!("[WS:PRESENCE] Dropping connections of stale instance {}", instance_id);
+                let _: () = redis::cmd("DEL").arg(presence_connections_key(instance_id)).query_async(redis_conn).await?;
+                let _: () = redis::cmd("SREM").arg(PRESENCE_INSTANCES).arg(instance_id).query_async(redis_conn).await?;
+                continue;
+            }
+            let connected: bool = redis::cmd("HEXISTS").arg(presence_connections_key(instance_id)).arg(user_id).query_async(redis_conn).await?;
+            if connected {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Cancella il contributo di questa istanza (arresto del server).
+    async fn clear(&self) {
+        let mut redis_conn = self.redis_manager.lock().await;
+        let cleared: Result<(), _> = redis::cmd("DEL")
+            .arg(presence_connections_key(&self.instance_id))
+            .arg(presence_alive_key(&self.instance_id))
+            .query_async(&mut *redis_conn)
+            .await;
+        let removed: Result<(), _> = redis::cmd("SREM")
+            .arg(PRESENCE_INSTANCES)
+            .arg(&self.instance_id)
+            .query_async(&mut *redis_conn)
+            .await;
+        match cleared.and(removed) {
+            Ok(()) => println!("[WS:PRESENCE] Cleared connections of instance {}", self.instance_id),
+            Err(e) => println!("[WS:PRESENCE] Failed to clear connections of instance {}: {}", self.instance_id, e),
+        }
+    }
+}
+
+/// Amici a cui inviare gli aggiornamenti di presenza di un utente (nessuno in caso di errore).
+async fn presence_recipients(db: &Arc<Database>, user_id: &str) -> Vec<UserId> {
+    crate::server::users::friend_ids(db.clone(), user_id).await.unwrap_or_else(|e| {
+        println!("[WS:PRESENCE] Failed to load friends of {}: {}", user_id, e.message);
+        Vec::new()
+    })
+}
+
+/// Stato di presenza visibile di un utente (`online`, `away`, `busy` o `offline`) con il testo di stato.
+fn user_status_event(user_id: &str, presence: &UserInfo) -> serde_json::Value {
+    serde_json::json!({
+        "message_type": "user_status",
+        "user_id": user_id,
+        "username": presence.username,
+        "status": presence.status,
+        "status_message": presence.status_message,
+        "last_seen_at": presence.last_seen_at,
+    })
+}
+
+/// Notifica agli amici dell'utente che è entrato (`UserJoined`) o uscito (`UserLeft`),
+/// anche se sono connessi ad altre istanze. Gli utenti invisibili non vengono annunciati;
+/// all'ingresso segue `user_status` se lo stato non è un semplice `online`, all'uscita
+/// se l'utente mostra l'ultimo accesso ai suoi amici.
+async fn announce_presence(db: &Arc<Database>, relay: &EventRelay, user_id: &str, online: bool) {
+    let (presence, chosen) = match crate::server::users::user_presence(db.clone(), user_id).await {
+        Ok(presence) => presence,
+        Err(e) => {
+            println!("[WS:PRESENCE] Failed to load presence of {}: {}", user_id, e.message);
+            return;
+        }
+    };
+    if chosen == PresenceStatus::Invisible {
+        return;
+    }
+    let friends = presence_recipients(db, user_id).await;
+    if friends.is_empty() {
+        return;
+    }
+    let message = WebSocketMessage {
+        id: Uuid::new_v4().to_string(),
+        message_type: if online { MessageType::UserJoined } else { MessageType::UserLeft },
+        sender: presence.username.clone(),
+        target: user_id.to_string(),
+        content: String::new(),
+        timestamp: chrono::Utc::now().timestamp(),
+    };
+    let Ok(event) = serde_json::to_value(&message) else { return };
+    let delivered = relay.send(&friends, &event).await;
+    println!("[WS:PRESENCE] {} is now {} (delivered locally to {}/{} friends)",
+        message.sender, if online { "online" } else { "offline" }, delivered, friends.len());
+    let custom_status = presence.status != PresenceStatus::Online || presence.status_message.is_some();
+    if (online && custom_status) || (!online && presence.last_seen_at.is_some()) {
+        relay.send(&friends, &user_status_event(user_id, &presence)).await;
+    }
+}
+
+/// Invia un evento a tutti i WebSocket aperti (uno per dispositivo) degli utenti indicati.
+/// Restituisce quanti utenti lo hanno ricevuto su almeno un dispositivo.
+async fn deliver_to_users(
+    connections: &Mutex<HashMap<ClientId, WebSocketConnection>>,
+    user_connections: &Mutex<HashMap<UserId, HashSet<ClientId>>>,
+    user_ids: &[String],
+    event: &serde_json::Value,
+) -> usize {
+    let json = event.to_string();
+    let user_connections = user_connections.lock().await;
+    let connections = connections.lock().await;
+    user_ids
+        .iter()
+        .filter(|user_id| {
+            let sent = user_connections.get(*user_id).into_iter().flatten()
+                .filter_map(|client_id| connections.get(client_id))
+                .filter(|connection| connection.sender.send(Message::Text(json.clone())).is_ok())
+                .count();
+            sent > 0
+        })
+        .count()
+}
+
+/// Contenuto del messaggio `System` inviato ai client quando il server si arresta
+pub const SERVER_SHUTTING_DOWN: &str = "Server is shutting down";
+
 pub type ClientId = String;
 pub type UserId = String;
 
@@ -66,26 +767,53 @@ pub struct WebSocketConnection {
 pub struct ChatWebSocketManager {
     // Mappa client_id -> connection info
     connections: Arc<Mutex<HashMap<ClientId, WebSocketConnection>>>,
-    // Mappa user_id -> client_id (per trovare rapidamente la connessione di un utente)
-    user_connections: Arc<Mutex<HashMap<UserId, ClientId>>>,
+    // Mappa user_id -> client_id delle connessioni aperte dall'utente (una per dispositivo)
+    user_connections: Arc<Mutex<HashMap<UserId, HashSet<ClientId>>>>,
     // Broadcaster per messaggi globali
     message_broadcaster: broadcast::Sender<WebSocketMessage>,
     // Redis connection per pub/sub tra istanze server
     redis_manager: Arc<Mutex<ConnectionManager>>,
+    // Limiti di frequenza, condivisi con la porta comandi
+    rate_limiter: RateLimiter,
+    // Arresto del server: i messaggi in elaborazione vengono attesi
+    shutdown: Shutdown,
+    // Eventi effimeri per utenti specifici, inoltrati anche alle altre istanze
+    relay: EventRelay,
+    // Chi sta scrivendo in quale chat, con la scadenza degli indicatori
+    typing: TypingTracker,
+    // Connessioni di questa istanza registrate in Redis per la presenza
+    presence: PresenceCounters,
 }
 
 impl ChatWebSocketManager {
-    pub async fn new(redis_url: &str) -> anyhow::Result<Self> {
+    pub async fn new(redis_url: &str, rate_limiter: RateLimiter, shutdown: Shutdown) -> anyhow::Result<Self> {
         let client = redis::Client::open(redis_url)?;
         let redis_manager = ConnectionManager::new(client).await?;
         
         let (message_broadcaster, _) = broadcast::channel(1000);
+        let connections = Arc::new(Mutex::new(HashMap::new()));
+        let user_connections = Arc::new(Mutex::new(HashMap::new()));
+        let redis_manager = Arc::new(Mutex::new(redis_manager));
+        let instance_id = Uuid::new_v4().to_string();
+        let presence = PresenceCounters { instance_id: instance_id.clone(), redis_manager: redis_manager.clone() };
+        presence.start_heartbeat().await;
+        let relay = EventRelay {
+            instance_id,
+            connections: connections.clone(),
+            user_connections: user_connections.clone(),
+            redis_manager: redis_manager.clone(),
+        };
         
         Ok(Self {
-            connections: Arc::new(Mutex::new(HashMap::new())),
-            user_connections: Arc::new(Mutex::new(HashMap::new())),
+            connections,
+            user_connections,
             message_broadcaster,
-            redis_manager: Arc::new(Mutex::new(redis_manager)),
+            redis_manager,
+            rate_limiter,
+            shutdown,
+            typing: TypingTracker::new(relay.clone()),
+            relay,
+            presence,
         })
     }
 
@@ -118,12 +846,15 @@ impl ChatWebSocketManager {
         }
     }
 
-    pub async fn handle_authenticated_connection(
+    pub async fn handle_authenticated_connection<S>(
         &self,
-        ws_stream: WebSocketStream<tokio::net::TcpStream>,
+        ws_stream: WebSocketStream<S>,
         db: Arc<Database>,
         config: crate::server::config::ServerConfig,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         let (mut ws_sender, mut ws_receiver) = ws_stream.split();
         
         // Wait for authentication message
@@ -144,6 +875,7 @@ impl ChatWebSocketManager {
                             success: false,
                             user_id: None,
                             error: Some("Invalid message type, expected 'auth'".to_string()),
+                            code: None,
                         };
                         let _ = ws_sender.send(Message::Text(serde_json::to_string(&error_response)?)).await;
                         return Err(anyhow::anyhow!("Invalid auth message type"));
@@ -154,6 +886,7 @@ impl ChatWebSocketManager {
                             success: false,
                             user_id: None,
                             error: Some(format!("Invalid JSON: {}", e)),
+                            code: None,
                         };
                         let _ = ws_sender.send(Message::Text(serde_json::to_string(&error_response)?)).await;
                         return Err(anyhow::anyhow!("Invalid JSON in auth message"));
@@ -171,6 +904,7 @@ impl ChatWebSocketManager {
                     success: false,
                     user_id: None,
                     error: Some("Expected text message for authentication".to_string()),
+                    code: None,
                 };
                 let _ = ws_sender.send(Message::Text(serde_json::to_string(&error_response)?)).await;
                 return Err(anyhow::anyhow!("Unexpected message type during auth"));
@@ -190,6 +924,7 @@ impl ChatWebSocketManager {
                     success: false,
                     user_id: None,
                     error: Some("Authentication timeout".to_string()),
+                    code: None,
                 };
                 let _ = ws_sender.send(Message::Text(serde_json::to_string(&error_response)?)).await;
                 return Err(anyhow::anyhow!("Authentication timeout"));
@@ -204,6 +939,7 @@ impl ChatWebSocketManager {
                 success: true,
                 user_id: Some(user_id.clone()),
                 error: None,
+                code: None,
             };
             
             let _ = ws_sender.send(Message::Text(serde_json::to_string(&success_response)?)).await;
@@ -213,7 +949,7 @@ impl ChatWebSocketManager {
             let rebuilt_stream = ws_sender.reunite(ws_receiver)
                 .map_err(|e| anyhow::anyhow!("Failed to reunite WebSocket stream: {}", e))?;
             
-            return self.add_connection(rebuilt_stream, user_id, auth_message.session_token, db, config).await;
+            return self.add_connection(rebuilt_stream, user_id, db, config).await;
         } else {
             // Authentication failed
             let error_response = AuthResponse {
@@ -221,6 +957,7 @@ impl ChatWebSocketManager {
                 success: false,
                 user_id: None,
                 error: Some("Invalid or expired session token".to_string()),
+                code: None,
             };
             
             let _ = ws_sender.send(Message::Text(serde_json::to_string(&error_response)?)).await;
@@ -230,31 +967,35 @@ impl ChatWebSocketManager {
         }
     }
 
-    pub async fn add_connection(
+    pub async fn add_connection<S>(
         &self,
-        ws_stream: WebSocketStream<tokio::net::TcpStream>,
+        ws_stream: WebSocketStream<S>,
         user_id: UserId,
-        session_token: String,
         db: Arc<Database>,
         config: crate::server::config::ServerConfig,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         let client_id = Uuid::new_v4().to_string();
         let (mut ws_sender, mut ws_receiver) = ws_stream.split();
         let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
 
         // Aggiungi connessione alle mappe
-        {
+        let first_local = {
             let mut connections = self.connections.lock().await;
             let mut user_connections = self.user_connections.lock().await;
+            let first_local = !connections.values().any(|conn| conn.user_id == user_id);
             
             connections.insert(client_id.clone(), WebSocketConnection {
                 client_id: client_id.clone(),
                 user_id: user_id.clone(),
-                sender: tx,
+                sender: tx.clone(),
             });
             
-            user_connections.insert(user_id.clone(), client_id.clone());
-        }
+            user_connections.entry(user_id.clone()).or_default().insert(client_id.clone());
+            first_local
+        };
 
         // Set user online when WebSocket connects
         let _ = sqlx::query("UPDATE users SET is_online = 1 WHERE id = ?")
@@ -262,6 +1003,9 @@ impl ChatWebSocketManager {
             .execute(&db.pool)
             .await;
         println!("[WS:ONLINE] Set is_online=1 for user {} due to WebSocket connection", user_id);
+        if self.presence.transition(&user_id, true, first_local).await {
+            announce_presence(&db, &self.relay, &user_id, true).await;
+        }
 
         let connections_clone = self.connections.clone();
         let user_connections_clone = self.user_connections.clone();
@@ -269,6 +1013,12 @@ impl ChatWebSocketManager {
         let user_id_clone = user_id.clone();
         let message_broadcaster = self.message_broadcaster.clone();
         let redis_manager = self.redis_manager.clone();
+        let presence = self.presence.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let shutdown = self.shutdown.clone();
+        let typing = self.typing.clone();
+        let relay = self.relay.clone();
+        let own_sender = tx;
 
         // Task per inviare messaggi al client
         let send_task = tokio::spawn(async move {
@@ -282,15 +1032,95 @@ impl ChatWebSocketManager {
         // Task per ricevere messaggi dal client
         let db_clone = db.clone();
         let config_clone = config.clone();
-        let session_token_clone = session_token.clone();
         let receive_task = tokio::spawn(async move {
             while let Some(message) = ws_receiver.next().await {
                 match message {
                     Ok(Message::Text(text)) => {
                         println!("[WS:RECV] Received message: {}", text);
+                        // Salvataggio e inoltro vengono completati anche durante l'arresto
+                        let _in_flight = shutdown.begin_command();
+
+                        // Ogni frame del client è un invio: oltre il limite il messaggio viene
+                        // scartato con un errore, la connessione resta aperta. Gli indicatori di
+                        // scrittura, più frequenti, e le sincronizzazioni rientrano nel limite
+                        // delle richieste.
+                        let typing_frame = serde_json::from_str::<TypingFrame>(&text).ok()
+                            .filter(|f| f.message_type == "start_typing" || f.message_type == "stop_typing");
+                        let sync_frame = serde_json::from_str::<SyncFrame>(&text).ok()
+                            .filter(|f| f.message_type == "sync");
+                        let class = if typing_frame.is_some() || sync_frame.is_some() { CommandClass::Lookup } else { CommandClass::Message };
+                        if let Err(e) = rate_limiter.check(RateSubject::User(user_id_clone.clone()), class) {
+                            println!("[WS:RATE] User {} rate limited: {}", user_id_clone, e);
+                            let _ = own_sender.send(error_frame(&e));
+                            continue;
+                        }
                         
+                        // Indicatore di scrittura: inoltrato senza salvare nulla
+                        if let Some(frame) = typing_frame {
+                            match typing_target(&db_clone, &user_id_clone, &frame).await {
+                                Ok((chat_key, recipients, event)) if frame.message_type == "start_typing" => {
+                                    typing.start(&user_id_clone, chat_key, recipients, event).await;
+                                }
+                                Ok((chat_key, _, _)) => typing.stop(&user_id_clone, &chat_key).await,
+                                Err(e) => {
+                                    println!("[WS:TYPING] Typing frame from {} rejected: {}", user_id_clone, e);
+                                    let _ = own_sender.send(error_frame(&e));
+                                }
+                            }
+                        }
+                        // Eventi persi mentre il client era disconnesso
+                        else if let Some(frame) = sync_frame {
+                            replay_events(&db_clone, &user_id_clone, frame.since, &own_sender, &config_clone).await;
+                        }
+                        // Modifica di un messaggio già inviato
+                        else if let Some(edit) = serde_json::from_str::<EditMessageFrame>(&text).ok().filter(|f| f.message_type == "edit_message") {
+                            match messages::edit_message(db_clone.clone(), &user_id_clone, edit.message_id, &edit.content, &config_clone).await {
+                                Ok(edited) => {
+                                    let event = message_edited_event(&db_clone, &edited).await;
+                                    let delivered = deliver_recorded(&db_clone, &relay, &edited.chat.member_ids, &sync::EventRef::message("message_edited", &edited.chat.chat_id, edited.id), &event).await;
+                                    println!("[WS:BROADCAST] Edit of message #{} delivered locally to {}/{} members", edited.id, delivered.len(), edited.chat.member_ids.len());
+                                }
+                                Err(e) => {
+                                    println!("[WS:EDIT] Edit of message #{} by {} rejected: {}", edit.message_id, user_id_clone, e);
+                                    let _ = own_sender.send(error_frame(&e));
+                                }
+                            }
+                        }
+                        // Ritiro di un messaggio per tutti i partecipanti
+                        else if let Some(delete) = serde_json::from_str::<DeleteMessageFrame>(&text).ok().filter(|f| f.message_type == "delete_message") {
+                            match messages::delete_message(db_clone.clone(), &user_id_clone, delete.message_id).await {
+                                Ok(deleted) => {
+                                    let event = message_deleted_event(&db_clone, &deleted).await;
+                                    let delivered = deliver_recorded(&db_clone, &relay, &deleted.chat.member_ids, &sync::EventRef::message("message_deleted", &deleted.chat.chat_id, deleted.id), &event).await;
+                                    println!("[WS:BROADCAST] Deletion of message #{} delivered locally to {}/{} members", deleted.id, delivered.len(), deleted.chat.member_ids.len());
+                                    announce_inbox(&db_clone, &relay, &deleted.chat, &deleted.chat.member_ids, &config_clone).await;
+                                }
+                                Err(e) => {
+                                    println!("[WS:DELETE] Deletion of message #{} by {} rejected: {}", delete.message_id, user_id_clone, e);
+                                    let _ = own_sender.send(error_frame(&e));
+                                }
+                            }
+                        }
+                        // Aggiunta o rimozione della propria reazione a un messaggio
+                        else if let Some(reaction) = serde_json::from_str::<ReactionFrame>(&text).ok().filter(|f| f.message_type == "add_reaction" || f.message_type == "remove_reaction") {
+                            let result = match reaction.emoji.as_deref().filter(|_| reaction.message_type == "add_reaction") {
+                                Some(emoji) => messages::add_reaction(db_clone.clone(), &user_id_clone, reaction.message_id, emoji).await,
+                                None => messages::remove_reaction(db_clone.clone(), &user_id_clone, reaction.message_id).await,
+                            };
+                            match result {
+                                Ok(update) => {
+                                    let event = reactions_updated_event(&db_clone, &update).await;
+                                    let delivered = deliver_recorded(&db_clone, &relay, &update.chat.member_ids, &sync::EventRef::message("reactions_updated", &update.chat.chat_id, update.id), &event).await;
+                                    println!("[WS:BROADCAST] Reactions on message #{} delivered locally to {}/{} members", update.id, delivered.len(), update.chat.member_ids.len());
+                                }
+                                Err(e) => {
+                                    println!("[WS:REACT] Reaction to message #{} by {} rejected: {}", reaction.message_id, user_id_clone, e);
+                                    let _ = own_sender.send(error_frame(&e));
+                                }
+                            }
+                        }
                         // Try to parse as OutgoingChatMessage (client format)
-                        if let Ok(outgoing_msg) = serde_json::from_str::<OutgoingChatMessage>(&text) {
+                        else if let Ok(outgoing_msg) = serde_json::from_str::<OutgoingChatMessage>(&text) {
                             println!("[WS:RECV] Parsed OutgoingChatMessage - chat_type: {}, content: {}", outgoing_msg.chat_type, outgoing_msg.content);
                             
                             if outgoing_msg.message_type == "send_message" {
@@ -300,15 +1130,17 @@ impl ChatWebSocketManager {
                                             println!("[WS:DB] Saving private message to database...");
                                             let result = messages::send_private_message(
                                                 db_clone.clone(),
-                                                &session_token_clone,
+                                                &user_id_clone,
                                                 to_user,
                                                 &outgoing_msg.content,
+                                                outgoing_msg.client_msg_id.as_deref(),
+                                                outgoing_msg.reply_to,
                                                 &config_clone
                                             ).await;
-                                            println!("[WS:DB] Private message save result: {}", result);
+                                            println!("[WS:DB] Private message save result: {:?}", result);
                                             
                                             // Force database synchronization to ensure immediate visibility
-                                            if result.starts_with("OK:") {
+                                            if result.is_ok() {
                                                 let _ = sqlx::query("PRAGMA wal_checkpoint;")
                                                     .execute(&db_clone.pool)
                                                     .await;
@@ -316,7 +1148,7 @@ impl ChatWebSocketManager {
                                             }
                                             
                                             // If message was saved successfully, broadcast via WebSocket
-                                            if result.starts_with("OK:") {
+                                            if let Ok(stored) = result {
                                                 // Get the username from user_id
                                                 let username = match sqlx::query("SELECT username FROM users WHERE id = ?")
                                                     .bind(&user_id_clone)
@@ -334,6 +1166,12 @@ impl ChatWebSocketManager {
                                                     }
                                                 };
                                                 
+                                                // Estratto del messaggio citato, per mostrare il contesto della risposta
+                                                let quoted = match outgoing_msg.reply_to {
+                                                    Some(parent_id) => messages::quoted_message(&db_clone, &user_id_clone, parent_id, &config_clone).await,
+                                                    None => None,
+                                                };
+
                                                 // Create incoming message format for client
                                                 let incoming_msg = serde_json::json!({
                                                     "message_type": "new_message",
@@ -341,7 +1179,12 @@ impl ChatWebSocketManager {
                                                     "from_user": username,
                                                     "to_user": to_user,
                                                     "content": outgoing_msg.content,
-                                                    "timestamp": chrono::Utc::now().timestamp()
+                                                    "timestamp": stored.sent_at,
+                                                    "id": stored.id,
+                                                    "seq": stored.seq,
+                                                    "client_msg_id": outgoing_msg.client_msg_id,
+                                                    "reply_to": outgoing_msg.reply_to,
+                                                    "quoted": quoted
                                                 });
                                                 
                                                 println!("[WS:BROADCAST] Broadcasting private message via WebSocket");
@@ -357,40 +1200,38 @@ impl ChatWebSocketManager {
                                                     Ok(Some(row)) => row.get::<String, _>("id"),
                                                     Ok(None) => {
                                                         println!("[WS:ERROR] Target username '{}' not found in database", to_user);
-                                                        return;
+                                                        continue;
                                                     }
                                                     Err(e) => {
                                                         println!("[WS:ERROR] Database error getting user_id for username '{}': {}", to_user, e);
-                                                        return;
+                                                        continue;
                                                     }
                                                 };
                                                 
                                                 println!("[WS:DEBUG] Converted username '{}' to user_id '{}'", to_user, target_user_id);
                                                 
-                                                // Find the target user's connection and send directly
-                                                let user_connections_guard = user_connections_clone.lock().await;
-                                                let connections_guard = connections_clone.lock().await;
-                                                
-                                                if let Some(client_id) = user_connections_guard.get(&target_user_id) {
-                                                    if let Some(connection) = connections_guard.get(client_id) {
-                                                        let json_msg = serde_json::to_string(&incoming_msg).unwrap_or_default();
-                                                        let _ = connection.sender.send(tokio_tungstenite::tungstenite::Message::Text(json_msg));
-                                                        println!("[WS:BROADCAST] ✅ Delivered message to user {} (user_id: {})", to_user, target_user_id);
-                                                    } else {
-                                                        println!("[WS:BROADCAST] ❌ Client ID found but connection not found for user {}", to_user);
-                                                    }
+                                                // Destinatario e mittente (per gli altri suoi dispositivi e come conferma):
+                                                // l'evento resta in coda per chi non è connesso
+                                                let mut members = vec![target_user_id.clone()];
+                                                if target_user_id != user_id_clone {
+                                                    members.push(user_id_clone.clone());
+                                                }
+                                                let delivered = if stored.duplicate {
+                                                    deliver_each(&relay, members.iter().map(|m| (m.clone(), incoming_msg.clone())).collect()).await
+                                                } else {
+                                                    deliver_recorded(&db_clone, &relay, &members, &sync::EventRef::message("new_message", &stored.chat_id, stored.id), &incoming_msg).await
+                                                };
+                                                let handed_over = delivered.contains(&target_user_id);
+                                                if handed_over {
+                                                    println!("[WS:BROADCAST] ✅ Delivered message to user {} (user_id: {})", to_user, target_user_id);
                                                 } else {
-                                                    println!("[WS:BROADCAST] ❌ User {} (user_id: {}) not connected via WebSocket", to_user, target_user_id);
+                                                    println!("[WS:BROADCAST] User {} (user_id: {}) not connected here, message queued for sync", to_user, target_user_id);
                                                 }
-                                                
-                                                // Also send to sender (echo back for confirmation)
-                                                if let Some(sender_client_id) = user_connections_guard.get(&user_id_clone) {
-                                                    if let Some(sender_connection) = connections_guard.get(sender_client_id) {
-                                                        let json_msg = serde_json::to_string(&incoming_msg).unwrap_or_default();
-                                                        let _ = sender_connection.sender.send(tokio_tungstenite::tungstenite::Message::Text(json_msg));
-                                                        println!("[WS:BROADCAST] Echoed message back to sender");
-                                                    }
+
+                                                if handed_over && target_user_id != user_id_clone {
+                                                    mark_handed_over(&db_clone, &relay, stored.id, &[target_user_id]).await;
                                                 }
+                                                announce_new_message_inbox(&db_clone, &relay, &stored, &config_clone).await;
                                             }
                                         }
                                     }
@@ -399,15 +1240,17 @@ impl ChatWebSocketManager {
                                             println!("[WS:DB] Saving group message to database...");
                                             let result = messages::send_group_message(
                                                 db_clone.clone(),
-                                                &session_token_clone,
+                                                &user_id_clone,
                                                 group_id,
                                                 &outgoing_msg.content,
+                                                outgoing_msg.client_msg_id.as_deref(),
+                                                outgoing_msg.reply_to,
                                                 &config_clone
                                             ).await;
-                                            println!("[WS:DB] Group message save result: {}", result);
+                                            println!("[WS:DB] Group message save result: {:?}", result);
                                             
                                             // If message was saved successfully, broadcast via WebSocket to all group members
-                                            if result.starts_with("OK:") {
+                                            if let Ok(stored) = result {
                                                 // Get the username from user_id
                                                 let username = match sqlx::query("SELECT username FROM users WHERE id = ?")
                                                     .bind(&user_id_clone)
@@ -425,6 +1268,12 @@ impl ChatWebSocketManager {
                                                     }
                                                 };
                                                 
+                                                // Estratto del messaggio citato, per mostrare il contesto della risposta
+                                                let quoted = match outgoing_msg.reply_to {
+                                                    Some(parent_id) => messages::quoted_message(&db_clone, &user_id_clone, parent_id, &config_clone).await,
+                                                    None => None,
+                                                };
+
                                                 // Create incoming message format for clients
                                                 let incoming_msg = serde_json::json!({
                                                     "message_type": "new_message",
@@ -432,7 +1281,12 @@ impl ChatWebSocketManager {
                                                     "from_user": username,
                                                     "group_id": group_id,
                                                     "content": outgoing_msg.content,
-                                                    "timestamp": chrono::Utc::now().timestamp()
+                                                    "timestamp": stored.sent_at,
+                                                    "id": stored.id,
+                                                    "seq": stored.seq,
+                                                    "client_msg_id": outgoing_msg.client_msg_id,
+                                                    "reply_to": outgoing_msg.reply_to,
+                                                    "quoted": quoted
                                                 });
                                                 
                                                 println!("[WS:BROADCAST] Broadcasting group message via WebSocket to group {}", group_id);
@@ -448,34 +1302,24 @@ impl ChatWebSocketManager {
                                                     }
                                                     Err(e) => {
                                                         println!("[WS:ERROR] Error getting group members: {}", e);
-                                                        return;
+                                                        continue;
                                                     }
                                                 };
                                                 
                                                 println!("[WS:DEBUG] Group {} has {} members", group_id, group_members.len());
                                                 
-                                                // Broadcast to all group members
-                                                let user_connections_guard = user_connections_clone.lock().await;
-                                                let connections_guard = connections_clone.lock().await;
-                                                let json_msg = serde_json::to_string(&incoming_msg).unwrap_or_default();
-                                                
-                                                let mut delivered_count = 0;
-                                                for member_user_id in &group_members {
-                                                    if let Some(client_id) = user_connections_guard.get(member_user_id) {
-                                                        if let Some(connection) = connections_guard.get(client_id) {
-                                                            let _ = connection.sender.send(tokio_tungstenite::tungstenite::Message::Text(json_msg.clone()));
-                                                            delivered_count += 1;
-                                                            println!("[WS:BROADCAST] ✅ Delivered group message to user_id: {}", member_user_id);
-                                                        } else {
-                                                            println!("[WS:BROADCAST] ❌ Client ID found but connection not found for user_id {}", member_user_id);
-                                                        }
-                                                    } else {
-                                                        println!("[WS:BROADCAST] ⚠️ Group member {} not connected via WebSocket", member_user_id);
-                                                    }
-                                                }
-                                                
-                                                println!("[WS:BROADCAST] ✅ Delivered group message to {}/{} members in group {}", 
-                                                    delivered_count, group_members.len(), group_id);
+                                                // Broadcast to all group members: l'evento resta in coda per chi non è connesso
+                                                let delivered = if stored.duplicate {
+                                                    deliver_each(&relay, group_members.iter().map(|m| (m.clone(), incoming_msg.clone())).collect()).await
+                                                } else {
+                                                    deliver_recorded(&db_clone, &relay, &group_members, &sync::EventRef::message("new_message", &stored.chat_id, stored.id), &incoming_msg).await
+                                                };
+                                                println!("[WS:BROADCAST] ✅ Delivered group message locally to {}/{} members in group {}",
+                                                    delivered.len(), group_members.len(), group_id);
+                                                let recipients: Vec<String> = delivered.into_iter().filter(|m| *m != user_id_clone).collect();
+
+                                                mark_handed_over(&db_clone, &relay, stored.id, &recipients).await;
+                                                announce_new_message_inbox(&db_clone, &relay, &stored, &config_clone).await;
                                             }
                                         }
                                     }
@@ -495,12 +1339,14 @@ impl ChatWebSocketManager {
                                     // Save private message to database
                                     let result = messages::send_private_message(
                                         db_clone.clone(),
-                                        &session_token_clone,
+                                        &user_id_clone,
                                         &ws_message.target,
                                         &ws_message.content,
+                                        None,
+                                        None,
                                         &config_clone
                                     ).await;
-                                    println!("[WS:DB] Private message save result: {}", result);
+                                    println!("[WS:DB] Private message save result: {:?}", result);
                                 }
                                 MessageType::GroupMessage => {
                                     // TODO: Implement group message saving if needed
@@ -539,26 +1385,37 @@ impl ChatWebSocketManager {
             }
 
             // Cleanup quando la connessione si chiude
-            {
+            let last_local = {
                 let mut connections = connections_clone.lock().await;
                 let mut user_connections = user_connections_clone.lock().await;
                 
                 connections.remove(&client_id_clone);
-                user_connections.remove(&user_id_clone);
+                if let Some(clients) = user_connections.get_mut(&user_id_clone) {
+                    clients.remove(&client_id_clone);
+                    if clients.is_empty() {
+                        user_connections.remove(&user_id_clone);
+                    }
+                }
                 
                 // Set user offline when WebSocket disconnects (only if no other WebSocket connections)
-                if !user_connections.values().any(|cid| {
-                    connections.get(cid).map_or(false, |conn| conn.user_id == user_id_clone)
-                }) {
-                    let _ = sqlx::query("UPDATE users SET is_online = 0 WHERE id = ?")
+                if !connections.values().any(|conn| conn.user_id == user_id_clone) {
+                    let _ = sqlx::query("UPDATE users SET is_online = 0, last_seen_at = ? WHERE id = ?")
+                        .bind(chrono::Utc::now().timestamp())
                         .bind(&user_id_clone)
                         .execute(&db_clone.pool)
                         .await;
                     println!("[WS:OFFLINE] Set is_online=0 for user {} due to WebSocket disconnection", user_id_clone);
+                    true
                 } else {
                     println!("[WS:ONLINE] User {} still has other WebSocket connections, keeping online", user_id_clone);
+                    false
                 }
+            };
+            if presence.transition(&user_id_clone, false, last_local).await {
+                announce_presence(&db_clone, &relay, &user_id_clone, false).await;
             }
+            // Chi si disconnette smette di scrivere
+            typing.stop_all(&user_id_clone).await;
         });
 
         // Aspetta che uno dei task finisca (disconnessione)
@@ -570,14 +1427,125 @@ impl ChatWebSocketManager {
         Ok(())
     }
 
+    /// Notifica la modifica di un messaggio ai membri della chat (mittente compreso),
+    /// in coda per chi non è connesso.
+    pub async fn notify_message_edited(&self, db: &Database, edited: &messages::EditedMessage) {
+        let event = message_edited_event(db, edited).await;
+        let delivered = deliver_recorded(db, &self.relay, &edited.chat.member_ids, &sync::EventRef::message("message_edited", &edited.chat.chat_id, edited.id), &event).await;
+        println!("[WS:BROADCAST] Edit of message #{} delivered locally to {}/{} members", edited.id, delivered.len(), edited.chat.member_ids.len());
+    }
+
+    /// Notifica il ritiro di un messaggio ai membri della chat (in coda per chi non è
+    /// connesso) e ne aggiorna l'inbox: il messaggio non conta più tra i non letti e può
+    /// cambiare l'anteprima.
+    pub async fn notify_message_deleted(&self, db: &Database, deleted: &messages::DeletedMessage, config: &crate::server::config::ServerConfig) {
+        let event = message_deleted_event(db, deleted).await;
+        let delivered = deliver_recorded(db, &self.relay, &deleted.chat.member_ids, &sync::EventRef::message("message_deleted", &deleted.chat.chat_id, deleted.id), &event).await;
+        println!("[WS:BROADCAST] Deletion of message #{} delivered locally to {}/{} members", deleted.id, delivered.len(), deleted.chat.member_ids.len());
+        announce_inbox(db, &self.relay, &deleted.chat, &deleted.chat.member_ids, config).await;
+    }
+
+    /// Notifica le reazioni aggiornate di un messaggio ai membri della chat, in coda per
+    /// chi non è connesso.
+    pub async fn notify_reactions_updated(&self, db: &Database, update: &messages::ReactionUpdate) {
+        let event = reactions_updated_event(db, update).await;
+        let delivered = deliver_recorded(db, &self.relay, &update.chat.member_ids, &sync::EventRef::message("reactions_updated", &update.chat.chat_id, update.id), &event).await;
+        println!("[WS:BROADCAST] Reactions on message #{} delivered locally to {}/{} members", update.id, delivered.len(), update.chat.member_ids.len());
+    }
+
+    /// Conferme di consegna o lettura avvenute fuori dal WebSocket (storico, segna come letto).
+    pub async fn notify_receipts(&self, db: &Database, updates: &[messages::ReceiptUpdate]) {
+        deliver_receipts(db, &self.relay, updates).await;
+        if !updates.is_empty() {
+            println!("[WS:BROADCAST] {} receipt updates sent to senders", updates.len());
+        }
+    }
+
+    /// Nuovo messaggio salvato fuori dal WebSocket: lo inoltra ai membri della chat come
+    /// gli invii via WebSocket (in coda per chi non è connesso) e ne aggiorna l'inbox.
+    pub async fn notify_new_message(&self, db: &Database, stored: &messages::StoredMessage, config: &crate::server::config::ServerConfig) {
+        if stored.duplicate {
+            return;
+        }
+        let sent = match messages::sent_message(db, stored.id, config).await {
+            Ok(sent) => sent,
+            Err(e) => {
+                println!("[WS:BROADCAST] Could not load message #{}: {}", stored.id, e.message);
+                return;
+            }
+        };
+        let event = new_message_event(db, &sent).await;
+        let delivered = deliver_recorded(db, &self.relay, &sent.chat.member_ids, &sync::EventRef::message("new_message", &sent.chat.chat_id, stored.id), &event).await;
+        println!("[WS:BROADCAST] Message #{} delivered locally to {}/{} members", stored.id, delivered.len(), sent.chat.member_ids.len());
+        let recipients: Vec<String> = delivered.into_iter().filter(|m| *m != sent.sender_id).collect();
+        mark_handed_over(db, &self.relay, stored.id, &recipients).await;
+        announce_new_message_inbox(db, &self.relay, stored, config).await;
+    }
+
+    /// Richiesta di amicizia appena inviata: la notifica al destinatario, in coda se non è connesso.
+    pub async fn notify_friend_request(&self, db: &Database, from_user_id: &str, to_username: &str) {
+        let pending = match crate::server::users::pending_friend_request_id(db, from_user_id, to_username).await {
+            Ok(Some(request_id)) => crate::server::users::pending_friend_request(db, request_id).await
+                .map(|request| request.map(|request| (request_id, request))),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        let (request_id, (to_user_id, request)) = match pending {
+            Ok(Some(pending)) => pending,
+            Ok(None) => return,
+            Err(e) => {
+                println!("[WS:FRIENDS] Could not load friend request to {}: {}", to_username, e.message);
+                return;
+            }
+        };
+        let event = friend_request_event(&request);
+        let delivered = deliver_recorded(db, &self.relay, std::slice::from_ref(&to_user_id), &sync::EventRef::friend_request(request_id), &event).await;
+        println!("[WS:FRIENDS] Friend request to {} {}", to_username, if delivered.is_empty() { "queued" } else { "delivered" });
+    }
+
+    /// Invito a un gruppo appena creato: lo notifica all'invitato, in coda se non è connesso.
+    pub async fn notify_group_invite(&self, db: &Database, group_id: &str, username: &str) {
+        let (user_id, invite) = match groups::pending_invite(db, group_id, username).await {
+            Ok(Some(pending)) => pending,
+            Ok(None) => return,
+            Err(e) => {
+                println!("[WS:GROUPS] Could not load invite of {} to {}: {}", username, group_id, e.message);
+                return;
+            }
+        };
+        let event = group_invite_event(group_id, &invite);
+        let delivered = deliver_recorded(db, &self.relay, std::slice::from_ref(&user_id), &sync::EventRef::group_invite(invite.id), &event).await;
+        println!("[WS:GROUPS] Invite #{} to {} {}", invite.id, username, if delivered.is_empty() { "queued" } else { "delivered" });
+    }
+
+    /// Chat segnata come letta: azzera i non letti sugli altri dispositivi dell'utente.
+    pub async fn notify_chat_read(&self, db: &Database, user_id: &str, chat: &messages::MessageChat, config: &crate::server::config::ServerConfig) {
+        announce_inbox(db, &self.relay, chat, &[user_id.to_string()], config).await;
+    }
+
+    /// Invia agli amici la presenza visibile dell'utente dopo un cambio di stato.
+    pub async fn notify_presence(&self, db: &Arc<Database>, user_id: &str) {
+        let presence = match crate::server::users::user_presence(db.clone(), user_id).await {
+            Ok((presence, _)) => presence,
+            Err(e) => {
+                println!("[WS:PRESENCE] Failed to load presence of {}: {}", user_id, e.message);
+                return;
+            }
+        };
+        let friends = presence_recipients(db, user_id).await;
+        let delivered = self.relay.send(&friends, &user_status_event(user_id, &presence)).await;
+        println!("[WS:PRESENCE] {} appears {} (delivered locally to {}/{} friends)",
+            presence.username, presence.status.as_str(), delivered, friends.len());
+    }
+
     pub async fn send_to_user(&self, user_id: &str, message: WebSocketMessage) -> anyhow::Result<()> {
         let connections = self.connections.lock().await;
         let user_connections = self.user_connections.lock().await;
         
-        if let Some(client_id) = user_connections.get(user_id) {
+        let json_message = serde_json::to_string(&message)?;
+        for client_id in user_connections.get(user_id).into_iter().flatten() {
             if let Some(connection) = connections.get(client_id) {
-                let json_message = serde_json::to_string(&message)?;
-                let _ = connection.sender.send(Message::Text(json_message));
+                let _ = connection.sender.send(Message::Text(json_message.clone()));
             }
         }
         
@@ -611,6 +1579,34 @@ impl ChatWebSocketManager {
         self.message_broadcaster.subscribe()
     }
 
+    /// Avvisa tutti i client dell'arresto del server (messaggio `System`) e chiude
+    /// le connessioni. Restituisce il numero di client avvisati.
+    pub async fn broadcast_shutdown(&self) -> usize {
+        use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+        let notice = WebSocketMessage {
+            id: Uuid::new_v4().to_string(),
+            message_type: MessageType::System,
+            sender: "server".to_string(),
+            target: "all".to_string(),
+            content: SERVER_SHUTTING_DOWN.to_string(),
+            timestamp: chrono::Utc::now().timestamp(),
+        };
+        let json = serde_json::to_string(&notice).unwrap_or_default();
+        let connections = self.connections.lock().await;
+        for connection in connections.values() {
+            let _ = connection.sender.send(Message::Text(json.clone()));
+            let close = CloseFrame { code: CloseCode::Away, reason: "server shutting down".into() };
+            let _ = connection.sender.send(Message::Close(Some(close)));
+        }
+        connections.len()
+    }
+
+    /// Toglie da Redis le connessioni di questa istanza, che altrimenti terrebbero online
+    /// i loro utenti fino alla scadenza del battito.
+    pub async fn clear_presence(&self) {
+        self.presence.clear().await;
+    }
+
     /// Disconnette e rimuove tutte le connessioni WebSocket per un utente specifico
     pub async fn disconnect_user(&self, user_id: &str) {
         println!("[WS:CLEANUP] Disconnecting all WebSocket connections for user: {}", user_id);
@@ -618,13 +1614,14 @@ impl ChatWebSocketManager {
         let mut connections = self.connections.lock().await;
         let mut user_connections = self.user_connections.lock().await;
         
-        // Trova il client_id per questo user_id
-        if let Some(client_id) = user_connections.remove(user_id) {
-            // Chiudi la connessione inviando un messaggio di chiusura
-            if let Some(connection) = connections.remove(&client_id) {
-                // Invia messaggio di chiusura (questo farà terminare il task del WebSocket)
-                let _ = connection.sender.send(tokio_tungstenite::tungstenite::Message::Close(None));
-                println!("[WS:CLEANUP] Sent close message to WebSocket connection for user: {}", user_id);
+        // Chiude tutte le connessioni dell'utente (una per dispositivo)
+        if let Some(client_ids) = user_connections.remove(user_id) {
+            for client_id in client_ids {
+                if let Some(connection) = connections.remove(&client_id) {
+                    // Invia messaggio di chiusura (questo farà terminare il task del WebSocket)
+                    let _ = connection.sender.send(tokio_tungstenite::tungstenite::Message::Close(None));
+                    println!("[WS:CLEANUP] Sent close message to WebSocket connection {} for user: {}", client_id, user_id);
+                }
             }
         } else {
             println!("[WS:CLEANUP] No active WebSocket connection found for user: {}", user_id);
@@ -636,6 +1633,7 @@ impl ChatWebSocketManager {
         let message_broadcaster = self.message_broadcaster.clone();
         let connections = self.connections.clone();
         let user_connections = self.user_connections.clone();
+        let relay = self.relay.clone();
         
         tokio::spawn(async move {
             println!("[WS:REDIS] Starting Redis pub/sub subscriber...");
@@ -655,8 +1653,9 @@ impl ChatWebSocketManager {
                                 let _ = pubsub.subscribe("group:*").await;
                                 let _ = pubsub.subscribe("system").await;
                                 let _ = pubsub.subscribe("notifications").await;
+                                let _ = pubsub.subscribe(RELAY_CHANNEL).await;
                                 
-                                println!("[WS:REDIS] Subscribed to channels: private:*, group:*, system, notifications");
+                                println!("[WS:REDIS] Subscribed to channels: private:*, group:*, system, notifications, {}", RELAY_CHANNEL);
                                 
                                 // Listen for messages
                                 let mut stream = pubsub.on_message();
@@ -669,6 +1668,10 @@ impl ChatWebSocketManager {
                                                 Err(_) => continue,
                                             };
                                             
+                                            if channel == RELAY_CHANNEL {
+                                                relay.receive(&payload).await;
+                                                continue;
+                                            }
                                             println!("[WS:REDIS] Received message on channel '{}': {}", channel, payload);
                                             
                                             if let Ok(ws_message) = serde_json::from_str::<WebSocketMessage>(&payload) {
@@ -679,10 +1682,10 @@ impl ChatWebSocketManager {
                                                         let user_connections_guard = user_connections.lock().await;
                                                         let connections_guard = connections.lock().await;
                                                         
-                                                        if let Some(client_id) = user_connections_guard.get(&ws_message.target) {
+                                                        let json_msg = serde_json::to_string(&ws_message).unwrap_or_default();
+                                                        for client_id in user_connections_guard.get(&ws_message.target).into_iter().flatten() {
                                                             if let Some(connection) = connections_guard.get(client_id) {
-                                                                let json_msg = serde_json::to_string(&ws_message).unwrap_or_default();
-                                                                let _ = connection.sender.send(tokio_tungstenite::tungstenite::Message::Text(json_msg));
+                                                                let _ = connection.sender.send(tokio_tungstenite::tungstenite::Message::Text(json_msg.clone()));
                                                                 println!("[WS:REDIS] Delivered private message to user {}", ws_message.target);
                                                             }
                                                         }
@@ -3,9 +3,10 @@ use sysinfo::System;
 use std::{fs::OpenOptions, io::Write, sync::Arc, time::Duration};
 use tokio::time;
 use crate::server::database::Database;
+use crate::server::limits::ConnectionLimiter;
 use log::{info, error, warn};
 
-pub async fn start_performance_logger(db: Arc<Database>, log_path: &str) {
+pub async fn start_performance_logger(db: Arc<Database>, limiter: ConnectionLimiter, log_path: &str) {
     let mut system = System::new_all();
     
     // Try to create/open the log file
@@ -27,7 +28,7 @@ pub async fn start_performance_logger(db: Arc<Database>, log_path: &str) {
             error!("Failed to write header to performance log: {}", e);
             return;
         }
-        if let Err(e) = writeln!(file, "# Timestamp, Active_Users, Groups, Total_Messages, CPU_Usage") {
+        if let Err(e) = writeln!(file, "# Timestamp, Active_Users, Groups, Total_Messages, CPU_Usage, Connections, Max_Connections, Distinct_IPs, Busiest_IP, Rejected") {
             error!("Failed to write header to performance log: {}", e);
             return;
         }
@@ -69,12 +70,16 @@ pub async fn start_performance_logger(db: Arc<Database>, log_path: &str) {
             }
         };
 
+        // Connessioni aperte (TCP + WebSocket) e rifiutate dai limiti
+        let conns = limiter.stats();
+
         // Log to console
-        info!("📊 Performance - Active Users: {}, Groups: {}, Messages: {}, CPU: {:.1}%", 
-            active_users, groups, total_messages, cpu_usage);
+        info!("📊 Performance - Active Users: {}, Groups: {}, Messages: {}, CPU: {:.1}%, Connections: {}/{} from {} IPs (busiest: {}), Rejected: {}", 
+            active_users, groups, total_messages, cpu_usage, conns.active, conns.max_total, conns.distinct_ips, conns.busiest_ip, conns.rejected);
 
         // Write to file
-        if let Err(e) = writeln!(file, "{}, {}, {}, {}, {:.1}%", timestamp, active_users, groups, total_messages, cpu_usage) {
+        if let Err(e) = writeln!(file, "{}, {}, {}, {}, {:.1}%, {}, {}, {}, {}, {}", timestamp, active_users, groups, total_messages, cpu_usage,
+            conns.active, conns.max_total, conns.distinct_ips, conns.busiest_ip, conns.rejected) {
             error!("Failed to write to performance log: {}", e);
         } else if let Err(e) = file.flush() {
             error!("Failed to flush performance log: {}", e);